@@ -0,0 +1,62 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Types of the Git LFS batch API
+//! (https://github.com/git-lfs/git-lfs/blob/master/docs/api/batch.md)
+
+use std::collections::HashMap;
+
+/// Content type of batch requests and responses
+pub const LFS_CONTENT_TYPE: &str = "application/vnd.git-lfs+json";
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Operation {
+    Download,
+    Upload,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BatchRequest {
+    pub operation: Operation,
+    pub objects: Vec<RequestObject>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RequestObject {
+    pub oid: String,
+    pub size: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BatchResponse {
+    pub transfer: &'static str,
+    pub objects: Vec<ResponseObject>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ResponseObject {
+    pub oid: String,
+    pub size: u64,
+    /// Missing if the client has nothing to do, ie. it wants to upload an object that is already
+    /// stored
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub actions: Option<HashMap<Operation, Action>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<ObjectError>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Action {
+    pub href: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ObjectError {
+    /// HTTP status code that describes the error
+    pub code: u16,
+    pub message: String,
+}
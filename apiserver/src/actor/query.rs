@@ -6,12 +6,13 @@
 
 use actix::Message;
 use actix::dev::Request;
+use bytes::Bytes;
 use failure::Error;
-use futures_ext::BoxFuture;
+use futures_ext::{BoxFuture, BoxStream};
 
 use super::{MononokeRepoActor, MononokeRepoResponse};
+use super::lfs::BatchRequest;
 
-#[derive(Debug)]
 pub enum MononokeRepoQuery {
     GetRawFile {
         path: String,
@@ -21,6 +22,23 @@ pub enum MononokeRepoQuery {
         proposed_ancestor: String,
         proposed_descendent: String,
     },
+    GetCloneBundle {
+        changeset: String,
+    },
+    LfsBatch {
+        request: BatchRequest,
+        /// URL of the repo, which the links to objects are relative to
+        base_url: String,
+    },
+    DownloadLfsObject {
+        oid: String,
+    },
+    UploadLfsObject {
+        oid: String,
+        /// Size of the object in the batch request, which the content must match
+        size: u64,
+        content: BoxStream<Bytes, Error>,
+    },
 }
 
 impl Message for MononokeRepoQuery {
@@ -4,28 +4,32 @@
 // This software may be used and distributed according to the terms of the
 // GNU General Public License version 2 or any later version.
 
+use std::collections::HashMap;
 use std::str::FromStr;
 use std::sync::Arc;
 
 use actix::{Actor, Context, Handler};
+use bytes::Bytes;
 use failure::{err_msg, Error, Result, ResultExt};
-use futures::Future;
-use futures_ext::BoxFuture;
+use futures::{future, Future};
+use futures_ext::{BoxFuture, BoxStream};
 use slog::Logger;
 
 use api;
-use blobrepo::BlobRepo;
+use blobrepo::{BlobRepo, MAX_LFS_OBJECT_SIZE};
 use futures_ext::FutureExt;
-use mercurial_types::{HgNodeHash, RepositoryId};
+use mercurial_types::{HgNodeHash, RepositoryId, Sha256};
 use mercurial_types::manifest::Content;
 use metaconfig::repoconfig::RepoConfig;
-use metaconfig::repoconfig::RepoType::{BlobManifold, BlobRocks};
+use metaconfig::repoconfig::RepoType::{BlobFiles, BlobManifold, BlobRocks, BlobRocksDurable};
 use reachabilityindex::{GenerationNumberBFS, ReachabilityIndex};
 
 use errors::ErrorKind;
 use from_string as FS;
 
 use super::{MononokeRepoQuery, MononokeRepoResponse};
+use super::lfs::{Action, BatchRequest, BatchResponse, ObjectError, Operation, RequestObject,
+                 ResponseObject};
 
 pub struct MononokeRepoActor {
     repo: Arc<BlobRepo>,
@@ -37,6 +41,10 @@ impl MononokeRepoActor {
         let repoid = RepositoryId::new(config.repoid);
         let repo = match config.repotype {
             BlobRocks(ref path) => BlobRepo::new_rocksdb(logger.clone(), &path, repoid),
+            BlobRocksDurable(ref path) => {
+                BlobRepo::new_rocksdb_durable(logger.clone(), &path, repoid)
+            }
+            BlobFiles(ref path) => BlobRepo::new_files(logger.clone(), &path, repoid),
             BlobManifold { ref args, .. } => BlobRepo::new_manifold(logger.clone(), args, repoid),
             _ => Err(err_msg("Unsupported repo type.")),
         };
@@ -92,6 +100,142 @@ impl MononokeRepoActor {
             .from_err()
             .boxify())
     }
+
+    fn get_clone_bundle(
+        &self,
+        changeset: String,
+    ) -> Result<BoxFuture<MononokeRepoResponse, Error>> {
+        debug!(self.logger, "Retrieving clone bundle at changeset {}.", changeset);
+
+        let changesetid = FS::get_changeset_id(changeset)?;
+
+        Ok(api::get_clone_bundle(self.repo.clone(), changesetid)
+            .map(|content| MononokeRepoResponse::GetCloneBundle { content })
+            .from_err()
+            .boxify())
+    }
+
+    fn lfs_batch(
+        &self,
+        request: BatchRequest,
+        base_url: String,
+    ) -> Result<BoxFuture<MononokeRepoResponse, Error>> {
+        debug!(
+            self.logger,
+            "LFS batch {:?} of {} objects.",
+            request.operation,
+            request.objects.len()
+        );
+
+        let operation = request.operation;
+        let objects = request.objects.into_iter().map({
+            let repo = self.repo.clone();
+            move |object| {
+                let oid = match Sha256::from_str(&object.oid) {
+                    Ok(oid) => oid,
+                    Err(_) => {
+                        let error = lfs_object_error(422, "Invalid oid");
+                        return future::ok(lfs_batch_object(object, Err(error))).boxify();
+                    }
+                };
+                let base_url = base_url.clone();
+                repo.lfs_object_exists(&oid)
+                    .map(move |exists| {
+                        let href = |endpoint| format!("{}/lfs/{}/{}", base_url, endpoint, oid);
+                        let actions = match (operation, exists) {
+                            (Operation::Download, true) => Ok(Some(href("download"))),
+                            (Operation::Download, false) => {
+                                Err(lfs_object_error(404, "Object does not exist"))
+                            }
+                            (Operation::Upload, false) if object.size > MAX_LFS_OBJECT_SIZE => {
+                                Err(lfs_object_error(422, "Object is too large"))
+                            }
+                            // The upload is checked against the size in this request
+                            (Operation::Upload, false) => {
+                                Ok(Some(format!("{}/{}", href("upload"), object.size)))
+                            }
+                            // Nothing to do, the object is already stored
+                            (Operation::Upload, true) => Ok(None),
+                        };
+                        let actions = actions.map(|href| {
+                            href.map(|href| {
+                                let mut actions = HashMap::new();
+                                actions.insert(operation, Action { href });
+                                actions
+                            })
+                        });
+                        lfs_batch_object(object, actions)
+                    })
+                    .boxify()
+            }
+        });
+
+        Ok(future::join_all(objects)
+            .map(|objects| MononokeRepoResponse::LfsBatch {
+                response: BatchResponse {
+                    transfer: "basic",
+                    objects,
+                },
+            })
+            .boxify())
+    }
+
+    fn download_lfs_object(&self, oid: String) -> Result<BoxFuture<MononokeRepoResponse, Error>> {
+        debug!(self.logger, "Downloading LFS object {}.", oid);
+
+        let oid = FS::get_sha256(oid)?;
+
+        Ok(self.repo
+            .get_lfs_object(oid)
+            .and_then(move |object| match object {
+                Some((_, content)) => Ok(MononokeRepoResponse::DownloadLfsObject { content }),
+                None => Err(ErrorKind::NotFound(oid.to_string()).into()),
+            })
+            .boxify())
+    }
+
+    fn upload_lfs_object(
+        &self,
+        oid: String,
+        size: u64,
+        content: BoxStream<Bytes, Error>,
+    ) -> Result<BoxFuture<MononokeRepoResponse, Error>> {
+        debug!(self.logger, "Uploading LFS object {}.", oid);
+
+        let oid = FS::get_sha256(oid)?;
+
+        Ok(self.repo
+            .upload_lfs_object(oid, size, content)
+            .map(|_| MononokeRepoResponse::UploadLfsObject)
+            .boxify())
+    }
+}
+
+fn lfs_object_error(code: u16, message: &str) -> ObjectError {
+    ObjectError {
+        code,
+        message: message.into(),
+    }
+}
+
+fn lfs_batch_object(
+    RequestObject { oid, size }: RequestObject,
+    actions: ::std::result::Result<Option<HashMap<Operation, Action>>, ObjectError>,
+) -> ResponseObject {
+    match actions {
+        Ok(actions) => ResponseObject {
+            oid,
+            size,
+            actions,
+            error: None,
+        },
+        Err(error) => ResponseObject {
+            oid,
+            size,
+            actions: None,
+            error: Some(error),
+        },
+    }
 }
 
 impl Actor for MononokeRepoActor {
@@ -110,6 +254,10 @@ impl Handler<MononokeRepoQuery> for MononokeRepoActor {
                 proposed_ancestor,
                 proposed_descendent,
             } => self.is_ancestor(proposed_ancestor, proposed_descendent),
+            GetCloneBundle { changeset } => self.get_clone_bundle(changeset),
+            LfsBatch { request, base_url } => self.lfs_batch(request, base_url),
+            DownloadLfsObject { oid } => self.download_lfs_object(oid),
+            UploadLfsObject { oid, size, content } => self.upload_lfs_object(oid, size, content),
         }
     }
 }
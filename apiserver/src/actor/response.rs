@@ -8,12 +8,22 @@ use std::result::Result;
 
 use actix_web::{Body, HttpRequest, HttpResponse, Responder};
 use bytes::Bytes;
+use failure::Error;
+use futures::Stream;
+use futures_ext::BoxStream;
+use serde_json;
 
 use errors::ErrorKind;
 
+use super::lfs::{BatchResponse, LFS_CONTENT_TYPE};
+
 pub enum MononokeRepoResponse {
     GetRawFile { content: Bytes },
     IsAncestor { answer: bool },
+    GetCloneBundle { content: Bytes },
+    LfsBatch { response: BatchResponse },
+    DownloadLfsObject { content: BoxStream<Bytes, Error> },
+    UploadLfsObject,
 }
 
 fn binary_response(content: Bytes) -> HttpResponse {
@@ -38,6 +48,14 @@ impl Responder for MononokeRepoResponse {
                     "false".into()
                 }
             })),
+            GetCloneBundle { content } => Ok(binary_response(content)),
+            LfsBatch { response } => serde_json::to_vec(&response)
+                .map(|body| HttpResponse::Ok().content_type(LFS_CONTENT_TYPE).body(body))
+                .map_err(|err| ErrorKind::InternalError(err.into())),
+            DownloadLfsObject { content } => Ok(HttpResponse::Ok()
+                .content_type("application/octet-stream")
+                .streaming(content.map_err(ErrorKind::from))),
+            UploadLfsObject => Ok(HttpResponse::Ok().finish()),
         }
     }
 }
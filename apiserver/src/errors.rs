@@ -88,6 +88,11 @@ impl From<BlobRepoError> for ErrorKind {
 
         match e {
             ChangesetMissing(cs) => ErrorKind::NotFound(cs.to_string()),
+            InconsistentLfsObjectHash(expected, actual) => ErrorKind::InvalidInput(format!(
+                "content of LFS object {} (actual sha256 {})",
+                expected, actual
+            )),
+            e @ LfsObjectTooLarge(..) => ErrorKind::InvalidInput(e.to_string()),
             e => ErrorKind::InternalError(e.into()),
         }
     }
@@ -11,7 +11,7 @@ use std::str::FromStr;
 
 use failure::{Result, ResultExt};
 
-use mercurial_types::HgChangesetId;
+use mercurial_types::{HgChangesetId, Sha256};
 use mononoke_types::MPath;
 
 use errors::ErrorKind;
@@ -27,3 +27,9 @@ pub fn get_changeset_id(changesetid: String) -> Result<HgChangesetId> {
         .with_context(|_| ErrorKind::InvalidInput(changesetid))
         .map_err(From::from)
 }
+
+pub fn get_sha256(oid: String) -> Result<Sha256> {
+    Sha256::from_str(&oid)
+        .with_context(|_| ErrorKind::InvalidInput(oid))
+        .map_err(From::from)
+}
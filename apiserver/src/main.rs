@@ -28,6 +28,7 @@ extern crate secure_utils;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
+extern crate serde_json;
 #[macro_use]
 extern crate slog;
 extern crate slog_glog_fmt;
@@ -47,12 +48,15 @@ use std::path::Path;
 use std::str::FromStr;
 
 use actix::{Actor, Addr};
-use actix_web::{http, server, App, HttpRequest, HttpResponse, State};
+use actix_web::{http, server, App, HttpMessage, HttpRequest, HttpResponse, State};
 use blobrepo::BlobRepo;
 use bookmarks::Bookmark;
+use bytes::Bytes;
 use clap::Arg;
-use failure::{err_msg, Result};
-use futures::Future;
+use failure::{err_msg, Error, Result};
+use futures::{Future, Sink, Stream};
+use futures::sync::mpsc;
+use futures_ext::StreamExt;
 use slog::{Drain, Level, Logger};
 use slog_glog_fmt::{kv_categorizer, kv_defaults, GlogFormat};
 use slog_logview::LogViewDrain;
@@ -63,10 +67,15 @@ use metaconfig::RepoConfigs;
 use scuba_ext::ScubaSampleBuilder;
 
 use actor::{unwrap_request, MononokeActor, MononokeQuery, MononokeRepoQuery, MononokeRepoResponse};
+use actor::lfs::BatchRequest;
 use errors::ErrorKind;
 
 mod config {
     pub const SCUBA_TABLE: &str = "mononoke_apiserver";
+    /// Batch requests list object ids and sizes only, so they are small
+    pub const LFS_BATCH_SIZE_LIMIT: usize = 10 * 1024 * 1024;
+    /// How many chunks of an uploaded large file are buffered between the request and the repo
+    pub const LFS_UPLOAD_BUFFER: usize = 2;
 }
 
 #[derive(Deserialize)]
@@ -82,6 +91,30 @@ struct IsAncestorQueryInfo {
     proposed_ancestor: String,
     proposed_descendent: String,
 }
+
+#[derive(Deserialize)]
+struct CloneBundleQueryInfo {
+    repo: String,
+    changeset: String,
+}
+
+#[derive(Deserialize)]
+struct LfsBatchQueryInfo {
+    repo: String,
+}
+
+#[derive(Deserialize)]
+struct LfsObjectQueryInfo {
+    repo: String,
+    oid: String,
+}
+
+#[derive(Deserialize)]
+struct LfsUploadQueryInfo {
+    repo: String,
+    oid: String,
+    size: u64,
+}
 // The argument of this function is because the trait `actix_web::FromRequest` is implemented
 // for tuple (A, B, ...) (up to 9 elements) [1]. These arguments must implement
 // `actix_web::FromRequest` as well so actix-web will try to extract them from `actix::HttpRequest`
@@ -111,6 +144,91 @@ fn is_ancestor(
     }))
 }
 
+fn get_clone_bundle(
+    (state, info): (State<HttpServerState>, actix_web::Path<CloneBundleQueryInfo>),
+) -> impl Future<Item = MononokeRepoResponse, Error = ErrorKind> {
+    unwrap_request(state.mononoke.send(MononokeQuery {
+        repo: info.repo.clone(),
+        kind: MononokeRepoQuery::GetCloneBundle {
+            changeset: info.changeset.clone(),
+        },
+    }))
+}
+
+// The batch request is parsed by hand, as the `Json` extractor refuses the content type Git LFS
+// uses.
+fn lfs_batch(
+    (state, info, req): (
+        State<HttpServerState>,
+        actix_web::Path<LfsBatchQueryInfo>,
+        HttpRequest<HttpServerState>,
+    ),
+) -> impl Future<Item = MononokeRepoResponse, Error = ErrorKind> {
+    let base_url = {
+        let conn = req.connection_info();
+        format!("{}://{}/{}", conn.scheme(), conn.host(), info.repo)
+    };
+
+    req.body()
+        .limit(config::LFS_BATCH_SIZE_LIMIT)
+        .map_err(|err| ErrorKind::InvalidInput(err.to_string()))
+        .and_then(|body| {
+            serde_json::from_slice::<BatchRequest>(&body)
+                .map_err(|err| ErrorKind::InvalidInput(err.to_string()))
+        })
+        .and_then(move |request| {
+            unwrap_request(state.mononoke.send(MononokeQuery {
+                repo: info.repo.clone(),
+                kind: MononokeRepoQuery::LfsBatch { request, base_url },
+            }))
+        })
+}
+
+fn download_lfs_object(
+    (state, info): (State<HttpServerState>, actix_web::Path<LfsObjectQueryInfo>),
+) -> impl Future<Item = MononokeRepoResponse, Error = ErrorKind> {
+    unwrap_request(state.mononoke.send(MononokeQuery {
+        repo: info.repo.clone(),
+        kind: MononokeRepoQuery::DownloadLfsObject {
+            oid: info.oid.clone(),
+        },
+    }))
+}
+
+// The request payload can't be sent to the repo actor, so it is forwarded through a channel. The
+// channel is bounded, so the payload is only read as fast as the repo takes it.
+fn upload_lfs_object(
+    (state, info, req): (
+        State<HttpServerState>,
+        actix_web::Path<LfsUploadQueryInfo>,
+        HttpRequest<HttpServerState>,
+    ),
+) -> impl Future<Item = MononokeRepoResponse, Error = ErrorKind> {
+    let (sender, receiver) =
+        mpsc::channel::<std::result::Result<Bytes, Error>>(config::LFS_UPLOAD_BUFFER);
+
+    let payload = req.payload()
+        .then(|chunk| Ok::<_, ()>(chunk.map_err(Error::from)));
+    // Sending fails if the upload was aborted and the receiver is gone, there's nothing to do then
+    actix::Arbiter::spawn(sender.sink_map_err(|_| ()).send_all(payload).map(|_| ()));
+
+    let content = receiver
+        .then(|chunk| match chunk {
+            Ok(chunk) => chunk,
+            Err(()) => Err(err_msg("upload payload channel failed")),
+        })
+        .boxify();
+
+    unwrap_request(state.mononoke.send(MononokeQuery {
+        repo: info.repo.clone(),
+        kind: MononokeRepoQuery::UploadLfsObject {
+            oid: info.oid.clone(),
+            size: info.size,
+            content,
+        },
+    }))
+}
+
 fn setup_logger(debug: bool) -> Logger {
     let level = if debug { Level::Debug } else { Level::Info };
 
@@ -197,23 +315,30 @@ fn main() -> Result<()> {
             Arg::with_name("config-path")
                 .long("config-path")
                 .value_name("PATH")
-                .required(true)
+                .required_unless("config-dir")
                 .help("directory of the config repository"),
         )
         .arg(
             Arg::with_name("config-bookmark")
                 .long("config-bookmark")
                 .value_name("BOOKMARK")
-                .required_unless("config-commit")
+                .required_unless_one(&["config-commit", "config-dir"])
                 .help("bookmark of the config repository"),
         )
         .arg(
             Arg::with_name("config-commit")
                 .long("config-commit")
                 .value_name("HASH")
-                .required_unless("config-bookmark")
+                .required_unless_one(&["config-bookmark", "config-dir"])
                 .help("commit hash of the config repository"),
         )
+        .arg(
+            Arg::with_name("config-dir")
+                .long("config-dir")
+                .value_name("PATH")
+                .conflicts_with_all(&["config-path", "config-bookmark", "config-commit"])
+                .help("directory with the config files, instead of a config repository"),
+        )
         .arg(
             Arg::with_name("ssl-certificate")
                 .long("ssl-certificate")
@@ -271,14 +396,20 @@ fn main() -> Result<()> {
 
     let sys = actix::System::new("mononoke-apiserver");
 
-    let repo_configs = create_config(
-        &root_logger,
-        matches
-            .value_of("config-path")
-            .expect("must set config-path"),
-        matches.value_of("config-bookmark"),
-        matches.value_of("config-commit"),
-    )?;
+    let repo_configs = match matches.value_of("config-dir") {
+        Some(config_dir) => {
+            info!(root_logger, "Reading config from directory: {}", config_dir);
+            RepoConfigs::read_from_dir(config_dir).wait()?
+        }
+        None => create_config(
+            &root_logger,
+            matches
+                .value_of("config-path")
+                .expect("must set config-path"),
+            matches.value_of("config-bookmark"),
+            matches.value_of("config-commit"),
+        )?,
+    };
 
     let addr =
         MononokeActor::create(move |_| MononokeActor::new(mononoke_logger.clone(), repo_configs));
@@ -316,7 +447,15 @@ fn main() -> Result<()> {
                 }).resource(
                     "/is_ancestor/{proposed_ancestor}/{proposed_descendent}",
                     |r| r.method(http::Method::GET).with_async(is_ancestor),
-                )
+                ).resource("/clonebundles/{changeset}", |r| {
+                    r.method(http::Method::GET).with_async(get_clone_bundle)
+                }).resource("/objects/batch", |r| {
+                    r.method(http::Method::POST).with_async(lfs_batch)
+                }).resource("/lfs/download/{oid}", |r| {
+                    r.method(http::Method::GET).with_async(download_lfs_object)
+                }).resource("/lfs/upload/{oid}/{size}", |r| {
+                    r.method(http::Method::PUT).with_async(upload_lfs_object)
+                })
             })
     });
 
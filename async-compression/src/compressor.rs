@@ -13,7 +13,7 @@ use std::result;
 use bzip2;
 use bzip2::write::BzEncoder;
 use flate2;
-use flate2::write::GzEncoder;
+use flate2::write::{GzEncoder, ZlibEncoder};
 use futures::Poll;
 use tokio_io::AsyncWrite;
 
@@ -25,6 +25,7 @@ use retry::retry_write;
 pub enum CompressorType {
     Bzip2(bzip2::Compression),
     Gzip(flate2::Compression),
+    Zlib(flate2::Compression),
     Zstd { level: i32 },
 }
 
@@ -33,6 +34,7 @@ impl CompressorType {
         match self {
             &CompressorType::Bzip2(_) => DecompressorType::Bzip2,
             &CompressorType::Gzip(_) => DecompressorType::Gzip,
+            &CompressorType::Zlib(_) => DecompressorType::Zlib,
             &CompressorType::Zstd { .. } => DecompressorType::Zstd,
         }
     }
@@ -56,6 +58,7 @@ where
             inner: match ct {
                 CompressorType::Bzip2(level) => Box::new(BzEncoder::new(w, level)),
                 CompressorType::Gzip(level) => Box::new(GzEncoder::new(w, level)),
+                CompressorType::Zlib(level) => Box::new(ZlibEncoder::new(w, level)),
                 CompressorType::Zstd { level } => Box::new(AsyncZstdEncoder::new(w, level)),
             },
         }
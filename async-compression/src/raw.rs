@@ -14,8 +14,8 @@ use tokio_io::AsyncWrite;
 
 use bzip2::bufread::BzDecoder;
 use bzip2::write::BzEncoder;
-use flate2::bufread::GzDecoder;
-use flate2::write::GzEncoder;
+use flate2::bufread::{GzDecoder, ZlibDecoder};
+use flate2::write::{GzEncoder, ZlibEncoder};
 use zstd::Encoder as ZstdEncoder;
 
 pub trait RawDecoder<R: BufRead>: Read {
@@ -58,6 +58,23 @@ impl<R: BufRead> RawDecoder<R> for GzDecoder<R> {
     }
 }
 
+impl<R: BufRead> RawDecoder<R> for ZlibDecoder<R> {
+    #[inline]
+    fn get_ref(&self) -> &R {
+        ZlibDecoder::get_ref(self)
+    }
+
+    #[inline]
+    fn get_mut(&mut self) -> &mut R {
+        ZlibDecoder::get_mut(self)
+    }
+
+    #[inline]
+    fn into_inner(self: Box<Self>) -> R {
+        ZlibDecoder::into_inner(*self)
+    }
+}
+
 pub trait RawEncoder<W>: AsyncWrite
 where
     W: AsyncWrite + Send,
@@ -95,6 +112,21 @@ where
     }
 }
 
+impl<W> RawEncoder<W> for ZlibEncoder<W>
+where
+    W: AsyncWrite + Send + 'static,
+{
+    #[inline]
+    fn try_finish(
+        mut self: Box<Self>,
+    ) -> result::Result<W, (Box<RawEncoder<W> + Send>, io::Error)> {
+        match ZlibEncoder::try_finish(&mut self) {
+            Ok(()) => Ok(ZlibEncoder::finish(*self).unwrap()),
+            Err(e) => Err((self, e)),
+        }
+    }
+}
+
 /// A wrapper around ZstdEncoder which depends on and implements AsyncWrite.
 ///
 /// The sole purpose of this struct is to work around the orphan rule: you
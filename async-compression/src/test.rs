@@ -30,6 +30,10 @@ quickcheck! {
         roundtrip(CompressorType::Gzip(cmprs.0), &input)
     }
 
+    fn test_zlib_roundtrip(cmprs: GzipCompression, input: Vec<u8>) -> TestResult {
+        roundtrip(CompressorType::Zlib(cmprs.0), &input)
+    }
+
     fn test_bzip_overreading(
         cmprs: BzipCompression,
         compressable_input: Vec<u8>,
@@ -53,6 +57,18 @@ quickcheck! {
             extra_input.as_slice(),
         )
     }
+
+    fn test_zlib_overreading(
+        cmprs: GzipCompression,
+        compressable_input: Vec<u8>,
+        extra_input: Vec<u8>
+    ) -> TestResult {
+        check_overreading(
+            CompressorType::Zlib(cmprs.0),
+            compressable_input.as_slice(),
+            extra_input.as_slice(),
+        )
+    }
 }
 
 #[derive(Debug, Clone)]
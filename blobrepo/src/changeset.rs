@@ -219,6 +219,11 @@ impl BlobChangeset {
     pub fn p2(&self) -> Option<&HgNodeHash> {
         self.content.p2()
     }
+
+    /// Name of the named branch the changeset is on
+    pub fn branch(&self) -> &[u8] {
+        self.content.extra.branch()
+    }
 }
 
 impl Changeset for BlobChangeset {
@@ -8,11 +8,12 @@ use std::fmt;
 
 use ascii::AsciiString;
 use bincode;
+use bookmarks::Bookmark;
 
 pub use failure::prelude::*;
 
 use mercurial_types::{HgBlob, HgBlobHash, HgChangesetId, HgFileNodeId, HgNodeHash, HgParents,
-                      MPath, RepoPath, Type};
+                      MPath, RepoPath, Sha256, Type};
 use mononoke_types::ContentId;
 
 use BlobChangeset;
@@ -25,6 +26,8 @@ pub enum StateOpenError {
     Changesets,
     Filenodes,
     BonsaiHgMapping,
+    Phases,
+    Obsmarkers,
 }
 
 impl fmt::Display for StateOpenError {
@@ -38,6 +41,8 @@ impl fmt::Display for StateOpenError {
             Changesets => write!(f, "changesets"),
             Filenodes => write!(f, "filenodes"),
             BonsaiHgMapping => write!(f, "bonsai_hg_mapping"),
+            Phases => write!(f, "phases"),
+            Obsmarkers => write!(f, "obsmarkers"),
         }
     }
 }
@@ -99,4 +104,15 @@ pub enum ErrorKind {
     #[fail(display = "Empty file path")] EmptyFilePath,
     #[fail(display = "Memory manifest conflict can not contain single entry")] SingleEntryConflict,
     #[fail(display = "Cannot find cache pool {}", _0)] MissingCachePool(String),
+    #[fail(display = "Inconsistent LFS object hash: provided: {}, computed: {}", _0, _1)]
+    InconsistentLfsObjectHash(Sha256, Sha256),
+    #[fail(display = "LFS object {} of size {} is larger than the maximum of {}", _0, _1, _2)]
+    LfsObjectTooLarge(Sha256, u64, u64),
+    #[fail(display = "Inconsistent size of LFS object {}: expected: {}, uploaded: {}", _0, _1, _2)]
+    InconsistentLfsObjectSize(Sha256, u64, u64),
+    #[fail(display = "Revlog flags of file {} are corrupt", _0)] CorruptFileRevlogFlags(HgNodeHash),
+    #[fail(display = "Index of LFS object {} is corrupt", _0)] CorruptLfsObjectIndex(Sha256),
+    #[fail(display = "Scratch bookmark {} is corrupt", _0)] CorruptScratchBookmark(Bookmark),
+    #[fail(display = "Chunk {} of LFS object {} is missing", _1, _0)]
+    LfsChunkMissing(Sha256, Sha256),
 }
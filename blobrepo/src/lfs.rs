@@ -0,0 +1,350 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Storage of large files (see `mercurial_types::lfs`)
+//!
+//! A file is split into chunks that are stored under their own SHA-256, and an index that lists
+//! the chunks is stored under the SHA-256 of the whole file. An uploaded file is held in memory
+//! until its size and hash are verified, so that content that doesn't match its pointer never
+//! reaches the blobstore, which is why uploads are limited to `MAX_LFS_OBJECT_SIZE`. Files are
+//! streamed chunk by chunk when they are read. The index is written last, so a file is only
+//! visible once all of its chunks are stored.
+
+use std::cmp;
+use std::str::{self, FromStr};
+
+use bytes::{Bytes, BytesMut};
+use futures::{stream, Future, IntoFuture, Stream};
+use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
+
+use blobstore::Blobstore;
+use mercurial_types::HgNodeHash;
+use mercurial_types::lfs::{Sha256, Sha256Context};
+use mononoke_types::BlobstoreBytes;
+
+use errors::*;
+
+/// Size of the chunks large files are split into
+const LFS_CHUNK_SIZE: usize = 4 * 1024 * 1024;
+
+/// How many chunks are fetched ahead of the one that is being sent
+const LFS_CHUNK_PREFETCH: usize = 2;
+
+/// Largest file that can be uploaded
+pub const MAX_LFS_OBJECT_SIZE: u64 = 512 * 1024 * 1024;
+
+fn lfs_object_key(oid: &Sha256) -> String {
+    format!("lfs.sha256.{}", oid)
+}
+
+fn lfs_chunk_key(chunk: &Sha256) -> String {
+    format!("lfs.chunk.sha256.{}", chunk)
+}
+
+fn file_revlog_flags_key(node: &HgNodeHash) -> String {
+    format!("hgfilenode.flags.sha1.{}", node)
+}
+
+/// Index of a large file: the size of the file on the first line, then the SHA-256 of each chunk
+/// in order, one per line
+struct LfsObjectIndex {
+    size: u64,
+    chunks: Vec<Sha256>,
+}
+
+impl LfsObjectIndex {
+    fn serialize(&self) -> Bytes {
+        let mut index = format!("{}\n", self.size);
+        for chunk in &self.chunks {
+            index.push_str(&format!("{}\n", chunk));
+        }
+        Bytes::from(index)
+    }
+
+    fn deserialize(oid: Sha256, bytes: &[u8]) -> Result<Self> {
+        let corrupt = || Error::from(ErrorKind::CorruptLfsObjectIndex(oid));
+
+        let index = str::from_utf8(bytes).map_err(|_| corrupt())?;
+        let mut lines = index.lines();
+        let size = lines
+            .next()
+            .and_then(|size| u64::from_str(size).ok())
+            .ok_or_else(corrupt)?;
+        let chunks = lines
+            .map(|chunk| Sha256::from_str(chunk).map_err(|_| corrupt()))
+            .collect::<Result<_>>()?;
+
+        Ok(LfsObjectIndex { size, chunks })
+    }
+}
+
+struct UploadState {
+    context: Sha256Context,
+    size: u64,
+    buffer: BytesMut,
+}
+
+pub fn lfs_object_exists<B: Blobstore>(blobstore: &B, oid: &Sha256) -> BoxFuture<bool, Error> {
+    blobstore.is_present(lfs_object_key(oid))
+}
+
+pub fn upload_lfs_object<B, S>(
+    blobstore: B,
+    oid: Sha256,
+    size: u64,
+    content: S,
+) -> BoxFuture<u64, Error>
+where
+    B: Blobstore + Clone,
+    S: Stream<Item = Bytes, Error = Error> + Send + 'static,
+{
+    if size > MAX_LFS_OBJECT_SIZE {
+        return Err(ErrorKind::LfsObjectTooLarge(oid, size, MAX_LFS_OBJECT_SIZE).into())
+            .into_future()
+            .boxify();
+    }
+    let state = UploadState {
+        context: Sha256Context::new(),
+        size: 0,
+        buffer: BytesMut::new(),
+    };
+
+    content
+        .fold(state, move |mut state, bytes| {
+            state.context.update(&bytes);
+            state.size += bytes.len() as u64;
+            // Stop buffering as soon as the content is too large
+            if state.size > size {
+                return Err(ErrorKind::InconsistentLfsObjectSize(oid, size, state.size).into());
+            }
+            state.buffer.extend_from_slice(&bytes);
+            Ok::<_, Error>(state)
+        })
+        .and_then(move |state| {
+            if state.size != size {
+                return Err(ErrorKind::InconsistentLfsObjectSize(oid, size, state.size).into())
+                    .into_future()
+                    .boxify();
+            }
+            let actual = state.context.finish();
+            if actual != oid {
+                return Err(ErrorKind::InconsistentLfsObjectHash(oid, actual).into())
+                    .into_future()
+                    .boxify();
+            }
+
+            let mut buffer = state.buffer;
+            let mut chunks = vec![];
+            while !buffer.is_empty() {
+                let len = cmp::min(buffer.len(), LFS_CHUNK_SIZE);
+                chunks.push(buffer.split_to(len).freeze());
+            }
+            put_chunks(blobstore.clone(), chunks)
+                .and_then(move |chunks| {
+                    let index = LfsObjectIndex { size, chunks };
+                    blobstore
+                        .put(
+                            lfs_object_key(&oid),
+                            BlobstoreBytes::from_bytes(index.serialize()),
+                        )
+                        .map(move |()| index.size)
+                })
+                .boxify()
+        })
+        .boxify()
+}
+
+/// Store chunks one after another, so that at most one is in flight
+fn put_chunks<B>(blobstore: B, chunks: Vec<Bytes>) -> BoxFuture<Vec<Sha256>, Error>
+where
+    B: Blobstore + Clone,
+{
+    stream::iter_ok(chunks)
+        .and_then(move |chunk| {
+            let chunk_id = Sha256::from(chunk.as_ref());
+            blobstore
+                .put(lfs_chunk_key(&chunk_id), BlobstoreBytes::from_bytes(chunk))
+                .map(move |()| chunk_id)
+        })
+        .collect()
+        .boxify()
+}
+
+pub fn get_lfs_object<B>(
+    blobstore: B,
+    oid: Sha256,
+) -> BoxFuture<Option<(u64, BoxStream<Bytes, Error>)>, Error>
+where
+    B: Blobstore + Clone,
+{
+    blobstore
+        .get(lfs_object_key(&oid))
+        .and_then(move |index| {
+            let index = match index {
+                Some(index) => LfsObjectIndex::deserialize(oid, index.as_bytes())?,
+                None => return Ok(None),
+            };
+
+            let content = stream::iter_ok(index.chunks)
+                .map(move |chunk| {
+                    blobstore.get(lfs_chunk_key(&chunk)).and_then(move |bytes| {
+                        bytes
+                            .map(BlobstoreBytes::into_bytes)
+                            .ok_or(ErrorKind::LfsChunkMissing(oid, chunk).into())
+                    })
+                })
+                .buffered(LFS_CHUNK_PREFETCH)
+                .boxify();
+            Ok(Some((index.size, content)))
+        })
+        .boxify()
+}
+
+/// Store the revlog flags of a file revision. Only revisions that have flags are stored, the
+/// others have no flags.
+pub fn put_file_revlog_flags<B: Blobstore>(
+    blobstore: &B,
+    node: &HgNodeHash,
+    flags: u16,
+) -> BoxFuture<(), Error> {
+    blobstore.put(
+        file_revlog_flags_key(node),
+        BlobstoreBytes::from_bytes(Bytes::from(flags.to_string())),
+    )
+}
+
+pub fn get_file_revlog_flags<B: Blobstore>(
+    blobstore: &B,
+    node: HgNodeHash,
+) -> BoxFuture<u16, Error> {
+    blobstore
+        .get(file_revlog_flags_key(&node))
+        .and_then(move |flags| match flags {
+            Some(flags) => str::from_utf8(flags.as_bytes())
+                .ok()
+                .and_then(|flags| u16::from_str(flags).ok())
+                .ok_or(ErrorKind::CorruptFileRevlogFlags(node).into()),
+            None => Ok(0),
+        })
+        .boxify()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use blobstore::EagerMemblob;
+
+    fn upload_and_fetch(content: Vec<Bytes>) -> (Vec<Bytes>, Vec<u8>) {
+        let blobstore = EagerMemblob::new();
+        let all_content: Vec<u8> = content.iter().flat_map(|bytes| bytes.to_vec()).collect();
+        let oid = Sha256::from(all_content.as_slice());
+
+        let size = all_content.len() as u64;
+        let size = upload_lfs_object(blobstore.clone(), oid, size, stream::iter_ok(content))
+            .wait()
+            .unwrap();
+        assert_eq!(size, all_content.len() as u64);
+        assert!(lfs_object_exists(&blobstore, &oid).wait().unwrap());
+
+        let (size, fetched) = get_lfs_object(blobstore, oid).wait().unwrap().unwrap();
+        assert_eq!(size, all_content.len() as u64);
+        (fetched.collect().wait().unwrap(), all_content)
+    }
+
+    #[test]
+    fn test_upload_small() {
+        let content = vec![Bytes::from("foo"), Bytes::from("bar")];
+        let (fetched, all_content) = upload_and_fetch(content);
+        assert_eq!(fetched, vec![Bytes::from(all_content)]);
+    }
+
+    #[test]
+    fn test_upload_empty() {
+        let (fetched, all_content) = upload_and_fetch(vec![]);
+        assert!(fetched.is_empty());
+        assert!(all_content.is_empty());
+    }
+
+    #[test]
+    fn test_upload_chunked() {
+        let piece = Bytes::from(vec![b'x'; LFS_CHUNK_SIZE / 3 + 1]);
+        let content = vec![piece; 7];
+        let (fetched, all_content) = upload_and_fetch(content);
+
+        // 7 pieces of a bit more than a third of a chunk fill 2 chunks, and the rest goes into
+        // a third one
+        let sizes: Vec<_> = fetched.iter().map(|chunk| chunk.len()).collect();
+        assert_eq!(
+            sizes,
+            vec![
+                LFS_CHUNK_SIZE,
+                LFS_CHUNK_SIZE,
+                all_content.len() - 2 * LFS_CHUNK_SIZE,
+            ]
+        );
+        let fetched: Vec<u8> = fetched.iter().flat_map(|chunk| chunk.to_vec()).collect();
+        assert_eq!(fetched, all_content);
+    }
+
+    #[test]
+    fn test_upload_wrong_hash() {
+        let blobstore = EagerMemblob::new();
+        let oid = Sha256::from(&b"foo"[..]);
+
+        let content = stream::iter_ok(vec![Bytes::from("bar")]);
+        assert!(
+            upload_lfs_object(blobstore.clone(), oid, 3, content)
+                .wait()
+                .is_err()
+        );
+        assert!(!lfs_object_exists(&blobstore, &oid).wait().unwrap());
+        // Nothing is stored for content that doesn't match its hash
+        let chunk = Sha256::from(&b"bar"[..]);
+        assert!(!blobstore.is_present(lfs_chunk_key(&chunk)).wait().unwrap());
+        assert!(get_lfs_object(blobstore, oid).wait().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_upload_too_large() {
+        let blobstore = EagerMemblob::new();
+        let oid = Sha256::from(&b"foo"[..]);
+
+        let content = stream::iter_ok(vec![Bytes::from("foo")]);
+        assert!(
+            upload_lfs_object(blobstore.clone(), oid, MAX_LFS_OBJECT_SIZE + 1, content)
+                .wait()
+                .is_err()
+        );
+        assert!(!lfs_object_exists(&blobstore, &oid).wait().unwrap());
+    }
+
+    #[test]
+    fn test_upload_wrong_size() {
+        let blobstore = EagerMemblob::new();
+        let oid = Sha256::from(&b"foobar"[..]);
+
+        for size in &[5, 7] {
+            let content = stream::iter_ok(vec![Bytes::from("foo"), Bytes::from("bar")]);
+            assert!(
+                upload_lfs_object(blobstore.clone(), oid, *size, content)
+                    .wait()
+                    .is_err()
+            );
+            assert!(!lfs_object_exists(&blobstore, &oid).wait().unwrap());
+        }
+    }
+
+    #[test]
+    fn test_file_revlog_flags() {
+        let blobstore = EagerMemblob::new();
+        let node = HgNodeHash::from_static_str("1111111111111111111111111111111111111111").unwrap();
+
+        assert_eq!(get_file_revlog_flags(&blobstore, node).wait().unwrap(), 0);
+        put_file_revlog_flags(&blobstore, &node, 1 << 13).wait().unwrap();
+        assert_eq!(get_file_revlog_flags(&blobstore, node).wait().unwrap(), 1 << 13);
+    }
+}
@@ -46,6 +46,8 @@ extern crate manifoldblob;
 extern crate mercurial;
 extern crate mercurial_types;
 extern crate mononoke_types;
+extern crate obsmarkers;
+extern crate phases;
 extern crate rocksblob;
 extern crate rocksdb;
 extern crate scuba_ext;
@@ -61,6 +63,7 @@ extern crate mercurial_types_mocks;
 mod changeset;
 mod errors;
 mod file;
+mod lfs;
 mod manifest;
 mod memory_manifest;
 mod repo;
@@ -70,6 +73,7 @@ pub use errors::*;
 
 pub use changeset::{BlobChangeset, ChangesetContent};
 pub use file::HgBlobEntry;
+pub use lfs::MAX_LFS_OBJECT_SIZE;
 pub use manifest::BlobManifest;
 pub use repo::{BlobRepo, ChangesetMetadata, ContentBlobInfo, ContentBlobMeta, CreateChangeset,
                ManifoldArgs, UploadHgFileContents, UploadHgFileEntry, UploadHgNodeHash,
@@ -4,9 +4,11 @@
 // This software may be used and distributed according to the terms of the
 // GNU General Public License version 2 or any later version.
 
-use std::collections::{BTreeMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
 use std::mem;
 use std::path::Path;
+use std::str::{self, FromStr};
 use std::sync::Arc;
 use std::time::Duration;
 use std::usize;
@@ -40,11 +42,14 @@ use filenodes::{CachingFilenodes, FilenodeInfo, Filenodes};
 use manifoldblob::ManifoldBlob;
 use mercurial::file::File;
 use mercurial_types::{Changeset, Entry, HgBlob, HgBlobNode, HgChangesetId, HgFileEnvelopeMut,
-                      HgFileNodeId, HgManifestEnvelopeMut, HgManifestId, HgNodeHash, HgParents,
-                      Manifest, RepoPath, RepositoryId, Type};
+                      HgFileNodeId, HgManifestEnvelopeMut, HgManifestId, HgNodeHash, HgObsmarker,
+                      HgParents, Manifest, RepoPath, RepositoryId, Sha256, Type};
 use mercurial_types::manifest::Content;
-use mononoke_types::{Blob, BlobstoreValue, BonsaiChangeset, ContentId, DateTime, FileChange,
-                     FileContents, FileType, Generation, MPath, MPathElement, MononokeId};
+use mononoke_types::{Blob, BlobstoreBytes, BlobstoreValue, BonsaiChangeset, ContentId, DateTime,
+                     FileChange, FileContents, FileType, Generation, MPath, MPathElement,
+                     MononokeId};
+use obsmarkers::{MysqlObsmarkers, Obsmarkers, SqliteObsmarkers};
+use phases::{MysqlPhases, Phase, Phases, SqlitePhases};
 use rocksblob::Rocksblob;
 use rocksdb;
 
@@ -52,6 +57,8 @@ use BlobChangeset;
 use BlobManifest;
 use errors::*;
 use file::{fetch_file_content_and_renames_from_blobstore, fetch_raw_filenode_bytes, HgBlobEntry};
+use lfs::{get_file_revlog_flags, get_lfs_object, lfs_object_exists, put_file_revlog_flags,
+          upload_lfs_object};
 use memory_manifest::MemoryRootManifest;
 use repo_commit::*;
 
@@ -71,10 +78,24 @@ define_stats! {
     get_bookmark: timeseries(RATE, SUM),
     get_bookmarks: timeseries(RATE, SUM),
     update_bookmark_transaction: timeseries(RATE, SUM),
+    get_scratch_bookmark: timeseries(RATE, SUM),
+    set_scratch_bookmark: timeseries(RATE, SUM),
     get_linknode: timeseries(RATE, SUM),
     get_all_filenodes: timeseries(RATE, SUM),
     get_generation_number: timeseries(RATE, SUM),
+    get_phases: timeseries(RATE, SUM),
+    add_draft: timeseries(RATE, SUM),
+    get_drafts: timeseries(RATE, SUM),
+    get_draft_roots: timeseries(RATE, SUM),
+    mark_public: timeseries(RATE, SUM),
+    add_obsmarkers: timeseries(RATE, SUM),
+    get_relevant_obsmarkers: timeseries(RATE, SUM),
     upload_blob: timeseries(RATE, SUM),
+    lfs_object_exists: timeseries(RATE, SUM),
+    upload_lfs_object: timeseries(RATE, SUM),
+    get_lfs_object: timeseries(RATE, SUM),
+    put_file_revlog_flags: timeseries(RATE, SUM),
+    get_file_revlog_flags: timeseries(RATE, SUM),
     upload_hg_file_entry: timeseries(RATE, SUM),
     upload_hg_tree_entry: timeseries(RATE, SUM),
     create_changeset: timeseries(RATE, SUM),
@@ -83,6 +104,10 @@ define_stats! {
     create_changeset_cf_count: timeseries("create_changeset.changed_files_count"; AVG, SUM),
 }
 
+fn scratch_bookmark_key(name: &Bookmark) -> String {
+    format!("scratchbookmark.{}", name)
+}
+
 /// Making PrefixBlobstore part of every blobstore does two things:
 /// 1. It ensures that the prefix applies first, which is important for shared caches like
 ///    memcache.
@@ -122,6 +147,8 @@ pub struct BlobRepo {
     filenodes: Arc<Filenodes>,
     changesets: Arc<Changesets>,
     bonsai_hg_mapping: Arc<BonsaiHgMapping>,
+    phases: Arc<Phases>,
+    obsmarkers: Arc<Obsmarkers>,
     repoid: RepositoryId,
 }
 
@@ -133,6 +160,8 @@ impl BlobRepo {
         filenodes: Arc<Filenodes>,
         changesets: Arc<Changesets>,
         bonsai_hg_mapping: Arc<BonsaiHgMapping>,
+        phases: Arc<Phases>,
+        obsmarkers: Arc<Obsmarkers>,
         repoid: RepositoryId,
     ) -> Self {
         BlobRepo {
@@ -142,12 +171,15 @@ impl BlobRepo {
             filenodes,
             changesets,
             bonsai_hg_mapping,
+            phases,
+            obsmarkers,
             repoid,
         }
     }
 
-    /// Most local use cases should use new_rocksdb instead. This is only meant for test
-    /// fixtures.
+    /// Blobs are stored as plain files and the rest of the state in SQLite files next to them.
+    /// Every write is synced to disk, so this is suitable for small single-node deployments as
+    /// well as for test fixtures.
     pub fn new_files(logger: Logger, path: &Path, repoid: RepositoryId) -> Result<Self> {
         let blobstore = Fileblob::create(path.join("blobs"))
             .context(ErrorKind::StateOpen(StateOpenError::Blobstore))?;
@@ -163,6 +195,18 @@ impl BlobRepo {
         Self::new_local(logger, path, Arc::new(blobstore), repoid)
     }
 
+    /// Like new_rocksdb, but every blob write waits until it reaches stable storage, so that
+    /// nothing is lost if the machine crashes.
+    pub fn new_rocksdb_durable(logger: Logger, path: &Path, repoid: RepositoryId) -> Result<Self> {
+        fs::create_dir_all(path).context(ErrorKind::StateOpen(StateOpenError::Blobstore))?;
+        let options = rocksdb::Options::new().create_if_missing(true);
+        let blobstore = Rocksblob::open_with_options(path.join("blobs"), options)
+            .context(ErrorKind::StateOpen(StateOpenError::Blobstore))?
+            .with_sync_writes();
+
+        Self::new_local(logger, path, Arc::new(blobstore), repoid)
+    }
+
     pub fn new_rocksdb_delayed<F>(
         logger: Logger,
         path: &Path,
@@ -210,6 +254,11 @@ impl BlobRepo {
         let bonsai_hg_mapping =
             SqliteBonsaiHgMapping::open_or_create(path.join("bonsai_hg_mapping").to_string_lossy())
                 .context(ErrorKind::StateOpen(StateOpenError::BonsaiHgMapping))?;
+        let phases = SqlitePhases::open_or_create(path.join("phases").to_string_lossy())
+            .context(ErrorKind::StateOpen(StateOpenError::Phases))?;
+        let obsmarkers =
+            SqliteObsmarkers::open_or_create(path.join("obsmarkers").to_string_lossy())
+                .context(ErrorKind::StateOpen(StateOpenError::Obsmarkers))?;
 
         Ok(Self::new(
             logger,
@@ -218,6 +267,8 @@ impl BlobRepo {
             Arc::new(filenodes),
             Arc::new(changesets),
             Arc::new(bonsai_hg_mapping),
+            Arc::new(phases),
+            Arc::new(obsmarkers),
             repoid,
         ))
     }
@@ -238,6 +289,10 @@ impl BlobRepo {
                 .context(ErrorKind::StateOpen(StateOpenError::Changesets))?),
             Arc::new(SqliteBonsaiHgMapping::in_memory()
                 .context(ErrorKind::StateOpen(StateOpenError::BonsaiHgMapping))?),
+            Arc::new(SqlitePhases::in_memory()
+                .context(ErrorKind::StateOpen(StateOpenError::Phases))?),
+            Arc::new(SqliteObsmarkers::in_memory()
+                .context(ErrorKind::StateOpen(StateOpenError::Obsmarkers))?),
             RepositoryId::new(0),
         ))
     }
@@ -283,6 +338,11 @@ impl BlobRepo {
             args.bonsai_hg_mapping_cache_size,
         );
 
+        let phases = MysqlPhases::open(&args.db_address)
+            .context(ErrorKind::StateOpen(StateOpenError::Phases))?;
+        let obsmarkers = MysqlObsmarkers::open(&args.db_address)
+            .context(ErrorKind::StateOpen(StateOpenError::Obsmarkers))?;
+
         Ok(Self::new(
             logger,
             Arc::new(bookmarks),
@@ -290,6 +350,8 @@ impl BlobRepo {
             Arc::new(filenodes),
             Arc::new(changesets),
             Arc::new(bonsai_hg_mapping),
+            Arc::new(phases),
+            Arc::new(obsmarkers),
             repoid,
         ))
     }
@@ -310,6 +372,8 @@ impl BlobRepo {
             filenodes,
             changesets,
             bonsai_hg_mapping,
+            phases,
+            obsmarkers,
             repoid,
         } = self;
 
@@ -324,6 +388,8 @@ impl BlobRepo {
             filenodes,
             changesets,
             bonsai_hg_mapping,
+            phases,
+            obsmarkers,
             repoid,
         )
     }
@@ -515,6 +581,38 @@ impl BlobRepo {
         self.bookmarks.create_transaction(&self.repoid)
     }
 
+    /// Get the changeset an infinitepush scratch bookmark points to. Scratch bookmarks live in
+    /// their own namespace, a scratch bookmark and a bookmark with the same name are unrelated.
+    pub fn get_scratch_bookmark(&self, name: &Bookmark) -> BoxFuture<Option<HgChangesetId>, Error> {
+        STATS::get_scratch_bookmark.add_value(1);
+        let name = name.clone();
+        self.blobstore
+            .get(scratch_bookmark_key(&name))
+            .and_then(move |cs_id| match cs_id {
+                Some(cs_id) => str::from_utf8(cs_id.as_bytes())
+                    .ok()
+                    .and_then(|cs_id| HgChangesetId::from_str(cs_id).ok())
+                    .map(Some)
+                    .ok_or(ErrorKind::CorruptScratchBookmark(name).into()),
+                None => Ok(None),
+            })
+            .boxify()
+    }
+
+    /// Point an infinitepush scratch bookmark at a changeset. Scratch bookmarks are owned by
+    /// whoever pushes them, so this always overwrites the current value.
+    pub fn set_scratch_bookmark(
+        &self,
+        name: &Bookmark,
+        cs_id: &HgChangesetId,
+    ) -> BoxFuture<(), Error> {
+        STATS::set_scratch_bookmark.add_value(1);
+        self.blobstore.put(
+            scratch_bookmark_key(name),
+            BlobstoreBytes::from_bytes(Bytes::from(cs_id.to_string())),
+        )
+    }
+
     pub fn get_linknode(
         &self,
         path: RepoPath,
@@ -550,6 +648,153 @@ impl BlobRepo {
             .boxify()
     }
 
+    /// Phases of the given changesets. Changesets without a recorded phase are draft.
+    pub fn get_phases(
+        &self,
+        cs_ids: Vec<HgChangesetId>,
+    ) -> BoxFuture<HashMap<HgChangesetId, Phase>, Error> {
+        STATS::get_phases.add_value(1);
+        self.phases
+            .get(self.repoid, cs_ids.clone())
+            .map(move |mut phases| {
+                for cs_id in cs_ids {
+                    phases.entry(cs_id).or_insert(Phase::Draft);
+                }
+                phases
+            })
+            .boxify()
+    }
+
+    /// Record changesets as draft, unless they are public already
+    pub fn add_draft(&self, cs_ids: Vec<HgChangesetId>) -> BoxFuture<(), Error> {
+        STATS::add_draft.add_value(1);
+        let phases = cs_ids.into_iter().map(|cs_id| (cs_id, Phase::Draft)).collect();
+        self.phases.add(self.repoid, phases)
+    }
+
+    /// Changesets recorded as draft. Only the drafts pushed without infinitepush are recorded.
+    pub fn get_drafts(&self) -> BoxFuture<Vec<HgChangesetId>, Error> {
+        STATS::get_drafts.add_value(1);
+        self.phases.list_by_phase(self.repoid, Phase::Draft)
+    }
+
+    /// Changesets recorded as draft whose parents are all public. Only the drafts pushed without
+    /// infinitepush are recorded, and they stay recorded until a bookmark is moved to them.
+    pub fn get_draft_roots(&self) -> BoxFuture<Vec<HgChangesetId>, Error> {
+        STATS::get_draft_roots.add_value(1);
+        let repo = self.clone();
+        self.phases
+            .list_by_phase(self.repoid, Phase::Draft)
+            .and_then(move |drafts| {
+                let draft_set: HashSet<_> = drafts.iter().cloned().collect();
+                let draft_set = Arc::new(draft_set);
+                stream::iter_ok(drafts)
+                    .map(move |cs_id| {
+                        let draft_set = draft_set.clone();
+                        repo.get_changeset_parents(&cs_id).map(move |parents| {
+                            if parents.iter().any(|p| draft_set.contains(p)) {
+                                None
+                            } else {
+                                Some(cs_id)
+                            }
+                        })
+                    })
+                    .buffered(100)
+                    .filter_map(|root| root)
+                    .collect()
+            })
+            .boxify()
+    }
+
+    /// Mark the given changesets and all their ancestors as public. The ancestors are walked
+    /// until public changesets, so this only touches the changesets that became public since the
+    /// last call. Ancestors are recorded before their descendants, so that an interrupted call
+    /// never leaves a draft ancestor behind a public changeset.
+    pub fn mark_public(&self, heads: Vec<HgChangesetId>) -> BoxFuture<(), Error> {
+        STATS::mark_public.add_value(1);
+        let repo = self.clone();
+        let phases = self.phases.clone();
+        let repoid = self.repoid;
+
+        future::loop_fn(
+            (heads, HashSet::new(), Vec::new()),
+            move |(frontier, mut seen, mut new_public): (Vec<_>, HashSet<_>, Vec<_>)| {
+                let repo = repo.clone();
+                let frontier: Vec<_> = frontier
+                    .into_iter()
+                    .filter(|cs_id| seen.insert(*cs_id))
+                    .collect();
+                if frontier.is_empty() {
+                    return future::ok(future::Loop::Break(new_public)).boxify();
+                }
+
+                repo.phases
+                    .get(repoid, frontier.clone())
+                    .and_then(move |known| {
+                        let drafts = frontier
+                            .into_iter()
+                            .filter(|cs_id| known.get(cs_id) != Some(&Phase::Public))
+                            .map(move |cs_id| {
+                                repo.changesets.get(repoid, cs_id).and_then(move |entry| {
+                                    entry
+                                        .ok_or(ErrorKind::ChangesetMissing(cs_id).into())
+                                        .map(|entry| (cs_id, entry))
+                                })
+                            });
+                        future::join_all(drafts)
+                    })
+                    .map(move |entries| {
+                        let mut parents = Vec::new();
+                        for (cs_id, entry) in entries {
+                            new_public.push((entry.gen, cs_id));
+                            parents.extend(entry.parents);
+                        }
+                        if parents.is_empty() {
+                            future::Loop::Break(new_public)
+                        } else {
+                            future::Loop::Continue((parents, seen, new_public))
+                        }
+                    })
+                    .boxify()
+            },
+        ).and_then(move |mut new_public| {
+            new_public.sort();
+            let new_public = new_public
+                .into_iter()
+                .map(|(_, cs_id)| (cs_id, Phase::Public))
+                .collect();
+            phases.add(repoid, new_public)
+        })
+            .boxify()
+    }
+
+    /// Mark everything that is reachable from a bookmark as public. Everything that moves
+    /// bookmarks marks their new targets public, this is only needed once for repos whose
+    /// bookmarks were moved before phases were tracked.
+    pub fn mark_public_from_bookmarks(&self) -> BoxFuture<(), Error> {
+        let repo = self.clone();
+        self.get_bookmarks()
+            .map(|(_, cs_id)| cs_id)
+            .collect()
+            .and_then(move |heads| repo.mark_public(heads))
+            .boxify()
+    }
+
+    /// Store obsolescence markers, returning how many of them were new
+    pub fn add_obsmarkers(&self, markers: Vec<HgObsmarker>) -> BoxFuture<usize, Error> {
+        STATS::add_obsmarkers.add_value(1);
+        self.obsmarkers.add(self.repoid, markers)
+    }
+
+    /// Obsolescence markers to send along with the given changesets
+    pub fn get_relevant_obsmarkers(
+        &self,
+        cs_ids: Vec<HgChangesetId>,
+    ) -> BoxFuture<Vec<HgObsmarker>, Error> {
+        STATS::get_relevant_obsmarkers.add_value(1);
+        self.obsmarkers.get_relevant(self.repoid, cs_ids)
+    }
+
     pub fn upload_blob<Id>(&self, blob: Blob<Id>) -> impl Future<Item = Id, Error = Error> + Send
     where
         Id: MononokeId,
@@ -582,6 +827,44 @@ impl BlobRepo {
             })
     }
 
+    /// Whether the large file with SHA-256 `oid` is stored
+    pub fn lfs_object_exists(&self, oid: &Sha256) -> BoxFuture<bool, Error> {
+        STATS::lfs_object_exists.add_value(1);
+        lfs_object_exists(&self.blobstore, oid)
+    }
+
+    /// Store a large file, which can arrive in pieces of any size. Fails if the content doesn't
+    /// hash to `oid` or isn't `size` bytes long. Resolves to the size of the file.
+    pub fn upload_lfs_object<S>(&self, oid: Sha256, size: u64, content: S) -> BoxFuture<u64, Error>
+    where
+        S: Stream<Item = Bytes, Error = Error> + Send + 'static,
+    {
+        STATS::upload_lfs_object.add_value(1);
+        upload_lfs_object(self.blobstore.clone(), oid, size, content)
+    }
+
+    /// Fetch a large file as its size and a stream of its content, or `None` if it isn't stored
+    pub fn get_lfs_object(
+        &self,
+        oid: Sha256,
+    ) -> BoxFuture<Option<(u64, BoxStream<Bytes, Error>)>, Error> {
+        STATS::get_lfs_object.add_value(1);
+        get_lfs_object(self.blobstore.clone(), oid)
+    }
+
+    /// Record the revlog flags that a file revision was pushed with, eg. that it is the pointer
+    /// to a large file
+    pub fn put_file_revlog_flags(&self, node: &HgNodeHash, flags: u16) -> BoxFuture<(), Error> {
+        STATS::put_file_revlog_flags.add_value(1);
+        put_file_revlog_flags(&self.blobstore, node, flags)
+    }
+
+    /// Revlog flags of a file revision, 0 unless it was pushed with flags
+    pub fn get_file_revlog_flags(&self, node: HgNodeHash) -> BoxFuture<u16, Error> {
+        STATS::get_file_revlog_flags.add_value(1);
+        get_file_revlog_flags(&self.blobstore, node)
+    }
+
     // This is used by tests in memory_manifest.rs
     pub fn get_blobstore(&self) -> RepoBlobstore {
         self.blobstore.clone()
@@ -1322,6 +1605,8 @@ impl Clone for BlobRepo {
             filenodes: self.filenodes.clone(),
             changesets: self.changesets.clone(),
             bonsai_hg_mapping: self.bonsai_hg_mapping.clone(),
+            phases: self.phases.clone(),
+            obsmarkers: self.obsmarkers.clone(),
             repoid: self.repoid.clone(),
         }
     }
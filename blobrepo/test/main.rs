@@ -14,9 +14,13 @@ extern crate futures;
 extern crate futures_ext;
 extern crate quickcheck;
 extern crate scuba_ext;
+#[macro_use]
+extern crate slog;
+extern crate tempdir;
 
 extern crate blobrepo;
 extern crate blobstore;
+extern crate bookmarks;
 extern crate changesets;
 extern crate dbbookmarks;
 extern crate many_files_dirs;
@@ -33,9 +37,14 @@ use quickcheck::{quickcheck, Arbitrary, Gen, TestResult, Testable};
 use std::collections::{BTreeMap, HashMap, HashSet};
 use std::marker::PhantomData;
 
+use bookmarks::Bookmark;
+use slog::{Discard, Drain, Logger};
+use tempdir::TempDir;
+
 use blobrepo::{compute_changed_files, BlobRepo, ErrorKind};
 use mercurial_types::{manifest, Changeset, Entry, FileType, HgChangesetId, HgEntryId,
-                      HgManifestId, HgNodeHash, HgParents, MPath, MPathElement, RepoPath};
+                      HgManifestId, HgNodeHash, HgParents, MPath, MPathElement, RepoPath,
+                      RepositoryId};
 use mononoke_types::{BonsaiChangeset, ChangesetId, ContentId, DateTime, FileChange, FileContents,
                      MononokeId};
 use mononoke_types::bonsai_changeset::BonsaiChangesetMut;
@@ -419,6 +428,66 @@ test_both_repotypes!(
     store_fetch_mononoke_types_eager
 );
 
+#[test]
+fn test_files_repo_survives_reopen() {
+    async_unit::tokio_unit_test(|| {
+        let tmpdir = TempDir::new("files_repo").unwrap();
+        let logger = Logger::root(Discard {}.ignore_res(), o!());
+        let repoid = RepositoryId::new(0);
+        let book = Bookmark::new("master").unwrap();
+
+        let (filehash, csid) = {
+            let repo = BlobRepo::new_files(logger.clone(), tmpdir.path(), repoid).unwrap();
+            let path = RepoPath::file("file").expect("Can't generate fake RepoPath");
+            let (filehash, file_future) = upload_file_no_parents(&repo, "blob", &path);
+            let (_, root_manifest_future) = upload_manifest_no_parents(
+                &repo,
+                format!("file\0{}\n", filehash),
+                &RepoPath::root(),
+            );
+            let commit = create_changeset_no_parents(
+                &repo,
+                root_manifest_future.map(Some).boxify(),
+                vec![file_future],
+            );
+            let csid = run_future(commit.get_completed_changeset())
+                .unwrap()
+                .get_changeset_id();
+
+            let mut txn = repo.update_bookmark_transaction();
+            txn.create(&book, &csid).unwrap();
+            assert!(run_future(txn.commit()).unwrap());
+            (filehash, csid)
+        };
+
+        let repo = BlobRepo::new_files(logger, tmpdir.path(), repoid).unwrap();
+        assert!(run_future(repo.changeset_exists(&csid)).unwrap());
+        assert_eq!(run_future(repo.get_bookmark(&book)).unwrap(), Some(csid));
+        let bytes = run_future(repo.get_file_content(&filehash)).unwrap();
+        assert_eq!(&bytes.into_bytes(), &b"blob"[..]);
+    });
+}
+
+#[test]
+fn test_scratch_bookmarks() {
+    async_unit::tokio_unit_test(|| {
+        let repo = many_files_dirs::getrepo(None);
+        let book = Bookmark::new("scratch/test").unwrap();
+        let csid = HgChangesetId::new(string_to_nodehash(
+            "a6cb7dddec32acaf9a28db46cdb3061682155531",
+        ));
+
+        assert_eq!(run_future(repo.get_scratch_bookmark(&book)).unwrap(), None);
+        run_future(repo.set_scratch_bookmark(&book, &csid)).unwrap();
+        assert_eq!(
+            run_future(repo.get_scratch_bookmark(&book)).unwrap(),
+            Some(csid)
+        );
+        // Scratch bookmarks are not visible as bookmarks
+        assert_eq!(run_future(repo.get_bookmark(&book)).unwrap(), None);
+    });
+}
+
 #[test]
 fn test_compute_changed_files_no_parents() {
     async_unit::tokio_unit_test(|| {
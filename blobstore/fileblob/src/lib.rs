@@ -16,9 +16,11 @@ extern crate futures_ext;
 extern crate blobstore;
 extern crate mononoke_types;
 
-use std::fs::{create_dir_all, File};
+use std::fs::{create_dir_all, rename, File};
 use std::io::{self, Read, Write};
 use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
 
 use failure::{Error, Result};
 use futures::Async;
@@ -31,6 +33,10 @@ use blobstore::Blobstore;
 use mononoke_types::BlobstoreBytes;
 
 const PREFIX: &str = "blob";
+const TMP_PREFIX: &str = "tmp";
+
+// Makes names of temporary files unique within the process
+static TMP_COUNTER: AtomicUsize = ATOMIC_USIZE_INIT;
 
 #[derive(Debug, Clone)]
 pub struct Fileblob {
@@ -60,6 +66,12 @@ impl Fileblob {
         let key = percent_encode(key.as_bytes(), DEFAULT_ENCODE_SET);
         self.base.join(format!("{}-{}", PREFIX, key))
     }
+
+    fn tmp_path(&self) -> PathBuf {
+        let id = TMP_COUNTER.fetch_add(1, Ordering::Relaxed);
+        self.base
+            .join(format!("{}-{}-{}", TMP_PREFIX, process::id(), id))
+    }
 }
 
 impl Blobstore for Fileblob {
@@ -83,9 +95,15 @@ impl Blobstore for Fileblob {
 
     fn put(&self, key: String, value: BlobstoreBytes) -> BoxFuture<(), Error> {
         let p = self.path(&key);
+        let tmp = self.tmp_path();
 
+        // Write to a temporary file and move it into place, so that a crash never leaves a
+        // truncated blob behind
         poll_fn::<_, Error, _>(move || {
-            File::create(&p)?.write_all(value.as_bytes().as_ref())?;
+            let mut f = File::create(&tmp)?;
+            f.write_all(value.as_bytes().as_ref())?;
+            f.sync_all()?;
+            rename(&tmp, &p)?;
             Ok(Async::Ready(()))
         }).boxify()
     }
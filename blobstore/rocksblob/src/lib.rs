@@ -31,6 +31,7 @@ pub type Result<T> = std::result::Result<T, Error>;
 #[derive(Clone, Debug)]
 pub struct Rocksblob {
     db: Db,
+    sync_writes: bool,
 }
 
 impl Rocksblob {
@@ -49,15 +50,25 @@ impl Rocksblob {
 
         Ok(Rocksblob {
             db: Db::open(path, opts)?,
+            sync_writes: false,
         })
     }
+
+    /// Make every write wait until the data reaches stable storage, so that no blob is lost if
+    /// the machine crashes
+    pub fn with_sync_writes(self) -> Self {
+        Rocksblob {
+            sync_writes: true,
+            ..self
+        }
+    }
 }
 
 #[must_use = "futures do nothing unless polled"]
 pub struct GetBlob(Db, String);
 
 #[must_use = "futures do nothing unless polled"]
-pub struct PutBlob(Db, String, BlobstoreBytes);
+pub struct PutBlob(Db, String, BlobstoreBytes, bool);
 
 impl Future for GetBlob {
     type Item = Option<BlobstoreBytes>;
@@ -75,7 +86,7 @@ impl Future for PutBlob {
     type Error = Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        let wropts = WriteOptions::new().set_sync(false);
+        let wropts = WriteOptions::new().set_sync(self.3);
         self.0
             .put(&self.1, &self.2.as_bytes(), &wropts)
             .map_err(Error::from)?;
@@ -93,6 +104,6 @@ impl Blobstore for Rocksblob where {
     fn put(&self, key: String, value: BlobstoreBytes) -> BoxFuture<(), Error> {
         let db = self.db.clone();
 
-        PutBlob(db, key, value).boxify()
+        PutBlob(db, key, value, self.sync_writes).boxify()
     }
 }
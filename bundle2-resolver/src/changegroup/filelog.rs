@@ -19,7 +19,9 @@ use quickcheck::{Arbitrary, Gen};
 use blobrepo::{BlobRepo, ContentBlobInfo, HgBlobEntry, UploadHgFileContents, UploadHgFileEntry,
                UploadHgNodeHash};
 use mercurial_bundles::changegroup::CgDeltaChunk;
-use mercurial_types::{delta, Delta, FileType, HgNodeHash, HgNodeKey, MPath, RepoPath, NULL_HASH};
+use mercurial_types::{delta, Delta, FileType, HgNodeHash, HgNodeKey, LfsPointer, MPath, RepoPath,
+                      NULL_HASH};
+use mercurial_types::lfs::{LfsNodeHasher, REVIDX_EXTSTORED};
 
 use errors::*;
 use stats::*;
@@ -38,6 +40,8 @@ pub struct Filelog {
     pub p2: Option<HgNodeHash>,
     pub linknode: HgNodeHash,
     pub data: Bytes,
+    /// Revlog flags, if the changegroup has them
+    pub flags: Option<u16>,
 }
 
 impl UploadableHgBlob for Filelog {
@@ -57,8 +61,59 @@ impl UploadableHgBlob for Filelog {
             RepoPath::FilePath(path) => path.clone(),
             other => bail_msg!("internal error: expected file path, got {}", other),
         };
+
+        let flags = self.flags.unwrap_or(0);
+        if flags & !REVIDX_EXTSTORED != 0 {
+            bail_err!(ErrorKind::UnsupportedRevlogFlags(path, flags));
+        }
+
+        // Large files are pushed as pointers, after their content was uploaded over HTTP. The
+        // pointer is stored as the content of the file, but the push is rejected if the content
+        // it points to isn't there, or if the node hash doesn't match it. The flag is stored
+        // once the node is verified, so that the clients that fetch the file resolve the pointer.
+        let (upload_node_id, lfs_check) = if flags & REVIDX_EXTSTORED != 0 {
+            let pointer = LfsPointer::parse(&self.data)
+                .with_context(|_| ErrorKind::InvalidLfsPointer(path.clone()))?;
+            let hasher = LfsNodeHasher::new(&self.data, self.p1.as_ref(), self.p2.as_ref())
+                .with_context(|_| ErrorKind::InvalidLfsPointer(path.clone()))?;
+            let store_flags = repo.put_file_revlog_flags(&node_key.hash, flags);
+            let path = path.clone();
+            let node = node_key.hash;
+            let lfs_check = repo.get_lfs_object(pointer.oid)
+                .and_then({
+                    let path = path.clone();
+                    move |object| match object {
+                        Some((_, content)) => Ok(content),
+                        None => Err(ErrorKind::LfsObjectMissing(path, pointer.oid).into()),
+                    }
+                })
+                .flatten_stream()
+                .fold(hasher, |mut hasher, chunk| {
+                    hasher.update(chunk);
+                    Ok::<_, Error>(hasher)
+                })
+                .and_then(move |hasher| {
+                    let computed = hasher.finish();
+                    if computed == node {
+                        Ok(())
+                    } else {
+                        Err(ErrorKind::InconsistentLfsNodeHash(path, node, computed).into())
+                    }
+                })
+                .and_then(move |()| store_flags)
+                .boxify();
+            // The node hash of a large file is the hash of its content, not of the pointer, so
+            // it's checked above instead
+            (UploadHgNodeHash::Supplied(node_key.hash), lfs_check)
+        } else {
+            (
+                UploadHgNodeHash::Checked(node_key.hash),
+                Ok(()).into_future().boxify(),
+            )
+        };
+
         let upload = UploadHgFileEntry {
-            upload_node_id: UploadHgNodeHash::Checked(node_key.hash),
+            upload_node_id,
             contents: UploadHgFileContents::RawBytes(self.data),
             // XXX should this really be Regular?
             file_type: FileType::Regular,
@@ -68,6 +123,8 @@ impl UploadableHgBlob for Filelog {
         };
 
         let (cbinfo, fut) = upload.upload(repo)?;
+        // Nothing is stored for a large file until its node hash was verified
+        let fut = lfs_check.and_then(move |()| fut);
         Ok((
             node_key,
             (cbinfo, fut.map_err(Error::compat).boxify().shared()),
@@ -89,6 +146,7 @@ where
                 p1,
                 p2,
                 linknode,
+                flags,
             } = chunk;
 
             delta_cache
@@ -106,6 +164,7 @@ where
                             p2: p2.into_option(),
                             linknode,
                             data,
+                            flags,
                         })
                     }
                 })
@@ -222,6 +281,7 @@ impl Arbitrary for Filelog {
             p2: HgNodeHash::arbitrary(g).into_option(),
             linknode: HgNodeHash::arbitrary(g),
             data: Bytes::from(Vec::<u8>::arbitrary(g)),
+            flags: None,
         }
     }
 
@@ -277,7 +337,7 @@ mod tests {
     use futures::stream::iter_ok;
     use itertools::{assert_equal, EitherOrBoth, Itertools};
 
-    use mercurial_types::NULL_HASH;
+    use mercurial_types::{HgBlob, HgBlobNode, Sha256, NULL_HASH};
     use mercurial_types::delta::Fragment;
     use mercurial_types_mocks::nodehash::*;
 
@@ -331,6 +391,7 @@ mod tests {
                 base: NULL_HASH,
                 linknode: f.linknode.clone(),
                 delta: Delta::new_fulltext(f.data.as_ref()),
+                flags: f.flags,
             },
         }
     }
@@ -393,6 +454,7 @@ mod tests {
             p2: Some(THREES_HASH),
             linknode: FOURS_HASH,
             data: Bytes::from("test file content"),
+            flags: None,
         };
 
         let f2 = Filelog {
@@ -404,6 +466,7 @@ mod tests {
             p2: Some(SEVENS_HASH),
             linknode: EIGHTS_HASH,
             data: Bytes::from("test2 file content"),
+            flags: None,
         };
 
         check_conversion(
@@ -412,6 +475,53 @@ mod tests {
         );
     }
 
+    #[test]
+    fn upload_large_file_pointer() {
+        let repo = BlobRepo::new_memblob_empty(None, None).unwrap();
+        let content = Bytes::from("large file content");
+        let oid = Sha256::from(content.as_ref());
+        // The node hash of a large file covers its content, not the pointer
+        let node = HgBlobNode::new(HgBlob::from(content.clone()), None, None)
+            .nodeid()
+            .unwrap();
+
+        let f = Filelog {
+            node_key: HgNodeKey {
+                path: RepoPath::FilePath(MPath::new(b"large").unwrap()),
+                hash: node,
+            },
+            p1: None,
+            p2: None,
+            linknode: TWOS_HASH,
+            data: Bytes::from(LfsPointer::new(oid, content.len() as u64).to_bytes()),
+            flags: Some(REVIDX_EXTSTORED),
+        };
+        let upload = |f: Filelog| {
+            let (_, (_, entry)) = f.upload(&repo).unwrap();
+            entry.wait().is_ok()
+        };
+
+        // The content must be uploaded before the pointer is pushed
+        assert!(!upload(f.clone()));
+        repo.upload_lfs_object(oid, content.len() as u64, iter_ok(vec![content]))
+            .wait()
+            .unwrap();
+
+        // A pointer can't be pushed under the node of another file
+        let mut wrong_node = f.clone();
+        wrong_node.node_key.hash = ONES_HASH;
+        assert!(!upload(wrong_node));
+        assert_eq!(repo.get_file_revlog_flags(ONES_HASH).wait().unwrap(), 0);
+        assert!(repo.get_file_content(&ONES_HASH).wait().is_err());
+
+        assert!(upload(f));
+        assert_eq!(
+            repo.get_file_revlog_flags(node).wait().unwrap(),
+            REVIDX_EXTSTORED
+        );
+        assert_eq!(repo.get_file_revlog_flags(TWOS_HASH).wait().unwrap(), 0);
+    }
+
     fn files_check_order(correct_order: bool) {
         let f1 = Filelog {
             node_key: HgNodeKey {
@@ -422,6 +532,7 @@ mod tests {
             p2: Some(THREES_HASH),
             linknode: FOURS_HASH,
             data: Bytes::from("test file content"),
+            flags: None,
         };
 
         let f2 = Filelog {
@@ -433,6 +544,7 @@ mod tests {
             p2: Some(SEVENS_HASH),
             linknode: EIGHTS_HASH,
             data: Bytes::from("test2 file content"),
+            flags: None,
         };
 
         let f1_deltaed = filelog_to_deltaed(&f1);
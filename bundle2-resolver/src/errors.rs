@@ -6,11 +6,22 @@
 
 pub use failure::prelude::*;
 
-use mercurial_types::HgNodeHash;
+use mercurial_types::{HgNodeHash, MPath, Sha256};
 
 #[derive(Debug, Fail)]
 pub enum ErrorKind {
     #[fail(display = "Malformed treemanifest part: {}", _0)] MalformedTreemanifestPart(String),
     #[fail(display = "Error while uploading data for changesets, hashes: {:?}", _0)]
     WhileUploadingData(Vec<HgNodeHash>),
+    #[fail(display = "Move of bookmark {} rejected by hook {}: {}", _0, _1, _2)]
+    BookmarkMoveRejected(String, String, String),
+    #[fail(display = "Invalid LFS pointer for file {}", _0)]
+    InvalidLfsPointer(MPath),
+    #[fail(display = "Content of large file {} (sha256 {}) was not uploaded", _0, _1)]
+    LfsObjectMissing(MPath, Sha256),
+    #[fail(display = "Node hash {} of large file {} doesn't match its content, expected {}", _1, _0,
+           _2)]
+    InconsistentLfsNodeHash(MPath, HgNodeHash, HgNodeHash),
+    #[fail(display = "Unsupported revlog flags {:#x} of file {}", _1, _0)]
+    UnsupportedRevlogFlags(MPath, u16),
 }
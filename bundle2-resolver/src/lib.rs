@@ -7,6 +7,7 @@
 #![deny(warnings)]
 
 extern crate ascii;
+extern crate async_compression;
 extern crate bytes;
 #[macro_use]
 extern crate failure_ext as failure;
@@ -28,6 +29,7 @@ extern crate quickcheck;
 #[macro_use]
 extern crate quickcheck;
 extern crate scuba_ext;
+extern crate serde_json;
 #[macro_use]
 extern crate slog;
 #[macro_use]
@@ -36,6 +38,7 @@ extern crate tokio_io;
 
 extern crate blobrepo;
 extern crate bookmarks;
+extern crate hooks;
 extern crate mercurial;
 extern crate mercurial_bundles;
 extern crate mercurial_types;
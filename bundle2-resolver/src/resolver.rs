@@ -7,9 +7,11 @@
 use std::collections::HashMap;
 use std::io::Cursor;
 use std::ops::AddAssign;
+use std::str::FromStr;
 use std::sync::Arc;
 
 use ascii::AsciiString;
+use async_compression::CompressorType;
 use blobrepo::{BlobRepo, ChangesetHandle, ChangesetMetadata, ContentBlobInfo, CreateChangeset,
                HgBlobEntry};
 use bookmarks;
@@ -19,12 +21,14 @@ use futures::{Future, IntoFuture, Stream};
 use futures::future::{self, err, ok, Shared};
 use futures::stream;
 use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
+use hooks::{HookBookmarkMove, HookExecution, HookManager};
 use mercurial::changeset::RevlogChangeset;
 use mercurial::manifest::{Details, ManifestContent};
-use mercurial_bundles::{parts, Bundle2EncodeBuilder, Bundle2Item};
-use mercurial_types::{HgChangesetId, HgManifestId, HgNodeHash, HgNodeKey, MPath, RepoPath,
-                      NULL_HASH};
+use mercurial_bundles::{parts, Bundle2EncodeBuilder, Bundle2Item, PartHeaderType};
+use mercurial_types::{HgChangesetId, HgManifestId, HgNodeHash, HgNodeKey, HgObsmarker, MPath,
+                      RepoPath, NULL_HASH};
 use scuba_ext::{ScubaSampleBuilder, ScubaSampleBuilderExt};
+use serde_json;
 use slog::Logger;
 use stats::*;
 
@@ -43,16 +47,31 @@ type UploadedChangesets = HashMap<HgNodeHash, ChangesetHandle>;
 /// The resolve function takes a bundle2, interprets it's content as Changesets, Filelogs and
 /// Manifests and uploades all of them to the provided BlobRepo in the correct order.
 /// It returns a Future that contains the response that should be send back to the requester.
+/// Bookmark moves are checked by the bookmark move hooks of `hook_manager` before they are
+/// applied, with `pusher` as the identity of the user that moves them. Pushed changesets are
+/// draft until a bookmark or the client makes them public, and the obsolescence markers that come
+/// with them are stored. The response is compressed with `response_compression`, negotiated with
+/// the client for the session.
 pub fn resolve(
     repo: Arc<BlobRepo>,
+    hook_manager: Arc<HookManager>,
+    pusher: Option<String>,
     logger: Logger,
     scuba_logger: ScubaSampleBuilder,
+    response_compression: Option<CompressorType>,
     heads: Vec<String>,
     bundle2: BoxStream<Bundle2Item, Error>,
 ) -> BoxFuture<Bytes, Error> {
     info!(logger, "unbundle heads {:?}", heads);
 
-    let resolver = Bundle2Resolver::new(repo, logger, scuba_logger);
+    let resolver = Bundle2Resolver::new(
+        repo,
+        hook_manager,
+        pusher,
+        logger,
+        scuba_logger,
+        response_compression,
+    );
 
     let bundle2 = resolver.resolve_start_and_replycaps(bundle2);
 
@@ -77,71 +96,180 @@ fn resolve_push(
                 resolver
                     .resolve_multiple_parts(bundle2, Bundle2Resolver::maybe_resolve_pushkey)
                     .map(move |(pushkeys, bundle2)| {
-                        let bookmark_push: Vec<_> = pushkeys
-                            .into_iter()
-                            .filter_map(|pushkey| match pushkey {
-                                Pushkey::Phases => None,
-                                Pushkey::BookmarkPush(bp) => Some(bp),
-                            })
-                            .collect();
+                        let mut bookmark_push = Vec::new();
+                        let mut phases_push = Vec::new();
+                        let mut obsmarkers_push = Vec::new();
+                        for pushkey in pushkeys {
+                            match pushkey {
+                                Pushkey::Phases(pp) => phases_push.push(pp),
+                                Pushkey::BookmarkPush(bp) => bookmark_push.push(bp),
+                                Pushkey::Obsmarkers(op) => obsmarkers_push.push(op),
+                            }
+                        }
 
                         STATS::bookmark_pushkeys_count.add_value(bookmark_push.len() as i64);
 
-                        (cg_push, bookmark_push, bundle2)
+                        (cg_push, bookmark_push, phases_push, obsmarkers_push, bundle2)
                     })
             }
         })
         .and_then({
             let resolver = resolver.clone();
-            move |(cg_push, bookmark_push, bundle2)| {
+            move |(cg_push, bookmark_push, phases_push, obsmarkers_push, bundle2)| {
                 if let Some(cg_push) = cg_push {
                     resolver
                         .resolve_b2xtreegroup2(bundle2)
                         .map(|(manifests, bundle2)| {
-                            (Some((cg_push, manifests)), bookmark_push, bundle2)
+                            (
+                                Some((cg_push, manifests)),
+                                bookmark_push,
+                                phases_push,
+                                obsmarkers_push,
+                                bundle2,
+                            )
                         })
                         .boxify()
                 } else {
-                    ok((None, bookmark_push, bundle2)).boxify()
+                    ok((None, bookmark_push, phases_push, obsmarkers_push, bundle2)).boxify()
                 }
             }
         })
         .and_then({
             let resolver = resolver.clone();
-            move |(cg_and_manifests, bookmark_push, bundle2)| {
+            move |(cg_and_manifests, bookmark_push, phases_push, obsmarkers_push, bundle2)| {
                 if let Some((cg_push, manifests)) = cg_and_manifests {
                     let changegroup_id = Some(cg_push.part_id);
                     resolver
                         .upload_changesets(cg_push, manifests)
-                        .map(move |()| (changegroup_id, bookmark_push, bundle2))
+                        .map(move |()| {
+                            (
+                                changegroup_id,
+                                bookmark_push,
+                                phases_push,
+                                obsmarkers_push,
+                                bundle2,
+                            )
+                        })
                         .boxify()
                 } else {
-                    ok((None, bookmark_push, bundle2)).boxify()
+                    ok((None, bookmark_push, phases_push, obsmarkers_push, bundle2)).boxify()
                 }
             }
         })
         .and_then({
             let resolver = resolver.clone();
-            move |(changegroup_id, bookmark_push, bundle2)| {
+            move |(changegroup_id, bookmark_push, phases_push, obsmarkers_push, bundle2)| {
                 resolver
                     .maybe_resolve_infinitepush_bookmarks(bundle2)
-                    .map(move |((), bundle2)| (changegroup_id, bookmark_push, bundle2))
+                    .map(move |(infinitepush_bookmarks, bundle2)| {
+                        (
+                            changegroup_id,
+                            bookmark_push,
+                            phases_push,
+                            obsmarkers_push,
+                            infinitepush_bookmarks,
+                            bundle2,
+                        )
+                    })
             }
         })
         .and_then({
             let resolver = resolver.clone();
-            move |(changegroup_id, bookmark_push, bundle2)| {
-                resolver
-                    .ensure_stream_finished(bundle2)
-                    .map(move |()| (changegroup_id, bookmark_push))
+            move |(
+                changegroup_id,
+                bookmark_push,
+                phases_push,
+                obsmarkers_push,
+                infinitepush_bookmarks,
+                bundle2,
+            )| {
+                resolver.ensure_stream_finished(bundle2).map(move |()| {
+                    (
+                        changegroup_id,
+                        bookmark_push,
+                        phases_push,
+                        obsmarkers_push,
+                        infinitepush_bookmarks,
+                    )
+                })
             }
         })
         .and_then({
             let resolver = resolver.clone();
-            move |(changegroup_id, bookmark_push)| {
+            move |(
+                changegroup_id,
+                bookmark_push,
+                phases_push,
+                obsmarkers_push,
+                infinitepush_bookmarks,
+            )| {
+                let mut moves: Vec<_> = bookmark_push
+                    .iter()
+                    .map(|bp| {
+                        HookBookmarkMove::new(
+                            bp.name.to_string(),
+                            bp.old.as_ref().map(|cs| cs.to_string()),
+                            bp.new.as_ref().map(|cs| cs.to_string()),
+                            resolver.pusher.clone(),
+                        )
+                    })
+                    .collect();
+                // Hooks see the current value of a scratch bookmark as its old value, like for
+                // the bookmarks moved with pushkey
+                let infinitepush_moves = infinitepush_bookmarks.clone().into_iter().map({
+                    let resolver = resolver.clone();
+                    move |(name, new)| {
+                        let pusher = resolver.pusher.clone();
+                        let bookmark = try_boxfuture!(bookmarks::Bookmark::new(name.as_str()));
+                        resolver
+                            .repo
+                            .get_scratch_bookmark(&bookmark)
+                            .map(move |old| {
+                                HookBookmarkMove::new(
+                                    name,
+                                    old.map(|cs| cs.to_string()),
+                                    Some(new.to_string()),
+                                    pusher,
+                                )
+                            })
+                            .boxify()
+                    }
+                });
+                future::join_all(infinitepush_moves)
+                    .and_then(move |infinitepush_moves| {
+                        moves.extend(infinitepush_moves);
+                        resolver.run_bookmark_move_hooks(moves)
+                    })
+                    .map(move |()| {
+                        (
+                            changegroup_id,
+                            bookmark_push,
+                            phases_push,
+                            obsmarkers_push,
+                            infinitepush_bookmarks,
+                        )
+                    })
+            }
+        })
+        .and_then({
+            let resolver = resolver.clone();
+            move |(
+                changegroup_id,
+                bookmark_push,
+                phases_push,
+                obsmarkers_push,
+                infinitepush_bookmarks,
+            )| {
+                let mut pushkey_ids: Vec<_> = bookmark_push.iter().map(|bp| bp.part_id).collect();
+                pushkey_ids.extend(phases_push.iter().filter_map(|pp| pp.part_id));
+
+                // Everything that the moved bookmarks point to is public now. The phases the
+                // client asked for are not trusted, what it can publish is what its bookmark
+                // moves reach.
+                let new_public: Vec<_> = bookmark_push.iter().filter_map(|bp| bp.new).collect();
+
+                let repo = resolver.repo.clone();
                 (move || {
-                    let bookmark_ids: Vec<_> = bookmark_push.iter().map(|bp| bp.part_id).collect();
-
                     let mut txn = resolver.repo.update_bookmark_transaction();
                     for bp in bookmark_push {
                         try_boxfuture!(add_bookmark_to_transaction(&mut txn, bp));
@@ -154,15 +282,51 @@ fn resolve_push(
                                 Err(format_err!("Bookmark transaction failed"))
                             }
                         })
-                        .map(move |()| (changegroup_id, bookmark_ids))
                         .boxify()
                 })()
                     .context("While updating Bookmarks")
                     .from_err()
+                    .and_then({
+                        let repo = repo.clone();
+                        move |()| {
+                            let updates = infinitepush_bookmarks.into_iter().map(
+                                move |(name, new)| {
+                                    let bookmark =
+                                        try_boxfuture!(bookmarks::Bookmark::new(name.as_str()));
+                                    repo.set_scratch_bookmark(&bookmark, &new)
+                                },
+                            );
+                            future::join_all(updates)
+                                .map(|_| ())
+                                .context("While updating scratch Bookmarks")
+                                .from_err()
+                        }
+                    })
+                    .and_then({
+                        let repo = repo.clone();
+                        move |()| {
+                            repo.mark_public(new_public)
+                                .context("While updating Phases")
+                                .from_err()
+                        }
+                    })
+                    .and_then(move |()| {
+                        let added = obsmarkers_push.into_iter().map(move |op| {
+                            let part_id = op.part_id;
+                            repo.add_obsmarkers(op.markers)
+                                .map(move |new| (part_id, new))
+                        });
+                        future::join_all(added)
+                            .context("While storing Obsmarkers")
+                            .from_err()
+                    })
+                    .map(move |obsmarkers_replies| {
+                        (changegroup_id, pushkey_ids, obsmarkers_replies)
+                    })
             }
         })
-        .and_then(move |(changegroup_id, bookmark_ids)| {
-            resolver.prepare_response(changegroup_id, bookmark_ids)
+        .and_then(move |(changegroup_id, pushkey_ids, obsmarkers_replies)| {
+            resolver.prepare_response(changegroup_id, pushkey_ids, obsmarkers_replies)
         })
         .context("bundle2-resolver error")
         .from_err()
@@ -185,6 +349,8 @@ fn next_item(
 
 struct ChangegroupPush {
     part_id: PartId,
+    // Whether the changesets were pushed with infinitepush, without moving a bookmark
+    infinitepush: bool,
     changesets: Changesets,
     filelogs: Filelogs,
     content_blobs: ContentBlobs,
@@ -196,7 +362,21 @@ struct CommonHeads {
 
 enum Pushkey {
     BookmarkPush(BookmarkPush),
-    Phases,
+    Phases(PhasesPush),
+    Obsmarkers(ObsmarkersPush),
+}
+
+/// A phases pushkey or a phase-heads part. The phases the client asks for are ignored, the
+/// changesets that the moved bookmarks reach are made public instead.
+struct PhasesPush {
+    /// Set for a pushkey, which needs a reply
+    part_id: Option<PartId>,
+}
+
+/// Obsolescence markers of an obsmarkers part
+struct ObsmarkersPush {
+    part_id: PartId,
+    markers: Vec<HgObsmarker>,
 }
 
 struct BookmarkPush {
@@ -210,16 +390,29 @@ struct BookmarkPush {
 #[derive(Clone)]
 struct Bundle2Resolver {
     repo: Arc<BlobRepo>,
+    hook_manager: Arc<HookManager>,
+    pusher: Option<String>,
     logger: Logger,
     scuba_logger: ScubaSampleBuilder,
+    response_compression: Option<CompressorType>,
 }
 
 impl Bundle2Resolver {
-    fn new(repo: Arc<BlobRepo>, logger: Logger, scuba_logger: ScubaSampleBuilder) -> Self {
+    fn new(
+        repo: Arc<BlobRepo>,
+        hook_manager: Arc<HookManager>,
+        pusher: Option<String>,
+        logger: Logger,
+        scuba_logger: ScubaSampleBuilder,
+        response_compression: Option<CompressorType>,
+    ) -> Self {
         Self {
             repo,
+            hook_manager,
+            pusher,
             logger,
             scuba_logger,
+            response_compression,
         }
     }
 
@@ -279,6 +472,7 @@ impl Bundle2Resolver {
                 Some(Bundle2Item::Changegroup(header, parts))
                 | Some(Bundle2Item::B2xInfinitepush(header, parts)) => {
                     let part_id = header.part_id();
+                    let infinitepush = *header.part_type() == PartHeaderType::B2xInfinitepush;
                     let (c, f) = split_changegroup(parts);
                     convert_to_revlog_changesets(c)
                         .collect()
@@ -302,6 +496,7 @@ impl Bundle2Resolver {
                         .map(move |(changesets, filelogs, content_blobs)| {
                             let cg_push = ChangegroupPush {
                                 part_id,
+                                infinitepush,
                                 changesets,
                                 filelogs,
                                 content_blobs,
@@ -318,7 +513,9 @@ impl Bundle2Resolver {
             .boxify()
     }
 
-    /// Parses pushkey part if it exists
+    /// Parses pushkey part if it exists, phase-heads parts are parsed here too because they are
+    /// sent instead of phases pushkeys by clients that know them, and so are obsmarkers parts,
+    /// which clients send between the phases and the bookmarks.
     /// Returns an error if the pushkey namespace is unknown
     fn maybe_resolve_pushkey(
         &self,
@@ -335,7 +532,16 @@ impl Bundle2Resolver {
                     );
 
                     let pushkey = match &namespace[..] {
-                        b"phases" => Pushkey::Phases,
+                        b"phases" => {
+                            let part_id = header.part_id();
+                            let mparams = header.mparams();
+                            let key = try_boxfuture!(get_ascii_param(mparams, "key"));
+                            try_boxfuture!(HgChangesetId::from_ascii_str(&key));
+                            // Phases follow the bookmarks, so the pushkey is accepted and ignored
+                            Pushkey::Phases(PhasesPush {
+                                part_id: Some(part_id),
+                            })
+                        }
                         b"bookmarks" => {
                             let part_id = header.part_id();
                             let mparams = header.mparams();
@@ -361,6 +567,23 @@ impl Bundle2Resolver {
 
                     emptypart.map(move |_| (Some(pushkey), bundle2)).boxify()
                 }
+                Some(Bundle2Item::PhaseHeads(_header, heads)) => heads
+                    .for_each(|_| Ok(()))
+                    .map(move |()| {
+                        let pushkey = Pushkey::Phases(PhasesPush { part_id: None });
+                        (Some(pushkey), bundle2)
+                    })
+                    .boxify(),
+                Some(Bundle2Item::Obsmarkers(header, markers)) => markers
+                    .collect()
+                    .map(move |markers| {
+                        let pushkey = Pushkey::Obsmarkers(ObsmarkersPush {
+                            part_id: header.part_id(),
+                            markers,
+                        });
+                        (Some(pushkey), bundle2)
+                    })
+                    .boxify(),
                 Some(part) => ok((None, stream::once(Ok(part)).chain(bundle2).boxify())).boxify(),
                 None => ok((None, bundle2)).boxify(),
             })
@@ -398,38 +621,79 @@ impl Bundle2Resolver {
     }
 
     /// Parse b2xinfinitepushscratchbookmarks.
-    /// This part is ignored, so just parse it and forget it
+    /// The bookmarks are returned so that bookmark move hooks can be run on them before they are
+    /// stored. The part is a JSON dictionary from bookmark name to hex changeset id. The parts
+    /// after it are not used by Mononoke, so they are skipped, and the whole rest of the bundle
+    /// is consumed.
     fn maybe_resolve_infinitepush_bookmarks(
         &self,
         bundle2: BoxStream<Bundle2Item, Error>,
-    ) -> BoxFuture<((), BoxStream<Bundle2Item, Error>), Error> {
-        next_item(bundle2)
-            .and_then(
-                move |(infinitepushbookmarks, bundle2)| match infinitepushbookmarks {
-                    Some(Bundle2Item::B2xInfinitepushBookmarks(_, bookmarks)) => {
-                        bookmarks.collect().map(|_| ((), bundle2)).boxify()
-                    }
-                    None => Ok(((), bundle2)).into_future().boxify(),
-                    _ => err(format_err!(
-                        "Expected B2xInfinitepushBookmarks or end of the stream"
-                    )).boxify(),
-                },
-            )
+    ) -> BoxFuture<(Vec<(String, HgChangesetId)>, BoxStream<Bundle2Item, Error>), Error> {
+        bundle2
+            .and_then(|part| match part {
+                Bundle2Item::B2xInfinitepushBookmarks(_, bookmarks) => bookmarks
+                    .collect()
+                    .and_then(|chunks| {
+                        let mut res = vec![];
+                        for chunk in chunks {
+                            let bookmarks: HashMap<String, String> =
+                                serde_json::from_slice(&chunk)?;
+                            for (name, hash) in bookmarks {
+                                res.push((name, HgChangesetId::from_str(&hash)?));
+                            }
+                        }
+                        Ok(res)
+                    })
+                    .boxify(),
+                other => skip_part(other).map(|()| vec![]).boxify(),
+            })
+            .concat2()
+            .map(|bookmarks| (bookmarks, stream::empty().boxify()))
             .context("While resolving B2xInfinitepushBookmarks")
             .from_err()
             .boxify()
     }
 
+    /// Runs the bookmark move hooks on all given moves. Fails if any hook doesn't accept a move,
+    /// in which case none of the bookmarks are updated.
+    fn run_bookmark_move_hooks(&self, moves: Vec<HookBookmarkMove>) -> BoxFuture<(), Error> {
+        let hook_manager = self.hook_manager.clone();
+        future::join_all(moves.into_iter().map(move |bookmark_move| {
+            let bookmark = bookmark_move.bookmark.clone();
+            hook_manager
+                .run_bookmark_move_hooks(bookmark_move)
+                .and_then(move |executions| {
+                    for (hook_name, execution) in executions {
+                        let description = match execution {
+                            HookExecution::Accepted => continue,
+                            HookExecution::Rejected(info) => info.description,
+                            other => format!("{:?}", other),
+                        };
+                        return Err(ErrorKind::BookmarkMoveRejected(
+                            bookmark,
+                            hook_name,
+                            description,
+                        ).into());
+                    }
+                    Ok(())
+                })
+        })).map(|_| ())
+            .context("While running bookmark move hooks")
+            .from_err()
+            .boxify()
+    }
+
     /// Takes parsed Changesets and scheduled for upload Filelogs and Manifests. The content of
     /// Manifests is used to figure out DAG of dependencies between a given Changeset and the
     /// Manifests and Filelogs it adds.
     /// The Changesets are scheduled for uploading and a Future is returned, whose completion means
-    /// that the changesets were uploaded
+    /// that the changesets were uploaded and recorded as draft
     fn upload_changesets(
         &self,
         cg_push: ChangegroupPush,
         manifests: Manifests,
     ) -> BoxFuture<(), Error> {
+        let infinitepush = cg_push.infinitepush;
         let changesets = cg_push.changesets;
         let filelogs = cg_push.filelogs;
         let content_blobs = cg_push.content_blobs;
@@ -505,6 +769,17 @@ impl Bundle2Resolver {
         let repo = self.repo.clone();
 
         let changesets_hashes: Vec<_> = changesets.iter().map(|(hash, _)| *hash).collect();
+        // Changesets without a recorded phase are draft. Infinitepush changesets are left
+        // unrecorded, there is no limit to how many of them are pushed and they would all be
+        // listed as draft roots to every client.
+        let drafts: Vec<_> = if infinitepush {
+            vec![]
+        } else {
+            changesets_hashes
+                .iter()
+                .map(|hash| HgChangesetId::new(*hash))
+                .collect()
+        };
 
         trace!(self.logger, "changesets: {:?}", changesets);
         trace!(self.logger, "filelogs: {:?}", filelogs.keys());
@@ -536,6 +811,10 @@ impl Bundle2Resolver {
                 ).map_err(Error::from)
                     .for_each(|_| Ok(()))
             })
+            .and_then({
+                let repo = self.repo.clone();
+                move |()| repo.add_draft(drafts)
+            })
             .context(ErrorKind::WhileUploadingData(changesets_hashes))
             .from_err()
             .boxify()
@@ -555,27 +834,29 @@ impl Bundle2Resolver {
     }
 
     /// Takes a changegroup id and prepares a Bytes response containing Bundle2 with reply to
-    /// changegroup part saying that the push was successful
+    /// changegroup part saying that the push was successful, and replies to pushkey parts and to
+    /// obsmarkers parts with the number of new markers.
     fn prepare_response(
         &self,
         changegroup_id: Option<PartId>,
-        bookmark_ids: Vec<PartId>,
+        pushkey_ids: Vec<PartId>,
+        obsmarkers_replies: Vec<(PartId, usize)>,
     ) -> BoxFuture<Bytes, Error> {
         let writer = Cursor::new(Vec::new());
         let mut bundle = Bundle2EncodeBuilder::new(writer);
-        // Mercurial currently hangs while trying to read compressed bundles over the wire:
-        // https://bz.mercurial-scm.org/show_bug.cgi?id=5646
-        // TODO: possibly enable compression support once this is fixed.
-        bundle.set_compressor_type(None);
+        bundle.set_compressor_type(self.response_compression);
         if let Some(changegroup_id) = changegroup_id {
             bundle.add_part(try_boxfuture!(parts::replychangegroup_part(
                 parts::ChangegroupApplyResult::Success { heads_num_diff: 0 },
                 changegroup_id,
             )));
         }
-        for part_id in bookmark_ids {
+        for part_id in pushkey_ids {
             bundle.add_part(try_boxfuture!(parts::replypushkey_part(true, part_id)));
         }
+        for (part_id, new) in obsmarkers_replies {
+            bundle.add_part(try_boxfuture!(parts::replyobsmarkers_part(new, part_id)));
+        }
         bundle
             .build()
             .map(|cursor| Bytes::from(cursor.into_inner()))
@@ -613,6 +894,23 @@ impl Bundle2Resolver {
     }
 }
 
+/// Read the payload of a part that is not used, so that the parts after it can be parsed
+fn skip_part(part: Bundle2Item) -> BoxFuture<(), Error> {
+    match part {
+        Bundle2Item::Start(_) => ok(()).boxify(),
+        Bundle2Item::Changegroup(_, parts) | Bundle2Item::B2xInfinitepush(_, parts) => {
+            parts.for_each(|_| Ok(())).boxify()
+        }
+        Bundle2Item::B2xCommonHeads(_, heads) => heads.for_each(|_| Ok(())).boxify(),
+        Bundle2Item::B2xTreegroup2(_, parts) => parts.for_each(|_| Ok(())).boxify(),
+        Bundle2Item::B2xInfinitepushBookmarks(_, bytes) => bytes.for_each(|_| Ok(())).boxify(),
+        Bundle2Item::Replycaps(_, caps) => caps.map(|_| ()).boxify(),
+        Bundle2Item::Pushkey(_, part) => part,
+        Bundle2Item::PhaseHeads(_, heads) => heads.for_each(|_| Ok(())).boxify(),
+        Bundle2Item::Obsmarkers(_, markers) => markers.for_each(|_| Ok(())).boxify(),
+    }
+}
+
 fn add_bookmark_to_transaction(
     txn: &mut Box<bookmarks::Transaction>,
     bookmark_push: BookmarkPush,
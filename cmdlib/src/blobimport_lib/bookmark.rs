@@ -108,20 +108,26 @@ pub fn upload_bookmarks(
             move |vec| {
                 let count = vec.len();
                 let mut transaction = blobrepo.update_bookmark_transaction();
+                let mut new_public = Vec::with_capacity(count);
 
                 for (key, value) in vec {
                     let key = Bookmark::new_ascii(try_boxfuture!(AsciiString::from_ascii(key)));
-                    try_boxfuture!(transaction.force_set(&key, &value))
+                    try_boxfuture!(transaction.force_set(&key, &value));
+                    new_public.push(value);
                 }
 
+                let blobrepo = blobrepo.clone();
                 transaction.commit()
                     .and_then(move |ok| {
                         if ok {
-                            Ok(count)
+                            Ok(())
                         } else {
                             Err(format_err!("Bookmark transaction failed"))
                         }
                     })
+                    // Everything that the bookmarks point to is public
+                    .and_then(move |()| blobrepo.mark_public(new_public))
+                    .map(move |()| count)
                     .boxify()
             }
         }).for_each(move |count| {
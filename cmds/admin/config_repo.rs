@@ -9,6 +9,7 @@ use std::fs;
 use std::io::Write;
 use std::path::PathBuf;
 use std::process::{Command, ExitStatus};
+use std::str::{self, FromStr};
 use std::sync::Arc;
 
 use clap::{App, Arg, ArgMatches, SubCommand};
@@ -22,13 +23,16 @@ use tokio_process::CommandExt;
 
 use blobrepo::BlobRepo;
 use cmdlib::{args::setup_blobrepo_dir, blobimport_lib::Blobimport};
-use mercurial_types::RepositoryId;
+use hooks::lua_hook::LuaHook;
+use mercurial_types::{HgChangesetId, RepositoryId};
+use metaconfig::{ConfigDiagnostic, ConfigValidation, RepoConfigs};
 
 const CLONE_CMD: &'static str = "clone";
 const CLONE_DFLT_DIR: &'static str = "mononoke-config";
 const IMPORT_CMD: &'static str = "import";
 const IMPORT_DFLT_DIR: &'static str = "mononoke-config-imported";
 const FBPKG_CMD: &'static str = "fbpkg";
+const VALIDATE_CMD: &'static str = "validate";
 
 const HGRC_CONTENT: &'static str = "
 [extensions]
@@ -48,6 +52,7 @@ enum ErrorKind {
     #[fail(display = "Aborting: command '{}' killed by a signal", _0)] KilledBySignal(&'static str),
     #[fail(display = "Aborting: command '{}' exited with exit status {}", _0, _1)]
     NonZeroExit(&'static str, i32),
+    #[fail(display = "Config is invalid, found {} problem(s)", _0)] InvalidConfig(usize),
 }
 
 pub fn prepare_command<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
@@ -81,10 +86,23 @@ pub fn prepare_command<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
                 .help("Do not use --revision-check on fbpkg build"),
         );
 
+    let validate = SubCommand::with_name(VALIDATE_CMD)
+        .about("check the config for errors, exits with non-zero status if any is found")
+        .arg(
+            Arg::with_name("PATH_OR_COMMIT")
+                .required(true)
+                .help(
+                    "Either a checkout of mononoke-config, whose working copy parent is \
+                     validated, or a commit of the imported config repo",
+                ),
+        )
+        .add_src();
+
     app.about("set of commands to interact with mononoke-config repository")
         .subcommand(clone)
         .subcommand(import)
         .subcommand(fbpkg)
+        .subcommand(validate)
 }
 
 trait AppExt {
@@ -135,6 +153,7 @@ pub fn handle_command<'a>(matches: &ArgMatches<'a>, logger: Logger) -> BoxFuture
         (CLONE_CMD, Some(sub_m)) => handle_clone(sub_m, logger),
         (IMPORT_CMD, Some(sub_m)) => handle_import(sub_m, logger),
         (FBPKG_CMD, Some(sub_m)) => handle_fbpkg(sub_m, logger),
+        (VALIDATE_CMD, Some(sub_m)) => handle_validate(sub_m, logger),
         _ => {
             println!("{}", matches.usage());
             ::std::process::exit(1);
@@ -261,6 +280,122 @@ fn handle_fbpkg<'a>(args: &ArgMatches<'a>, logger: Logger) -> BoxFuture<(), Erro
         .boxify()
 }
 
+fn handle_validate<'a>(args: &ArgMatches<'a>, logger: Logger) -> BoxFuture<(), Error> {
+    let path_or_commit = args.value_of("PATH_OR_COMMIT").unwrap();
+
+    let validation = match HgChangesetId::from_str(path_or_commit) {
+        Ok(changesetid) => {
+            // A commit of an already imported config repo
+            let src = match args.value_of("src") {
+                Some(dir) => PathBuf::from(dir),
+                None => try_boxfuture!(data_dir()).join(IMPORT_DFLT_DIR),
+            };
+            info!(
+                logger,
+                "Validating commit {} of {}",
+                changesetid,
+                src.display()
+            );
+            let blobrepo = try_boxfuture!(BlobRepo::new_rocksdb(
+                logger.new(o!["BlobRepo:Rocksdb" => src.to_string_lossy().into_owned()]),
+                &src,
+                RepositoryId::new(0),
+            ));
+            RepoConfigs::validate_config_repo(blobrepo, changesetid)
+        }
+        Err(_) => {
+            // A checkout of the config repo, import it first to validate exactly what the
+            // server would read
+            let src = PathBuf::from(path_or_commit);
+            let tmpdir = try_boxfuture!(TempDir::new(IMPORT_DFLT_DIR));
+            let import_dir = tmpdir.path().to_owned();
+            info!(logger, "Validating {}", src.display());
+
+            working_copy_parent(src.clone())
+                .and_then({
+                    cloned!(logger, import_dir);
+                    move |changesetid| {
+                        import(logger, src, import_dir).map(move |()| changesetid)
+                    }
+                })
+                .and_then(move |changesetid| {
+                    let blobrepo = BlobRepo::new_rocksdb(
+                        logger.new(o!["BlobRepo:Rocksdb" => "imported config"]),
+                        &import_dir,
+                        RepositoryId::new(0),
+                    )?;
+                    Ok(RepoConfigs::validate_config_repo(blobrepo, changesetid))
+                })
+                .flatten()
+                // Make sure that the TempDir is dropped not earlier than at the end
+                .map(move |validation| {
+                    let _ = tmpdir;
+                    validation
+                })
+                .boxify()
+        }
+    };
+
+    validation
+        .and_then(|validation| {
+            let ConfigValidation {
+                mut diagnostics,
+                hooks,
+                hook_libs,
+            } = validation;
+            for location in hooks {
+                let hook = LuaHook::new(location.hook.name, location.hook.code);
+                if let Err(err) = hook.check_syntax() {
+                    diagnostics.push(ConfigDiagnostic {
+                        file: location.file,
+                        key: None,
+                        message: format!("hook {} failed to compile: {}", hook.name, err),
+                    });
+                }
+            }
+            for location in hook_libs {
+                let module = LuaHook::new(location.name, location.code);
+                if let Err(err) = module.check_syntax() {
+                    diagnostics.push(ConfigDiagnostic {
+                        file: location.file,
+                        key: None,
+                        message: format!("hook module {} failed to compile: {}", module.name, err),
+                    });
+                }
+            }
+
+            diagnostics.sort_by(|a, b| (&a.file, &a.key).cmp(&(&b.file, &b.key)));
+            for diagnostic in &diagnostics {
+                println!("{}", diagnostic);
+            }
+            if diagnostics.is_empty() {
+                println!("Config is valid");
+                Ok(())
+            } else {
+                Err(ErrorKind::InvalidConfig(diagnostics.len()).into())
+            }
+        })
+        .boxify()
+}
+
+/// Commit that the working copy of the given checkout is based on
+fn working_copy_parent(src: PathBuf) -> BoxFuture<HgChangesetId, Error> {
+    Command::new("hg")
+        .arg("log")
+        .arg("--rev")
+        .arg(".")
+        .arg("--template")
+        .arg("{node}")
+        .current_dir(&src)
+        .output_async()
+        .from_err()
+        .and_then(|output| {
+            check_status(output.status, "hg log")?;
+            HgChangesetId::from_str(str::from_utf8(&output.stdout)?.trim())
+        })
+        .boxify()
+}
+
 fn data_dir() -> Result<PathBuf> {
     Ok(PathBuf::from("/data/users").join(env::var("USER")?))
 }
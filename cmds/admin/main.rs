@@ -20,8 +20,10 @@ extern crate blobstore;
 extern crate cmdlib;
 #[macro_use]
 extern crate futures_ext;
+extern crate hooks;
 extern crate manifoldblob;
 extern crate mercurial_types;
+extern crate metaconfig;
 extern crate mononoke_types;
 #[macro_use]
 extern crate slog;
@@ -54,6 +56,7 @@ use slog::Logger;
 const BLOBSTORE_FETCH: &'static str = "blobstore-fetch";
 const CONTENT_FETCH: &'static str = "content-fetch";
 const CONFIG_REPO: &'static str = "config";
+const PHASES_BACKFILL: &'static str = "phases-backfill";
 const MAX_CONCURRENT_REQUESTS_PER_IO_THREAD: usize = 4;
 
 fn setup_app<'a, 'b>() -> App<'a, 'b> {
@@ -94,6 +97,11 @@ fn setup_app<'a, 'b>() -> App<'a, 'b> {
              <PATH>            'path to fetch'",
         );
 
+    let phases_backfill = SubCommand::with_name(PHASES_BACKFILL).about(
+        "marks everything reachable from a bookmark as public, for repos whose bookmarks were \
+         moved before phases were tracked",
+    );
+
     let app = args::MononokeApp {
         safe_writes: false,
         hide_advanced_args: true,
@@ -105,6 +113,7 @@ fn setup_app<'a, 'b>() -> App<'a, 'b> {
         .about("Poke at mononoke internals for debugging and investigating data structures.")
         .subcommand(blobstore_fetch)
         .subcommand(content_fetch)
+        .subcommand(phases_backfill)
         .subcommand(config_repo::prepare_command(SubCommand::with_name(
             CONFIG_REPO,
         )))
@@ -297,6 +306,12 @@ fn main() {
                 })
                 .boxify()
         }
+        (PHASES_BACKFILL, Some(_)) => {
+            let repo = args::open_blobrepo(&logger, &matches);
+            repo.mark_public_from_bookmarks()
+                .map(move |()| info!(logger, "Phases backfilled"))
+                .boxify()
+        }
         (CONFIG_REPO, Some(sub_m)) => config_repo::handle_command(sub_m, logger),
         _ => {
             println!("{}", matches.usage());
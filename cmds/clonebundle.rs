@@ -0,0 +1,110 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Generates the clone bundle of a bookmark into the blobstore. Servers advertise the newest
+//! bundle they find among the ancestors of the bookmark, so running this periodically (with
+//! `--interval`) keeps the amount of history a fresh clone has to `getbundle` small.
+
+#![deny(warnings)]
+
+extern crate blobrepo;
+extern crate bookmarks;
+extern crate clap;
+extern crate cmdlib;
+#[macro_use]
+extern crate failure_ext as failure;
+extern crate futures;
+extern crate futures_ext;
+extern crate repo_client;
+#[macro_use]
+extern crate slog;
+extern crate tokio;
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use clap::App;
+use failure::{Error, Result, SlogKVError};
+use futures::{future, Future, Stream};
+use futures_ext::{BoxFuture, FutureExt};
+use slog::Logger;
+
+use blobrepo::BlobRepo;
+use bookmarks::Bookmark;
+use cmdlib::args;
+use repo_client::build_clone_bundle;
+
+fn setup_app<'a, 'b>() -> App<'a, 'b> {
+    let app = args::MononokeApp {
+        safe_writes: false,
+        hide_advanced_args: false,
+        local_instances: true,
+        default_glog: true,
+    };
+    app.build("clone bundle generator")
+        .version("0.0.0")
+        .about("Generate the clone bundle of a bookmark into the blobstore.")
+        .args_from_usage(
+            r#"
+            <BOOKMARK>                      'bookmark to generate the bundle for'
+            --interval [SECS]               'if provided, generate a bundle every SECS seconds'
+            --delta-threshold [PERCENT]     'max delta size in percent of the fulltext, default 50'
+        "#,
+        )
+}
+
+fn generate(
+    repo: Arc<BlobRepo>,
+    bookmark: Bookmark,
+    delta_threshold: usize,
+    logger: Logger,
+) -> BoxFuture<(), Error> {
+    repo.get_bookmark(&bookmark)
+        .and_then(move |changesetid| match changesetid {
+            Some(changesetid) => build_clone_bundle(repo, changesetid, delta_threshold, logger),
+            None => future::err(format_err!("bookmark {} not found", bookmark)).boxify(),
+        })
+        .boxify()
+}
+
+fn main() -> Result<()> {
+    let matches = setup_app().get_matches();
+
+    let logger = args::get_logger(&matches);
+
+    let repo = Arc::new(args::open_blobrepo(&logger, &matches));
+
+    let bookmark = Bookmark::new(
+        matches
+            .value_of("BOOKMARK")
+            .expect("bookmark is not specified"),
+    )?;
+
+    let delta_threshold = args::get_usize(&matches, "delta-threshold", 50);
+
+    let mut runtime = tokio::runtime::Runtime::new()?;
+    let result = match args::get_usize_opt(&matches, "interval") {
+        None => runtime.block_on(generate(repo, bookmark, delta_threshold, logger)),
+        Some(interval) => {
+            let interval = Duration::from_secs(interval as u64);
+            let generate_periodically = tokio::timer::Interval::new(Instant::now(), interval)
+                .map_err(Error::from)
+                .for_each(move |_| {
+                    let logger = logger.clone();
+                    // A failed attempt is retried at the next tick
+                    generate(repo.clone(), bookmark.clone(), delta_threshold, logger.clone())
+                        .or_else(move |err| {
+                            error!(logger, "failed to generate clone bundle"; SlogKVError(err));
+                            Ok(())
+                        })
+                });
+            runtime.block_on(generate_periodically)
+        }
+    };
+    // Let the runtime finish remaining work - uploading logs etc
+    runtime.shutdown_on_idle();
+    result
+}
@@ -0,0 +1,455 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Replays sessions recorded by the server (see its `--record-sessions-dir`) and reports latency
+//! percentiles per command.
+//!
+//! Sessions are replayed concurrently and start at the same offsets from each other as they did
+//! when they were recorded. The requests of a session are sent one after the other, each not
+//! earlier than its recorded offset from the start of the session. `--speed` divides all offsets,
+//! and 0 sends every request as soon as the previous one of the session finished.
+//!
+//! Requests are either sent to a server, over a new connection each so that every response can be
+//! told apart, or handled by a `RepoClient` in this process, which leaves the network and the
+//! listener out of the measurement. Only the time from sending a request to receiving the end of
+//! its response is measured. Streaming arguments aren't recorded, so the commands that have them
+//! (`unbundle`, `getfiles` and `getpackv1`) are skipped.
+
+#![deny(warnings)]
+
+extern crate bytes;
+extern crate clap;
+extern crate cmdlib;
+#[macro_use]
+extern crate failure_ext as failure;
+extern crate futures;
+extern crate futures_ext;
+extern crate hgproto;
+extern crate metaconfig;
+extern crate openssl;
+extern crate repo_client;
+extern crate scuba_ext;
+extern crate secure_utils;
+#[macro_use]
+extern crate slog;
+extern crate sshrelay;
+extern crate tokio;
+extern crate tokio_io;
+extern crate tokio_openssl;
+extern crate tracing;
+extern crate uuid;
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use clap::{App, ArgMatches};
+use failure::{Error, Result};
+use futures::{future, stream, Future, Stream};
+use futures_ext::{BoxFuture, FutureExt};
+use openssl::ssl::{SslConnector, SslMethod};
+use slog::Logger;
+use tokio::net::TcpStream;
+use tokio::timer::Delay;
+use tokio_io::AsyncRead;
+use tokio_io::codec::{FramedRead, FramedWrite};
+use tokio_openssl::SslConnectorExt;
+use uuid::Uuid;
+
+use cmdlib::args;
+use hgproto::{HgProtoHandler, Request, SingleRequest};
+use hgproto::sshproto::{self, record};
+use hgproto::sshproto::record::SessionRecord;
+use metaconfig::RepoConfigs;
+use repo_client::{LoadKind, LoadLimiter, LoadLimits, MononokeRepo, RepoClient};
+use scuba_ext::ScubaSampleBuilder;
+use secure_utils::{build_identity, read_x509};
+use sshrelay::{Preamble, SshDecoder, SshEncoder, SshMsg, SshStream};
+use tracing::TraceContext;
+
+fn setup_app<'a, 'b>() -> App<'a, 'b> {
+    let app = args::MononokeApp {
+        safe_writes: true,
+        hide_advanced_args: true,
+        local_instances: true,
+        default_glog: true,
+    };
+    app.build("session replay")
+        .version("0.0.0")
+        .about("Replay recorded sessions and report the latency of every command.")
+        .args_from_usage(
+            r#"
+            <RECORDS>...                'session record files to replay'
+            --speed [FACTOR]            'replay FACTOR times faster than recorded, 0 to not wait between requests, default 1'
+            --server [HOST:PORT]        'replay against this server'
+            --cert [PATH]               'path to a file with the client certificate, for --server'
+            --private-key [PATH]        'path to a file with the private key, for --server'
+            --ca-pem [PATH]             'path to a file with the CA certificate, for --server'
+            --common-name [NAME]        'common name of the certificate of the server, for --server'
+            --config-dir [PATH]         'replay against the repos of the config in this directory, in this process'
+        "#,
+        )
+}
+
+/// Where the requests are sent
+enum Target {
+    Server {
+        addr: SocketAddr,
+        connector: SslConnector,
+        common_name: String,
+    },
+    Local(HashMap<String, Arc<MononokeRepo>>),
+}
+
+impl Target {
+    fn server<'a>(matches: &ArgMatches<'a>, server: &str) -> Result<Self> {
+        let required = |name| {
+            matches
+                .value_of(name)
+                .ok_or_else(|| format_err!("--{} is required with --server", name))
+        };
+        let pkcs12 = build_identity(
+            required("cert")?.to_string(),
+            required("private-key")?.to_string(),
+        )?;
+        let mut connector = SslConnector::builder(SslMethod::tls())?;
+        connector.set_certificate(&pkcs12.cert)?;
+        connector.set_private_key(&pkcs12.pkey)?;
+        connector
+            .cert_store_mut()
+            .add_cert(read_x509(required("ca-pem")?)?)?;
+
+        Ok(Target::Server {
+            addr: server.parse()?,
+            connector: connector.build(),
+            common_name: required("common-name")?.to_string(),
+        })
+    }
+
+    /// Open the repos of the recorded sessions from the config in `config_dir`
+    fn local(logger: &Logger, config_dir: &str, records: &[SessionRecord]) -> Result<Self> {
+        let mut configs = RepoConfigs::read_from_dir(config_dir).wait()?.repos;
+        let mut repos = HashMap::new();
+        for record in records {
+            if repos.contains_key(&record.reponame) {
+                continue;
+            }
+            let config = configs
+                .remove(&record.reponame)
+                .ok_or_else(|| format_err!("repo {} is not in the config", record.reponame))?;
+            let repo = MononokeRepo::new(
+                logger.new(o!("repo" => record.reponame.clone())),
+                record.reponame.clone(),
+                &config,
+            )?;
+            repos.insert(record.reponame.clone(), Arc::new(repo));
+        }
+        Ok(Target::Local(repos))
+    }
+}
+
+/// How a session talks to the target
+enum Session {
+    Server {
+        target: Arc<Target>,
+        reponame: String,
+    },
+    Local {
+        client: RepoClient,
+        logger: Logger,
+    },
+}
+
+impl Session {
+    fn new(target: Arc<Target>, reponame: &str, logger: &Logger) -> Self {
+        let repo = match *target {
+            Target::Server { .. } => {
+                return Session::Server {
+                    target: target.clone(),
+                    reponame: reponame.to_string(),
+                }
+            }
+            Target::Local(ref repos) => repos[reponame].clone(),
+        };
+
+        let session_uuid = Uuid::new_v4();
+        let logger = logger.new(o!("session_uuid" => format!("{}", session_uuid)));
+        let client = RepoClient::new(
+            repo,
+            logger.clone(),
+            ScubaSampleBuilder::with_opt_table(None),
+            TraceContext::new(session_uuid, Instant::now()),
+            None,
+            None,
+            "replay".to_string(),
+            LoadLimiter::new(LoadKind::Commands, LoadLimits::unlimited()),
+        );
+        Session::Local { client, logger }
+    }
+
+    /// Send a request and wait for the end of its response. Yields how long that took.
+    fn send(&self, request: Bytes) -> BoxFuture<Duration, Error> {
+        match *self {
+            Session::Server {
+                ref target,
+                ref reponame,
+            } => send_to_server(target, reponame.clone(), request),
+            Session::Local {
+                ref client,
+                ref logger,
+            } => {
+                let start = Instant::now();
+                HgProtoHandler::new(
+                    stream::once(Ok::<_, io::Error>(request)),
+                    client.clone(),
+                    sshproto::HgSshCommandDecode,
+                    sshproto::HgSshCommandEncode,
+                    logger,
+                    Arc::new(Mutex::new(Vec::new())),
+                ).for_each(|_| Ok(()))
+                    .map(move |()| start.elapsed())
+                    .boxify()
+            }
+        }
+    }
+}
+
+fn send_to_server(target: &Target, reponame: String, request: Bytes) -> BoxFuture<Duration, Error> {
+    let (addr, connector, common_name) = match *target {
+        Target::Server {
+            ref addr,
+            ref connector,
+            ref common_name,
+        } => (*addr, connector.clone(), common_name.clone()),
+        Target::Local(_) => unreachable!("not a server"),
+    };
+
+    TcpStream::connect(&addr)
+        .from_err()
+        .and_then(move |sock| {
+            connector
+                .connect_async(&common_name, sock)
+                .map_err(|err| format_err!("tls handshake failed: {}", err))
+        })
+        .and_then(move |sock| {
+            let start = Instant::now();
+            let (rd, wr) = sock.split();
+            let rx = FramedRead::new(rd, SshDecoder::new());
+            let tx = FramedWrite::new(wr, SshEncoder::new());
+
+            let preamble = Preamble::new(reponame, Uuid::new_v4(), None, None);
+            let msgs = vec![
+                SshMsg::new(SshStream::Preamble(preamble), Bytes::new()),
+                SshMsg::new(SshStream::Stdin, request),
+            ];
+            // Closing stdin once the request is sent makes the server close the connection after
+            // the response
+            let send = stream::iter_ok(msgs).forward(tx).from_err();
+            // The server only writes to stderr to report errors
+            let receive = rx.from_err().for_each(|msg| match msg.stream() {
+                SshStream::Stderr => Err(format_err!(
+                    "{}",
+                    String::from_utf8_lossy(&msg.data()).trim()
+                )),
+                _ => Ok(()),
+            });
+            send.join(receive).map(move |_| start.elapsed())
+        })
+        .boxify()
+}
+
+/// Latencies and failures of every command of all sessions
+#[derive(Default)]
+struct Stats {
+    latencies: BTreeMap<&'static str, Vec<Duration>>,
+    failures: BTreeMap<&'static str, usize>,
+    skipped: BTreeMap<&'static str, usize>,
+}
+
+impl Stats {
+    fn print(&mut self) {
+        println!(
+            "{:<16} {:>8} {:>8} {:>10} {:>10} {:>10} {:>10}",
+            "command", "count", "failed", "p50 ms", "p90 ms", "p99 ms", "max ms"
+        );
+        for (command, latencies) in self.latencies.iter_mut() {
+            latencies.sort();
+            println!(
+                "{:<16} {:>8} {:>8} {:>10.1} {:>10.1} {:>10.1} {:>10.1}",
+                command,
+                latencies.len(),
+                self.failures.get(command).cloned().unwrap_or(0),
+                percentile(latencies, 50),
+                percentile(latencies, 90),
+                percentile(latencies, 99),
+                percentile(latencies, 100),
+            );
+        }
+        for (command, count) in self.failures.iter() {
+            if !self.latencies.contains_key(command) {
+                println!("{:<16} {:>8} {:>8}", command, 0, count);
+            }
+        }
+        for (command, count) in self.skipped.iter() {
+            println!("{}: {} skipped, streaming arguments are not recorded", command, count);
+        }
+    }
+}
+
+/// Nearest-rank percentile of sorted latencies, in milliseconds
+fn percentile(sorted: &[Duration], percent: usize) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    let rank = (sorted.len() * percent + 99) / 100;
+    as_secs_f64(sorted[rank.max(1) - 1]) * 1000.0
+}
+
+fn as_secs_f64(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1_000_000_000.0
+}
+
+/// `duration` replayed at `speed`
+fn scale(duration: Duration, speed: f64) -> Duration {
+    if speed <= 0.0 {
+        return Duration::from_secs(0);
+    }
+    let secs = as_secs_f64(duration) / speed;
+    Duration::new(secs as u64, (secs.fract() * 1_000_000_000.0) as u32)
+}
+
+fn command_name(request: &Request) -> &'static str {
+    match request {
+        &Request::Batch(_) => "batch",
+        &Request::Single(ref request) => request.name(),
+    }
+}
+
+fn has_streaming_args(request: &Request) -> bool {
+    match request {
+        &Request::Single(SingleRequest::Unbundle { .. })
+        | &Request::Single(SingleRequest::Getfiles)
+        | &Request::Single(SingleRequest::Getpackv1) => true,
+        _ => false,
+    }
+}
+
+fn replay_session(
+    target: Arc<Target>,
+    record: SessionRecord,
+    start: Instant,
+    speed: f64,
+    stats: Arc<Mutex<Stats>>,
+    logger: Logger,
+) -> BoxFuture<(), Error> {
+    let session = Arc::new(Session::new(target, &record.reponame, &logger));
+    let reponame = record.reponame;
+
+    Delay::new(start)
+        .from_err()
+        .and_then(move |()| {
+            stream::iter_ok(record.requests).for_each(move |recorded| {
+                let command = command_name(&recorded.request);
+                if has_streaming_args(&recorded.request) {
+                    *stats
+                        .lock()
+                        .expect("lock poisoned")
+                        .skipped
+                        .entry(command)
+                        .or_insert(0) += 1;
+                    return future::ok(()).boxify();
+                }
+
+                let request = sshproto::request::encode_request(&recorded.request);
+                let session = session.clone();
+                let stats = stats.clone();
+                let logger = logger.clone();
+                let reponame = reponame.clone();
+                Delay::new(start + scale(recorded.offset, speed))
+                    .from_err()
+                    .and_then(move |()| session.send(request))
+                    .then(move |res| {
+                        let mut stats = stats.lock().expect("lock poisoned");
+                        match res {
+                            Ok(latency) => {
+                                stats.latencies.entry(command).or_insert(vec![]).push(latency)
+                            }
+                            Err(err) => {
+                                warn!(logger, "{} failed on {}: {}", command, reponame, err);
+                                *stats.failures.entry(command).or_insert(0) += 1;
+                            }
+                        }
+                        Ok(())
+                    })
+                    .boxify()
+            })
+        })
+        .boxify()
+}
+
+fn main() -> Result<()> {
+    let matches = setup_app().get_matches();
+    let logger = args::get_logger(&matches);
+
+    let speed = match matches.value_of("speed") {
+        Some(speed) => speed.parse::<f64>()?,
+        None => 1.0,
+    };
+
+    let mut records = vec![];
+    for path in matches.values_of("RECORDS").expect("no session records") {
+        let data = fs::read(path)?;
+        let record = record::decode_session_record(&data)
+            .map_err(|err| format_err!("{}: {}", path, err))?;
+        records.push(record);
+    }
+
+    let target = match (matches.value_of("server"), matches.value_of("config-dir")) {
+        (Some(server), None) => Target::server(&matches, server)?,
+        (None, Some(config_dir)) => {
+            args::init_cachelib(&matches);
+            Target::local(&logger, config_dir, &records)?
+        }
+        _ => bail_msg!("exactly one of --server and --config-dir must be given"),
+    };
+    let target = Arc::new(target);
+
+    let stats = Arc::new(Mutex::new(Stats::default()));
+    let first_start = records.iter().map(|record| record.start).min();
+    let now = Instant::now();
+    let sessions: Vec<_> = records
+        .into_iter()
+        .map(|record| {
+            let offset = match first_start {
+                Some(first_start) => record
+                    .start
+                    .duration_since(first_start)
+                    .unwrap_or(Duration::from_secs(0)),
+                None => Duration::from_secs(0),
+            };
+            replay_session(
+                target.clone(),
+                record,
+                now + scale(offset, speed),
+                speed,
+                stats.clone(),
+                logger.clone(),
+            )
+        })
+        .collect();
+
+    let mut runtime = tokio::runtime::Runtime::new()?;
+    let result = runtime.block_on(future::join_all(sessions));
+    runtime.shutdown_on_idle();
+    result?;
+
+    stats.lock().expect("lock poisoned").print();
+    Ok(())
+}
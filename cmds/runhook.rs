@@ -9,6 +9,12 @@
 //! It's main purpose is to allow easy testing of hooks without having to run them as part of
 //! a push in a Mononoke server
 //! It currently supports hooks written in Lua only
+//!
+//! With `--revset` the hook (or all hooks configured for a bookmark, with
+//! `--hooks-for-bookmark`) is run over a range of history instead, and the result for every
+//! commit is printed as a line of JSON followed by a summary. This is useful to find out how
+//! many historical commits a new policy would have rejected before enabling it.
+//! Commits the hooks fail to run on are reported and skipped, and make runhook exit with 1.
 
 #![deny(warnings)]
 #![feature(try_from)]
@@ -17,6 +23,7 @@ extern crate blobrepo;
 extern crate blobstore;
 extern crate cachelib;
 extern crate clap;
+#[macro_use]
 extern crate failure_ext as failure;
 extern crate futures;
 #[macro_use]
@@ -24,7 +31,13 @@ extern crate futures_ext;
 extern crate hooks;
 extern crate manifoldblob;
 extern crate mercurial_types;
+extern crate metaconfig;
 extern crate mononoke_types;
+extern crate revset;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
 #[macro_use]
 extern crate slog;
 extern crate slog_glog_fmt;
@@ -42,97 +55,419 @@ use blobrepo::{BlobRepo, ManifoldArgs};
 use bookmarks::Bookmark;
 use clap::{App, ArgMatches};
 use failure::{Error, Result};
-use futures::Future;
+use futures::{Future, IntoFuture, Stream};
+use futures::future::join_all;
 use futures_ext::{BoxFuture, FutureExt};
 use hooks::{BlobRepoChangesetStore, HookExecution, HookManager};
+use hooks::hook_loader::load_hooks;
 use hooks::lua_hook::LuaHook;
-use mercurial_types::{HgChangesetId, RepositoryId};
+use mercurial_types::{HgChangesetId, HgNodeHash, RepositoryId};
+use metaconfig::RepoConfigs;
+use revset::DifferenceOfUnionsOfAncestorsNodeStream;
 use slog::{Drain, Level, Logger};
 use slog_glog_fmt::default_drain as glog_drain;
+use std::collections::{BTreeMap, BTreeSet};
 use std::env::args;
 use std::fs::File;
 use std::io::prelude::*;
+use std::path::PathBuf;
+use std::process;
 use std::str::FromStr;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 const MAX_CONCURRENT_REQUESTS_PER_IO_THREAD: usize = 4;
+const DEFAULT_JOBS: usize = 16;
 
-fn run_hook(
-    args: Vec<String>,
-    repo_creator: fn(&Logger, &ArgMatches) -> BlobRepo,
-) -> BoxFuture<HookExecution, Error> {
-    // Define command line args and parse command line
-    let matches = App::new("runhook")
+fn setup_app<'a, 'b>() -> App<'a, 'b> {
+    App::new("runhook")
         .version("0.0.0")
         .about("run a hook")
         .args_from_usage(concat!(
-            "<REPO_NAME>           'name of repository\n",
-            "<HOOK_FILE>           'file containing hook code\n",
-            "<HOOK_TYPE>           'the type of the hook (perfile, percs)\n",
-            "<REV>                 'revision hash'"
+            "<REPO_NAME>           'name of repository'\n",
+            "[HOOK_FILE]           'file containing hook code'\n",
+            "[HOOK_TYPE]           'the type of the hook (perfile, percs)'\n",
+            "[REV]                 'revision hash'\n",
+            "--revset [REVSET]     'run over a range of commits, e.g. \"ancestors(master) - ancestors(X)\"'\n",
+            "--limit [N]           'only run over the first N commits of the revset, newest first'\n",
+            "--jobs [N]            'number of commits to run hooks on in parallel'\n",
+            "--hooks-for-bookmark [BOOK] 'run all hooks configured for this bookmark in the config repo'\n",
+            "--configrepo_path [PATH] 'path to the config repo in rocksdb form'\n",
+            "--configrepo_book [BOOK] 'config repo bookmark'\n",
+            "--config-dir [PATH]  'directory with the config files, instead of a config repo'\n",
+            "-d, --debug           'print debug level output'"
         ))
-        .get_matches_from(args);
-
-    let logger = {
-        let level = if matches.is_present("debug") {
-            Level::Debug
-        } else {
-            Level::Info
-        };
+}
 
-        let drain = glog_drain().filter_level(level).fuse();
-        slog::Logger::root(drain, o![])
+fn create_logger(matches: &ArgMatches) -> Logger {
+    let level = if matches.is_present("debug") {
+        Level::Debug
+    } else {
+        Level::Info
     };
 
+    let drain = glog_drain().filter_level(level).fuse();
+    slog::Logger::root(drain, o![])
+}
+
+fn run_hook(
+    args: Vec<String>,
+    repo_creator: fn(&Logger, &ArgMatches) -> BlobRepo,
+) -> BoxFuture<HookExecution, Error> {
+    // Define command line args and parse command line
+    let matches = setup_app().get_matches_from(args);
+    let logger = create_logger(&matches);
+
     let repo_name = String::from(matches.value_of("REPO_NAME").unwrap());
-    let hook_file = matches.value_of("HOOK_FILE").unwrap();
-    let hook_type = matches.value_of("HOOK_TYPE").unwrap();
+    let hook_file = matches.value_of("HOOK_FILE").expect("HOOK_FILE must be specified");
+    let hook_type = matches.value_of("HOOK_TYPE").expect("HOOK_TYPE must be specified");
     println!("hook type is {}", hook_type);
     let file_hook = match hook_type.as_ref() {
         "perfile" => true,
         "percs" => false,
         _ => panic!("Invalid hook type"),
     };
-    let revstr = matches.value_of("REV").unwrap();
+    let revstr = matches.value_of("REV").expect("REV must be specified");
     let repo = repo_creator(&logger, &matches);
 
-    let mut file = File::open(hook_file).expect("Unable to open the hook file");
-    let mut code = String::new();
-    file.read_to_string(&mut code)
-        .expect("Unable to read the file");
     println!("======= Running hook =========");
     println!("Repository name is {}", repo_name);
     println!("Hook file is {} revision is {:?}", hook_file, revstr);
-    println!("Hook code is {}", code);
     println!("==============================");
 
-    let store = Box::new(BlobRepoChangesetStore::new(repo.clone()));
-    let mut hook_manager = HookManager::new(repo_name, store, 1024, 1024 * 1024);
-    let hook = LuaHook {
-        name: String::from("testhook"),
-        code,
-    };
-    if file_hook {
-        hook_manager.register_file_hook("testhook", Arc::new(hook));
-    } else {
-        hook_manager.register_changeset_hook("testhook", Arc::new(hook));
-    }
-    let bookmark = Bookmark::new("testbm").unwrap();
-    hook_manager.set_hooks_for_bookmark(bookmark.clone(), vec!["testhook".to_string()]);
+    let (hook_manager, bookmark) = try_boxfuture!(create_hook_manager(&logger, &matches, repo));
     let id = try_boxfuture!(HgChangesetId::from_str(revstr));
+    let no_hook = format!("no {} hook configured for bookmark {}", hook_type, bookmark);
     if file_hook {
         hook_manager
             .run_file_hooks_for_bookmark(id, &bookmark)
-            .map(|executions| executions.get(0).unwrap().1.clone())
+            .and_then(move |executions| match executions.into_iter().next() {
+                Some((_, execution)) => Ok(execution),
+                None => Err(format_err!("{}", no_hook)),
+            })
             .boxify()
     } else {
         hook_manager
             .run_changeset_hooks_for_bookmark(id, &bookmark)
-            .map(|executions| executions.get(0).unwrap().1.clone())
+            .and_then(move |executions| match executions.into_iter().next() {
+                Some((_, execution)) => Ok(execution),
+                None => Err(format_err!("{}", no_hook)),
+            })
             .boxify()
     }
 }
 
+/// Result of running all selected hooks on a single commit
+#[derive(Debug, Serialize)]
+struct CommitReport {
+    changeset: String,
+    accepted: bool,
+    hooks: Vec<HookReport>,
+    /// Set if the hooks could not be run on this commit
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct HookReport {
+    hook: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path: Option<String>,
+    result: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+}
+
+impl HookReport {
+    fn new(hook: String, path: Option<String>, execution: HookExecution) -> Self {
+        let (result, description) = match execution {
+            HookExecution::Accepted => ("accepted", None),
+            HookExecution::Rejected(info) => ("rejected", Some(info.description)),
+            HookExecution::InstructionLimitExceeded => ("instruction_limit_exceeded", None),
+            HookExecution::MemoryLimitExceeded => ("memory_limit_exceeded", None),
+            HookExecution::TimedOut => ("timed_out", None),
+        };
+        HookReport {
+            hook,
+            path,
+            result,
+            description,
+        }
+    }
+
+    fn accepted(&self) -> bool {
+        self.result == "accepted"
+    }
+
+    fn rejected(&self) -> bool {
+        self.result == "rejected"
+    }
+}
+
+/// Summary of a run over a range of commits
+#[derive(Debug, Default, Serialize)]
+struct RangeSummary {
+    total: usize,
+    accepted: usize,
+    /// Commits rejected by at least one hook
+    rejected: usize,
+    /// Commits that were not rejected, but where at least one hook hit one of its limits
+    limit_exceeded: usize,
+    /// Commits the hooks failed to run on
+    failed: usize,
+    /// Number of rejected commits per hook
+    rejections_per_hook: BTreeMap<String, usize>,
+    /// Number of commits per hook and limit outcome (e.g. `timed_out`)
+    limits_exceeded_per_hook: BTreeMap<String, BTreeMap<&'static str, usize>>,
+}
+
+impl RangeSummary {
+    fn add(&mut self, report: &CommitReport) {
+        self.total += 1;
+        if report.error.is_some() {
+            self.failed += 1;
+            return;
+        }
+        // A per-file hook runs once per file, but the commit counts once for the hook
+        let rejecting: BTreeSet<_> = report
+            .hooks
+            .iter()
+            .filter(|hook| hook.rejected())
+            .map(|hook| hook.hook.clone())
+            .collect();
+        let exceeding: BTreeSet<_> = report
+            .hooks
+            .iter()
+            .filter(|hook| !hook.accepted() && !hook.rejected())
+            .map(|hook| (hook.hook.clone(), hook.result))
+            .collect();
+        if report.accepted {
+            self.accepted += 1;
+        } else if !rejecting.is_empty() {
+            self.rejected += 1;
+        } else {
+            self.limit_exceeded += 1;
+        }
+        for hook in rejecting {
+            *self.rejections_per_hook.entry(hook).or_insert(0) += 1;
+        }
+        for (hook, result) in exceeding {
+            *self.limits_exceeded_per_hook
+                .entry(hook)
+                .or_insert_with(BTreeMap::new)
+                .entry(result)
+                .or_insert(0) += 1;
+        }
+    }
+
+    fn succeeded(&self) -> bool {
+        self.failed == 0
+    }
+}
+
+/// Parse a revset of the form `ancestors(A, ...) - ancestors(B, ...) - ...`. The first term is
+/// included, all the other terms are excluded. Revisions are hashes or bookmark names.
+fn parse_revset(revset: &str) -> Result<(Vec<String>, Vec<String>)> {
+    let mut terms = vec![];
+    let mut rest = revset.trim();
+    loop {
+        if !rest.starts_with("ancestors(") {
+            bail_msg!("invalid revset '{}': expected ancestors(...)", revset);
+        }
+        let end = match rest.find(')') {
+            Some(end) => end,
+            None => bail_msg!("invalid revset '{}': missing ')'", revset),
+        };
+        let revs: Vec<String> = rest["ancestors(".len()..end]
+            .split(',')
+            .map(|rev| rev.trim().to_string())
+            .filter(|rev| !rev.is_empty())
+            .collect();
+        if revs.is_empty() {
+            bail_msg!("invalid revset '{}': empty ancestors()", revset);
+        }
+        terms.push(revs);
+        rest = rest[end + 1..].trim_left();
+        if rest.is_empty() {
+            break;
+        }
+        if !rest.starts_with('-') {
+            bail_msg!("invalid revset '{}': expected '-'", revset);
+        }
+        rest = rest[1..].trim_left();
+    }
+    let include = terms.remove(0);
+    let exclude = terms.into_iter().flat_map(|revs| revs).collect();
+    Ok((include, exclude))
+}
+
+fn resolve_revision(repo: &BlobRepo, rev: String) -> BoxFuture<HgNodeHash, Error> {
+    if let Ok(cs_id) = HgChangesetId::from_str(&rev) {
+        return Ok(cs_id.into_nodehash()).into_future().boxify();
+    }
+    let bookmark = try_boxfuture!(Bookmark::new(rev.clone()));
+    repo.get_bookmark(&bookmark)
+        .and_then(move |cs_id| match cs_id {
+            Some(cs_id) => Ok(cs_id.into_nodehash()),
+            None => Err(format_err!("'{}' is neither a hash nor a bookmark", rev)),
+        })
+        .boxify()
+}
+
+fn get_config(logger: &Logger, matches: &ArgMatches) -> Result<RepoConfigs> {
+    if let Some(config_dir) = matches.value_of("config-dir") {
+        return RepoConfigs::read_from_dir(config_dir).wait();
+    }
+    let crpath = PathBuf::from(
+        matches
+            .value_of("configrepo_path")
+            .ok_or_else(|| format_err!("--configrepo_path must be specified"))?,
+    );
+    let crbook = matches
+        .value_of("configrepo_book")
+        .ok_or_else(|| format_err!("--configrepo_book must be specified"))?;
+    let config_repo = BlobRepo::new_rocksdb(
+        logger.new(o!["repo" => "Config repo"]),
+        &crpath,
+        RepositoryId::new(0),
+    )?;
+    let book = Bookmark::new(crbook)?;
+    let changesetid = config_repo
+        .get_bookmark(&book)
+        .wait()?
+        .ok_or_else(|| format_err!("config repo bookmark {} not found", crbook))?;
+    RepoConfigs::read_config_repo(config_repo, changesetid).wait()
+}
+
+/// Create a hook manager with the hooks to run, and the bookmark they are registered for
+fn create_hook_manager(
+    logger: &Logger,
+    matches: &ArgMatches,
+    repo: BlobRepo,
+) -> Result<(HookManager, Bookmark)> {
+    let repo_name = String::from(matches.value_of("REPO_NAME").unwrap());
+    let store = Box::new(BlobRepoChangesetStore::new(repo));
+    let mut hook_manager = HookManager::new(repo_name.clone(), store, 1024, 1024 * 1024);
+
+    if let Some(book) = matches.value_of("hooks-for-bookmark") {
+        let mut configs = get_config(logger, matches)?;
+        let config = configs
+            .repos
+            .remove(&repo_name)
+            .ok_or_else(|| format_err!("repo {} not found in config repo", repo_name))?;
+        load_hooks(&mut hook_manager, config)?;
+        return Ok((hook_manager, Bookmark::new(book)?));
+    }
+
+    let hook_file = matches
+        .value_of("HOOK_FILE")
+        .ok_or_else(|| format_err!("either HOOK_FILE or --hooks-for-bookmark must be specified"))?;
+    let hook_type = matches
+        .value_of("HOOK_TYPE")
+        .ok_or_else(|| format_err!("HOOK_TYPE must be specified"))?;
+    let mut code = String::new();
+    File::open(hook_file)?.read_to_string(&mut code)?;
+    let hook = Arc::new(LuaHook::new(String::from("testhook"), code));
+    match hook_type {
+        "perfile" => hook_manager.register_file_hook("testhook", hook),
+        "percs" => hook_manager.register_changeset_hook("testhook", hook),
+        _ => bail_msg!("Invalid hook type {}", hook_type),
+    }
+    let bookmark = Bookmark::new("testbm")?;
+    hook_manager.set_hooks_for_bookmark(bookmark.clone(), vec!["testhook".to_string()]);
+    Ok((hook_manager, bookmark))
+}
+
+fn run_hooks_on_commit(
+    hook_manager: Arc<HookManager>,
+    bookmark: Bookmark,
+    node: HgNodeHash,
+) -> BoxFuture<CommitReport, Error> {
+    let cs_id = HgChangesetId::new(node);
+    let cs_hooks = hook_manager.run_changeset_hooks_for_bookmark(cs_id, &bookmark);
+    let file_hooks = hook_manager.run_file_hooks_for_bookmark(cs_id, &bookmark);
+    cs_hooks
+        .join(file_hooks)
+        .map(move |(cs_executions, file_executions)| {
+            let mut hooks: Vec<_> = cs_executions
+                .into_iter()
+                .map(|(hook_name, execution)| HookReport::new(hook_name, None, execution))
+                .collect();
+            hooks.extend(file_executions.into_iter().map(|(id, execution)| {
+                HookReport::new(id.hook_name, Some(id.path), execution)
+            }));
+            CommitReport {
+                changeset: node.to_string(),
+                accepted: hooks.iter().all(|hook| hook.accepted()),
+                hooks,
+                error: None,
+            }
+        })
+        .or_else(move |err| {
+            // Keep going over the rest of the range, the failure is recorded in the summary
+            Ok::<_, Error>(CommitReport {
+                changeset: node.to_string(),
+                accepted: false,
+                hooks: vec![],
+                error: Some(format!("{}", err)),
+            })
+        })
+        .boxify()
+}
+
+/// Run the selected hooks over every commit in `--revset`, printing a JSON line per commit,
+/// and return the summary of the run
+fn run_hook_range(
+    args: Vec<String>,
+    repo_creator: fn(&Logger, &ArgMatches) -> BlobRepo,
+) -> BoxFuture<RangeSummary, Error> {
+    let matches = setup_app().get_matches_from(args);
+    let logger = create_logger(&matches);
+    let revset = matches.value_of("revset").expect("--revset must be specified");
+    let (include, exclude) = try_boxfuture!(parse_revset(revset));
+    let limit = try_boxfuture!(
+        matches
+            .value_of("limit")
+            .map(|limit| limit.parse::<u64>())
+            .unwrap_or(Ok(u64::max_value()))
+    );
+    let jobs = try_boxfuture!(
+        matches
+            .value_of("jobs")
+            .map(|jobs| jobs.parse::<usize>())
+            .unwrap_or(Ok(DEFAULT_JOBS))
+    );
+
+    let repo = repo_creator(&logger, &matches);
+    let (hook_manager, bookmark) =
+        try_boxfuture!(create_hook_manager(&logger, &matches, repo.clone()));
+    let hook_manager = Arc::new(hook_manager);
+
+    let resolve = |revs: Vec<String>| {
+        join_all(
+            revs.into_iter()
+                .map(|rev| resolve_revision(&repo, rev))
+                .collect::<Vec<_>>(),
+        )
+    };
+    let repo = Arc::new(repo.clone());
+    resolve(include)
+        .join(resolve(exclude))
+        .map(move |(include, exclude)| {
+            DifferenceOfUnionsOfAncestorsNodeStream::new_with_excludes(&repo, include, exclude)
+        })
+        .flatten_stream()
+        .take(limit)
+        .map(move |node| run_hooks_on_commit(hook_manager.clone(), bookmark.clone(), node))
+        .buffered(jobs)
+        .fold(RangeSummary::default(), |mut summary, report| {
+            println!("{}", serde_json::to_string(&report)?);
+            summary.add(&report);
+            Ok::<_, Error>(summary)
+        })
+        .boxify()
+}
+
 fn create_blobrepo(logger: &Logger, matches: &ArgMatches) -> BlobRepo {
     let bucket = matches
         .value_of("manifold-bucket")
@@ -161,17 +496,62 @@ fn create_blobrepo(logger: &Logger, matches: &ArgMatches) -> BlobRepo {
 
 // It all starts here
 fn main() -> Result<()> {
-    let args_vec = args().collect();
-    tokio::run(run_hook(args_vec, create_blobrepo).then(|res| {
+    let args_vec: Vec<String> = args().collect();
+    let succeeded = Arc::new(AtomicBool::new(true));
+    if setup_app()
+        .get_matches_from(args_vec.clone())
+        .is_present("revset")
+    {
+        let range_succeeded = succeeded.clone();
+        tokio::run(
+            run_hook_range(args_vec, create_blobrepo).then(move |res| {
+                let ok = match res {
+                    Ok(summary) => match serde_json::to_string(&summary) {
+                        Ok(json) => {
+                            println!("{}", json);
+                            summary.succeeded()
+                        }
+                        Err(e) => {
+                            println!("Failed to serialize summary {:?}", e);
+                            false
+                        }
+                    },
+                    Err(e) => {
+                        println!("Failed to run hooks {:?}", e);
+                        false
+                    }
+                };
+                range_succeeded.store(ok, Ordering::SeqCst);
+                Ok(())
+            }),
+        );
+        if !succeeded.load(Ordering::SeqCst) {
+            process::exit(1);
+        }
+        return Ok(());
+    }
+    let hook_succeeded = succeeded.clone();
+    tokio::run(run_hook(args_vec, create_blobrepo).then(move |res| {
         match res {
             Ok(HookExecution::Accepted) => println!("Hook accepted the changeset"),
             Ok(HookExecution::Rejected(rejection_info)) => {
                 println!("Hook rejected the changeset {}", rejection_info.description)
             }
-            Err(e) => println!("Failed to run hook {:?}", e),
+            Ok(HookExecution::InstructionLimitExceeded) => {
+                println!("Hook exceeded its instruction limit")
+            }
+            Ok(HookExecution::MemoryLimitExceeded) => println!("Hook exceeded its memory limit"),
+            Ok(HookExecution::TimedOut) => println!("Hook timed out"),
+            Err(e) => {
+                println!("Failed to run hook {:?}", e);
+                hook_succeeded.store(false, Ordering::SeqCst);
+            }
         }
         Ok(())
     }));
+    if !succeeded.load(Ordering::SeqCst) {
+        process::exit(1);
+    }
     Ok(())
 }
 
@@ -203,7 +583,7 @@ mod test {
             let changeset_id = String::from("a5ffa77602a066db7d5cfb9fb5823a0895717c5a");
             match test_hook(code, changeset_id, file) {
                 Ok(HookExecution::Accepted) => (),
-                Ok(HookExecution::Rejected(_)) => assert!(false, "Hook should be accepted"),
+                Ok(other) => assert!(false, format!("Hook should be accepted {:?}", other)),
                 Err(e) => assert!(false, format!("Unexpected error {:?}", e)),
             }
         });
@@ -232,11 +612,132 @@ mod test {
                 Ok(HookExecution::Rejected(rejection_info)) => {
                     assert!(rejection_info.description.starts_with("sausages"))
                 }
+                Ok(other) => assert!(false, format!("Hook should be rejected {:?}", other)),
                 Err(e) => assert!(false, format!("Unexpected error {:?}", e)),
             }
         });
     }
 
+    #[test]
+    fn test_parse_revset() {
+        assert_eq!(
+            parse_revset("ancestors(master) - ancestors(abc, release-1)").unwrap(),
+            (
+                vec!["master".to_string()],
+                vec!["abc".to_string(), "release-1".to_string()]
+            )
+        );
+        assert_eq!(
+            parse_revset("ancestors(master)").unwrap(),
+            (vec!["master".to_string()], vec![])
+        );
+        assert!(parse_revset("master").is_err());
+        assert!(parse_revset("ancestors(master) + ancestors(x)").is_err());
+        assert!(parse_revset("ancestors()").is_err());
+    }
+
+    #[test]
+    fn test_range_limit() {
+        async_unit::tokio_unit_test(|| {
+            let code = String::from(
+                "hook = function (ctx)\n\
+                 return false, \"sausages\"\n\
+                 end",
+            );
+            let summary = test_hook_range(
+                code,
+                "ancestors(a5ffa77602a066db7d5cfb9fb5823a0895717c5a)",
+                Some(3),
+            ).unwrap();
+            assert_eq!(summary.total, 3);
+            assert_eq!(summary.rejected, 3);
+            assert_eq!(summary.rejections_per_hook.get("testhook"), Some(&3));
+        });
+    }
+
+    #[test]
+    fn test_range_empty_difference() {
+        async_unit::tokio_unit_test(|| {
+            let code = String::from(
+                "hook = function (ctx)\n\
+                 return true\n\
+                 end",
+            );
+            let summary = test_hook_range(
+                code,
+                "ancestors(a5ffa77602a066db7d5cfb9fb5823a0895717c5a) - \
+                 ancestors(a5ffa77602a066db7d5cfb9fb5823a0895717c5a)",
+                None,
+            ).unwrap();
+            assert_eq!(summary.total, 0);
+        });
+    }
+
+    #[test]
+    fn test_range_runtime_error() {
+        async_unit::tokio_unit_test(|| {
+            let code = String::from(
+                "hook = function (ctx)\n\
+                 error(\"sausages\")\n\
+                 end",
+            );
+            let summary = test_hook_range(
+                code,
+                "ancestors(a5ffa77602a066db7d5cfb9fb5823a0895717c5a)",
+                Some(3),
+            ).unwrap();
+            assert_eq!(summary.total, 3);
+            assert_eq!(summary.failed, 3);
+            assert_eq!(summary.rejected, 0);
+            assert!(!summary.succeeded());
+        });
+    }
+
+    #[test]
+    fn test_range_limit_exceeded() {
+        async_unit::tokio_unit_test(|| {
+            let code = String::from(
+                "hook = function (ctx)\n\
+                 local s = string.rep(\"x\", 128 * 1024 * 1024)\n\
+                 return true\n\
+                 end",
+            );
+            let summary = test_hook_range(
+                code,
+                "ancestors(a5ffa77602a066db7d5cfb9fb5823a0895717c5a)",
+                Some(2),
+            ).unwrap();
+            assert_eq!(summary.total, 2);
+            assert_eq!(summary.rejected, 0);
+            assert_eq!(summary.limit_exceeded, 2);
+            assert!(summary.rejections_per_hook.is_empty());
+            assert_eq!(
+                summary.limits_exceeded_per_hook["testhook"]["memory_limit_exceeded"],
+                2
+            );
+        });
+    }
+
+    fn test_hook_range(code: String, revset: &str, limit: Option<u64>) -> Result<RangeSummary> {
+        let dir = TempDir::new("runhook").unwrap();
+        let file_path = dir.path().join("testhook.lua");
+        let mut file = File::create(file_path.clone()).unwrap();
+        file.write(code.as_bytes()).unwrap();
+        let mut args = vec![
+            String::from("runhook"),
+            String::from("test_repo"),
+            file_path.to_str().unwrap().into(),
+            String::from("percs"),
+            String::from("--revset"),
+            String::from(revset),
+        ];
+        if let Some(limit) = limit {
+            args.push(String::from("--limit"));
+            args.push(limit.to_string());
+        }
+        run_hook_range(args, test_blobrepo).wait()
+    }
+
     fn test_hook(code: String, changeset_id: String, run_file: bool) -> Result<HookExecution> {
         let dir = TempDir::new("runhook").unwrap();
         let file_path = dir.path().join("testhook.lua");
@@ -15,7 +15,7 @@ use std::str::FromStr;
 
 use slog::Logger;
 
-use bytes::{Bytes, BytesMut};
+use bytes::{Buf, Bytes, BytesMut, IntoBuf};
 use failure::err_msg;
 use futures::IntoFuture;
 use futures::future::{self, err, ok, Either, Future};
@@ -36,6 +36,7 @@ use {GetbundleArgs, GettreepackArgs, SingleRequest, SingleResponse};
 use errors::*;
 
 const HASH_SIZE: usize = 40;
+const NODE_SIZE: usize = 20;
 
 pub struct HgCommandHandler<H> {
     commands: H,
@@ -92,6 +93,15 @@ impl<H: HgCommands + Send + 'static> HgCommandHandler<H> {
                     .boxify(),
                 ok(instream).boxify(),
             ),
+            SingleRequest::Clonebundles => (
+                hgcmds
+                    .clonebundles()
+                    .map(SingleResponse::Clonebundles)
+                    .map_err(self::Error::into)
+                    .into_stream()
+                    .boxify(),
+                ok(instream).boxify(),
+            ),
             SingleRequest::Debugwireargs { one, two, all_args } => (
                 self.debugwireargs(one, two, all_args)
                     .map(SingleResponse::Debugwireargs)
@@ -105,7 +115,6 @@ impl<H: HgCommands + Send + 'static> HgCommandHandler<H> {
                     .getbundle(args)
                     .map(SingleResponse::Getbundle)
                     .map_err(self::Error::into)
-                    .into_stream()
                     .boxify(),
                 ok(instream).boxify(),
             ),
@@ -145,6 +154,15 @@ impl<H: HgCommands + Send + 'static> HgCommandHandler<H> {
                     .boxify(),
                 ok(instream).boxify(),
             ),
+            SingleRequest::Protocaps { caps } => (
+                hgcmds
+                    .protocaps(caps)
+                    .map(|()| SingleResponse::Protocaps)
+                    .map_err(self::Error::into)
+                    .into_stream()
+                    .boxify(),
+                ok(instream).boxify(),
+            ),
             SingleRequest::Known { nodes } => (
                 hgcmds
                     .known(nodes)
@@ -154,6 +172,20 @@ impl<H: HgCommands + Send + 'static> HgCommandHandler<H> {
                     .boxify(),
                 ok(instream).boxify(),
             ),
+            SingleRequest::Pushkey {
+                namespace,
+                key,
+                old,
+                new,
+            } => (
+                hgcmds
+                    .pushkey(namespace, key, old, new)
+                    .map(SingleResponse::Pushkey)
+                    .map_err(self::Error::into)
+                    .into_stream()
+                    .boxify(),
+                ok(instream).boxify(),
+            ),
             SingleRequest::Unbundle { heads } => {
                 let bundle2stream =
                     Bundle2Stream::new(Dechunker::new(instream), self.logger.new(o!()));
@@ -216,6 +248,25 @@ impl<H: HgCommands + Send + 'static> HgCommandHandler<H> {
                     instream,
                 )
             }
+            SingleRequest::Getpackv1 => {
+                let (reqs, instream) = decode_getpack_arg_stream(instream);
+                (
+                    hgcmds
+                        .getpackv1(reqs)
+                        .map(SingleResponse::Getpackv1)
+                        .map_err(self::Error::into)
+                        .boxify(),
+                    instream,
+                )
+            }
+            SingleRequest::StreamOut => (
+                hgcmds
+                    .stream_out()
+                    .map(SingleResponse::StreamOut)
+                    .map_err(self::Error::into)
+                    .boxify(),
+                ok(instream).boxify(),
+            ),
         }
     }
 
@@ -300,6 +351,45 @@ impl Decoder for GetfilesArgDecoder {
     }
 }
 
+struct GetpackArgDecoder {}
+
+// Parses the nodes requested for one file
+impl Decoder for GetpackArgDecoder {
+    // If None has been decoded, then that means that client has sent all the data
+    type Item = Option<(MPath, Vec<HgNodeHash>)>;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>> {
+        if src.len() < 2 {
+            return Ok(None);
+        }
+        let path_len = (&src[..2]).into_buf().get_u16_be() as usize;
+        if path_len == 0 {
+            // Finished parsing the stream
+            src.split_to(2);
+            return Ok(Some(None));
+        }
+
+        let count_offset = 2 + path_len;
+        if src.len() < count_offset + 4 {
+            return Ok(None);
+        }
+        let count = (&src[count_offset..count_offset + 4]).into_buf().get_u32_be() as usize;
+        let entry_len = count_offset + 4 + count * NODE_SIZE;
+        if src.len() < entry_len {
+            return Ok(None);
+        }
+
+        let entry = src.split_to(entry_len);
+        let path = MPath::new(&entry[2..count_offset])?;
+        let nodes = entry[count_offset + 4..]
+            .chunks(NODE_SIZE)
+            .map(HgNodeHash::from_bytes)
+            .collect::<Result<_>>()?;
+        Ok(Some(Some((path, nodes))))
+    }
+}
+
 // getfiles args format:
 // (nodepath\n)*\n
 // nodepath := node path
@@ -314,6 +404,37 @@ fn decode_getfiles_arg_stream<S>(
 )
 where
     S: Stream<Item = Bytes, Error = io::Error> + Send + 'static,
+{
+    decode_arg_stream(input, || GetfilesArgDecoder {})
+}
+
+// getpackv1 args format:
+// (filenodes)*\0\0
+// filenodes := path len: u16, path, node count: u32, nodes
+// nodes are binary hashes, and all numbers are big endian
+fn decode_getpack_arg_stream<S>(
+    input: BytesStream<S>,
+) -> (
+    BoxStream<(MPath, Vec<HgNodeHash>), Error>,
+    BoxFuture<BytesStream<S>, Error>,
+)
+where
+    S: Stream<Item = Bytes, Error = io::Error> + Send + 'static,
+{
+    decode_arg_stream(input, || GetpackArgDecoder {})
+}
+
+// Decodes the entries of commands that stream their arguments, until the decoder returns
+// `Some(None)`, which marks the end of the arguments
+fn decode_arg_stream<S, D, F, T>(
+    input: BytesStream<S>,
+    new_decoder: F,
+) -> (BoxStream<T, Error>, BoxFuture<BytesStream<S>, Error>)
+where
+    S: Stream<Item = Bytes, Error = io::Error> + Send + 'static,
+    D: Decoder<Item = Option<T>, Error = Error> + Send + 'static,
+    F: Fn() -> D + Send + 'static,
+    T: Send + 'static,
 {
     let (send, recv) = oneshot::channel();
 
@@ -325,7 +446,7 @@ where
     // waits for it.
     let entry_stream: BoxStream<_, ::std::result::Result<BytesStream<S>, (_, BytesStream<S>)>> =
         stream::unfold(input, move |input| {
-            let fut_decode = input.into_future_decode(GetfilesArgDecoder {});
+            let fut_decode = input.into_future_decode(new_decoder());
             let fut = fut_decode
                 .map_err(|err| Err(err)) // Real error happened, wrap it in result
                 .and_then(|(maybe_item, instream)| match maybe_item {
@@ -458,8 +579,7 @@ pub trait HgCommands {
 
     // @wireprotocommand('branchmap')
     fn branchmap(&self) -> HgCommandRes<HashMap<String, HashSet<HgNodeHash>>> {
-        // We have no plans to support mercurial branches and hence no plans for branchmap,
-        // so just return fake response.
+        // Servers that don't track named branches report none, which is a valid response.
         future::ok(HashMap::new()).boxify()
     }
 
@@ -468,10 +588,14 @@ pub trait HgCommands {
         unimplemented("capabilities")
     }
 
+    // @wireprotocommand('clonebundles', '')
+    fn clonebundles(&self) -> HgCommandRes<Bytes> {
+        unimplemented("clonebundles")
+    }
+
     // @wireprotocommand('getbundle', '*')
-    // TODO: make this streaming
-    fn getbundle(&self, _args: GetbundleArgs) -> HgCommandRes<Bytes> {
-        unimplemented("getbundle")
+    fn getbundle(&self, _args: GetbundleArgs) -> BoxStream<Bytes, Error> {
+        once(Err(ErrorKind::Unimplemented("getbundle".into()).into())).boxify()
     }
 
     // @wireprotocommand('heads')
@@ -494,11 +618,27 @@ pub trait HgCommands {
         unimplemented("lookup")
     }
 
+    // @wireprotocommand('protocaps', 'caps')
+    fn protocaps(&self, _caps: Vec<String>) -> HgCommandRes<()> {
+        unimplemented("protocaps")
+    }
+
     // @wireprotocommand('known', 'nodes *')
     fn known(&self, _nodes: Vec<HgNodeHash>) -> HgCommandRes<Vec<bool>> {
         unimplemented("known")
     }
 
+    // @wireprotocommand('pushkey', 'namespace key old new')
+    fn pushkey(
+        &self,
+        _namespace: String,
+        _key: String,
+        _old: String,
+        _new: String,
+    ) -> HgCommandRes<bool> {
+        unimplemented("pushkey")
+    }
+
     // @wireprotocommand('unbundle', 'heads')
     fn unbundle(
         &self,
@@ -517,6 +657,19 @@ pub trait HgCommands {
     fn getfiles(&self, _params: BoxStream<(HgNodeHash, MPath), Error>) -> BoxStream<Bytes, Error> {
         once(Err(ErrorKind::Unimplemented("getfiles".into()).into())).boxify()
     }
+
+    // @wireprotocommand('getpackv1', '*')
+    fn getpackv1(
+        &self,
+        _params: BoxStream<(MPath, Vec<HgNodeHash>), Error>,
+    ) -> BoxStream<Bytes, Error> {
+        once(Err(ErrorKind::Unimplemented("getpackv1".into()).into())).boxify()
+    }
+
+    // @wireprotocommand('stream_out')
+    fn stream_out(&self) -> BoxStream<Bytes, Error> {
+        once(Err(ErrorKind::Unimplemented("stream_out".into()).into())).boxify()
+    }
 }
 
 #[cfg(test)]
@@ -649,4 +802,68 @@ mod test {
         let (paramstream, _input) = decode_getfiles_arg_stream(BytesStream::new(stream::empty()));
         assert!(paramstream.collect().wait().is_err());
     }
+
+    fn getpack_entry(path: &str, nodes: &[HgNodeHash]) -> Vec<u8> {
+        let mut entry = vec![0, path.len() as u8];
+        entry.extend_from_slice(path.as_bytes());
+        entry.extend_from_slice(&[0, 0, 0, nodes.len() as u8]);
+        for node in nodes {
+            entry.extend_from_slice(node.as_ref());
+        }
+        entry
+    }
+
+    #[test]
+    fn getpackdecoder() {
+        let mut decoder = GetpackArgDecoder {};
+        let entry = getpack_entry("path", &[hash_ones(), hash_twos()]);
+
+        let mut input = BytesMut::from(&entry[..entry.len() - 1]);
+        assert!(
+            decoder
+                .decode(&mut input)
+                .expect("unexpected error")
+                .is_none()
+        );
+
+        let mut input = BytesMut::from(&entry[..]);
+        let res = decoder
+            .decode(&mut input)
+            .expect("unexpected error")
+            .expect("empty result");
+        assert_eq!(
+            Some((MPath::new("path").unwrap(), vec![hash_ones(), hash_twos()])),
+            res
+        );
+        assert!(input.is_empty());
+
+        let mut input = BytesMut::from(&b"\0\0"[..]);
+        let res = decoder
+            .decode(&mut input)
+            .expect("unexpected error")
+            .expect("empty result");
+        assert_eq!(None, res);
+    }
+
+    #[test]
+    fn getpackargs() {
+        let mut input = getpack_entry("path", &[hash_ones()]);
+        input.extend(getpack_entry("path2", &[hash_ones(), hash_twos()]));
+        input.extend_from_slice(b"\0\0");
+        let (paramstream, _input) =
+            decode_getpack_arg_stream(BytesStream::new(stream::once(Ok(Bytes::from(input)))));
+
+        let res = paramstream.collect().wait().unwrap();
+        assert_eq!(
+            res,
+            vec![
+                (MPath::new("path").unwrap(), vec![hash_ones()]),
+                (MPath::new("path2").unwrap(), vec![hash_ones(), hash_twos()]),
+            ]
+        );
+
+        // Unexpected end of file
+        let (paramstream, _input) = decode_getpack_arg_stream(BytesStream::new(stream::empty()));
+        assert!(paramstream.collect().wait().is_err());
+    }
 }
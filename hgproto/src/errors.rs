@@ -13,6 +13,8 @@ pub enum ErrorKind {
     #[fail(display = "unconsumed data left after parsing '{}'", _0)] UnconsumedData(String),
     #[fail(display = "malformed batch with command '{}'", _0)] BatchInvalid(String),
     #[fail(display = "malformed bundle2 '{}'", _0)] Bundle2Invalid(String),
+    #[fail(display = "malformed HTTP request: {}", _0)] HttpRequestInvalid(String),
+    #[fail(display = "malformed session record: {}", _0)] SessionRecordInvalid(String),
     #[fail(display = "unknown escape character in batch command '{}'", _0)] BatchEscape(u8),
     #[fail(display = "Repo error")] RepoError,
     #[fail(display = "cannot serve revlog repos")] CantServeRevlogRepo,
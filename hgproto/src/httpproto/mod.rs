@@ -0,0 +1,90 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! HTTP protocol
+//!
+//! Reference is https://www.mercurial-scm.org/wiki/HttpCommandProtocol.
+//!
+//! Every command is a separate HTTP request to the repo URL. The name of the command is in the
+//! `cmd` query parameter, and the arguments are urlencoded and sent in one of (or a mix of):
+//! - the query string
+//! - the `X-HgArg-<N>` headers, concatenated in order of `N` (`httpheader` capability)
+//! - the first `X-HgArgs-Post` bytes of the body of a POST request (`httppostargs` capability)
+//!
+//! The rest of the body is the streaming argument of the command, f.e. the bundle of `unbundle`.
+//!
+//! Responses are sent as the body of the HTTP response, without the framing of the SSH protocol.
+//! Responses of the commands that send bundles are `application/mercurial-0.2` if the client
+//! supports it: a byte with the length of the name of the compression engine, the name itself,
+//! and then the compressed response. Older clients get them as `application/mercurial-0.1`
+//! compressed with zlib, like Mercurial does. Everything else is `application/mercurial-0.1` and
+//! sent as is.
+//!
+//! To let the commands be handled exactly as they are for SSH, the HTTP request is translated into
+//! the SSH encoding of the command followed by the chunked streaming argument.
+
+use bytes::BytesMut;
+use tokio_io::codec::Decoder;
+
+use {Request, Response};
+use handler::{OutputStream, ResponseEncoder};
+use sshproto;
+
+use errors::*;
+
+pub mod request;
+pub mod response;
+
+pub use self::request::{decode_request, HttpRequest};
+
+/// Content type of the responses, negotiated with the `X-HgProto-<N>` headers of the request
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MediaType {
+    V01,
+    /// `application/mercurial-0.1` compressed with zlib, for bundles sent to clients that don't
+    /// accept `application/mercurial-0.2`
+    V01Zlib,
+    V02,
+}
+
+impl MediaType {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            &MediaType::V01 | &MediaType::V01Zlib => "application/mercurial-0.1",
+            &MediaType::V02 => "application/mercurial-0.2",
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct HgHttpCommandEncode {
+    media_type: MediaType,
+}
+
+impl HgHttpCommandEncode {
+    pub fn new(media_type: MediaType) -> Self {
+        HgHttpCommandEncode { media_type }
+    }
+}
+
+/// Decodes the translated request, see the module doc
+#[derive(Clone)]
+pub struct HgHttpCommandDecode;
+
+impl ResponseEncoder for HgHttpCommandEncode {
+    fn encode(&self, response: Response) -> OutputStream {
+        response::encode(response, self.media_type)
+    }
+}
+
+impl Decoder for HgHttpCommandDecode {
+    type Item = Request;
+    type Error = Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Request>> {
+        sshproto::request::parse_request(buf)
+    }
+}
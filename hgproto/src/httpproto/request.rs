@@ -0,0 +1,456 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::str;
+
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::{future, stream, Future, IntoFuture, Stream};
+use futures::future::Loop;
+use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
+use url::percent_encoding::percent_decode;
+
+use super::MediaType;
+
+use errors::*;
+
+/// Commands that can be sent over HTTP: the name, the named arguments and whether other arguments
+/// are accepted as `*`, like Mercurial declares them with `@wireprotocommand`
+const COMMANDS: &[(&str, &[&str], bool)] = &[
+    ("batch", &["cmds"], true),
+    ("between", &["pairs"], false),
+    ("branchmap", &[], false),
+    ("clonebundles", &[], false),
+    ("debugwireargs", &["one", "two"], true),
+    ("getbundle", &[], true),
+    ("getfiles", &[], false),
+    ("getpackv1", &[], false),
+    ("gettreepack", &[], true),
+    ("heads", &[], false),
+    ("hello", &[], false),
+    ("known", &["nodes"], true),
+    ("listkeys", &["namespace"], false),
+    ("lookup", &["key"], false),
+    ("protocaps", &["caps"], false),
+    ("pushkey", &["namespace", "key", "old", "new"], false),
+    ("stream_out", &[], false),
+    ("unbundle", &["heads"], false),
+];
+
+/// Commands whose response is a bundle, sent as `application/mercurial-0.2` or compressed
+/// `application/mercurial-0.1`
+const BUNDLE_COMMANDS: &[&str] = &["getbundle", "gettreepack", "stream_out"];
+
+/// A command received over HTTP
+pub struct HttpRequest {
+    /// Name of the command, as sent by the client
+    pub command: String,
+    /// Content type of the response
+    pub media_type: MediaType,
+    /// Protocol capabilities of the client from the `X-HgProto-<N>` headers, f.e. the compression
+    /// engines it supports in `comp=`
+    pub protocaps: Vec<String>,
+    /// The command in the SSH encoding followed by its chunked streaming argument, to be decoded
+    /// with `HgHttpCommandDecode`
+    pub input: BoxStream<Bytes, io::Error>,
+}
+
+/// Decode a command from the query string, the headers and the body of an HTTP request. `header`
+/// returns the value of a header given its name.
+pub fn decode_request<H, S>(query: &str, header: H, body: S) -> BoxFuture<HttpRequest, Error>
+where
+    H: Fn(&str) -> Option<Bytes>,
+    S: Stream<Item = Bytes, Error = io::Error> + Send + 'static,
+{
+    let mut args = parse_urlencoded(query.as_bytes());
+    let command = match args.remove(&b"cmd"[..]) {
+        Some(command) => String::from_utf8_lossy(&command).into_owned(),
+        None => return invalid_request("missing cmd parameter"),
+    };
+    args.extend(parse_urlencoded(&multiline_header(&header, "X-HgArg")));
+
+    let postargs_len = match header("X-HgArgs-Post") {
+        Some(len) => match str::from_utf8(&len).ok().and_then(|len| len.parse().ok()) {
+            Some(len) => len,
+            None => return invalid_request("X-HgArgs-Post is not a number"),
+        },
+        None => 0,
+    };
+
+    let protocaps = multiline_header(&header, "X-HgProto");
+    let media_type = if BUNDLE_COMMANDS.contains(&command.as_str()) {
+        if accepts_uncompressed_v02(&protocaps) {
+            MediaType::V02
+        } else {
+            MediaType::V01Zlib
+        }
+    } else {
+        MediaType::V01
+    };
+
+    // The capabilities are sent by `hello` over SSH. Their encoding for HTTP is done by
+    // `HgHttpCommandEncode`.
+    let ssh_command = if command == "capabilities" {
+        "hello".to_owned()
+    } else {
+        command.clone()
+    };
+    let (named_args, star) = match COMMANDS.iter().find(|&&(name, ..)| name == ssh_command) {
+        Some(&(_, named_args, star)) => (named_args, star),
+        None => return invalid_request(&format!("unknown command {}", command)),
+    };
+
+    let protocaps: Vec<_> = String::from_utf8_lossy(&protocaps)
+        .split_whitespace()
+        .map(String::from)
+        .collect();
+
+    split_body(body, postargs_len)
+        .and_then(move |(postargs, body)| {
+            args.extend(parse_urlencoded(&postargs));
+            let encoded = encode_ssh_command(&ssh_command, named_args, star, args)?;
+            let body = if ssh_command == "unbundle" {
+                chunked(body)
+            } else {
+                body
+            };
+
+            Ok(HttpRequest {
+                command,
+                media_type,
+                protocaps,
+                input: stream::once(Ok(encoded)).chain(body).boxify(),
+            })
+        })
+        .boxify()
+}
+
+fn invalid_request<T: Send + 'static>(msg: &str) -> BoxFuture<T, Error> {
+    Err(ErrorKind::HttpRequestInvalid(msg.to_owned()).into())
+        .into_future()
+        .boxify()
+}
+
+/// Values that don't fit in one header are split over the `<name>-1`, `<name>-2`, ... headers
+fn multiline_header<H>(header: &H, name: &str) -> Vec<u8>
+where
+    H: Fn(&str) -> Option<Bytes>,
+{
+    let mut value = Vec::new();
+    for idx in 1.. {
+        match header(&format!("{}-{}", name, idx)) {
+            Some(part) => value.extend_from_slice(&part),
+            None => break,
+        }
+    }
+    value
+}
+
+/// Whether the `X-HgProto` capabilities of the client allow sending
+/// `application/mercurial-0.2` responses without compression
+fn accepts_uncompressed_v02(protocaps: &[u8]) -> bool {
+    let protocaps = String::from_utf8_lossy(protocaps);
+    let mut v02 = false;
+    let mut uncompressed = false;
+    for cap in protocaps.split_whitespace() {
+        if cap == "0.2" {
+            v02 = true;
+        } else if cap.starts_with("comp=") {
+            uncompressed = cap["comp=".len()..].split(',').any(|engine| engine == "none");
+        }
+    }
+    v02 && uncompressed
+}
+
+fn parse_urlencoded(input: &[u8]) -> HashMap<Vec<u8>, Vec<u8>> {
+    fn decode(input: &[u8]) -> Vec<u8> {
+        let input: Vec<_> = input
+            .iter()
+            .map(|c| if *c == b'+' { b' ' } else { *c })
+            .collect();
+        percent_decode(&input).collect()
+    }
+
+    input
+        .split(|c| *c == b'&')
+        .filter(|kv| !kv.is_empty())
+        .map(|kv| {
+            let mut kv = kv.splitn(2, |c| *c == b'=');
+            let key = kv.next().unwrap_or(b"");
+            let val = kv.next().unwrap_or(b"");
+            (decode(key), decode(val))
+        })
+        .collect()
+}
+
+/// Split the first `len` bytes off the body
+fn split_body<S>(body: S, len: usize) -> BoxFuture<(Bytes, BoxStream<Bytes, io::Error>), Error>
+where
+    S: Stream<Item = Bytes, Error = io::Error> + Send + 'static,
+{
+    if len == 0 {
+        return future::ok((Bytes::new(), body.boxify())).boxify();
+    }
+
+    future::loop_fn(
+        (BytesMut::with_capacity(len), body.boxify()),
+        move |(mut head, body)| {
+            body.into_future()
+                .map_err(|(err, _)| Error::from(err))
+                .and_then(move |(chunk, body)| match chunk {
+                    None => Err(ErrorKind::HttpRequestInvalid(
+                        "body is shorter than X-HgArgs-Post".into(),
+                    ).into()),
+                    Some(mut chunk) => {
+                        let missing = len - head.len();
+                        if chunk.len() < missing {
+                            head.extend_from_slice(&chunk);
+                            Ok(Loop::Continue((head, body)))
+                        } else {
+                            let rest = chunk.split_off(missing);
+                            head.extend_from_slice(&chunk);
+                            let body = stream::once(Ok(rest)).chain(body).boxify();
+                            Ok(Loop::Break((head.freeze(), body)))
+                        }
+                    }
+                })
+        },
+    ).boxify()
+}
+
+/// Encode the command the way `sshproto` expects it: the named arguments in order, and the rest
+/// of them as `*` arguments
+fn encode_ssh_command(
+    command: &str,
+    named_args: &[&str],
+    star: bool,
+    mut args: HashMap<Vec<u8>, Vec<u8>>,
+) -> Result<Bytes> {
+    fn encode_arg(out: &mut Vec<u8>, key: &[u8], val: &[u8]) {
+        out.extend_from_slice(key);
+        write!(out, " {}\n", val.len()).expect("write to vec failed");
+        out.extend_from_slice(val);
+    }
+
+    let mut out = Vec::new();
+    write!(out, "{}\n", command).expect("write to vec failed");
+    for key in named_args {
+        match args.remove(key.as_bytes()) {
+            Some(val) => encode_arg(&mut out, key.as_bytes(), &val),
+            None => {
+                let msg = format!("missing argument {} of {}", key, command);
+                return Err(ErrorKind::HttpRequestInvalid(msg).into());
+            }
+        }
+    }
+    if star {
+        write!(out, "* {}\n", args.len()).expect("write to vec failed");
+        for (key, val) in args {
+            encode_arg(&mut out, &key, &val);
+        }
+    }
+    Ok(Bytes::from(out))
+}
+
+/// Chunk the body the way streaming arguments are sent over SSH, see `hgproto/dechunker.rs`
+fn chunked(body: BoxStream<Bytes, io::Error>) -> BoxStream<Bytes, io::Error> {
+    body.filter(|chunk| !chunk.is_empty())
+        .map(|chunk| {
+            let mut out = BytesMut::with_capacity(10 + chunk.len());
+            out.put_slice(format!("{}\n", chunk.len()).as_bytes());
+            out.put(chunk);
+            out.freeze()
+        })
+        .chain(stream::once(Ok(Bytes::from(&b"0\n"[..]))))
+        .boxify()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use tokio_io::codec::Decoder;
+
+    use {Request, SingleRequest};
+    use httpproto::HgHttpCommandDecode;
+
+    fn decode(
+        query: &str,
+        headers: HashMap<&'static str, &'static str>,
+        body: Vec<&'static [u8]>,
+    ) -> Result<(HttpRequest, Bytes)> {
+        let body = stream::iter_ok(body.into_iter().map(Bytes::from));
+        let request = decode_request(
+            query,
+            |name| headers.get(name).map(|val| Bytes::from(val.as_bytes())),
+            body,
+        ).wait()?;
+        let input = request.input.concat2().wait()?;
+        Ok((
+            HttpRequest {
+                input: stream::empty().boxify(),
+                ..request
+            },
+            input,
+        ))
+    }
+
+    fn parse(input: &Bytes) -> Request {
+        let mut buf = BytesMut::from(input.as_ref());
+        HgHttpCommandDecode
+            .decode(&mut buf)
+            .expect("decode failed")
+            .expect("incomplete request")
+    }
+
+    #[test]
+    fn test_parse_urlencoded() {
+        assert_eq!(
+            parse_urlencoded(b"cmd=lookup&key=a%20b+c&empty=&novalue"),
+            hashmap! {
+                b"cmd".to_vec() => b"lookup".to_vec(),
+                b"key".to_vec() => b"a b c".to_vec(),
+                b"empty".to_vec() => b"".to_vec(),
+                b"novalue".to_vec() => b"".to_vec(),
+            }
+        );
+        assert_eq!(parse_urlencoded(b""), hashmap!{});
+    }
+
+    #[test]
+    fn test_accepts_uncompressed_v02() {
+        assert!(accepts_uncompressed_v02(b"0.1 0.2 comp=zstd,zlib,none,bzip2"));
+        assert!(!accepts_uncompressed_v02(b"0.1 0.2 comp=zstd,zlib"));
+        assert!(!accepts_uncompressed_v02(b"0.1 comp=none"));
+        assert!(!accepts_uncompressed_v02(b""));
+    }
+
+    #[test]
+    fn test_query_args() {
+        let (request, input) = decode("cmd=listkeys&namespace=bookmarks", hashmap!{}, vec![])
+            .expect("decode failed");
+        assert_eq!(request.command, "listkeys");
+        assert_eq!(request.media_type, MediaType::V01);
+        assert_eq!(
+            parse(&input),
+            Request::Single(SingleRequest::Listkeys {
+                namespace: "bookmarks".into(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_header_args() {
+        let headers = hashmap! {
+            "X-HgArg-1" => "key=mas",
+            "X-HgArg-2" => "ter",
+        };
+        let (_, input) = decode("cmd=lookup", headers, vec![]).expect("decode failed");
+        assert_eq!(
+            parse(&input),
+            Request::Single(SingleRequest::Lookup {
+                key: "master".into(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_post_args() {
+        let headers = hashmap! {
+            "X-HgArgs-Post" => "13",
+        };
+        let body = vec![&b"key=ma"[..], &b"ster%"[..], &b"21"[..]];
+        let (_, input) = decode("cmd=lookup", headers.clone(), body).expect("decode failed");
+        assert_eq!(
+            parse(&input),
+            Request::Single(SingleRequest::Lookup {
+                key: "master!".into(),
+            })
+        );
+
+        assert!(decode("cmd=lookup", headers, vec![&b"key=master"[..]]).is_err());
+    }
+
+    #[test]
+    fn test_star_args() {
+        let headers = hashmap! {
+            "X-HgProto-1" => "0.1 0.2 comp=zstd,zlib,none,bzip2",
+        };
+        let (request, input) =
+            decode("cmd=getbundle&depth=1", headers, vec![]).expect("decode failed");
+        assert_eq!(request.media_type, MediaType::V02);
+        assert_eq!(
+            request.protocaps,
+            vec!["0.1", "0.2", "comp=zstd,zlib,none,bzip2"]
+        );
+        match parse(&input) {
+            Request::Single(SingleRequest::Getbundle(args)) => assert_eq!(args.depth, Some(1)),
+            bad => panic!("unexpected request {:?}", bad),
+        }
+
+        // Older clients get bundles compressed with zlib
+        let (request, _) = decode("cmd=getbundle", hashmap!{}, vec![]).expect("decode failed");
+        assert_eq!(request.media_type, MediaType::V01Zlib);
+        let headers = hashmap! {
+            "X-HgProto-1" => "0.1 0.2 comp=zstd,zlib",
+        };
+        let (request, _) = decode("cmd=getbundle", headers, vec![]).expect("decode failed");
+        assert_eq!(request.media_type, MediaType::V01Zlib);
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let (request, input) =
+            decode("cmd=capabilities", hashmap!{}, vec![]).expect("decode failed");
+        assert_eq!(request.command, "capabilities");
+        assert_eq!(parse(&input), Request::Single(SingleRequest::Hello));
+    }
+
+    #[test]
+    fn test_pushkey() {
+        let (request, input) = decode(
+            "cmd=pushkey&namespace=bookmarks&key=master&old=&new=1234",
+            hashmap!{},
+            vec![],
+        ).expect("decode failed");
+        assert_eq!(request.media_type, MediaType::V01);
+        assert_eq!(
+            parse(&input),
+            Request::Single(SingleRequest::Pushkey {
+                namespace: "bookmarks".into(),
+                key: "master".into(),
+                old: "".into(),
+                new: "1234".into(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_getfiles_body() {
+        let body = vec![&b"0000path\n\n"[..]];
+        let (request, input) = decode("cmd=getfiles", hashmap!{}, body).expect("decode failed");
+        assert_eq!(request.media_type, MediaType::V01);
+        assert!(input.ends_with(b"getfiles\n0000path\n\n"));
+    }
+
+    #[test]
+    fn test_unbundle_body() {
+        let (_, input) = decode(
+            "cmd=unbundle&heads=666f726365",
+            hashmap!{},
+            vec![&b"HG20"[..], &b""[..], &b"rest"[..]],
+        ).expect("decode failed");
+        assert!(input.ends_with(b"4\nHG204\nrest0\n"));
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(decode("namespace=bookmarks", hashmap!{}, vec![]).is_err());
+        assert!(decode("cmd=nosuchcommand", hashmap!{}, vec![]).is_err());
+        assert!(decode("cmd=lookup", hashmap!{}, vec![]).is_err());
+    }
+}
@@ -0,0 +1,139 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::collections::HashMap;
+use std::io::Write;
+
+use bytes::{BufMut, Bytes, BytesMut};
+use flate2::Compression;
+use flate2::write::ZlibEncoder;
+use futures::stream;
+use futures_ext::StreamExt;
+
+use {Response, SingleResponse};
+use handler::OutputStream;
+use sshproto::response::{encode_batch, encode_cmd};
+
+use super::MediaType;
+
+/// Capabilities of the HTTP protocol itself, advertised in addition to the capabilities of the
+/// repo
+const HTTP_CAPABILITIES: &[&str] = &[
+    "httpheader=1024",
+    "httppostargs",
+    "httpmediatype=0.1rx,0.1tx,0.2tx",
+    "compression=none",
+];
+
+/// Name of the compression engine of `application/mercurial-0.2` responses
+const COMPRESSION_ENGINE: &[u8] = b"none";
+
+pub fn encode(response: Response, media_type: MediaType) -> OutputStream {
+    let res = match response {
+        Response::Batch(ref resps) => encode_batch(resps),
+        // Streaming arguments are part of the request body, there is nothing to acknowledge
+        Response::Single(SingleResponse::ReadyForStream) => return stream::empty().boxify(),
+        Response::Single(SingleResponse::Hello(ref hello)) => encode_capabilities(hello),
+        Response::Single(ref resp) => encode_cmd(resp),
+    };
+
+    let out = match media_type {
+        MediaType::V01 => res,
+        MediaType::V01Zlib => {
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+            encoder
+                .write_all(&res)
+                .and_then(|()| encoder.finish())
+                .map(Bytes::from)
+                .expect("zlib compression into vec failed")
+        }
+        MediaType::V02 => {
+            let mut out = BytesMut::with_capacity(1 + COMPRESSION_ENGINE.len() + res.len());
+            out.put_u8(COMPRESSION_ENGINE.len() as u8);
+            out.put_slice(COMPRESSION_ENGINE);
+            out.put(res);
+            out.freeze()
+        }
+    };
+    stream::once(Ok(out)).boxify()
+}
+
+/// `capabilities` is sent to the commands handler as `hello`, see `decode_request`
+fn encode_capabilities(hello: &HashMap<String, Vec<String>>) -> Bytes {
+    let caps: Vec<_> = hello
+        .get("capabilities")
+        .into_iter()
+        .flat_map(|caps| caps.iter().map(String::as_str))
+        .chain(HTTP_CAPABILITIES.iter().cloned())
+        .collect();
+    Bytes::from(caps.join(" "))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io::Read;
+
+    use flate2::read::ZlibDecoder;
+    use futures::{Future, Stream};
+
+    fn encode_bytes(response: Response, media_type: MediaType) -> Bytes {
+        encode(response, media_type)
+            .concat2()
+            .wait()
+            .expect("encode failed")
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let hello = hashmap! {
+            "capabilities".to_owned() => vec!["lookup".to_owned(), "known".to_owned()],
+        };
+        let res = encode_bytes(
+            Response::Single(SingleResponse::Hello(hello)),
+            MediaType::V01,
+        );
+        assert_eq!(
+            res,
+            Bytes::from(
+                &b"lookup known httpheader=1024 httppostargs httpmediatype=0.1rx,0.1tx,0.2tx \
+                   compression=none"[..]
+            )
+        );
+    }
+
+    #[test]
+    fn test_media_types() {
+        let res = encode_bytes(
+            Response::Single(SingleResponse::Getbundle(Bytes::from(&b"HG20"[..]))),
+            MediaType::V02,
+        );
+        assert_eq!(res, Bytes::from(&b"\x04noneHG20"[..]));
+
+        let res = encode_bytes(
+            Response::Single(SingleResponse::Getbundle(Bytes::from(&b"HG20"[..]))),
+            MediaType::V01Zlib,
+        );
+        let mut decoded = Vec::new();
+        ZlibDecoder::new(res.as_ref())
+            .read_to_end(&mut decoded)
+            .expect("zlib decompression failed");
+        assert_eq!(decoded, b"HG20");
+
+        let res = encode_bytes(
+            Response::Single(SingleResponse::Known(vec![true, false])),
+            MediaType::V01,
+        );
+        assert_eq!(res, Bytes::from(&b"10"[..]));
+
+        let res = encode_bytes(
+            Response::Single(SingleResponse::ReadyForStream),
+            MediaType::V01,
+        );
+        assert!(res.is_empty());
+    }
+}
@@ -14,6 +14,7 @@
 
 // Tokio/IO
 extern crate bytes;
+extern crate flate2;
 extern crate futures;
 #[macro_use]
 extern crate tokio_io;
@@ -36,6 +37,7 @@ extern crate mercurial;
 extern crate mercurial_bundles;
 extern crate mercurial_types;
 extern crate revset;
+extern crate url;
 
 #[cfg(test)]
 extern crate mercurial_types_mocks;
@@ -57,6 +59,7 @@ mod dechunker;
 mod errors;
 mod handler;
 mod commands;
+pub mod httpproto;
 pub mod sshproto;
 
 const MAX_NODES_TO_LOG: usize = 5;
@@ -86,6 +89,7 @@ pub enum SingleRequest {
     },
     Branchmap,
     Capabilities,
+    Clonebundles,
     Debugwireargs {
         one: Vec<u8>,
         two: Vec<u8>,
@@ -100,14 +104,25 @@ pub enum SingleRequest {
     Lookup {
         key: String,
     },
+    Protocaps {
+        caps: Vec<String>,
+    },
     Known {
         nodes: Vec<HgNodeHash>,
     },
+    Pushkey {
+        namespace: String,
+        key: String,
+        old: String,
+        new: String,
+    },
     Unbundle {
         heads: Vec<String>,
     },
     Gettreepack(GettreepackArgs),
     Getfiles,
+    Getpackv1,
+    StreamOut,
 }
 
 impl SingleRequest {
@@ -116,16 +131,21 @@ impl SingleRequest {
             &SingleRequest::Between { .. } => "between",
             &SingleRequest::Branchmap => "branchmap",
             &SingleRequest::Capabilities => "capabilities",
+            &SingleRequest::Clonebundles => "clonebundles",
             &SingleRequest::Debugwireargs { .. } => "debugwireargs",
             &SingleRequest::Getbundle(_) => "getbundle",
             &SingleRequest::Heads => "heads",
             &SingleRequest::Hello => "hello",
             &SingleRequest::Listkeys { .. } => "listkeys",
             &SingleRequest::Lookup { .. } => "lookup",
+            &SingleRequest::Protocaps { .. } => "protocaps",
             &SingleRequest::Known { .. } => "known",
+            &SingleRequest::Pushkey { .. } => "pushkey",
             &SingleRequest::Unbundle { .. } => "unbundle",
             &SingleRequest::Gettreepack(_) => "gettreepack",
             &SingleRequest::Getfiles => "getfiles",
+            &SingleRequest::Getpackv1 => "getpackv1",
+            &SingleRequest::StreamOut => "stream_out",
         }
     }
 }
@@ -138,6 +158,20 @@ pub struct GetbundleArgs {
     pub common: Vec<HgNodeHash>,
     pub bundlecaps: Vec<Vec<u8>>,
     pub listkeys: Vec<Vec<u8>>,
+    /// Narrowspec include patterns of a narrow clone, empty if everything is included.
+    pub includepattern: Vec<Vec<u8>>,
+    /// Narrowspec exclude patterns of a narrow clone.
+    pub excludepattern: Vec<Vec<u8>>,
+    /// Number of commits of history to send for a shallow clone, everything if not set.
+    pub depth: Option<usize>,
+    /// Whether to add a branchmap part to the bundle.
+    pub branchmap: bool,
+    /// Whether to add a phase-heads part to the bundle.
+    pub phases: bool,
+    /// Whether to add an obsmarkers part with the markers relevant to the changesets sent.
+    pub obsmarkers: bool,
+    /// Whether to send a streaming clone in a stream2 part instead of the whole changegroup.
+    pub stream: bool,
 }
 
 impl Debug for GetbundleArgs {
@@ -150,6 +184,14 @@ impl Debug for GetbundleArgs {
             .iter()
             .map(|s| String::from_utf8_lossy(&s))
             .collect();
+        let includepattern: Vec<_> = self.includepattern
+            .iter()
+            .map(|s| String::from_utf8_lossy(&s))
+            .collect();
+        let excludepattern: Vec<_> = self.excludepattern
+            .iter()
+            .map(|s| String::from_utf8_lossy(&s))
+            .collect();
         let heads: Vec<_> = self.heads.iter().take(MAX_NODES_TO_LOG).collect();
         let common: Vec<_> = self.common.iter().take(MAX_NODES_TO_LOG).collect();
         fmt.debug_struct("GetbundleArgs")
@@ -157,6 +199,13 @@ impl Debug for GetbundleArgs {
             .field("common", &common)
             .field("bundlecaps", &bcaps)
             .field("listkeys", &listkeys)
+            .field("includepattern", &includepattern)
+            .field("excludepattern", &excludepattern)
+            .field("depth", &self.depth)
+            .field("branchmap", &self.branchmap)
+            .field("phases", &self.phases)
+            .field("obsmarkers", &self.obsmarkers)
+            .field("stream", &self.stream)
             .finish()
     }
 }
@@ -175,6 +224,14 @@ pub struct GettreepackArgs {
     ///  The fullpath (not relative path) of directories underneath
     /// the rootdir that should be sent.
     pub directories: Vec<Bytes>,
+    /// Narrowspec include patterns, trees outside of them are not sent. Empty if everything is
+    /// included.
+    pub includepattern: Vec<Vec<u8>>,
+    /// Narrowspec exclude patterns.
+    pub excludepattern: Vec<Vec<u8>>,
+    /// How many levels of directories to send, counting the root directory as the first one.
+    /// Everything below rootdir is sent if it's not set.
+    pub depth: Option<usize>,
 }
 
 #[derive(Debug)]
@@ -188,17 +245,22 @@ pub enum SingleResponse {
     Between(Vec<Vec<HgNodeHash>>),
     Branchmap(HashMap<String, HashSet<HgNodeHash>>),
     Capabilities(Vec<String>),
+    Clonebundles(Bytes),
     Debugwireargs(Bytes),
     Getbundle(Bytes),
     Heads(HashSet<HgNodeHash>),
     Hello(HashMap<String, Vec<String>>),
     Listkeys(HashMap<Vec<u8>, Vec<u8>>),
     Lookup(Bytes),
+    Protocaps,
     Known(Vec<bool>),
+    Pushkey(bool),
     ReadyForStream,
     Unbundle(Bytes),
     Gettreepack(Bytes),
     Getfiles(Bytes),
+    Getpackv1(Bytes),
+    StreamOut(Bytes),
 }
 
 impl SingleResponse {
@@ -211,6 +273,8 @@ impl SingleResponse {
             &ReadyForStream => true,
             &Unbundle(_) => true,
             &Gettreepack(_) => true,
+            &Getpackv1(_) => true,
+            &StreamOut(_) => true,
             _ => false,
         }
     }
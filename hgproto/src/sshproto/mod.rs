@@ -50,6 +50,7 @@ use handler::{OutputStream, ResponseEncoder};
 
 use errors::*;
 
+pub mod record;
 pub mod request;
 pub mod response;
 
@@ -0,0 +1,202 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Session records
+//!
+//! A session record is the list of requests a client sent in a session, with when they were
+//! received, so that the session can be replayed later. The encoding is:
+//! ```
+//! record := header request*
+//! header := 'mononoke-session-record 1 ' <start> ' ' <reponame> '\n'
+//! request := <offset> ' ' <numbytes> '\n' <byte>{numbytes}
+//! ```
+//!
+//! Where `start` is when the session started, in microseconds since the epoch, `offset` is when
+//! the request was received, in microseconds since the start of the session, and the bytes are
+//! the request encoded by `encode_request`. Streaming arguments of requests are not recorded.
+
+use std::str::{self, FromStr};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bytes::{Bytes, BytesMut};
+
+use Request;
+use errors::*;
+
+use super::request::{encode_request, parse_request};
+
+const MAGIC: &str = "mononoke-session-record";
+const VERSION: u32 = 1;
+
+/// A request of a recorded session
+#[derive(Debug, Eq, PartialEq)]
+pub struct RecordedRequest {
+    /// When the request was received, since the start of the session
+    pub offset: Duration,
+    pub request: Request,
+}
+
+/// A recorded session
+#[derive(Debug, Eq, PartialEq)]
+pub struct SessionRecord {
+    pub reponame: String,
+    /// When the session started
+    pub start: SystemTime,
+    pub requests: Vec<RecordedRequest>,
+}
+
+/// Encode the header of the record of a session of `reponame` that started at `start`
+pub fn encode_header(reponame: &str, start: SystemTime) -> Bytes {
+    let start = start
+        .duration_since(UNIX_EPOCH)
+        .map(duration_us)
+        .unwrap_or(0);
+    Bytes::from(format!("{} {} {} {}\n", MAGIC, VERSION, start, reponame))
+}
+
+/// Encode a request that was received `offset` after the start of the session
+pub fn encode_recorded_request(offset: Duration, request: &Request) -> Bytes {
+    let request = encode_request(request);
+    let mut out = BytesMut::from(format!("{} {}\n", duration_us(offset), request.len()));
+    out.extend_from_slice(&request);
+    out.freeze()
+}
+
+/// Decode a whole session record. A request that was cut short, because the server stopped
+/// while the session was recorded, is ignored.
+pub fn decode_session_record(data: &[u8]) -> Result<SessionRecord> {
+    let (header, mut rest) = split_line(data).ok_or_else(|| invalid("missing header"))?;
+    let header: Vec<_> = header.splitn(4, ' ').collect();
+    if header.len() != 4 || header[0] != MAGIC {
+        return Err(invalid("not a session record"));
+    }
+    if u32::from_str(header[1]).ok() != Some(VERSION) {
+        return Err(invalid(format!("unsupported version {}", header[1])));
+    }
+    let start = UNIX_EPOCH + from_us(parse_number(header[2])?);
+
+    let mut requests = vec![];
+    while let Some((line, after_line)) = split_line(rest) {
+        let line: Vec<_> = line.split(' ').collect();
+        if line.len() != 2 {
+            return Err(invalid("malformed request header"));
+        }
+        let offset = parse_number(line[0])?;
+        let len = parse_number(line[1])? as usize;
+        if after_line.len() < len {
+            break;
+        }
+
+        let mut buf = BytesMut::from(&after_line[..len]);
+        let request = parse_request(&mut buf)?.ok_or_else(|| invalid("incomplete request"))?;
+        if !buf.is_empty() {
+            return Err(invalid("unconsumed data after request"));
+        }
+        requests.push(RecordedRequest {
+            offset: from_us(offset),
+            request,
+        });
+        rest = &after_line[len..];
+    }
+
+    Ok(SessionRecord {
+        reponame: header[3].to_string(),
+        start,
+        requests,
+    })
+}
+
+fn split_line(data: &[u8]) -> Option<(&str, &[u8])> {
+    let pos = data.iter().position(|b| *b == b'\n')?;
+    let line = str::from_utf8(&data[..pos]).ok()?;
+    Some((line, &data[pos + 1..]))
+}
+
+fn parse_number(s: &str) -> Result<u64> {
+    u64::from_str(s).map_err(|_| invalid(format!("invalid number {}", s)))
+}
+
+fn invalid<S: Into<String>>(msg: S) -> Error {
+    ErrorKind::SessionRecordInvalid(msg.into()).into()
+}
+
+fn duration_us(duration: Duration) -> u64 {
+    duration.as_secs() * 1_000_000 + u64::from(duration.subsec_nanos() / 1000)
+}
+
+fn from_us(us: u64) -> Duration {
+    Duration::new(us / 1_000_000, ((us % 1_000_000) * 1000) as u32)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use SingleRequest;
+
+    #[test]
+    fn test_roundtrip() {
+        let start = UNIX_EPOCH + Duration::from_secs(1_500_000_000);
+        let mut data = BytesMut::from(encode_header("fbsource", start));
+        data.extend_from_slice(&encode_recorded_request(
+            Duration::from_millis(0),
+            &Request::Single(SingleRequest::Hello),
+        ));
+        data.extend_from_slice(&encode_recorded_request(
+            Duration::from_millis(1500),
+            &Request::Batch(vec![SingleRequest::Heads, SingleRequest::Branchmap]),
+        ));
+
+        let record = decode_session_record(&data).expect("failed to decode record");
+        assert_eq!(
+            record,
+            SessionRecord {
+                reponame: "fbsource".to_string(),
+                start,
+                requests: vec![
+                    RecordedRequest {
+                        offset: Duration::from_millis(0),
+                        request: Request::Single(SingleRequest::Hello),
+                    },
+                    RecordedRequest {
+                        offset: Duration::from_millis(1500),
+                        request: Request::Batch(vec![
+                            SingleRequest::Heads,
+                            SingleRequest::Branchmap,
+                        ]),
+                    },
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn test_truncated() {
+        let mut data = BytesMut::from(encode_header("fbsource", UNIX_EPOCH));
+        data.extend_from_slice(&encode_recorded_request(
+            Duration::from_millis(10),
+            &Request::Single(SingleRequest::Heads),
+        ));
+        let request = encode_recorded_request(
+            Duration::from_millis(20),
+            &Request::Single(SingleRequest::Hello),
+        );
+        data.extend_from_slice(&request[..request.len() - 2]);
+
+        let record = decode_session_record(&data).expect("failed to decode record");
+        assert_eq!(
+            record.requests,
+            vec![
+                RecordedRequest {
+                    offset: Duration::from_millis(10),
+                    request: Request::Single(SingleRequest::Heads),
+                },
+            ]
+        );
+
+        assert!(decode_session_record(b"something else\n").is_err());
+    }
+}
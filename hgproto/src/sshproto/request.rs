@@ -43,6 +43,23 @@ named!(
     )
 );
 
+/// Parse an unsigned decimal integer. The input is assumed to be complete and exact.
+fn optional_integer_complete(input: &[u8]) -> IResult<&[u8], Option<usize>> {
+    match str::from_utf8(input).ok().and_then(|s| usize::from_str(s).ok()) {
+        Some(val) => IResult::Done(b"", Some(val)),
+        None => IResult::Error(ErrorKind::Digit),
+    }
+}
+
+/// Parse a boolean the way Mercurial encodes it. The input is assumed to be complete and exact.
+fn boolean_complete(input: &[u8]) -> IResult<&[u8], bool> {
+    match input {
+        b"1" | b"true" | b"True" => IResult::Done(b"", true),
+        b"0" | b"false" | b"False" => IResult::Done(b"", false),
+        _ => IResult::Error(ErrorKind::Custom(0)),
+    }
+}
+
 /// Return an identifier of the form [a-zA-Z_][a-zA-Z0-9_]*. Returns Incomplete
 /// if it manages to reach the end of input, as there may be more identifier coming.
 fn ident(input: &[u8]) -> IResult<&[u8], &[u8]> {
@@ -200,6 +217,18 @@ named!(
     )
 );
 
+/// A space-separated list of arbitrary strings. The input is assumed to be
+/// complete and exact.
+fn spacevalues(input: &[u8]) -> IResult<&[u8], Vec<String>> {
+    IResult::Done(
+        b"",
+        String::from_utf8_lossy(input)
+            .split_whitespace()
+            .map(String::from)
+            .collect(),
+    )
+}
+
 /// A comma-separated list of arbitrary values. The input is assumed to be
 /// complete and exact.
 fn commavalues(input: &[u8]) -> IResult<&[u8], Vec<Vec<u8>>> {
@@ -439,6 +468,173 @@ pub fn parse_request(buf: &mut BytesMut) -> Result<Option<Request>> {
     }))
 }
 
+/// Encode a request the way `parse_request` expects it. Streaming arguments of the request, f.e.
+/// the bundle of `unbundle`, are not part of it.
+pub fn encode_request(req: &Request) -> Bytes {
+    let mut out = Vec::new();
+    match req {
+        &Request::Single(ref req) => {
+            let (named, star) = request_args(req);
+            encode_command(&mut out, req.name(), named, star);
+        }
+        &Request::Batch(ref reqs) => {
+            let cmds: Vec<_> = reqs.iter().map(encode_batch_command).collect();
+            let cmds = cmds.join(&b';');
+            encode_command(&mut out, "batch", vec![("cmds", cmds)], Some(vec![]));
+        }
+    }
+    Bytes::from(out)
+}
+
+type StarArgs = Vec<(Vec<u8>, Vec<u8>)>;
+
+/// Named arguments of a command, and its `*` arguments if it accepts them
+fn request_args(req: &SingleRequest) -> (Vec<(&'static str, Vec<u8>)>, Option<StarArgs>) {
+    use SingleRequest::*;
+
+    match req {
+        &Between { ref pairs } => {
+            let pairs: Vec<_> = pairs
+                .iter()
+                .map(|&(ref a, ref b)| format!("{}-{}", a, b))
+                .collect();
+            (vec![("pairs", pairs.join(" ").into_bytes())], None)
+        }
+        &Debugwireargs {
+            ref one,
+            ref two,
+            ref all_args,
+        } => {
+            let rest = all_args
+                .iter()
+                .filter(|&(key, _)| key.as_slice() != b"one" && key.as_slice() != b"two")
+                .map(|(key, val)| (key.clone(), val.clone()))
+                .collect();
+            (vec![("one", one.clone()), ("two", two.clone())], Some(rest))
+        }
+        &Getbundle(ref args) => {
+            let mut star = vec![];
+            push_nonempty(&mut star, "heads", hashes(&args.heads));
+            push_nonempty(&mut star, "common", hashes(&args.common));
+            push_nonempty(&mut star, "bundlecaps", args.bundlecaps.join(&b','));
+            push_nonempty(&mut star, "listkeys", args.listkeys.join(&b','));
+            push_nonempty(&mut star, "includepattern", args.includepattern.join(&b','));
+            push_nonempty(&mut star, "excludepattern", args.excludepattern.join(&b','));
+            if let Some(depth) = args.depth {
+                star.push((b"depth".to_vec(), depth.to_string().into_bytes()));
+            }
+            if args.branchmap {
+                star.push((b"branchmap".to_vec(), b"1".to_vec()));
+            }
+            if args.phases {
+                star.push((b"phases".to_vec(), b"1".to_vec()));
+            }
+            if args.obsmarkers {
+                star.push((b"obsmarkers".to_vec(), b"1".to_vec()));
+            }
+            if args.stream {
+                star.push((b"stream".to_vec(), b"1".to_vec()));
+            }
+            (vec![], Some(star))
+        }
+        &Listkeys { ref namespace } => (vec![("namespace", namespace.clone().into_bytes())], None),
+        &Lookup { ref key } => (vec![("key", key.clone().into_bytes())], None),
+        &Protocaps { ref caps } => (vec![("caps", caps.join(" ").into_bytes())], None),
+        &Known { ref nodes } => (vec![("nodes", hashes(nodes))], Some(vec![])),
+        &Pushkey {
+            ref namespace,
+            ref key,
+            ref old,
+            ref new,
+        } => (
+            vec![
+                ("namespace", namespace.clone().into_bytes()),
+                ("key", key.clone().into_bytes()),
+                ("old", old.clone().into_bytes()),
+                ("new", new.clone().into_bytes()),
+            ],
+            None,
+        ),
+        &Unbundle { ref heads } => (vec![("heads", heads.join(" ").into_bytes())], None),
+        &Gettreepack(ref args) => {
+            let directories: Vec<_> = args.directories.iter().map(batch::escape).collect();
+            let mut star = vec![
+                (b"rootdir".to_vec(), args.rootdir.to_vec()),
+                (b"mfnodes".to_vec(), hashes(&args.mfnodes)),
+                (b"basemfnodes".to_vec(), hashes(&args.basemfnodes)),
+                (b"directories".to_vec(), directories.join(&b',')),
+            ];
+            push_nonempty(&mut star, "includepattern", args.includepattern.join(&b','));
+            push_nonempty(&mut star, "excludepattern", args.excludepattern.join(&b','));
+            if let Some(depth) = args.depth {
+                star.push((b"depth".to_vec(), depth.to_string().into_bytes()));
+            }
+            (vec![], Some(star))
+        }
+        &Getpackv1 => (vec![], Some(vec![])),
+        &Branchmap | &Capabilities | &Clonebundles | &Heads | &Hello | &Getfiles | &StreamOut => {
+            (vec![], None)
+        }
+    }
+}
+
+fn encode_command(
+    out: &mut Vec<u8>,
+    name: &str,
+    named: Vec<(&'static str, Vec<u8>)>,
+    star: Option<StarArgs>,
+) {
+    fn encode_arg(out: &mut Vec<u8>, key: &[u8], val: &[u8]) {
+        out.extend_from_slice(key);
+        out.extend_from_slice(format!(" {}\n", val.len()).as_bytes());
+        out.extend_from_slice(val);
+    }
+
+    out.extend_from_slice(name.as_bytes());
+    out.push(b'\n');
+    for (key, val) in named {
+        encode_arg(out, key.as_bytes(), &val);
+    }
+    if let Some(star) = star {
+        out.extend_from_slice(format!("* {}\n", star.len()).as_bytes());
+        for (key, val) in star {
+            encode_arg(out, &key, &val);
+        }
+    }
+}
+
+/// A command of a batch is `<name> <key>=<value>,...`, with keys and values batch-escaped
+fn encode_batch_command(req: &SingleRequest) -> Vec<u8> {
+    let (named, star) = request_args(req);
+    let args: Vec<_> = named
+        .into_iter()
+        .map(|(key, val)| (key.as_bytes().to_vec(), val))
+        .chain(star.into_iter().flat_map(|star| star))
+        .map(|(key, val)| {
+            let mut arg = batch::escape(&Bytes::from(key));
+            arg.push(b'=');
+            arg.extend(batch::escape(&Bytes::from(val)));
+            arg
+        })
+        .collect();
+
+    let mut out = req.name().as_bytes().to_vec();
+    out.push(b' ');
+    out.extend(args.join(&b','));
+    out
+}
+
+fn hashes(hashes: &[HgNodeHash]) -> Vec<u8> {
+    let hashes: Vec<_> = hashes.iter().map(|hash| hash.to_string()).collect();
+    hashes.join(" ").into_bytes()
+}
+
+fn push_nonempty(args: &mut StarArgs, key: &str, val: Vec<u8>) {
+    if !val.is_empty() {
+        args.push((key.as_bytes().to_vec(), val));
+    }
+}
+
 /// Common parser, generalized over how to parse parameters (either unbatched or
 /// batched syntax.)
 #[cfg_attr(rustfmt, rustfmt_skip)]
@@ -455,6 +651,7 @@ fn parse_with_params(
           })
         | command!("branchmap", Branchmap, parse_params, {})
         | command!("capabilities", Capabilities, parse_params, {})
+        | command!("clonebundles", Clonebundles, parse_params, {})
         | call!(parse_command, "debugwireargs", parse_params, 2+1,
             |kv| Ok(Debugwireargs {
                 one: parseval(&kv, "one", ident_complete)?.to_vec(),
@@ -464,7 +661,6 @@ fn parse_with_params(
         | call!(parse_command, "getbundle", parse_params, 0+1,
             |kv| Ok(Getbundle(GetbundleArgs {
                 // Some params are currently ignored, like:
-                // - obsmarkers
                 // - cg
                 // - cbattempted
                 // If those params are needed, they should be parsed here.
@@ -472,6 +668,13 @@ fn parse_with_params(
                 common: parseval_default(&kv, "common", hashlist)?,
                 bundlecaps: parseval_default(&kv, "bundlecaps", commavalues)?,
                 listkeys: parseval_default(&kv, "listkeys", commavalues)?,
+                includepattern: parseval_default(&kv, "includepattern", commavalues)?,
+                excludepattern: parseval_default(&kv, "excludepattern", commavalues)?,
+                depth: parseval_default(&kv, "depth", optional_integer_complete)?,
+                branchmap: parseval_default(&kv, "branchmap", boolean_complete)?,
+                phases: parseval_default(&kv, "phases", boolean_complete)?,
+                obsmarkers: parseval_default(&kv, "obsmarkers", boolean_complete)?,
+                stream: parseval_default(&kv, "stream", boolean_complete)?,
             })))
         | command!("heads", Heads, parse_params, {})
         | command!("hello", Hello, parse_params, {})
@@ -481,9 +684,18 @@ fn parse_with_params(
         | command!("lookup", Lookup, parse_params, {
               key => utf8_string_complete,
           })
+        | command!("protocaps", Protocaps, parse_params, {
+              caps => spacevalues,
+          })
         | command_star!("known", Known, parse_params, {
               nodes => hashlist,
           })
+        | command!("pushkey", Pushkey, parse_params, {
+              namespace => ident_string,
+              key => utf8_string_complete,
+              old => utf8_string_complete,
+              new => utf8_string_complete,
+          })
         | command!("unbundle", Unbundle, parse_params, {
               heads => stringlist,
           })
@@ -493,8 +705,13 @@ fn parse_with_params(
                 mfnodes: parseval(&kv, "mfnodes", hashlist)?,
                 basemfnodes: parseval(&kv, "basemfnodes", hashlist)?,
                 directories: parseval(&kv, "directories", gettreepack_directories)?,
+                includepattern: parseval_default(&kv, "includepattern", commavalues)?,
+                excludepattern: parseval_default(&kv, "excludepattern", commavalues)?,
+                depth: parseval_default(&kv, "depth", optional_integer_complete)?,
             })))
         | command!("getfiles", Getfiles, parse_params, {})
+        | command_star!("getpackv1", Getpackv1, parse_params, {})
+        | command!("stream_out", StreamOut, parse_params, {})
     )
 }
 
@@ -1155,6 +1372,13 @@ mod test_parse {
                 common: vec![],
                 bundlecaps: vec![],
                 listkeys: vec![],
+                includepattern: vec![],
+                excludepattern: vec![],
+                depth: None,
+                branchmap: false,
+                phases: false,
+                obsmarkers: false,
+                stream: false,
             })),
         );
 
@@ -1179,8 +1403,118 @@ mod test_parse {
                 common: vec![hash_twos(), hash_threes()],
                 bundlecaps: vec![b"cap1".to_vec(), b"CAP2".to_vec(), b"cap3".to_vec()],
                 listkeys: vec![b"key1".to_vec(), b"key2".to_vec()],
+                includepattern: vec![],
+                excludepattern: vec![],
+                depth: None,
+                branchmap: false,
+                phases: false,
+                obsmarkers: false,
+                stream: false,
+            })),
+        );
+
+        // narrow clone
+        let inp = "getbundle\n\
+                   * 2\n\
+                   includepattern 28\n\
+                   path:foo/bar,rootfilesin:baz\
+                   excludepattern 16\n\
+                   path:foo/bar/qux";
+        test_parse(
+            inp,
+            Request::Single(SingleRequest::Getbundle(GetbundleArgs {
+                heads: vec![],
+                common: vec![],
+                bundlecaps: vec![],
+                listkeys: vec![],
+                includepattern: vec![b"path:foo/bar".to_vec(), b"rootfilesin:baz".to_vec()],
+                excludepattern: vec![b"path:foo/bar/qux".to_vec()],
+                depth: None,
+                branchmap: false,
+                phases: false,
+                obsmarkers: false,
+                stream: false,
+            })),
+        );
+
+        // shallow clone
+        let inp = "getbundle\n\
+                   * 2\n\
+                   heads 40\n\
+                   1111111111111111111111111111111111111111\
+                   depth 3\n\
+                   100";
+        test_parse(
+            inp,
+            Request::Single(SingleRequest::Getbundle(GetbundleArgs {
+                heads: vec![hash_ones()],
+                common: vec![],
+                bundlecaps: vec![],
+                listkeys: vec![],
+                includepattern: vec![],
+                excludepattern: vec![],
+                depth: Some(100),
+                branchmap: false,
+                phases: false,
+                obsmarkers: false,
+                stream: false,
+            })),
+        );
+
+        // with the branchmap, phases and obsmarkers
+        let inp = "getbundle\n\
+                   * 3\n\
+                   branchmap 1\n\
+                   1\n\
+                   obsmarkers 1\n\
+                   1\n\
+                   phases 4\n\
+                   True";
+        test_parse(
+            inp,
+            Request::Single(SingleRequest::Getbundle(GetbundleArgs {
+                heads: vec![],
+                common: vec![],
+                bundlecaps: vec![],
+                listkeys: vec![],
+                includepattern: vec![],
+                excludepattern: vec![],
+                depth: None,
+                branchmap: true,
+                phases: true,
+                obsmarkers: true,
+                stream: false,
+            })),
+        );
+
+        // streaming clone
+        let inp = "getbundle\n\
+                   * 1\n\
+                   stream 1\n\
+                   1";
+        test_parse(
+            inp,
+            Request::Single(SingleRequest::Getbundle(GetbundleArgs {
+                heads: vec![],
+                common: vec![],
+                bundlecaps: vec![],
+                listkeys: vec![],
+                includepattern: vec![],
+                excludepattern: vec![],
+                depth: None,
+                branchmap: false,
+                phases: false,
+                obsmarkers: false,
+                stream: true,
             })),
         );
+
+        let inp = "getbundle\n\
+                   * 1\n\
+                   depth 3\n\
+                   abc";
+        let mut buf = BytesMut::from(inp.as_bytes());
+        assert!(parse_request(&mut buf).is_err());
     }
 
     #[test]
@@ -1197,6 +1531,28 @@ mod test_parse {
         test_parse(inp, Request::Single(SingleRequest::Hello {}));
     }
 
+    #[test]
+    fn test_parse_clonebundles() {
+        let inp = "clonebundles\n";
+
+        test_parse(inp, Request::Single(SingleRequest::Clonebundles {}));
+    }
+
+    #[test]
+    fn test_parse_stream_out() {
+        let inp = "stream_out\n";
+
+        test_parse(inp, Request::Single(SingleRequest::StreamOut {}));
+    }
+
+    #[test]
+    fn test_parse_getpackv1() {
+        let inp = "getpackv1\n\
+                   * 0\n";
+
+        test_parse(inp, Request::Single(SingleRequest::Getpackv1 {}));
+    }
+
     #[test]
     fn test_parse_listkeys() {
         let inp = "listkeys\n\
@@ -1211,6 +1567,45 @@ mod test_parse {
         );
     }
 
+    #[test]
+    fn test_parse_protocaps() {
+        let inp = "protocaps\n\
+                   caps 38\n\
+                   comp=zstd,zlib,none,bzip2 partial-pull";
+
+        test_parse(
+            inp,
+            Request::Single(SingleRequest::Protocaps {
+                caps: vec![
+                    "comp=zstd,zlib,none,bzip2".to_string(),
+                    "partial-pull".to_string(),
+                ],
+            }),
+        );
+    }
+
+    #[test]
+    fn test_parse_pushkey() {
+        let inp = "pushkey\n\
+                   namespace 8\n\
+                   obsolete\
+                   key 5\n\
+                   dump0\
+                   old 0\n\
+                   new 5\n\
+                   0123~";
+
+        test_parse(
+            inp,
+            Request::Single(SingleRequest::Pushkey {
+                namespace: "obsolete".to_string(),
+                key: "dump0".to_string(),
+                old: "".to_string(),
+                new: "0123~".to_string(),
+            }),
+        );
+    }
+
     #[test]
     fn test_parse_lookup() {
         let inp = "lookup\n\
@@ -1257,6 +1652,9 @@ mod test_parse {
                 mfnodes: vec![hash_ones()],
                 basemfnodes: vec![hash_ones()],
                 directories: vec![],
+                includepattern: vec![],
+                excludepattern: vec![],
+                depth: None,
             })),
         );
 
@@ -1279,6 +1677,34 @@ mod test_parse {
                 mfnodes: vec![hash_ones(), hash_twos()],
                 basemfnodes: vec![hash_twos(), hash_ones()],
                 directories: vec![Bytes::from(",".as_bytes()), Bytes::from(";".as_bytes())],
+                includepattern: vec![],
+                excludepattern: vec![],
+                depth: None,
+            })),
+        );
+
+        let inp =
+            "gettreepack\n\
+             * 5\n\
+             rootdir 0\n\
+             mfnodes 40\n\
+             1111111111111111111111111111111111111111\
+             basemfnodes 81\n\
+             2222222222222222222222222222222222222222 1111111111111111111111111111111111111111\
+             directories 0\n\
+             depth 1\n\
+             3";
+
+        test_parse(
+            inp,
+            Request::Single(SingleRequest::Gettreepack(GettreepackArgs {
+                rootdir: Bytes::new(),
+                mfnodes: vec![hash_ones()],
+                basemfnodes: vec![hash_twos(), hash_ones()],
+                directories: vec![],
+                includepattern: vec![],
+                excludepattern: vec![],
+                depth: Some(3),
             })),
         );
     }
@@ -1369,4 +1795,64 @@ mod test_parse {
         );
     }
 
+    #[test]
+    fn test_encode_request() {
+        let reqs = vec![
+            Request::Single(SingleRequest::Hello),
+            Request::Single(SingleRequest::Between {
+                pairs: vec![(hash_ones(), hash_twos())],
+            }),
+            Request::Single(SingleRequest::Listkeys {
+                namespace: "bookmarks".to_string(),
+            }),
+            Request::Single(SingleRequest::Known {
+                nodes: vec![hash_ones(), hash_twos()],
+            }),
+            Request::Single(SingleRequest::Getbundle(GetbundleArgs {
+                heads: vec![hash_ones()],
+                common: vec![],
+                bundlecaps: vec![b"HG20".to_vec(), b"bundle2=HG20%0Achangegroup%3D02".to_vec()],
+                listkeys: vec![b"bookmarks".to_vec()],
+                includepattern: vec![],
+                excludepattern: vec![],
+                depth: Some(10),
+                branchmap: true,
+                phases: true,
+                obsmarkers: true,
+                stream: true,
+            })),
+            Request::Single(SingleRequest::Gettreepack(GettreepackArgs {
+                rootdir: Bytes::from("dir"),
+                mfnodes: vec![hash_ones()],
+                basemfnodes: vec![],
+                directories: vec![Bytes::from("a,b"), Bytes::from("c")],
+                includepattern: vec![b"path:dir".to_vec()],
+                excludepattern: vec![],
+                depth: None,
+            })),
+            Request::Single(SingleRequest::Getpackv1),
+            Request::Single(SingleRequest::Pushkey {
+                namespace: "bookmarks".to_string(),
+                key: "master".to_string(),
+                old: "".to_string(),
+                new: hash_ones().to_string(),
+            }),
+            Request::Batch(vec![
+                SingleRequest::Heads,
+                SingleRequest::Lookup {
+                    key: "a=b".to_string(),
+                },
+                SingleRequest::Known {
+                    nodes: vec![hash_ones(), hash_twos()],
+                },
+            ]),
+        ];
+
+        for req in reqs {
+            let mut buf = BytesMut::from(encode_request(&req));
+            let decoded = parse_request(&mut buf).expect("failed to parse encoded request");
+            assert_eq!(decoded, Some(req));
+            assert!(buf.is_empty());
+        }
+    }
 }
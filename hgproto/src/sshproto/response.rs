@@ -11,6 +11,8 @@ use bytes::{BufMut, Bytes, BytesMut};
 use futures::stream;
 use futures_ext::StreamExt;
 
+use mercurial_bundles::parts::encode_branchmap;
+
 use {batch, Response, SingleResponse};
 use handler::OutputStream;
 
@@ -39,11 +41,7 @@ pub fn encode(response: Response) -> OutputStream {
     let mut out = BytesMut::new();
     match response {
         Response::Batch(ref resps) => {
-            let escaped_results: Vec<_> = resps
-                .iter()
-                .map(|resp| batch::escape(&encode_cmd(resp)))
-                .collect();
-            let escaped_results = Bytes::from(escaped_results.join(&b';'));
+            let escaped_results = encode_batch(resps);
             out.reserve(10 + escaped_results.len());
             out.put_slice(format!("{}\n", escaped_results.len()).as_bytes());
             out.put(escaped_results)
@@ -53,6 +51,15 @@ pub fn encode(response: Response) -> OutputStream {
     stream::once(Ok(out.freeze())).boxify()
 }
 
+/// Encode the results of batched commands. This is used by both SSH and HTTP batch responses
+pub(crate) fn encode_batch(resps: &[SingleResponse]) -> Bytes {
+    let escaped_results: Vec<_> = resps
+        .iter()
+        .map(|resp| batch::escape(&encode_cmd(resp)))
+        .collect();
+    Bytes::from(escaped_results.join(&b';'))
+}
+
 fn encode_single(response: &SingleResponse, out: &mut BytesMut) {
     let res = encode_cmd(response);
     out.reserve(10 + res.len());
@@ -63,8 +70,8 @@ fn encode_single(response: &SingleResponse, out: &mut BytesMut) {
 }
 
 /// Encode the result of an individual command completion. This is used by both
-/// single and batch responses encoding
-fn encode_cmd(response: &SingleResponse) -> Bytes {
+/// single and batch responses encoding, and by the HTTP protocol
+pub(crate) fn encode_cmd(response: &SingleResponse) -> Bytes {
     use SingleResponse::*;
 
     match response {
@@ -88,6 +95,8 @@ fn encode_cmd(response: &SingleResponse) -> Bytes {
             Bytes::from(out)
         }
 
+        &Clonebundles(ref res) => res.clone(),
+
         &Debugwireargs(ref res) => res.clone(),
 
         &Heads(ref set) => {
@@ -107,6 +116,14 @@ fn encode_cmd(response: &SingleResponse) -> Bytes {
             Bytes::from(out)
         }
 
+        &Pushkey(ref res) => {
+            if *res {
+                Bytes::from(b"1\n".as_ref())
+            } else {
+                Bytes::from(b"0\n".as_ref())
+            }
+        }
+
         &ReadyForStream => Bytes::from(b"0\n".as_ref()),
 
         // TODO(luk, T25574469) The response for Unbundle should be chunked stream of bundle2
@@ -118,8 +135,14 @@ fn encode_cmd(response: &SingleResponse) -> Bytes {
 
         &Getfiles(ref res) => res.clone(),
 
+        &Getpackv1(ref res) => res.clone(),
+
+        &StreamOut(ref res) => res.clone(),
+
         &Lookup(ref res) => res.clone(),
 
+        &Protocaps => Bytes::from(b"OK".as_ref()),
+
         &Listkeys(ref res) => {
             let mut bytes = BytesMut::new();
             for (name, key) in res.iter() {
@@ -131,11 +154,7 @@ fn encode_cmd(response: &SingleResponse) -> Bytes {
             bytes.freeze()
         }
 
-        &Branchmap(ref _res) => {
-            // We have no plans to support mercurial branches and hence no plans for branchmap,
-            // so just return fake response.
-            Bytes::new()
-        }
+        &Branchmap(ref res) => encode_branchmap(res),
 
         r => panic!("Response for {:?} unimplemented", r),
     }
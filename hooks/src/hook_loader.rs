@@ -17,17 +17,22 @@ use std::collections::HashSet;
 use std::sync::Arc;
 
 pub fn load_hooks(hook_manager: &mut HookManager, config: RepoConfig) -> Result<(), Error> {
+    let hook_libs = Arc::new(config.hook_libs.unwrap_or_default());
     match config.hooks {
         Some(hooks) => {
             let mut hook_set = HashSet::new();
             for hook in hooks {
                 let name = hook.name;
-                let lua_hook = LuaHook::new(name.clone(), hook.code.clone());
+                let mut lua_hook = LuaHook::new(name.clone(), hook.code.clone());
+                lua_hook.libs = hook_libs.clone();
                 match hook.hook_type {
                     HookType::PerFile => hook_manager.register_file_hook(&name, Arc::new(lua_hook)),
                     HookType::PerChangeset => {
                         hook_manager.register_changeset_hook(&name, Arc::new(lua_hook))
                     }
+                    HookType::PerBookmarkMove => {
+                        hook_manager.register_bookmark_move_hook(&name, Arc::new(lua_hook))
+                    }
                 }
                 hook_set.insert(name);
             }
@@ -105,6 +110,12 @@ mod test {
                         hook_type: HookType::PerChangeset,
                     },
                 ]),
+                hook_libs: None,
+                streaming_clone: None,
+                clone_bundles: None,
+                delta_threshold: 50,
+                compress_responses: true,
+                acl: None,
             };
 
             let mut hm = hook_manager_blobrepo();
@@ -138,6 +149,12 @@ mod test {
                         hook_type: HookType::PerFile,
                     },
                 ]),
+                hook_libs: None,
+                streaming_clone: None,
+                clone_bundles: None,
+                delta_threshold: 50,
+                compress_responses: true,
+                acl: None,
             };
 
             let mut hm = hook_manager_blobrepo();
@@ -32,6 +32,8 @@ extern crate hlua;
 extern crate hlua_futures;
 #[macro_use]
 extern crate lazy_static;
+extern crate libc;
+extern crate lua52_sys;
 #[cfg(test)]
 extern crate many_files_dirs;
 #[macro_use]
@@ -64,6 +66,7 @@ use std::sync::{Arc, Mutex};
 
 type ChangesetHooks = HashMap<String, Arc<Hook<HookChangeset>>>;
 type FileHooks = Arc<Mutex<HashMap<String, Arc<Hook<HookFile>>>>>;
+type BookmarkMoveHooks = HashMap<String, Arc<Hook<HookBookmarkMove>>>;
 type Cache = Asyncmemo<HookCacheFiller>;
 
 /// Manages hooks and allows them to be installed and uninstalled given a name
@@ -72,6 +75,7 @@ pub struct HookManager {
     cache: Cache,
     changeset_hooks: ChangesetHooks,
     file_hooks: FileHooks,
+    bookmark_move_hooks: BookmarkMoveHooks,
     bookmark_hooks: HashMap<Bookmark, Vec<String>>,
     repo_name: String,
     store: Box<ChangesetStore>,
@@ -95,6 +99,7 @@ impl HookManager {
             cache,
             changeset_hooks,
             file_hooks,
+            bookmark_move_hooks: HashMap::new(),
             bookmark_hooks: HashMap::new(),
             repo_name,
             store,
@@ -110,6 +115,16 @@ impl HookManager {
         hooks.insert(hook_name.to_string(), hook);
     }
 
+    /// Bookmark move hooks are not configured per bookmark: they run on every bookmark move
+    /// and get the name of the bookmark, so they can decide themselves which moves to check
+    pub fn register_bookmark_move_hook(
+        &mut self,
+        hook_name: &str,
+        hook: Arc<Hook<HookBookmarkMove>>,
+    ) {
+        self.bookmark_move_hooks.insert(hook_name.to_string(), hook);
+    }
+
     pub fn set_hooks_for_bookmark(&mut self, bookmark: Bookmark, hooks: Vec<String>) {
         self.bookmark_hooks.insert(bookmark, hooks);
     }
@@ -130,6 +145,36 @@ impl HookManager {
             .collect()
     }
 
+    pub fn bookmark_move_hook_names(&self) -> HashSet<String> {
+        self.bookmark_move_hooks
+            .iter()
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    // Bookmark move hooks
+
+    pub fn run_bookmark_move_hooks(
+        &self,
+        bookmark_move: HookBookmarkMove,
+    ) -> BoxFuture<Vec<(String, HookExecution)>, Error> {
+        let v: Vec<BoxFuture<(String, HookExecution), _>> = self.bookmark_move_hooks
+            .iter()
+            .map(|(hook_name, hook)| {
+                let hook_context = HookContext::new(
+                    hook_name.clone(),
+                    self.repo_name.clone(),
+                    bookmark_move.clone(),
+                );
+                let hook_name = hook_name.clone();
+                hook.run(hook_context)
+                    .map(move |he| (hook_name, he))
+                    .boxify()
+            })
+            .collect();
+        futures::future::join_all(v).boxify()
+    }
+
     // Changeset hooks
 
     pub fn run_changeset_hooks_for_bookmark(
@@ -319,6 +364,33 @@ impl HookFile {
     }
 }
 
+/// Represents a move of a bookmark. A bookmark that is created has no `old` changeset, and a
+/// bookmark that is deleted has no `new` changeset.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HookBookmarkMove {
+    pub bookmark: String,
+    pub old: Option<String>,
+    pub new: Option<String>,
+    /// Identity of the user that moves the bookmark, if known
+    pub pusher: Option<String>,
+}
+
+impl HookBookmarkMove {
+    pub fn new(
+        bookmark: String,
+        old: Option<String>,
+        new: Option<String>,
+        pusher: Option<String>,
+    ) -> HookBookmarkMove {
+        HookBookmarkMove {
+            bookmark,
+            old,
+            new,
+            pusher,
+        }
+    }
+}
+
 impl HookChangeset {
     pub fn new(
         author: String,
@@ -339,13 +411,19 @@ impl HookChangeset {
 pub enum HookExecution {
     Accepted,
     Rejected(HookRejectionInfo),
+    /// The hook was aborted because it ran too many instructions
+    InstructionLimitExceeded,
+    /// The hook was aborted because it tried to allocate too much memory
+    MemoryLimitExceeded,
+    /// The hook was aborted because it ran for too long
+    TimedOut,
 }
 
 impl Weight for HookExecution {
     fn get_weight(&self) -> usize {
         match self {
-            HookExecution::Accepted => mem::size_of::<Self>(),
             HookExecution::Rejected(info) => mem::size_of::<Self>() + info.get_weight(),
+            _ => mem::size_of::<Self>(),
         }
     }
 }
@@ -459,9 +537,9 @@ impl Filler for HookCacheFiller {
 // TODO Note that when we move to Bonsai changesets the ID that we use in the cache will
 // be the content hash
 pub struct FileHookExecutionID {
-    cs_id: HgChangesetId,
-    hook_name: String,
-    path: String,
+    pub cs_id: HgChangesetId,
+    pub hook_name: String,
+    pub path: String,
 }
 
 impl Weight for FileHookExecutionID {
@@ -859,6 +937,58 @@ mod test {
         });
     }
 
+    #[derive(Clone, Debug)]
+    struct FnBookmarkMoveHook {
+        f: fn(HookContext<HookBookmarkMove>) -> HookExecution,
+    }
+
+    impl Hook<HookBookmarkMove> for FnBookmarkMoveHook {
+        fn run(&self, context: HookContext<HookBookmarkMove>) -> BoxFuture<HookExecution, Error> {
+            finished((self.f)(context)).boxify()
+        }
+    }
+
+    fn release_bookmark_move_hook() -> Box<Hook<HookBookmarkMove>> {
+        let f: fn(HookContext<HookBookmarkMove>) -> HookExecution = |context| {
+            if context.data.bookmark.starts_with("release/") && context.data.old.is_some() {
+                default_rejection()
+            } else {
+                HookExecution::Accepted
+            }
+        };
+        Box::new(FnBookmarkMoveHook { f })
+    }
+
+    #[test]
+    fn test_bookmark_move_hooks() {
+        async_unit::tokio_unit_test(|| {
+            let mut hook_manager = hook_manager_inmem();
+            hook_manager.register_bookmark_move_hook("hook1", release_bookmark_move_hook().into());
+            assert_eq!(
+                hook_manager.bookmark_move_hook_names(),
+                hashset!["hook1".to_string()]
+            );
+
+            let create = HookBookmarkMove::new(
+                "release/1".into(),
+                None,
+                Some("new-hash".into()),
+                Some("some-user".into()),
+            );
+            let res = hook_manager.run_bookmark_move_hooks(create).wait().unwrap();
+            assert_eq!(res, vec![("hook1".to_string(), HookExecution::Accepted)]);
+
+            let moved = HookBookmarkMove::new(
+                "release/1".into(),
+                Some("old-hash".into()),
+                Some("new-hash".into()),
+                Some("some-user".into()),
+            );
+            let res = hook_manager.run_bookmark_move_hooks(moved).wait().unwrap();
+            assert_eq!(res, vec![("hook1".to_string(), default_rejection())]);
+        });
+    }
+
     #[test]
     fn test_with_blob_store() {
         async_unit::tokio_unit_test(|| {
@@ -8,14 +8,59 @@
 
 #![deny(warnings)]
 
-use super::{Hook, HookChangeset, HookChangesetParents, HookContext, HookExecution, HookFile,
-            HookRejectionInfo};
+use super::{Hook, HookBookmarkMove, HookChangeset, HookChangesetParents, HookContext,
+            HookExecution, HookFile, HookRejectionInfo};
 use super::errors::*;
 use failure::Error;
-use futures::{failed, Future};
+use futures::{future, Future, IntoFuture, Poll};
 use futures_ext::{BoxFuture, FutureExt};
-use hlua::{Lua, LuaFunctionCallError, LuaTable, PushGuard, TuplePushError, Void};
+use hlua::{AsMutLua, Lua, LuaFunctionCallError, LuaTable, PushGuard, TuplePushError, Void};
 use hlua_futures::{LuaCoroutine, LuaCoroutineBuilder};
+use libc::{self, c_int, c_void, size_t};
+use lua52_sys as ffi;
+use std::collections::HashMap;
+use std::ptr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// Number of VM instructions between two invocations of the sandbox count hook
+const INSTRUCTION_HOOK_INTERVAL: c_int = 1000;
+
+// Globals from the base library which give access to the filesystem or allow loading
+// arbitrary (possibly binary) chunks. Only `base`, `table`, `string` and `math` are opened,
+// so `io`, `os`, `package` and `debug` are never available to hooks. `require` is replaced by
+// a version that only loads the shared hook modules from `__hook_libs`.
+const SANDBOX_PRELUDE: &'static str = "
+local load = load
+local libs = __hook_libs or {}
+local loaded = {}
+__hook_libs = nil
+require = function(name)
+    if loaded[name] ~= nil then
+        return loaded[name]
+    end
+    local code = libs[name]
+    if code == nil then
+        error(\"module '\" .. tostring(name) .. \"' not found\")
+    end
+    local chunk, err = load(code, \"=\" .. name, \"t\")
+    if chunk == nil then
+        error(err)
+    end
+    local res = chunk(name)
+    if res == nil then
+        res = true
+    end
+    loaded[name] = res
+    return res
+end
+dofile = nil
+loadfile = nil
+load = nil
+collectgarbage = nil
+string.dump = nil
+";
 
 const HOOK_START_CODE_BASE: &'static str = "
 __hook_start = function(info, arg)
@@ -58,11 +103,42 @@ lazy_static! {
     };
 }
 
+lazy_static! {
+    static ref HOOK_START_CODE_BOOKMARK_MOVE: String = {
+        HOOK_START_CODE_BASE.to_string().replace("@@@", "ctx.bookmark=arg")
+    };
+}
+
+/// Resource limits applied to a single execution of a Lua hook
+#[derive(Clone, Debug, PartialEq)]
+pub struct LuaHookLimits {
+    /// Maximum number of Lua VM instructions the hook may execute
+    pub instruction_limit: usize,
+    /// Maximum number of bytes the Lua state may allocate
+    pub memory_limit: usize,
+    /// Maximum wall-clock time the hook may take, including time spent waiting on futures
+    pub timeout: Duration,
+}
+
+impl Default for LuaHookLimits {
+    fn default() -> Self {
+        LuaHookLimits {
+            instruction_limit: 100_000_000,
+            memory_limit: 64 * 1024 * 1024,
+            timeout: Duration::from_secs(30),
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct LuaHook {
     pub name: String,
     /// The Lua code of the hook
     pub code: String,
+    /// The limits the hook runs under
+    pub limits: LuaHookLimits,
+    /// Lua modules the hook can load with `require`, by module name
+    pub libs: Arc<HashMap<String, String>>,
 }
 
 impl Hook<HookChangeset> for LuaHook {
@@ -82,11 +158,16 @@ impl Hook<HookChangeset> for LuaHook {
                 hook_info.insert("parent2_hash", parent2_hash.to_string());
             }
         }
-        let builder = match self.create_builder(&format!("{}{}", &*HOOK_START_CODE_CS, self.code)) {
+        let sandbox = Arc::new(Sandbox::new(&self.limits));
+        let code = format!("{}{}", &*HOOK_START_CODE_CS, self.code);
+        let builder = match self.create_builder(&sandbox, &code) {
             Ok(builder) => builder,
-            Err(e) => return failed(e).boxify(),
+            Err(e) => return sandbox.check_error(e).into_future().boxify(),
         };
-        self.convert_coroutine_res(builder.create((hook_info, context.data.files.clone())))
+        self.convert_coroutine_res(
+            sandbox,
+            builder.create((hook_info, context.data.files.clone())),
+        )
     }
 }
 
@@ -95,28 +176,102 @@ impl Hook<HookFile> for LuaHook {
         let hook_info = hashmap! {
             "repo_name" => context.repo_name.to_string(),
         };
+        let sandbox = Arc::new(Sandbox::new(&self.limits));
         let mut code = HOOK_START_CODE_FILE.clone();
         code.push_str(&self.code);
-        let builder = match self.create_builder(&code) {
+        let builder = match self.create_builder(&sandbox, &code) {
             Ok(builder) => builder,
-            Err(e) => return failed(e).boxify(),
+            Err(e) => return sandbox.check_error(e).into_future().boxify(),
         };
-        self.convert_coroutine_res(builder.create((hook_info, context.data.path.clone())))
+        self.convert_coroutine_res(
+            sandbox,
+            builder.create((hook_info, context.data.path.clone())),
+        )
+    }
+}
+
+impl Hook<HookBookmarkMove> for LuaHook {
+    fn run(&self, context: HookContext<HookBookmarkMove>) -> BoxFuture<HookExecution, Error> {
+        let hook_info = hashmap! {
+            "repo_name" => context.repo_name.to_string(),
+        };
+        // Absent values are nil in Lua land
+        let mut bookmark_move = hashmap! {
+            "name" => context.data.bookmark.clone(),
+        };
+        if let Some(ref old) = context.data.old {
+            bookmark_move.insert("old", old.clone());
+        }
+        if let Some(ref new) = context.data.new {
+            bookmark_move.insert("new", new.clone());
+        }
+        if let Some(ref pusher) = context.data.pusher {
+            bookmark_move.insert("pusher", pusher.clone());
+        }
+        let sandbox = Arc::new(Sandbox::new(&self.limits));
+        let mut code = HOOK_START_CODE_BOOKMARK_MOVE.clone();
+        code.push_str(&self.code);
+        let builder = match self.create_builder(&sandbox, &code) {
+            Ok(builder) => builder,
+            Err(e) => return sandbox.check_error(e).into_future().boxify(),
+        };
+        self.convert_coroutine_res(sandbox, builder.create((hook_info, bookmark_move)))
     }
 }
 
 impl LuaHook {
     pub fn new(name: String, code: String) -> LuaHook {
-        LuaHook { name, code }
+        LuaHook::with_limits(name, code, LuaHookLimits::default())
+    }
+
+    pub fn with_limits(name: String, code: String, limits: LuaHookLimits) -> LuaHook {
+        LuaHook {
+            name,
+            code,
+            limits,
+            libs: Arc::new(HashMap::new()),
+        }
+    }
+
+    /// Compile the hook code without running it, so broken hooks can be caught before they are
+    /// deployed
+    pub fn check_syntax(&self) -> Result<(), Error> {
+        let mut lua = Lua::new();
+        lua.open_base();
+        lua.set("__hook_code", self.code.as_str());
+        lua.set("__hook_name", self.name.as_str());
+        let err = lua.execute::<String>(
+            "local _, err = load(__hook_code, __hook_name, \"t\") return err or \"\"",
+        ).map_err(|e| ErrorKind::HookParseError(e.to_string()))?;
+        if err.is_empty() {
+            Ok(())
+        } else {
+            Err(ErrorKind::HookParseError(err).into())
+        }
     }
 
     fn create_builder(
         &self,
+        sandbox: &Arc<Sandbox>,
         code: &str,
     ) -> Result<LuaCoroutineBuilder<PushGuard<Lua<'static>>>, Error> {
-        let mut lua = Lua::new();
-        lua.openlibs();
-        let res: Result<(), Error> = lua.execute::<()>(code)
+        let mut lua = sandbox.new_lua()?;
+        lua.open_base();
+        lua.open_table();
+        lua.open_string();
+        lua.open_math();
+        {
+            // Borrow the module sources so they are not copied out of the shared map
+            let mut libs = lua.empty_array("__hook_libs");
+            for (name, lib) in self.libs.iter() {
+                libs.set(name.as_str(), lib.as_str());
+            }
+        }
+        sandbox
+            .enforced(|| lua.execute::<()>(SANDBOX_PRELUDE))
+            .map_err(|e| ErrorKind::HookRuntimeError(e.to_string()))?;
+        let res: Result<(), Error> = sandbox
+            .enforced(|| lua.execute::<()>(code))
             .map_err(|e| ErrorKind::HookParseError(e.to_string()).into());
         res?;
         // Note the lifetime becomes static as the into_get method moves the lua
@@ -127,13 +282,25 @@ impl LuaHook {
 
     fn convert_coroutine_res(
         &self,
+        sandbox: Arc<Sandbox>,
         res: Result<
             LuaCoroutine<PushGuard<Lua<'static>>, LuaTable<PushGuard<Lua<'static>>>>,
             LuaFunctionCallError<TuplePushError<Void, Void>>,
         >,
     ) -> BoxFuture<HookExecution, Error> {
-        let res = res.map_err(|err| ErrorKind::HookRuntimeError(format!("{:#?}", err)));
-        try_boxfuture!(res)
+        let coroutine = match res {
+            Ok(coroutine) => coroutine,
+            Err(err) => {
+                let err = ErrorKind::HookRuntimeError(format!("{:#?}", err)).into();
+                return sandbox.check_error(err).into_future().boxify();
+            }
+        };
+        let timeout = self.limits.timeout;
+        let coroutine = SandboxedFuture {
+            inner: coroutine,
+            sandbox: sandbox.clone(),
+        };
+        let execution = coroutine
             .map_err(move |err| Error::from(ErrorKind::HookRuntimeError(format!("{:#?}", err))))
             .map(|mut t| {
                 t.get::<bool, _, _>(1)
@@ -154,17 +321,234 @@ impl LuaHook {
                         }
                     })
             })
-            .flatten()
+            .flatten();
+        // Hooks may be queued before they run, so the timeout starts at the first poll
+        future::lazy(move || execution.timeout(timeout))
+            .then(move |res| match res {
+                Ok(execution) => Ok(execution),
+                Err(ref err) if err.is_elapsed() => Ok(HookExecution::TimedOut),
+                Err(err) => match err.into_inner() {
+                    // The Lua state was dropped together with the coroutine by now, so the
+                    // sandbox is only referenced from here
+                    Some(err) => sandbox.check_error(err),
+                    None => Err(Error::from(ErrorKind::HookRuntimeError(
+                        "unexpected timer error".into(),
+                    ))),
+                },
+            })
             .boxify()
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum LimitExceeded {
+    Instructions,
+    Memory,
+    Timeout,
+}
+
+/// Accounting for a single sandboxed Lua state. A pointer to it is the userdata of the Lua
+/// allocator, which is how the allocator and the count hook find it. It must outlive the Lua
+/// state, which is guaranteed by holding an `Arc` to it in the hook's future chain after the
+/// point where the coroutine is consumed.
+///
+/// The memory limit is only enforced while Lua code runs in protected mode, see `enforced`. A
+/// failed allocation outside of it, like when arguments are pushed from Rust, would make Lua
+/// abort the whole process.
+struct Sandbox {
+    instruction_limit: usize,
+    memory_limit: usize,
+    timeout: Duration,
+    deadline: Mutex<Option<Instant>>,
+    instructions: AtomicUsize,
+    memory: AtomicUsize,
+    enforcing: AtomicBool,
+    exceeded: AtomicUsize,
+}
+
+// 0 is used for "no limit exceeded"
+const EXCEEDED_INSTRUCTIONS: usize = 1;
+const EXCEEDED_MEMORY: usize = 2;
+const EXCEEDED_TIMEOUT: usize = 3;
+
+impl Sandbox {
+    fn new(limits: &LuaHookLimits) -> Sandbox {
+        Sandbox {
+            instruction_limit: limits.instruction_limit,
+            memory_limit: limits.memory_limit,
+            timeout: limits.timeout,
+            deadline: Mutex::new(None),
+            instructions: AtomicUsize::new(0),
+            memory: AtomicUsize::new(0),
+            enforcing: AtomicBool::new(false),
+            exceeded: AtomicUsize::new(0),
+        }
+    }
+
+    /// Start the clock of the timeout, if it wasn't started yet
+    fn start(&self) {
+        let mut deadline = self.deadline.lock().expect("lock poisoned");
+        if deadline.is_none() {
+            *deadline = Some(Instant::now() + self.timeout);
+        }
+    }
+
+    /// Run `f`, which must only call into Lua through protected calls like `lua_pcall` and
+    /// `lua_resume`, with the memory limit enforced
+    fn enforced<T, F: FnOnce() -> T>(&self, f: F) -> T {
+        self.enforcing.store(true, Ordering::SeqCst);
+        let res = f();
+        self.enforcing.store(false, Ordering::SeqCst);
+        res
+    }
+
+    /// Create a Lua state which allocates through this sandbox and runs the count hook
+    fn new_lua(&self) -> Result<Lua<'static>, Error> {
+        let ud = self as *const Sandbox as *mut c_void;
+        let state = unsafe { ffi::lua_newstate(sandbox_alloc, ud) };
+        if state.is_null() {
+            return Err(ErrorKind::HookRuntimeError("failed to create Lua state".into()).into());
+        }
+        let mut lua = unsafe { Lua::from_existing_state(state, true) };
+        unsafe {
+            ffi::lua_sethook(
+                lua.as_mut_lua().state_ptr(),
+                sandbox_count_hook,
+                ffi::LUA_MASKCOUNT,
+                INSTRUCTION_HOOK_INTERVAL,
+            );
+        }
+        Ok(lua)
+    }
+
+    fn set_exceeded(&self, limit: LimitExceeded) {
+        let code = match limit {
+            LimitExceeded::Instructions => EXCEEDED_INSTRUCTIONS,
+            LimitExceeded::Memory => EXCEEDED_MEMORY,
+            LimitExceeded::Timeout => EXCEEDED_TIMEOUT,
+        };
+        // Only the first limit hit is reported
+        let _ = self.exceeded
+            .compare_exchange(0, code, Ordering::SeqCst, Ordering::SeqCst);
+    }
+
+    fn exceeded(&self) -> Option<LimitExceeded> {
+        match self.exceeded.load(Ordering::SeqCst) {
+            EXCEEDED_INSTRUCTIONS => Some(LimitExceeded::Instructions),
+            EXCEEDED_MEMORY => Some(LimitExceeded::Memory),
+            EXCEEDED_TIMEOUT => Some(LimitExceeded::Timeout),
+            _ => None,
+        }
+    }
+
+    /// Errors raised while a limit was exceeded are the result of the sandbox aborting the
+    /// hook, so they are turned into the corresponding `HookExecution`
+    fn check_error(&self, err: Error) -> Result<HookExecution, Error> {
+        match self.exceeded() {
+            Some(LimitExceeded::Instructions) => Ok(HookExecution::InstructionLimitExceeded),
+            Some(LimitExceeded::Memory) => Ok(HookExecution::MemoryLimitExceeded),
+            Some(LimitExceeded::Timeout) => Ok(HookExecution::TimedOut),
+            None => Err(err),
+        }
+    }
+
+    /// Returns the limit that was hit, if any, after another batch of instructions was run
+    fn on_instructions(&self, count: usize) -> Option<LimitExceeded> {
+        let total = self.instructions.fetch_add(count, Ordering::SeqCst) + count;
+        let deadline = *self.deadline.lock().expect("lock poisoned");
+        if total > self.instruction_limit {
+            Some(LimitExceeded::Instructions)
+        } else if deadline.map_or(false, |deadline| Instant::now() > deadline) {
+            Some(LimitExceeded::Timeout)
+        } else {
+            None
+        }
+    }
+
+    /// Returns true if the allocation is allowed. `osize` is the size of the block being
+    /// reallocated or freed, or 0 for a fresh allocation.
+    fn on_realloc(&self, osize: usize, nsize: usize) -> bool {
+        if nsize <= osize {
+            self.memory.fetch_sub(osize - nsize, Ordering::SeqCst);
+            return true;
+        }
+        let grow = nsize - osize;
+        let used = self.memory.fetch_add(grow, Ordering::SeqCst) + grow;
+        if used > self.memory_limit && self.enforcing.load(Ordering::SeqCst) {
+            self.memory.fetch_sub(grow, Ordering::SeqCst);
+            self.set_exceeded(LimitExceeded::Memory);
+            false
+        } else {
+            true
+        }
+    }
+}
+
+/// Polls the coroutine of a hook with the memory limit of its sandbox enforced, `lua_resume`
+/// runs the hook in protected mode
+struct SandboxedFuture<F> {
+    inner: F,
+    sandbox: Arc<Sandbox>,
+}
+
+impl<F: Future> Future for SandboxedFuture<F> {
+    type Item = F::Item;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        self.sandbox.start();
+        let inner = &mut self.inner;
+        self.sandbox.enforced(|| inner.poll())
+    }
+}
+
+unsafe fn sandbox_from_state<'a>(lua: *mut ffi::lua_State) -> &'a Sandbox {
+    let mut ud: *mut c_void = ptr::null_mut();
+    ffi::lua_getallocf(lua, &mut ud);
+    &*(ud as *const Sandbox)
+}
+
+extern "C" fn sandbox_alloc(
+    ud: *mut c_void,
+    ptr: *mut c_void,
+    osize: size_t,
+    nsize: size_t,
+) -> *mut c_void {
+    let sandbox = unsafe { &*(ud as *const Sandbox) };
+    // When ptr is null Lua passes the type of the object being created in osize
+    let osize = if ptr.is_null() { 0 } else { osize as usize };
+    if !sandbox.on_realloc(osize, nsize as usize) {
+        return ptr::null_mut();
+    }
+    unsafe {
+        if nsize == 0 {
+            libc::free(ptr);
+            ptr::null_mut()
+        } else {
+            libc::realloc(ptr, nsize)
+        }
+    }
+}
+
+extern "C" fn sandbox_count_hook(lua: *mut ffi::lua_State, _ar: *mut ffi::lua_Debug) {
+    let sandbox = unsafe { sandbox_from_state(lua) };
+    if let Some(limit) = sandbox.on_instructions(INSTRUCTION_HOOK_INTERVAL as usize) {
+        sandbox.set_exceeded(limit);
+        // lua_error longjmps out of this function, so nothing with a destructor may be alive
+        unsafe {
+            ffi::lua_pushstring(lua, b"hook exceeded its resource limits\0".as_ptr() as *const _);
+            ffi::lua_error(lua);
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
     use super::super::{HookChangeset, HookChangesetParents};
     use async_unit;
     use futures::Future;
+    use std::thread;
 
     #[test]
     fn test_cs_hook_simple_rejected() {
@@ -597,6 +981,287 @@ mod test {
         });
     }
 
+    #[test]
+    fn test_cs_hook_no_io_lib() {
+        async_unit::tokio_unit_test(|| {
+            let changeset = default_changeset();
+            let code = String::from(
+                "hook = function (ctx)\n\
+                 return io == nil and os == nil and package == nil and dofile == nil\n\
+                 end",
+            );
+            assert_matches!(
+                run_changeset_hook(code, changeset),
+                Ok(HookExecution::Accepted)
+            );
+        });
+    }
+
+    #[test]
+    fn test_check_syntax() {
+        let hook = LuaHook::new(
+            String::from("testhook"),
+            String::from("hook = function (ctx) return true end"),
+        );
+        assert!(hook.check_syntax().is_ok());
+        let hook = LuaHook::new(
+            String::from("testhook"),
+            String::from("hook = function (ctx) return true"),
+        );
+        assert_matches!(
+            hook.check_syntax()
+                .unwrap_err()
+                .downcast::<ErrorKind>(),
+            Ok(ErrorKind::HookParseError(ref err)) if err.contains("testhook")
+        );
+    }
+
+    #[test]
+    fn test_cs_hook_require_lib() {
+        async_unit::tokio_unit_test(|| {
+            let changeset = default_changeset();
+            let code = String::from(
+                "local utils = require(\"utils\")\n\
+                 hook = function (ctx)\n\
+                 return utils.is_author(ctx, \"some-author\") and require(\"utils\") == utils\n\
+                 end",
+            );
+            let mut hook = LuaHook::new(String::from("testhook"), code);
+            hook.libs = Arc::new(hashmap! {
+                "utils".to_string() => String::from(
+                    "local M = {}\n\
+                     M.is_author = function (ctx, author)\n\
+                     return ctx.info.author == author\n\
+                     end\n\
+                     return M",
+                ),
+            });
+            let context = HookContext::new(hook.name.clone(), "some-repo".into(), changeset);
+            assert_matches!(hook.run(context).wait(), Ok(HookExecution::Accepted));
+        });
+    }
+
+    #[test]
+    fn test_cs_hook_require_missing_lib() {
+        async_unit::tokio_unit_test(|| {
+            let changeset = default_changeset();
+            let code = String::from(
+                "hook = function (ctx)\n\
+                 return require(\"nope\")\n\
+                 end",
+            );
+            assert_matches!(
+                run_changeset_hook(code, changeset).unwrap_err().downcast::<ErrorKind>(),
+                Ok(ErrorKind::HookRuntimeError(ref err_msg))
+                    if err_msg.contains("module 'nope' not found")
+            );
+        });
+    }
+
+    #[test]
+    fn test_cs_hook_instruction_limit() {
+        async_unit::tokio_unit_test(|| {
+            let changeset = default_changeset();
+            let code = String::from(
+                "hook = function (ctx)\n\
+                 while true do end\n\
+                 end",
+            );
+            let limits = LuaHookLimits {
+                instruction_limit: 100_000,
+                ..LuaHookLimits::default()
+            };
+            assert_matches!(
+                run_changeset_hook_with_limits(code, changeset, limits),
+                Ok(HookExecution::InstructionLimitExceeded)
+            );
+        });
+    }
+
+    #[test]
+    fn test_cs_hook_memory_limit() {
+        async_unit::tokio_unit_test(|| {
+            let changeset = default_changeset();
+            let code = String::from(
+                "hook = function (ctx)\n\
+                 local t = {}\n\
+                 for i = 1, 10000000 do t[i] = string.rep(\"x\", 100) .. i end\n\
+                 return true\n\
+                 end",
+            );
+            let limits = LuaHookLimits {
+                memory_limit: 1024 * 1024,
+                ..LuaHookLimits::default()
+            };
+            assert_matches!(
+                run_changeset_hook_with_limits(code, changeset, limits),
+                Ok(HookExecution::MemoryLimitExceeded)
+            );
+        });
+    }
+
+    #[test]
+    fn test_cs_hook_timeout() {
+        async_unit::tokio_unit_test(|| {
+            let changeset = default_changeset();
+            let code = String::from(
+                "hook = function (ctx)\n\
+                 while true do end\n\
+                 end",
+            );
+            let limits = LuaHookLimits {
+                instruction_limit: usize::max_value(),
+                timeout: Duration::from_millis(100),
+                ..LuaHookLimits::default()
+            };
+            assert_matches!(
+                run_changeset_hook_with_limits(code, changeset, limits),
+                Ok(HookExecution::TimedOut)
+            );
+        });
+    }
+
+    #[test]
+    fn test_cs_hook_memory_limit_arguments() {
+        async_unit::tokio_unit_test(|| {
+            // The arguments alone exceed the limit. Pushing them must not abort the process.
+            let files = (0..20000).map(|i| format!("{:0>50}", i)).collect();
+            let changeset = HookChangeset::new(
+                "some-author".into(),
+                files,
+                "some-comments".into(),
+                HookChangesetParents::None,
+            );
+            let code = String::from(
+                "hook = function (ctx)\n\
+                 return #ctx.files > 0\n\
+                 end",
+            );
+            let limits = LuaHookLimits {
+                memory_limit: 256 * 1024,
+                ..LuaHookLimits::default()
+            };
+            assert_matches!(
+                run_changeset_hook_with_limits(code, changeset, limits),
+                Ok(HookExecution::MemoryLimitExceeded)
+            );
+        });
+    }
+
+    #[test]
+    fn test_cs_hook_timeout_starts_at_first_poll() {
+        async_unit::tokio_unit_test(|| {
+            let changeset = default_changeset();
+            let code = String::from(
+                "hook = function (ctx)\n\
+                 return true\n\
+                 end",
+            );
+            let limits = LuaHookLimits {
+                timeout: Duration::from_millis(100),
+                ..LuaHookLimits::default()
+            };
+            let hook = LuaHook::with_limits(String::from("testhook"), code, limits);
+            let context = HookContext::new(hook.name.clone(), "some-repo".into(), changeset);
+            let execution = hook.run(context);
+            thread::sleep(Duration::from_millis(200));
+            assert_matches!(execution.wait(), Ok(HookExecution::Accepted));
+        });
+    }
+
+    #[test]
+    fn test_file_hook_instruction_limit() {
+        async_unit::tokio_unit_test(|| {
+            let hook_file = default_hook_file();
+            let code = String::from(
+                "hook = function (ctx)\n\
+                 while true do end\n\
+                 end",
+            );
+            let hook = LuaHook::with_limits(
+                String::from("testhook"),
+                code,
+                LuaHookLimits {
+                    instruction_limit: 100_000,
+                    ..LuaHookLimits::default()
+                },
+            );
+            let context = HookContext::new(hook.name.clone(), "some-repo".into(), hook_file);
+            assert_matches!(
+                hook.run(context).wait(),
+                Ok(HookExecution::InstructionLimitExceeded)
+            );
+        });
+    }
+
+    fn run_changeset_hook_with_limits(
+        code: String,
+        changeset: HookChangeset,
+        limits: LuaHookLimits,
+    ) -> Result<HookExecution, Error> {
+        let hook = LuaHook::with_limits(String::from("testhook"), code, limits);
+        let context = HookContext::new(hook.name.clone(), "some-repo".into(), changeset);
+        hook.run(context).wait()
+    }
+
+    #[test]
+    fn test_bookmark_move_hook_create_accepted() {
+        async_unit::tokio_unit_test(|| {
+            let code = String::from(
+                "hook = function (ctx)\n\
+                 return ctx.bookmark.name == \"release/1\" and ctx.bookmark.old == nil and\n\
+                 ctx.bookmark.new == \"new-hash\" and ctx.bookmark.pusher == \"some-user\" and\n\
+                 ctx.info.repo_name == \"some-repo\"\n\
+                 end",
+            );
+            let bookmark_move = HookBookmarkMove::new(
+                "release/1".into(),
+                None,
+                Some("new-hash".into()),
+                Some("some-user".into()),
+            );
+            assert_matches!(
+                run_bookmark_move_hook(code, bookmark_move),
+                Ok(HookExecution::Accepted)
+            );
+        });
+    }
+
+    #[test]
+    fn test_bookmark_move_hook_move_rejected() {
+        async_unit::tokio_unit_test(|| {
+            let code = String::from(
+                "hook = function (ctx)\n\
+                 if string.sub(ctx.bookmark.name, 1, 8) == \"release/\" and\n\
+                 ctx.bookmark.old ~= nil then\n\
+                 return false, \"release bookmarks can't be moved\"\n\
+                 end\n\
+                 return true\n\
+                 end",
+            );
+            let bookmark_move = HookBookmarkMove::new(
+                "release/1".into(),
+                Some("old-hash".into()),
+                None,
+                None,
+            );
+            assert_matches!(
+                run_bookmark_move_hook(code, bookmark_move),
+                Ok(HookExecution::Rejected(HookRejectionInfo{ref description, ..}))
+                    if description==&"release bookmarks can't be moved"
+            );
+        });
+    }
+
+    fn run_bookmark_move_hook(
+        code: String,
+        bookmark_move: HookBookmarkMove,
+    ) -> Result<HookExecution, Error> {
+        let hook = LuaHook::new(String::from("testhook"), code.to_string());
+        let context = HookContext::new(hook.name.clone(), "some-repo".into(), bookmark_move);
+        hook.run(context).wait()
+    }
+
     fn run_changeset_hook(code: String, changeset: HookChangeset) -> Result<HookExecution, Error> {
         let hook = LuaHook::new(String::from("testhook"), code.to_string());
         let context = HookContext::new(hook.name.clone(), "some-repo".into(), changeset);
@@ -95,8 +95,13 @@ where
         self
     }
 
+    /// Bundle2 has no gzip compression: "GZ" bundles are compressed with zlib, without gzip
+    /// headers. Gzip is never encoded, it is replaced with zlib at the same level.
     pub fn set_compressor_type<C: Into<Option<CompressorType>>>(&mut self, ct: C) -> &mut Self {
-        self.compressor_type = ct.into();
+        self.compressor_type = ct.into().map(|ct| match ct {
+            CompressorType::Gzip(level) => CompressorType::Zlib(level),
+            ct => ct,
+        });
         self
     }
 
@@ -9,17 +9,81 @@
 use std::borrow::Cow;
 use std::collections::HashMap;
 
+use async_compression::{CompressorType, FlateCompression};
 use bytes::BytesMut;
+use mercurial_types::ObsmarkersVersion;
 use tokio_io::codec::Decoder;
 use url::percent_encoding::percent_decode;
 
 use errors::*;
 
+/// Engines that bundle2 responses can be compressed with, in order of preference
+pub const COMPRESSION_ENGINES: &[&str] = &["zstd", "zlib", "none"];
+
+/// How to compress bundle2 responses to a client with the protocol capabilities `protocaps`, sent
+/// with the `protocaps` command over SSH or in the `X-HgProto-<N>` headers over HTTP. The client
+/// lists the engines it can decompress in `comp=`, and like Mercurial the most preferred engine
+/// of the server among them is picked. Responses to clients that sent no engines aren't
+/// compressed.
+pub fn response_compression<S: AsRef<str>>(protocaps: &[S]) -> Option<CompressorType> {
+    let prefix = "comp=";
+    let engines: Vec<_> = protocaps
+        .iter()
+        .map(AsRef::as_ref)
+        .filter(|cap| cap.starts_with(prefix))
+        .flat_map(|cap| cap[prefix.len()..].split(','))
+        .collect();
+    match COMPRESSION_ENGINES
+        .iter()
+        .find(|engine| engines.contains(engine))
+    {
+        Some(&"zstd") => Some(CompressorType::Zstd { level: 3 }),
+        Some(&"zlib") => Some(CompressorType::Zlib(FlateCompression::default())),
+        _ => None,
+    }
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub struct Capabilities {
     caps: HashMap<String, Vec<String>>,
 }
 
+impl Capabilities {
+    /// The bundle2 capabilities in the `bundlecaps` argument of getbundle, which holds them
+    /// url encoded after `bundle2=`. A client that sent none has no capabilities.
+    pub fn from_bundlecaps(bundlecaps: &[Vec<u8>]) -> Result<Self> {
+        let prefix = b"bundle2=";
+        let encoded = bundlecaps
+            .iter()
+            .find(|cap| cap.starts_with(prefix))
+            .map(|cap| &cap[prefix.len()..]);
+        match encoded {
+            Some(encoded) => {
+                let mut buf = BytesMut::from(percent_decode(encoded).collect::<Vec<_>>());
+                let caps = CapabilitiesUnpacker.decode_eof(&mut buf)?;
+                Ok(caps.expect("CapabilitiesUnpacker always decodes at EOF"))
+            }
+            None => Ok(Capabilities {
+                caps: HashMap::new(),
+            }),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&[String]> {
+        self.caps.get(key).map(|values| values.as_slice())
+    }
+
+    /// The newest version of obsolescence markers in the `obsmarkers` capability, if any of them
+    /// is supported
+    pub fn obsmarkers_version(&self) -> Option<ObsmarkersVersion> {
+        self.get("obsmarkers")
+            .unwrap_or(&[])
+            .iter()
+            .filter_map(|version| ObsmarkersVersion::from_capability(version))
+            .max()
+    }
+}
+
 /// This is a tokio_io Decoder for capabilities used f.e. in "replycaps" part of bundle2
 ///
 /// The format is as follows:
@@ -6,9 +6,46 @@
 
 use mercurial_types::{Delta, HgNodeHash, MPath};
 
+use errors::*;
+
 pub mod packer;
 pub mod unpacker;
 
+/// Changegroup formats that can be unpacked
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CgVersion {
+    Cg2Version,
+    /// Adds revlog flags to every delta. Clients push with it when some file revisions are
+    /// stored out of band (large files).
+    Cg3Version,
+}
+
+impl CgVersion {
+    /// Parse the version parameter of a changegroup part
+    pub fn from_param(version: &[u8]) -> Result<Self> {
+        match version {
+            b"02" => Ok(CgVersion::Cg2Version),
+            b"03" => Ok(CgVersion::Cg3Version),
+            _ => bail_err!(ErrorKind::Cg2Decode(format!(
+                "unsupported changegroup version {:?}",
+                String::from_utf8_lossy(version)
+            ))),
+        }
+    }
+
+    /// The version parameter of a changegroup part
+    pub fn to_param(&self) -> &'static str {
+        match self {
+            &CgVersion::Cg2Version => "02",
+            &CgVersion::Cg3Version => "03",
+        }
+    }
+}
+
+/// Revlog flag of the changesets of a shallow changegroup whose parents were not sent and were
+/// replaced with the null hash, see the ellipsis nodes of the narrow extension
+pub const REVIDX_ELLIPSIS: u16 = 1 << 14;
+
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum Section {
     Changeset,
@@ -40,13 +77,16 @@ pub struct CgDeltaChunk {
     pub base: HgNodeHash,
     pub linknode: HgNodeHash,
     pub delta: Delta,
+    /// Revlog flags of the revision, only changegroup 3 has them
+    pub flags: Option<u16>,
 }
 
 #[cfg(test)]
 mod test {
     use std::io::{self, Cursor};
 
-    use futures::{Future, Stream};
+    use bytes::{BufMut, Bytes};
+    use futures::{stream, Future, Stream};
     use quickcheck::{QuickCheck, StdGen, TestResult};
     use quickcheck::rand;
     use slog::{Drain, Logger};
@@ -57,7 +97,12 @@ mod test {
     use futures_ext::StreamLayeredExt;
     use partial_io::{GenWouldBlock, PartialAsyncRead, PartialAsyncWrite, PartialWithErrors};
 
+    use mercurial_types::NULL_HASH;
+    use mercurial_types::lfs::REVIDX_EXTSTORED;
+    use mercurial_types_mocks::nodehash::{ONES_HASH, THREES_HASH, TWOS_HASH};
+
     use chunk::{ChunkDecoder, ChunkEncoder};
+    use delta;
     use quickcheck_types::Cg2PartSequence;
 
     use super::*;
@@ -123,7 +168,7 @@ mod test {
                     .map(|chunk| chunk.into_bytes().expect("expected normal chunk"));
 
                 let logger = make_root_logger();
-                let unpacker = unpacker::Cg2Unpacker::new(logger);
+                let unpacker = unpacker::CgUnpacker::new(logger, CgVersion::Cg2Version);
                 let part_stream = chunks.decode(unpacker);
 
                 let parts = Vec::new();
@@ -145,6 +190,103 @@ mod test {
         result.unwrap()
     }
 
+    #[test]
+    fn test_unpack_cg3() {
+        let path = MPath::new("large").unwrap();
+        let chunk = CgDeltaChunk {
+            node: ONES_HASH,
+            p1: TWOS_HASH,
+            p2: NULL_HASH,
+            base: NULL_HASH,
+            linknode: THREES_HASH,
+            delta: Delta::new_fulltext(&b"pointer"[..]),
+            flags: Some(REVIDX_EXTSTORED),
+        };
+
+        let mut encoded_chunk = vec![];
+        for node in &[chunk.node, chunk.p1, chunk.p2, chunk.base, chunk.linknode] {
+            encoded_chunk.put_slice(node.as_ref());
+        }
+        encoded_chunk.put_u16_be(REVIDX_EXTSTORED);
+        delta::encode_delta(&chunk.delta, &mut encoded_chunk);
+
+        let mut data = vec![];
+        // Changesets, manifests and directory manifests are all empty
+        for _ in 0..3 {
+            data.put_i32_be(0);
+        }
+        data.put_i32_be(4 + path.to_vec().len() as i32);
+        data.put_slice(&path.to_vec());
+        data.put_i32_be(4 + encoded_chunk.len() as i32);
+        data.put_slice(&encoded_chunk);
+        data.put_i32_be(0);
+        data.put_i32_be(0);
+
+        let unpack = |version| {
+            let logger = make_root_logger();
+            stream::iter_ok::<_, Error>(vec![Bytes::from(data.clone())])
+                .decode(unpacker::CgUnpacker::new(logger, version))
+                .collect()
+                .wait()
+        };
+
+        let parts = unpack(CgVersion::Cg3Version).expect("unpacking changegroup 3 failed");
+        assert_eq!(
+            parts,
+            vec![
+                Part::SectionEnd(Section::Changeset),
+                Part::SectionEnd(Section::Manifest),
+                Part::CgChunk(Section::Filelog(path.clone()), chunk),
+                Part::SectionEnd(Section::Filelog(path)),
+                Part::End,
+            ]
+        );
+        assert!(unpack(CgVersion::Cg2Version).is_err());
+    }
+
+    #[test]
+    fn test_pack_cg3() {
+        let chunk = CgDeltaChunk {
+            node: ONES_HASH,
+            p1: NULL_HASH,
+            p2: NULL_HASH,
+            base: NULL_HASH,
+            linknode: ONES_HASH,
+            delta: Delta::new_fulltext(&b"changeset"[..]),
+            flags: Some(REVIDX_ELLIPSIS),
+        };
+        let parts = vec![
+            Part::CgChunk(Section::Changeset, chunk.clone()),
+            Part::SectionEnd(Section::Changeset),
+            Part::SectionEnd(Section::Manifest),
+            // Directory manifests
+            Part::SectionEnd(Section::Manifest),
+            Part::End,
+        ];
+
+        let data = packer::Cg2Packer::new(stream::iter_ok::<_, Error>(parts))
+            .and_then(|chunk| chunk.into_bytes())
+            .concat2()
+            .wait()
+            .expect("packing changegroup 3 failed");
+
+        let logger = make_root_logger();
+        let unpacked: Vec<_> = stream::iter_ok::<_, Error>(vec![data])
+            .decode(unpacker::CgUnpacker::new(logger, CgVersion::Cg3Version))
+            .collect()
+            .wait()
+            .expect("unpacking changegroup 3 failed");
+        assert_eq!(
+            unpacked,
+            vec![
+                Part::CgChunk(Section::Changeset, chunk),
+                Part::SectionEnd(Section::Changeset),
+                Part::SectionEnd(Section::Manifest),
+                Part::End,
+            ]
+        );
+    }
+
     fn make_root_logger() -> Logger {
         let plain = slog_term::PlainSyncDecorator::new(io::stdout());
         Logger::root(slog_term::FullFormat::new(plain).build().fuse(), o!())
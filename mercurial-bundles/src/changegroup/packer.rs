@@ -111,6 +111,10 @@ impl ChunkBuilder {
         self.inner.put_slice(chunk.p2.as_ref());
         self.inner.put_slice(chunk.base.as_ref());
         self.inner.put_slice(chunk.linknode.as_ref());
+        // Only changegroup 3 chunks have flags
+        if let Some(flags) = chunk.flags {
+            self.inner.put_u16_be(flags);
+        }
 
         delta::encode_delta(&chunk.delta, &mut self.inner);
 
@@ -20,11 +20,12 @@ use delta;
 use errors::*;
 use utils::BytesExt;
 
-use super::{CgDeltaChunk, Part, Section};
+use super::{CgDeltaChunk, CgVersion, Part, Section};
 
 #[derive(Debug)]
-pub struct Cg2Unpacker {
+pub struct CgUnpacker {
     logger: slog::Logger,
+    version: CgVersion,
     state: State,
 }
 
@@ -59,12 +60,15 @@ impl Part {
 // for the length field itself.
 const CHUNK_HEADER_LEN: usize = 20 + 20 + 20 + 20 + 20 + 4;
 
-impl Decoder for Cg2Unpacker {
+// Changegroup 3 chunk headers end with 2 bytes of revlog flags.
+const CHUNK_HEADER3_LEN: usize = CHUNK_HEADER_LEN + 2;
+
+impl Decoder for CgUnpacker {
     type Item = Part;
     type Error = Error;
 
     fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>> {
-        match Self::decode_next(buf, self.state.take()) {
+        match Self::decode_next(buf, self.state.take(), self.version) {
             Err(e) => {
                 self.state = State::Invalid;
                 Err(e)
@@ -106,17 +110,22 @@ impl Decoder for Cg2Unpacker {
     }
 }
 
-impl Cg2Unpacker {
-    pub fn new(logger: slog::Logger) -> Self {
-        Cg2Unpacker {
+impl CgUnpacker {
+    pub fn new(logger: slog::Logger, version: CgVersion) -> Self {
+        CgUnpacker {
             logger: logger,
+            version: version,
             state: State::Changeset,
         }
     }
 
-    fn decode_next(buf: &mut BytesMut, state: State) -> Result<(Option<Part>, State)> {
+    fn decode_next(
+        buf: &mut BytesMut,
+        state: State,
+        version: CgVersion,
+    ) -> Result<(Option<Part>, State)> {
         match state {
-            State::Changeset => match Self::decode_chunk(buf)? {
+            State::Changeset => match Self::decode_chunk(buf, version)? {
                 None => Ok((None, State::Changeset)),
                 Some(CgChunk::Empty) => {
                     Ok((Some(Part::SectionEnd(Section::Changeset)), State::Manifest))
@@ -126,10 +135,14 @@ impl Cg2Unpacker {
                     State::Changeset,
                 )),
             },
-            State::Manifest => match Self::decode_chunk(buf)? {
+            State::Manifest => match Self::decode_chunk(buf, version)? {
                 None => Ok((None, State::Manifest)),
                 Some(CgChunk::Empty) => {
-                    Ok((Some(Part::SectionEnd(Section::Manifest)), State::Filename))
+                    let next = match version {
+                        CgVersion::Cg2Version => State::Filename,
+                        CgVersion::Cg3Version => State::TreeManifests,
+                    };
+                    Ok((Some(Part::SectionEnd(Section::Manifest)), next))
                 }
                 Some(CgChunk::Delta(chunk)) => Ok((
                     Some(Part::CgChunk(Section::Manifest, chunk)),
@@ -140,18 +153,32 @@ impl Cg2Unpacker {
                 let filename = Self::decode_filename(buf)?;
                 match filename {
                     DecodeRes::None => Ok((None, State::Filename)),
-                    DecodeRes::Some(f) => Self::decode_filelog_chunk(buf, f),
+                    DecodeRes::Some(f) => Self::decode_filelog_chunk(buf, f, version),
                     DecodeRes::End => Ok((Some(Part::End), State::End)),
                 }
             }
-            State::Filelog(filename) => Self::decode_filelog_chunk(buf, filename),
+            // Changegroup 3 has a section of directory manifests between the root manifest and
+            // the filelogs. Clients send trees in separate parts, so it is always empty.
+            State::TreeManifests => match Self::decode_filename(buf)? {
+                DecodeRes::None => Ok((None, State::TreeManifests)),
+                DecodeRes::Some(dir) => {
+                    let msg = format!("unexpected directory manifest {} in changegroup", dir);
+                    bail_err!(ErrorKind::Cg2Decode(msg));
+                }
+                DecodeRes::End => Self::decode_next(buf, State::Filename, version),
+            },
+            State::Filelog(filename) => Self::decode_filelog_chunk(buf, filename, version),
             State::End => Ok((None, State::End)),
             State::Invalid => Err(ErrorKind::Cg2Decode("byte stream corrupt".into()).into()),
         }
     }
 
-    fn decode_filelog_chunk(buf: &mut BytesMut, f: MPath) -> Result<(Option<Part>, State)> {
-        match Self::decode_chunk(buf)? {
+    fn decode_filelog_chunk(
+        buf: &mut BytesMut,
+        f: MPath,
+        version: CgVersion,
+    ) -> Result<(Option<Part>, State)> {
+        match Self::decode_chunk(buf, version)? {
             None => Ok((None, State::Filelog(f))),
             Some(CgChunk::Empty) => {
                 Ok((Some(Part::SectionEnd(Section::Filelog(f))), State::Filename))
@@ -163,7 +190,7 @@ impl Cg2Unpacker {
         }
     }
 
-    fn decode_chunk(buf: &mut BytesMut) -> Result<Option<CgChunk>> {
+    fn decode_chunk(buf: &mut BytesMut, version: CgVersion) -> Result<Option<CgChunk>> {
         if buf.len() < 4 {
             return Ok(None);
         }
@@ -176,10 +203,14 @@ impl Cg2Unpacker {
             let _ = buf.drain_i32();
             return Ok(Some(CgChunk::Empty));
         }
-        if chunk_len < CHUNK_HEADER_LEN {
+        let header_len = match version {
+            CgVersion::Cg2Version => CHUNK_HEADER_LEN,
+            CgVersion::Cg3Version => CHUNK_HEADER3_LEN,
+        };
+        if chunk_len < header_len {
             let msg = format!(
                 "invalid chunk: length >= {} required, found {}",
-                CHUNK_HEADER_LEN, chunk_len
+                header_len, chunk_len
             );
             bail_err!(ErrorKind::Cg2Decode(msg));
         }
@@ -196,6 +227,7 @@ impl Cg2Unpacker {
         // p2: HgNodeHash (20 bytes) -- NULL_HASH if only 1 parent
         // base node: HgNodeHash (20 bytes) (new in changegroup2)
         // link node: HgNodeHash (20 bytes)
+        // flags: u16 (2 bytes) (new in changegroup3)
         // ---
 
         let node = buf.drain_node();
@@ -203,8 +235,12 @@ impl Cg2Unpacker {
         let p2 = buf.drain_node();
         let base = buf.drain_node();
         let linknode = buf.drain_node();
+        let flags = match version {
+            CgVersion::Cg2Version => None,
+            CgVersion::Cg3Version => Some(buf.drain_u16()),
+        };
 
-        let delta = delta::decode_delta(buf.split_to(chunk_len - CHUNK_HEADER_LEN))?;
+        let delta = delta::decode_delta(buf.split_to(chunk_len - header_len))?;
         return Ok(Some(CgChunk::Delta(CgDeltaChunk {
             node: node,
             p1: p1,
@@ -212,6 +248,7 @@ impl Cg2Unpacker {
             base: base,
             linknode: linknode,
             delta: delta,
+            flags: flags,
         })));
     }
 
@@ -255,6 +292,7 @@ enum State {
     Changeset,
     Manifest,
     Filename,
+    TreeManifests,
     Filelog(MPath),
     End,
     Invalid,
@@ -9,7 +9,8 @@
 use bytes::{BufMut, BytesMut};
 
 use bytes_ext::SizeCounter;
-use mercurial_types::delta::{Delta, Fragment};
+use mercurial_types::{HgNodeHash, NULL_HASH};
+use mercurial_types::delta::{self, Delta, Fragment};
 
 use errors::*;
 use utils::BytesExt;
@@ -81,11 +82,32 @@ pub fn encode_delta<B: BufMut>(delta: &Delta, out: &mut B) {
     }
 }
 
+/// Pick how a revision is sent: as a delta against `base`, a revision the client has, if the
+/// delta is at most `threshold` percent of the size of the fulltext, and as a fulltext otherwise.
+/// Returns the delta base, which is `NULL_HASH` for fulltexts, and the delta.
+pub fn select_delta(
+    text: &[u8],
+    base: Option<(HgNodeHash, &[u8])>,
+    threshold: usize,
+) -> (HgNodeHash, Delta) {
+    if let Some((base_node, base_text)) = base {
+        if threshold > 0 {
+            let delta = delta::diff(base_text, text);
+            if encoded_len(&delta) * 100 <= text.len() * threshold {
+                return (base_node, delta);
+            }
+        }
+    }
+    (NULL_HASH, Delta::new_fulltext(text.to_vec()))
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
     use failure;
 
+    use mercurial_types_mocks::nodehash::AS_HASH;
+
     #[test]
     fn invalid_deltas() {
         let short_delta = BytesMut::from(&b"\0\0\0\0\0\0\0\0\0\0\0\x20"[..]);
@@ -116,6 +138,32 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_select_delta() {
+        let base: Vec<u8> = (0..100)
+            .flat_map(|n| format!("line {}\n", n).into_bytes())
+            .collect();
+        let mut text = base.clone();
+        text.extend_from_slice(b"one more line\n");
+
+        let (delta_base, delta) = select_delta(&text, Some((AS_HASH, &base)), 50);
+        assert_eq!(delta_base, AS_HASH);
+        assert_eq!(delta::apply(&base, &delta).unwrap(), text);
+
+        // No base, a base that's too different, and deltas turned off all give fulltexts
+        let other = b"something else\n";
+        let cases = vec![
+            (None, 50),
+            (Some((AS_HASH, &other[..])), 50),
+            (Some((AS_HASH, &base[..])), 0),
+        ];
+        for (base, threshold) in cases {
+            let (delta_base, delta) = select_delta(&text, base, threshold);
+            assert_eq!(delta_base, NULL_HASH);
+            assert_eq!(delta.maybe_fulltext(), Some(&text[..]));
+        }
+    }
+
     quickcheck! {
         fn roundtrip(delta: Delta) -> bool {
             let mut out = vec![];
@@ -23,6 +23,9 @@ pub enum ErrorKind {
     #[fail(display = "unknown params for bundle2 part '{:?}': {:?}", _0, _1)]
     BundleUnknownPartParams(PartHeaderType, Vec<String>),
     #[fail(display = "error while generating listkey part")] ListkeyGeneration,
+    #[fail(display = "error while generating branchmap part")] BranchmapGeneration,
+    #[fail(display = "error while generating phase-heads part")] PhaseHeadsGeneration,
+    #[fail(display = "error while generating obsmarkers part")] ObsmarkersGeneration,
 }
 
 impl ErrorKind {
@@ -57,11 +57,13 @@ pub mod infinitepush;
 mod capabilities;
 mod chunk;
 mod delta;
+mod obsmarkers;
 pub mod parts;
 pub mod part_encode;
 mod part_header;
 mod part_inner;
 mod part_outer;
+mod phases;
 mod pushrebase;
 mod quickcheck_types;
 mod stream_start;
@@ -85,6 +87,8 @@ use std::fmt;
 use futures_ext::{BoxFuture, BoxStream};
 
 pub use bundle2_encode::Bundle2EncodeBuilder;
+pub use capabilities::{response_compression, Capabilities, COMPRESSION_ENGINES};
+pub use delta::select_delta;
 pub use part_header::{PartHeader, PartHeaderType};
 pub use types::StreamHeader;
 
@@ -98,6 +102,8 @@ pub enum Bundle2Item {
     B2xInfinitepushBookmarks(PartHeader, BoxStream<bytes::Bytes, Error>),
     Replycaps(PartHeader, BoxFuture<capabilities::Capabilities, Error>),
     Pushkey(PartHeader, BoxFuture<(), Error>),
+    PhaseHeads(PartHeader, BoxStream<(u32, mercurial_types::HgChangesetId), Error>),
+    Obsmarkers(PartHeader, BoxStream<mercurial_types::HgObsmarker, Error>),
 }
 
 impl Bundle2Item {
@@ -139,6 +145,8 @@ impl fmt::Debug for Bundle2Item {
             }
             &Replycaps(ref header, _) => write!(f, "Bundle2Item::Replycaps({:?}, ...)", header),
             &Pushkey(ref header, _) => write!(f, "Bundle2Item::Pushkey({:?}, ...)", header),
+            &PhaseHeads(ref header, _) => write!(f, "Bundle2Item::PhaseHeads({:?}, ...)", header),
+            &Obsmarkers(ref header, _) => write!(f, "Bundle2Item::Obsmarkers({:?}, ...)", header),
         }
     }
 }
@@ -0,0 +1,52 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+// Obsmarkers part codecs
+
+use bytes::BytesMut;
+use mercurial_types::{HgObsmarker, ObsmarkersVersion};
+use tokio_codec::Decoder;
+
+use errors::*;
+
+/// Decodes the markers of an obsmarkers part, which is a version byte followed by the markers
+#[derive(Debug)]
+pub struct ObsmarkersUnpacker {
+    version: Option<ObsmarkersVersion>,
+}
+
+impl ObsmarkersUnpacker {
+    pub fn new() -> Self {
+        Self { version: None }
+    }
+}
+
+impl Decoder for ObsmarkersUnpacker {
+    type Item = HgObsmarker;
+    type Error = Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>> {
+        let version = match self.version {
+            Some(version) => version,
+            None => {
+                if buf.is_empty() {
+                    return Ok(None);
+                }
+                let version = ObsmarkersVersion::from_byte(buf.split_to(1)[0])?;
+                self.version = Some(version);
+                version
+            }
+        };
+
+        match version.marker_size(buf) {
+            Some(size) if buf.len() >= size => {
+                let marker = buf.split_to(size).freeze();
+                Ok(Some(HgObsmarker::decode(version, &marker)?))
+            }
+            _ => Ok(None),
+        }
+    }
+}
@@ -47,6 +47,18 @@ pub enum PartHeaderType {
     Pushkey,
     /// Respond to a corresponding pushkey part
     ReplyPushkey,
+    /// The heads of every named branch, in the same format as the response of the `branchmap`
+    /// wire command.
+    Branchmap,
+    /// The heads of the public and draft changesets, as pairs of the number of the phase and the
+    /// changeset id.
+    PhaseHeads,
+    /// Obsolescence markers, as a version byte followed by the markers in that version
+    Obsmarkers,
+    /// Respond to a corresponding obsmarkers part with the number of markers that were new
+    ReplyObsmarkers,
+    /// Store files of a streaming clone, which the client copies into its store as they are
+    Stream2,
     // RemoteChangegroup,       // We don't wish to support this functionality
     // CheckBookmarks,          // TODO Do we want to support this?
     // CheckHeads,              // TODO Do we want to support this?
@@ -59,10 +71,7 @@ pub enum PartHeaderType {
     // ErrorPushRaced,          // TODO Do we want to support this?
     // Pushkey,                 // TODO Do we want to support this?
     // Bookmarks,               // TODO Do we want to support this?
-    // PhaseHeads,              // TODO Do we want to support this?
     // ReplyPushkey,            // TODO Do we want to support this?
-    // Obsmarkers,              // TODO Do we want to support this?
-    // ReplyObsmarkers,         // TODO Do we want to support this?
     // HgtagsFnodes,            // TODO Do we want to support this?
     // Pushvars,                // TODO Do we want to support this?
 }
@@ -82,6 +91,11 @@ impl PartHeaderType {
             "check:heads" => Ok(CheckHeads),
             "pushkey" => Ok(Pushkey),
             "reply:pushkey" => Ok(ReplyPushkey),
+            "branchmap" => Ok(Branchmap),
+            "phase-heads" => Ok(PhaseHeads),
+            "obsmarkers" => Ok(Obsmarkers),
+            "reply:obsmarkers" => Ok(ReplyObsmarkers),
+            "stream2" => Ok(Stream2),
             bad => bail_msg!("unknown header type {}", bad),
         }
     }
@@ -100,6 +114,11 @@ impl PartHeaderType {
             CheckHeads => "check:heads",
             Pushkey => "pushkey",
             ReplyPushkey => "reply:pushkey",
+            Branchmap => "branchmap",
+            PhaseHeads => "phase-heads",
+            Obsmarkers => "obsmarkers",
+            ReplyObsmarkers => "reply:obsmarkers",
+            Stream2 => "stream2",
         }
     }
 }
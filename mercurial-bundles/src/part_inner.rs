@@ -14,8 +14,8 @@ use std::str;
 use slog;
 
 use bytes::{Bytes, BytesMut};
-use futures::{future, Future, Stream};
-use futures_ext::{BoxFuture, FutureExt, StreamWrapper};
+use futures::{future, stream, Future, Stream};
+use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamWrapper};
 use tokio_io::AsyncRead;
 use tokio_io::codec::Decoder;
 
@@ -27,6 +27,8 @@ use futures_ext::{StreamExt, StreamLayeredExt};
 use infinitepush;
 use part_header::{PartHeader, PartHeaderType};
 use part_outer::{OuterFrame, OuterStream};
+use obsmarkers;
+use phases;
 use pushrebase;
 use wirepack;
 
@@ -46,6 +48,8 @@ lazy_static! {
         m.insert(PartHeaderType::B2xTreegroup2, hashset!{"version", "cache", "category"});
         m.insert(PartHeaderType::Replycaps, hashset!{});
         m.insert(PartHeaderType::Pushkey, hashset!{ "namespace", "key", "old", "new" });
+        m.insert(PartHeaderType::PhaseHeads, hashset!{});
+        m.insert(PartHeaderType::Obsmarkers, hashset!{});
         m
     };
 }
@@ -90,20 +94,16 @@ pub fn inner_stream<R: AsyncRead + BufRead + 'static + Send>(
 
     let bundle2item = match header.part_type() {
         &PartHeaderType::Changegroup => {
-            let cg2_stream = wrapped_stream.decode(changegroup::unpacker::Cg2Unpacker::new(
-                logger.new(o!("stream" => "cg2")),
-            ));
-            Bundle2Item::Changegroup(header, Box::new(cg2_stream))
+            let cg_stream = changegroup_stream(&header, "version", wrapped_stream, logger);
+            Bundle2Item::Changegroup(header, cg_stream)
         }
         &PartHeaderType::B2xCommonHeads => {
             let heads_stream = wrapped_stream.decode(pushrebase::CommonHeadsUnpacker::new());
             Bundle2Item::B2xCommonHeads(header, Box::new(heads_stream))
         }
         &PartHeaderType::B2xInfinitepush => {
-            let cg2_stream = wrapped_stream.decode(changegroup::unpacker::Cg2Unpacker::new(
-                logger.new(o!("stream" => "cg2")),
-            ));
-            Bundle2Item::B2xInfinitepush(header, Box::new(cg2_stream))
+            let cg_stream = changegroup_stream(&header, "cgversion", wrapped_stream, logger);
+            Bundle2Item::B2xInfinitepush(header, cg_stream)
         }
         &PartHeaderType::B2xInfinitepushBookmarks => {
             let bookmarks_stream =
@@ -135,6 +135,14 @@ pub fn inner_stream<R: AsyncRead + BufRead + 'static + Send>(
             let empty = wrapped_stream.decode(EmptyUnpacker).for_each(|_| Ok(()));
             Bundle2Item::Pushkey(header, Box::new(empty))
         }
+        &PartHeaderType::PhaseHeads => {
+            let heads_stream = wrapped_stream.decode(phases::PhaseHeadsUnpacker::new());
+            Bundle2Item::PhaseHeads(header, Box::new(heads_stream))
+        }
+        &PartHeaderType::Obsmarkers => {
+            let markers_stream = wrapped_stream.decode(obsmarkers::ObsmarkersUnpacker::new());
+            Bundle2Item::Obsmarkers(header, Box::new(markers_stream))
+        }
         _ => panic!("TODO: make this an error"),
     };
 
@@ -147,6 +155,36 @@ pub fn inner_stream<R: AsyncRead + BufRead + 'static + Send>(
     )
 }
 
+/// Unpack a changegroup in the version given by the `version_param` parameter of the part.
+/// Parts without it are assumed to be changegroup 2, which is what clients send by default.
+fn changegroup_stream<S>(
+    header: &PartHeader,
+    version_param: &str,
+    payload: S,
+    logger: &slog::Logger,
+) -> BoxStream<changegroup::Part, Error>
+where
+    S: Stream<Item = Bytes, Error = Error> + Send + 'static,
+{
+    let version = header
+        .mparams()
+        .get(version_param)
+        .or_else(|| header.aparams().get(version_param));
+    let version = match version {
+        Some(version) => changegroup::CgVersion::from_param(version),
+        None => Ok(changegroup::CgVersion::Cg2Version),
+    };
+    match version {
+        Ok(version) => payload
+            .decode(changegroup::unpacker::CgUnpacker::new(
+                logger.new(o!("stream" => "changegroup")),
+                version,
+            ))
+            .boxify(),
+        Err(err) => stream::once(Err(err)).boxify(),
+    }
+}
+
 // Decoder for an empty part (for example, pushkey)
 pub struct EmptyUnpacker;
 
@@ -4,6 +4,8 @@
 // This software may be used and distributed according to the terms of the
 // GNU General Public License version 2 or any later version.
 
+use std::cmp;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
 
 use bytes::Bytes;
@@ -11,15 +13,20 @@ use futures::{Future, Stream};
 use futures::stream::{iter_ok, once};
 use futures_ext::BoxFuture;
 
-use super::changegroup::{CgDeltaChunk, Part, Section};
+use super::changegroup::{CgDeltaChunk, CgVersion, Part, Section, REVIDX_ELLIPSIS};
 use super::changegroup::packer::Cg2Packer;
+use super::chunk::Chunk;
+use super::delta::select_delta;
 use super::wirepack;
 use super::wirepack::packer::WirePackPacker;
 
 use errors::*;
-use mercurial_types::{Delta, HgBlobNode, HgNodeHash, MPath, MPathElement, RepoPath, NULL_HASH};
+use mercurial_types::{percent_encode, HgBlobNode, HgChangesetId, HgNodeHash, HgObsmarker, MPath,
+                      MPathElement, ObsmarkersVersion, RepoPath, NULL_HASH};
+use mercurial_types::obsmarker::encode_markers;
 use part_encode::PartEncodeBuilder;
 use part_header::PartHeaderType;
+use phases::encode_phase_heads;
 
 pub fn listkey_part<N, S, K, V>(namespace: N, items: S) -> Result<PartEncodeBuilder>
 where
@@ -47,18 +54,148 @@ where
     Ok(builder)
 }
 
-pub fn changegroup_part<S>(changelogentries: S) -> Result<PartEncodeBuilder>
+/// Advisory part with the heads of every named branch. Clients that know it don't have to ask for
+/// the branchmap separately.
+pub fn branchmap_part<F>(branchmap: F) -> Result<PartEncodeBuilder>
+where
+    F: Future<Item = HashMap<String, HashSet<HgNodeHash>>, Error = Error> + Send + 'static,
+{
+    let mut builder = PartEncodeBuilder::advisory(PartHeaderType::Branchmap)?;
+    let fut = branchmap
+        .map(|branchmap| encode_branchmap(&branchmap))
+        .map_err(|err| Error::from(err.context(ErrorKind::BranchmapGeneration)));
+
+    builder.set_data_future(fut);
+
+    Ok(builder)
+}
+
+/// Encode a branchmap the way Mercurial does: one line per branch, with the url-quoted name of the
+/// branch followed by the hex hashes of its heads, all separated by spaces.
+pub fn encode_branchmap(branchmap: &HashMap<String, HashSet<HgNodeHash>>) -> Bytes {
+    let mut branches: Vec<_> = branchmap.iter().collect();
+    branches.sort_by(|a, b| a.0.cmp(b.0));
+
+    let lines: Vec<_> = branches
+        .into_iter()
+        .map(|(branch, heads)| {
+            let mut heads: Vec<_> = heads.iter().collect();
+            heads.sort();
+            let mut line = percent_encode(branch);
+            for head in heads {
+                line.push(' ');
+                line.push_str(head.to_hex().as_str());
+            }
+            line
+        })
+        .collect();
+    Bytes::from(lines.join("\n"))
+}
+
+/// Advisory part with the heads of the changesets of each phase, as (phase, head) pairs where
+/// the phase is 0 for public and 1 for draft.
+pub fn phase_heads_part<F>(phase_heads: F) -> Result<PartEncodeBuilder>
+where
+    F: Future<Item = Vec<(u32, HgChangesetId)>, Error = Error> + Send + 'static,
+{
+    let mut builder = PartEncodeBuilder::advisory(PartHeaderType::PhaseHeads)?;
+    let fut = phase_heads
+        .map(|phase_heads| encode_phase_heads(&phase_heads))
+        .map_err(|err| Error::from(err.context(ErrorKind::PhaseHeadsGeneration)));
+
+    builder.set_data_future(fut);
+
+    Ok(builder)
+}
+
+/// Obsolescence markers encoded in `version`, which must be one that the client supports
+pub fn obsmarkers_part<F>(markers: F, version: ObsmarkersVersion) -> Result<PartEncodeBuilder>
+where
+    F: Future<Item = Vec<HgObsmarker>, Error = Error> + Send + 'static,
+{
+    let mut builder = PartEncodeBuilder::mandatory(PartHeaderType::Obsmarkers)?;
+    let fut = markers
+        .and_then(move |markers| encode_markers(version, &markers))
+        .map_err(|err| Error::from(err.context(ErrorKind::ObsmarkersGeneration)));
+
+    builder.set_data_future(fut);
+
+    Ok(builder)
+}
+
+/// Size of the chunks the payload of a stream2 part is split into, it can be bigger than what
+/// fits in a single chunk
+const STREAM2_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Streaming clone in the version 2 format of Mercurial. `data` has `filecount` store files of
+/// `bytecount` bytes in total, and the client must support all the `requirements` to apply them.
+pub fn stream2_part(
+    filecount: usize,
+    bytecount: usize,
+    requirements: &[&str],
+    data: Bytes,
+) -> Result<PartEncodeBuilder> {
+    let mut builder = PartEncodeBuilder::mandatory(PartHeaderType::Stream2)?;
+    builder.add_mparam("filecount", format!("{}", filecount))?;
+    builder.add_mparam("bytecount", format!("{}", bytecount))?;
+    let mut requirements = requirements.to_vec();
+    requirements.sort();
+    builder.add_mparam("requirements", percent_encode(&requirements.join(",")))?;
+    let chunks = (0..data.len())
+        .step_by(STREAM2_CHUNK_SIZE)
+        .map(|start| {
+            let end = cmp::min(start + STREAM2_CHUNK_SIZE, data.len());
+            Chunk::new(data.slice(start, end))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    builder.set_data_generated(iter_ok(chunks));
+
+    Ok(builder)
+}
+
+/// Changegroup with the given changesets. A changeset is sent as a delta against its p1 if the p1
+/// is the changeset sent right before it, see `select_delta` for the meaning of `delta_threshold`.
+pub fn changegroup_part<S>(changelogentries: S, delta_threshold: usize) -> Result<PartEncodeBuilder>
 where
     S: Stream<Item = (HgNodeHash, HgBlobNode), Error = Error> + Send + 'static,
+{
+    let changelogentries = changelogentries.map(|(node, blobnode)| (node, blobnode, None));
+    changegroup_part_impl(changelogentries, CgVersion::Cg2Version, delta_threshold)
+}
+
+/// Changegroup 3 for a shallow clone, like the narrow extension sends it. Changesets flagged as
+/// ellipsis have the parents that aren't sent replaced with the null hash, clients store them as
+/// they are without checking their hash.
+pub fn ellipsis_changegroup_part<S>(
+    changelogentries: S,
+    delta_threshold: usize,
+) -> Result<PartEncodeBuilder>
+where
+    S: Stream<Item = (HgNodeHash, HgBlobNode, bool), Error = Error> + Send + 'static,
+{
+    let changelogentries = changelogentries.map(|(node, blobnode, ellipsis)| {
+        let flags = if ellipsis { REVIDX_ELLIPSIS } else { 0 };
+        (node, blobnode, Some(flags))
+    });
+    changegroup_part_impl(changelogentries, CgVersion::Cg3Version, delta_threshold)
+}
+
+fn changegroup_part_impl<S>(
+    changelogentries: S,
+    version: CgVersion,
+    delta_threshold: usize,
+) -> Result<PartEncodeBuilder>
+where
+    S: Stream<Item = (HgNodeHash, HgBlobNode, Option<u16>), Error = Error> + Send + 'static,
 {
     let mut builder = PartEncodeBuilder::mandatory(PartHeaderType::Changegroup)?;
-    builder.add_mparam("version", "02")?;
+    builder.add_mparam("version", version.to_param())?;
 
-    let changelogentries = changelogentries.map(|(node, blobnode)| {
+    let mut previous: Option<(HgNodeHash, Bytes)> = None;
+    let changelogentries = changelogentries.map(move |(node, blobnode, flags)| {
         let parents = blobnode.parents().get_nodes();
         let p1 = *parents.0.unwrap_or(&NULL_HASH);
         let p2 = *parents.1.unwrap_or(&NULL_HASH);
-        let base = NULL_HASH;
         // Linknode is the same as node
         let linknode = node;
         let text = blobnode
@@ -66,7 +203,16 @@ where
             .as_inner()
             .unwrap_or(&Bytes::new())
             .clone();
-        let delta = Delta::new_fulltext(text.to_vec());
+        let (base, delta) = {
+            let delta_base = match previous {
+                Some((ref previous_node, ref previous_text)) if *previous_node == p1 => {
+                    Some((*previous_node, previous_text.as_ref()))
+                }
+                _ => None,
+            };
+            select_delta(&text, delta_base, delta_threshold)
+        };
+        previous = Some((node, text));
 
         let deltachunk = CgDeltaChunk {
             node,
@@ -75,17 +221,24 @@ where
             base,
             linknode,
             delta,
+            flags,
         };
         Part::CgChunk(Section::Changeset, deltachunk)
     });
 
-    let changelogentries = changelogentries
-        .chain(once(Ok(Part::SectionEnd(Section::Changeset))))
+    let mut trailer = vec![
+        Part::SectionEnd(Section::Changeset),
         // One more SectionEnd entry is necessary because hg client excepts filelog section
         // even if it's empty. Add a fake SectionEnd part (the choice of
         // Manifest is just for convenience).
-        .chain(once(Ok(Part::SectionEnd(Section::Manifest))))
-        .chain(once(Ok(Part::End)));
+        Part::SectionEnd(Section::Manifest),
+    ];
+    if version == CgVersion::Cg3Version {
+        // Changegroup 3 has an empty section of directory manifests before the filelogs
+        trailer.push(Part::SectionEnd(Section::Manifest));
+    }
+    trailer.push(Part::End);
+    let changelogentries = changelogentries.chain(iter_ok(trailer));
 
     let cgdata = Cg2Packer::new(changelogentries);
     builder.set_data_generated(cgdata);
@@ -101,9 +254,13 @@ pub struct TreepackPartInput {
     pub name: Option<MPathElement>,
     pub linknode: HgNodeHash,
     pub basepath: Option<MPath>,
+    /// A revision of the same tree that the client has, with its content. The tree can be sent as
+    /// a delta against it.
+    pub delta_base: Option<(HgNodeHash, Bytes)>,
 }
 
-pub fn treepack_part<S>(entries: S) -> Result<PartEncodeBuilder>
+/// Treepack with the given trees, see `select_delta` for the meaning of `delta_threshold`
+pub fn treepack_part<S>(entries: S, delta_threshold: usize) -> Result<PartEncodeBuilder>
 where
     S: Stream<Item = BoxFuture<TreepackPartInput, Error>, Error = Error> + Send + 'static,
 {
@@ -115,7 +272,7 @@ where
     let buffer_size = 10000; // TODO(stash): make it configurable
     let wirepack_parts = entries
         .buffered(buffer_size)
-        .map(|input| {
+        .map(move |input| {
             let path = match MPath::join_element_opt(input.basepath.as_ref(), input.name.as_ref()) {
                 Some(path) => RepoPath::DirectoryPath(path),
                 None => RepoPath::RootPath,
@@ -140,10 +297,18 @@ where
                 entry_count: 1,
             };
 
+            let (delta_base, delta) = select_delta(
+                &input.content,
+                input
+                    .delta_base
+                    .as_ref()
+                    .map(|&(ref node, ref content)| (*node, content.as_ref())),
+                delta_threshold,
+            );
             let data = wirepack::Part::Data(wirepack::DataEntry {
                 node: input.node,
-                delta_base: NULL_HASH,
-                delta: Delta::new_fulltext(input.content.to_vec()),
+                delta_base,
+                delta,
             });
 
             iter_ok(vec![history_meta, history, data_meta, data].into_iter())
@@ -219,3 +384,11 @@ pub fn replypushkey_part(res: bool, in_reply_to: u32) -> Result<PartEncodeBuilde
 
     Ok(builder)
 }
+
+pub fn replyobsmarkers_part(new: usize, in_reply_to: u32) -> Result<PartEncodeBuilder> {
+    let mut builder = PartEncodeBuilder::mandatory(PartHeaderType::ReplyObsmarkers)?;
+    builder.add_mparam("new", format!("{}", new))?;
+    builder.add_mparam("in-reply-to", format!("{}", in_reply_to))?;
+
+    Ok(builder)
+}
@@ -0,0 +1,52 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+// Phase heads codecs
+
+use byteorder::{BigEndian, ByteOrder};
+use bytes::{BufMut, Bytes, BytesMut};
+use mercurial_types::{HgChangesetId, HgNodeHash};
+use tokio_codec::Decoder;
+
+use errors::*;
+
+/// Size of an entry of the phase-heads part: the phase as a big endian u32, then the node
+const ENTRY_SIZE: usize = 4 + 20;
+
+/// Decodes the entries of a phase-heads part, which are (phase, head) pairs
+#[derive(Debug)]
+pub struct PhaseHeadsUnpacker {}
+
+impl PhaseHeadsUnpacker {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+impl Decoder for PhaseHeadsUnpacker {
+    type Item = (u32, HgChangesetId);
+    type Error = Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>> {
+        if buf.len() >= ENTRY_SIZE {
+            let entry = buf.split_to(ENTRY_SIZE).freeze();
+            let phase = BigEndian::read_u32(&entry[..4]);
+            let nodehash = HgNodeHash::from_bytes(&entry[4..])?;
+            Ok(Some((phase, HgChangesetId::new(nodehash))))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+pub fn encode_phase_heads(heads: &[(u32, HgChangesetId)]) -> Bytes {
+    let mut payload = BytesMut::with_capacity(heads.len() * ENTRY_SIZE);
+    for &(phase, ref cs_id) in heads {
+        payload.put_u32_be(phase);
+        payload.put_slice(cs_id.into_nodehash().as_ref());
+    }
+    payload.freeze()
+}
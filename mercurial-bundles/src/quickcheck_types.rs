@@ -197,6 +197,8 @@ impl Arbitrary for changegroup::CgDeltaChunk {
             base: HgNodeHash::arbitrary(g),
             linknode: HgNodeHash::arbitrary(g),
             delta: Delta::arbitrary(g),
+            // Flags are only encoded in changegroup 3, which can't be packed
+            flags: None,
         }
     }
 
@@ -213,6 +215,7 @@ impl Arbitrary for changegroup::CgDeltaChunk {
                     base: clone.base.clone(),
                     linknode: clone.linknode.clone(),
                     delta: delta,
+                    flags: clone.flags,
                 }),
         )
     }
@@ -11,6 +11,8 @@ use std::io::{self, BufRead, BufReader, Cursor};
 use std::iter::Iterator;
 use std::str::FromStr;
 
+use bytes::Bytes;
+use futures::future;
 use futures::stream::Stream;
 use futures_ext::BoxStream;
 use slog::{Drain, Logger};
@@ -20,7 +22,8 @@ use tokio_io::AsyncRead;
 
 use async_compression::{Bzip2Compression, CompressorType, FlateCompression};
 use async_compression::membuf::MemBuf;
-use mercurial_types::{HgNodeHash, MPath, RepoPath, NULL_HASH};
+use mercurial_types::{HgNodeHash, HgObsmarker, MPath, ObsmarkersVersion, RepoPath, NULL_HASH};
+use mercurial_types_mocks::nodehash::{ONES_CSID, THREES_CSID, TWOS_CSID};
 use partial_io::{GenWouldBlock, PartialAsyncRead, PartialWithErrors};
 use quickcheck::{QuickCheck, StdGen};
 use quickcheck::rand;
@@ -28,10 +31,12 @@ use quickcheck::rand;
 use Bundle2Item;
 use bundle2::{Bundle2Stream, StreamEvent};
 use bundle2_encode::Bundle2EncodeBuilder;
+use capabilities::{response_compression, Capabilities};
 use changegroup;
 use errors::*;
 use part_encode::PartEncodeBuilder;
 use part_header::{PartHeaderBuilder, PartHeaderType};
+use parts;
 use types::StreamHeader;
 use utils::get_compression_param;
 use wirepack;
@@ -89,6 +94,11 @@ fn test_empty_bundle_roundtrip_gzip() {
     empty_bundle_roundtrip(Some(CompressorType::Gzip(FlateCompression::best())));
 }
 
+#[test]
+fn test_empty_bundle_roundtrip_zlib() {
+    empty_bundle_roundtrip(Some(CompressorType::Zlib(FlateCompression::best())));
+}
+
 #[test]
 fn test_empty_bundle_roundtrip_uncompressed() {
     empty_bundle_roundtrip(None);
@@ -148,6 +158,11 @@ fn test_unknown_part_gzip() {
     unknown_part(Some(CompressorType::Gzip(FlateCompression::best())));
 }
 
+#[test]
+fn test_unknown_part_zlib() {
+    unknown_part(Some(CompressorType::Zlib(FlateCompression::best())));
+}
+
 #[test]
 fn test_unknown_part_uncompressed() {
     unknown_part(None);
@@ -381,6 +396,132 @@ fn verify_cg2(runtime: &mut Runtime, stream: BoxStream<changegroup::Part, Error>
     );
 }
 
+#[test]
+fn test_response_compression() {
+    assert_matches!(
+        response_compression(&["0.1", "0.2", "comp=zstd,zlib,none,bzip2"]),
+        Some(CompressorType::Zstd { .. })
+    );
+    assert_matches!(
+        response_compression(&["partial-pull", "comp=bzip2,zlib"]),
+        Some(CompressorType::Zlib(_))
+    );
+    // The order of preference is the server's, not the client's
+    assert_matches!(
+        response_compression(&["comp=none,zlib,zstd"]),
+        Some(CompressorType::Zstd { .. })
+    );
+    assert!(response_compression(&["comp=none,bzip2"]).is_none());
+    assert!(response_compression(&["0.1", "0.2"]).is_none());
+    assert!(response_compression::<&str>(&[]).is_none());
+}
+
+#[test]
+fn test_obsmarkers_version() {
+    let caps = |bundlecaps: &[u8]| {
+        Capabilities::from_bundlecaps(&[bundlecaps.to_vec()]).expect("invalid bundlecaps")
+    };
+
+    assert_eq!(
+        caps(b"bundle2=HG20%0Aobsmarkers%3DV0%2CV1").obsmarkers_version(),
+        Some(ObsmarkersVersion::V1)
+    );
+    assert_eq!(
+        caps(b"bundle2=obsmarkers%3DV0%2CV2").obsmarkers_version(),
+        Some(ObsmarkersVersion::V0)
+    );
+    assert_eq!(caps(b"bundle2=HG20").obsmarkers_version(), None);
+}
+
+#[test]
+fn test_phase_heads_roundtrip() {
+    let phase_heads = vec![(0, ONES_CSID), (1, TWOS_CSID), (1, THREES_CSID)];
+
+    let cursor = Cursor::new(Vec::with_capacity(32 * 1024));
+    let mut builder = Bundle2EncodeBuilder::new(cursor);
+    builder.set_compressor_type(None);
+    builder.add_part(parts::phase_heads_part(future::ok(phase_heads.clone())).unwrap());
+    let encode_fut = builder.build();
+
+    let mut runtime = Runtime::new().unwrap();
+    let mut buf = runtime.block_on(encode_fut).unwrap();
+    buf.set_position(0);
+
+    let logger = make_root_logger();
+    let stream = Bundle2Stream::new(buf, logger);
+    let (item, stream) = runtime.block_on(stream.into_future()).unwrap();
+    assert_matches!(item, Some(StreamEvent::Next(Bundle2Item::Start(_))));
+
+    let (item, stream) = runtime.block_on(stream.into_future()).unwrap();
+    let heads = match item.unwrap().into_next().unwrap() {
+        Bundle2Item::PhaseHeads(header, heads) => {
+            assert_eq!(header.part_type(), &PartHeaderType::PhaseHeads);
+            assert!(!header.mandatory());
+            heads
+        }
+        bad => panic!("Unexpected bundle2 item: {:?}", bad),
+    };
+    assert_eq!(runtime.block_on(heads.collect()).unwrap(), phase_heads);
+
+    let (item, _stream) = runtime.block_on(stream.into_future()).unwrap();
+    assert_matches!(item, Some(StreamEvent::Done(_)));
+}
+
+#[test]
+fn test_obsmarkers_roundtrip() {
+    let markers = vec![
+        HgObsmarker {
+            predecessor: ONES_CSID,
+            successors: vec![TWOS_CSID],
+            parents: None,
+            flags: 0,
+            metadata: vec![(Bytes::from("operation"), Bytes::from("amend"))],
+            date: 1500000000.0,
+            tz_offset: 3600,
+        },
+        HgObsmarker {
+            predecessor: THREES_CSID,
+            successors: vec![],
+            parents: Some(vec![ONES_CSID]),
+            flags: 0,
+            metadata: vec![],
+            date: 1500000001.0,
+            tz_offset: 0,
+        },
+    ];
+
+    for &version in &[ObsmarkersVersion::V0, ObsmarkersVersion::V1] {
+        let cursor = Cursor::new(Vec::with_capacity(32 * 1024));
+        let mut builder = Bundle2EncodeBuilder::new(cursor);
+        builder.set_compressor_type(None);
+        builder.add_part(parts::obsmarkers_part(future::ok(markers.clone()), version).unwrap());
+        let encode_fut = builder.build();
+
+        let mut runtime = Runtime::new().unwrap();
+        let mut buf = runtime.block_on(encode_fut).unwrap();
+        buf.set_position(0);
+
+        let logger = make_root_logger();
+        let stream = Bundle2Stream::new(buf, logger);
+        let (item, stream) = runtime.block_on(stream.into_future()).unwrap();
+        assert_matches!(item, Some(StreamEvent::Next(Bundle2Item::Start(_))));
+
+        let (item, stream) = runtime.block_on(stream.into_future()).unwrap();
+        let decoded = match item.unwrap().into_next().unwrap() {
+            Bundle2Item::Obsmarkers(header, decoded) => {
+                assert_eq!(header.part_type(), &PartHeaderType::Obsmarkers);
+                assert!(header.mandatory());
+                decoded
+            }
+            bad => panic!("Unexpected bundle2 item: {:?}", bad),
+        };
+        assert_eq!(runtime.block_on(decoded.collect()).unwrap(), markers);
+
+        let (item, _stream) = runtime.block_on(stream.into_future()).unwrap();
+        assert_matches!(item, Some(StreamEvent::Done(_)));
+    }
+}
+
 #[test]
 fn test_parse_wirepack() {
     let rng = StdGen::new(rand::thread_rng(), 20);
@@ -130,7 +130,8 @@ pub fn is_mandatory_param(s: &str) -> Result<bool> {
 pub fn get_decompressor_type(compression: Option<&str>) -> Result<Option<DecompressorType>> {
     match compression {
         Some("BZ") => Ok(Some(DecompressorType::Bzip2)),
-        Some("GZ") => Ok(Some(DecompressorType::Gzip)),
+        // Mercurial compresses GZ bundles with zlib, without gzip headers
+        Some("GZ") => Ok(Some(DecompressorType::Zlib)),
         Some("ZS") => Ok(Some(DecompressorType::Zstd)),
         Some("UN") => Ok(None),
         Some(s) => bail_err!(ErrorKind::Bundle2Decode(format!(
@@ -144,7 +145,8 @@ pub fn get_decompressor_type(compression: Option<&str>) -> Result<Option<Decompr
 pub fn get_compression_param(ct: &Option<CompressorType>) -> &'static str {
     match ct {
         &Some(CompressorType::Bzip2(_)) => "BZ",
-        &Some(CompressorType::Gzip(_)) => "GZ",
+        // Gzip is encoded as zlib, see Bundle2EncodeBuilder::set_compressor_type
+        &Some(CompressorType::Gzip(_)) | &Some(CompressorType::Zlib(_)) => "GZ",
         &Some(CompressorType::Zstd { .. }) => "ZS",
         &None => "UN",
     }
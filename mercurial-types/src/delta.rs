@@ -4,6 +4,8 @@
 // This software may be used and distributed according to the terms of the
 // GNU General Public License version 2 or any later version.
 
+use std::collections::HashMap;
+
 use quickcheck::{Arbitrary, Gen};
 use quickcheck::rand::distributions::{IndependentSample, LogNormal};
 
@@ -249,6 +251,131 @@ pub fn apply_chain<I: IntoIterator<Item = Delta>>(text: &[u8], deltas: I) -> Res
     Ok(res)
 }
 
+/// Compute a Delta that turns `base` into `text`, the way Mercurial's bdiff does: the texts are
+/// compared line by line, and every run of lines that differs becomes a fragment.
+pub fn diff(base: &[u8], text: &[u8]) -> Delta {
+    let (base_lines, base_offsets) = split_lines(base);
+    let (text_lines, text_offsets) = split_lines(text);
+
+    let mut frags = vec![];
+    let (mut i, mut j) = (0, 0);
+    for (match_i, match_j, len) in matching_blocks(&base_lines, &text_lines) {
+        if i < match_i || j < match_j {
+            frags.push(Fragment {
+                start: base_offsets[i],
+                end: base_offsets[match_i],
+                content: text[text_offsets[j]..text_offsets[match_j]].to_vec(),
+            });
+        }
+        i = match_i + len;
+        j = match_j + len;
+    }
+    Delta { frags }
+}
+
+/// Split a text into lines that keep their line endings. The returned offsets are the offsets of
+/// the lines in the text, followed by the length of the text.
+fn split_lines(text: &[u8]) -> (Vec<&[u8]>, Vec<usize>) {
+    let mut lines = vec![];
+    let mut offsets = vec![0];
+    let mut start = 0;
+    for (idx, byte) in text.iter().enumerate() {
+        if *byte == b'\n' {
+            lines.push(&text[start..idx + 1]);
+            start = idx + 1;
+            offsets.push(start);
+        }
+    }
+    if start < text.len() {
+        lines.push(&text[start..]);
+        offsets.push(text.len());
+    }
+    (lines, offsets)
+}
+
+/// Runs of lines that are the same in both texts, as (start in a, start in b, length). The runs
+/// are sorted and followed by an empty run at the ends of the texts.
+fn matching_blocks(a: &[&[u8]], b: &[&[u8]]) -> Vec<(usize, usize, usize)> {
+    let prefix = a.iter().zip(b.iter()).take_while(|&(x, y)| x == y).count();
+    let suffix = a[prefix..]
+        .iter()
+        .rev()
+        .zip(b[prefix..].iter().rev())
+        .take_while(|&(x, y)| x == y)
+        .count();
+
+    let mut blocks = vec![];
+    if prefix > 0 {
+        blocks.push((0, 0, prefix));
+    }
+    if suffix > 0 {
+        blocks.push((a.len() - suffix, b.len() - suffix, suffix));
+    }
+
+    // Lines of b by content. Lines that are very common (like blank lines) would make finding
+    // matches quadratic, so they don't start matches.
+    let mut b_lines: HashMap<&[u8], Vec<usize>> = HashMap::new();
+    for (j, line) in b.iter().enumerate().take(b.len() - suffix).skip(prefix) {
+        b_lines.entry(*line).or_insert_with(Vec::new).push(j);
+    }
+    if b.len() >= POPULAR_LINES_MIN_TEXT {
+        let popular = b.len() / 100 + 1;
+        b_lines.retain(|_, positions| positions.len() <= popular);
+    }
+
+    let mut ranges = vec![(prefix, a.len() - suffix, prefix, b.len() - suffix)];
+    while let Some((a_lo, a_hi, b_lo, b_hi)) = ranges.pop() {
+        let (i, j, len) = longest_match(a, &b_lines, (a_lo, a_hi), (b_lo, b_hi));
+        if len > 0 {
+            blocks.push((i, j, len));
+            if a_lo < i && b_lo < j {
+                ranges.push((a_lo, i, b_lo, j));
+            }
+            if i + len < a_hi && j + len < b_hi {
+                ranges.push((i + len, a_hi, j + len, b_hi));
+            }
+        }
+    }
+
+    blocks.sort();
+    blocks.push((a.len(), b.len(), 0));
+    blocks
+}
+
+/// Texts with fewer lines than this have no popular lines
+const POPULAR_LINES_MIN_TEXT: usize = 200;
+
+/// The longest run of lines in the given ranges of a and b that is the same in both
+fn longest_match(
+    a: &[&[u8]],
+    b_lines: &HashMap<&[u8], Vec<usize>>,
+    (a_lo, a_hi): (usize, usize),
+    (b_lo, b_hi): (usize, usize),
+) -> (usize, usize, usize) {
+    let (mut best_i, mut best_j, mut best_len) = (a_lo, b_lo, 0);
+    // Length of the match that ends at each line of b, for the previous line of a
+    let mut match_lens: HashMap<usize, usize> = HashMap::new();
+    for i in a_lo..a_hi {
+        let mut new_match_lens = HashMap::new();
+        if let Some(positions) = b_lines.get(a[i]) {
+            for &j in positions.iter().filter(|&&j| b_lo <= j && j < b_hi) {
+                let len = match j.checked_sub(1).and_then(|prev| match_lens.get(&prev)) {
+                    Some(prev_len) => prev_len + 1,
+                    None => 1,
+                };
+                new_match_lens.insert(j, len);
+                if len > best_len {
+                    best_i = i + 1 - len;
+                    best_j = j + 1 - len;
+                    best_len = len;
+                }
+            }
+        }
+        match_lens = new_match_lens;
+    }
+    (best_i, best_j, best_len)
+}
+
 /// XXX: Compatibility functions for the old bdiff module for testing purposes. The delta
 /// module will replace that one once all instances of Vec<bdiff::Delta> are replaced
 /// with delta::Delta, and this compatibility module will be removed at that time.
@@ -333,7 +460,61 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_diff() {
+        let test_cases: Vec<(&[u8], &[u8], usize)> = vec![
+            (b"", b"", 0),
+            (b"aaaa\n", b"aaaa\n", 0),
+            (b"", b"aaaa\n", 1),
+            (b"aaaa\n", b"", 1),
+            (b"aaaa\nbbbb\ncccc\n", b"aaaa\nxxxx\ncccc\n", 1),
+            (b"aaaa\n", b"aaaa\nbbbb\n", 1),
+            (b"aaaa\n", b"bbbb\naaaa\n", 1),
+            (b"aa", b"aaa", 1),
+            (b"aaaa\nbbbb\ncccc\ndddd\n", b"xxxx\nbbbb\ncccc\nyyyy\n", 2),
+            (b"aaaa\nbbbb\ncccc\n", b"cccc\nbbbb\naaaa\n", 2),
+            (b"aaaa\nbbbb", b"aaaa\nbbbb\n", 1),
+        ];
+
+        for (base, text, frags) in test_cases.into_iter() {
+            let delta = diff(base, text);
+            assert_eq!(delta.fragments().len(), frags);
+            assert_eq!(apply(base, &delta).unwrap(), text);
+        }
+    }
+
+    #[test]
+    fn test_diff_fragments() {
+        let delta = diff(b"aaaa\nbbbb\ncccc\n", b"aaaa\nxxxx\nyyyy\ncccc\n");
+        assert_eq!(
+            delta.fragments(),
+            &[
+                Fragment {
+                    start: 5,
+                    end: 10,
+                    content: (&b"xxxx\nyyyy\n"[..]).into(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_diff_popular_lines() {
+        let base: Vec<u8> = (0..300)
+            .flat_map(|n| format!("line {}\n\n", n).into_bytes())
+            .collect();
+        let text: Vec<u8> = (0..300)
+            .flat_map(|n| format!("line {}\n\n", n + 1).into_bytes())
+            .collect();
+        let delta = diff(&base, &text);
+        assert_eq!(apply(&base, &delta).unwrap(), text);
+    }
+
     quickcheck! {
+        fn diff_roundtrip(base: Vec<u8>, text: Vec<u8>) -> bool {
+            apply(&base, &diff(&base, &text)).unwrap() == text
+        }
+
         fn delta_gen(delta: Delta) -> bool {
             Delta::verify(&delta.frags).is_ok()
         }
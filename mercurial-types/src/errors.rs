@@ -9,9 +9,14 @@ pub use failure::{Error, ResultExt};
 #[derive(Debug, Fail)]
 pub enum ErrorKind {
     #[fail(display = "invalid sha-1 input: {}", _0)] InvalidSha1Input(String),
+    #[fail(display = "invalid sha-256 input: {}", _0)] InvalidSha256Input(String),
     #[fail(display = "invalid fragment list: {}", _0)] InvalidFragmentList(String),
     #[fail(display = "invalid Thrift structure '{}': {}", _0, _1)] InvalidThrift(String, String),
     #[fail(display = "error while deserializing blob for '{}'", _0)] BlobDeserializeError(String),
+    #[fail(display = "invalid narrow pattern '{}': {}", _0, _1)] InvalidNarrowPattern(String, String),
+    #[fail(display = "invalid LFS pointer: {}", _0)] InvalidLfsPointer(String),
+    #[fail(display = "invalid base85 input: {}", _0)] InvalidBase85(String),
+    #[fail(display = "invalid obsolescence marker: {}", _0)] InvalidObsmarker(String),
 }
 
 pub type Result<T> = ::std::result::Result<T, Error>;
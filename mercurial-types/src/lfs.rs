@@ -0,0 +1,412 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Large files stored out of band, compatible with Git LFS
+//!
+//! The filelog revision of a large file doesn't contain the file itself, but a small pointer to
+//! it (https://github.com/git-lfs/git-lfs/blob/master/docs/spec.md). The content is stored
+//! separately, keyed by its SHA-256, and clients fetch it over HTTP.
+
+use std::collections::BTreeMap;
+use std::fmt::{self, Debug, Display};
+use std::str::{self, FromStr};
+
+use rust_crypto::digest::Digest;
+use rust_crypto::sha2;
+
+use errors::*;
+use hash::{self, Context};
+use nodehash::HgNodeHash;
+
+/// First line of every pointer
+pub const LFS_POINTER_VERSION: &str = "version https://git-lfs.github.com/spec/v1\n";
+
+/// Revlog flag of revisions whose content is stored out of band. Clients that see it resolve the
+/// pointer instead of using the revision as file content.
+pub const REVIDX_EXTSTORED: u16 = 1 << 13;
+
+/// Mercurial moves filelog metadata (like copy information) into pointer keys with this prefix
+const HG_METADATA_KEY_PREFIX: &str = "x-hg-";
+
+/// Marker around the filelog metadata at the start of a revision
+const HG_METADATA_MARKER: &[u8] = b"\x01\n";
+
+/// Pointers are tiny, anything larger than this is file content even if it looks like a pointer
+const MAX_POINTER_SIZE: usize = 1024;
+
+const HEX_CHARS: &[u8] = b"0123456789abcdef";
+
+/// Raw SHA-256 hash, which is what LFS objects are identified by
+#[derive(Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct Sha256([u8; 32]);
+
+impl Sha256 {
+    /// Construct a `Sha256` from an array of 32 bytes containing a SHA-256 (ie, *not* a hash of
+    /// the bytes).
+    pub fn from_bytes<B: AsRef<[u8]>>(bytes: B) -> Result<Sha256> {
+        let bytes = bytes.as_ref();
+        if bytes.len() != 32 {
+            bail_err!(ErrorKind::InvalidSha256Input("need exactly 32 bytes".into()));
+        }
+        let mut ret = Sha256([0; 32]);
+        ret.0.copy_from_slice(bytes);
+        Ok(ret)
+    }
+
+    pub fn to_hex(&self) -> String {
+        let mut s = String::with_capacity(64);
+        for &byte in self.0.iter() {
+            s.push(HEX_CHARS[(byte >> 4) as usize] as char);
+            s.push(HEX_CHARS[(byte & 0xf) as usize] as char);
+        }
+        s
+    }
+}
+
+/// Context for incrementally computing a `Sha256` hash, for content that arrives in chunks.
+#[derive(Clone)]
+pub struct Sha256Context(sha2::Sha256);
+
+impl Sha256Context {
+    pub fn new() -> Self {
+        Sha256Context(sha2::Sha256::new())
+    }
+
+    pub fn update<T>(&mut self, data: T)
+    where
+        T: AsRef<[u8]>,
+    {
+        self.0.input(data.as_ref())
+    }
+
+    pub fn finish(mut self) -> Sha256 {
+        let mut ret = Sha256([0; 32]);
+        self.0.result(&mut ret.0[..]);
+        ret
+    }
+}
+
+/// Compute the `Sha256` for a slice of bytes.
+impl<'a> From<&'a [u8]> for Sha256 {
+    fn from(data: &[u8]) -> Sha256 {
+        let mut context = Sha256Context::new();
+        context.update(data);
+        context.finish()
+    }
+}
+
+impl FromStr for Sha256 {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Sha256> {
+        if s.len() != 64 {
+            bail_err!(ErrorKind::InvalidSha256Input(
+                "need exactly 64 hex digits".into()
+            ));
+        }
+        // Digits are sliced out by byte offset below, which panics inside multibyte characters
+        if !s.is_ascii() {
+            bail_err!(ErrorKind::InvalidSha256Input("bad digit".into()));
+        }
+
+        let mut ret = Sha256([0; 32]);
+        for idx in 0..ret.0.len() {
+            ret.0[idx] = match u8::from_str_radix(&s[(idx * 2)..(idx * 2 + 2)], 16) {
+                Ok(v) => v,
+                Err(_) => bail_err!(ErrorKind::InvalidSha256Input("bad digit".into())),
+            }
+        }
+        Ok(ret)
+    }
+}
+
+impl Display for Sha256 {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str(&self.to_hex())
+    }
+}
+
+impl Debug for Sha256 {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "Sha256({})", self)
+    }
+}
+
+/// Pointer to a large file. Only the keys needed to fetch the file are kept, others (like the
+/// `x-hg-copy` keys Mercurial adds) stay in the filelog revision.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LfsPointer {
+    pub oid: Sha256,
+    pub size: u64,
+}
+
+impl LfsPointer {
+    pub fn new(oid: Sha256, size: u64) -> Self {
+        LfsPointer { oid, size }
+    }
+
+    /// Whether a filelog revision is a pointer rather than file content. A revision can look like
+    /// a pointer and still fail to parse.
+    pub fn is_pointer(content: &[u8]) -> bool {
+        content.len() <= MAX_POINTER_SIZE && content.starts_with(LFS_POINTER_VERSION.as_bytes())
+    }
+
+    pub fn parse(content: &[u8]) -> Result<Self> {
+        if !Self::is_pointer(content) {
+            bail_err!(ErrorKind::InvalidLfsPointer("not a pointer".into()));
+        }
+        let content = str::from_utf8(content)
+            .map_err(|_| ErrorKind::InvalidLfsPointer("not utf-8".into()))?;
+
+        let mut oid = None;
+        let mut size = None;
+        for line in content.lines().skip(1) {
+            let mut kv = line.splitn(2, ' ');
+            match (kv.next(), kv.next()) {
+                (Some("oid"), Some(value)) => {
+                    if !value.starts_with("sha256:") {
+                        bail_err!(ErrorKind::InvalidLfsPointer(format!(
+                            "unsupported oid {}",
+                            value
+                        )));
+                    }
+                    oid = Some(Sha256::from_str(&value["sha256:".len()..])?);
+                }
+                (Some("size"), Some(value)) => {
+                    let value = u64::from_str(value).map_err(|_| {
+                        ErrorKind::InvalidLfsPointer(format!("invalid size {}", value))
+                    })?;
+                    size = Some(value);
+                }
+                (Some(_), Some(_)) => {}
+                _ => bail_err!(ErrorKind::InvalidLfsPointer(format!(
+                    "malformed line {:?}",
+                    line
+                ))),
+            }
+        }
+
+        match (oid, size) {
+            (Some(oid), Some(size)) => Ok(LfsPointer { oid, size }),
+            _ => bail_err!(ErrorKind::InvalidLfsPointer("missing oid or size".into())),
+        }
+    }
+
+    /// The canonical form of the pointer
+    pub fn to_bytes(&self) -> Vec<u8> {
+        format!(
+            "{}oid sha256:{}\nsize {}\n",
+            LFS_POINTER_VERSION, self.oid, self.size
+        ).into_bytes()
+    }
+}
+
+/// The filelog metadata header of a large file revision, built from the `x-hg-` keys of its
+/// pointer. Empty if the pointer has no such keys.
+fn hg_metadata_header(pointer: &[u8]) -> Result<Vec<u8>> {
+    let content =
+        str::from_utf8(pointer).map_err(|_| ErrorKind::InvalidLfsPointer("not utf-8".into()))?;
+
+    let mut metadata = BTreeMap::new();
+    for line in content.lines().skip(1) {
+        let mut kv = line.splitn(2, ' ');
+        if let (Some(key), Some(value)) = (kv.next(), kv.next()) {
+            if key.starts_with(HG_METADATA_KEY_PREFIX) {
+                metadata.insert(&key[HG_METADATA_KEY_PREFIX.len()..], value);
+            }
+        }
+    }
+
+    let mut header = vec![];
+    if !metadata.is_empty() {
+        header.extend_from_slice(HG_METADATA_MARKER);
+        for (key, value) in metadata {
+            header.extend_from_slice(format!("{}: {}\n", key, value).as_bytes());
+        }
+        header.extend_from_slice(HG_METADATA_MARKER);
+    }
+    Ok(header)
+}
+
+/// The filelog revision of a large file as Mercurial stores it without LFS, which is what its
+/// node hash covers: the metadata from the pointer followed by the content.
+pub fn lfs_fulltext(pointer: &[u8], content: &[u8]) -> Result<Vec<u8>> {
+    let mut fulltext = hg_metadata_header(pointer)?;
+    // Without metadata, the header is only there if the content could be mistaken for it
+    if fulltext.is_empty() && content.starts_with(HG_METADATA_MARKER) {
+        fulltext.extend_from_slice(HG_METADATA_MARKER);
+        fulltext.extend_from_slice(HG_METADATA_MARKER);
+    }
+    fulltext.extend_from_slice(content);
+    Ok(fulltext)
+}
+
+/// Computes the node hash of a large file revision (see `lfs_fulltext`) without holding its
+/// content in memory. The content is fed in chunks as it's read from the store.
+pub struct LfsNodeHasher {
+    context: Context,
+    /// Start of the content while it's too short to tell whether it needs an empty metadata
+    /// header, or `None` once the header was decided
+    head: Option<Vec<u8>>,
+}
+
+impl LfsNodeHasher {
+    pub fn new(pointer: &[u8], p1: Option<&HgNodeHash>, p2: Option<&HgNodeHash>) -> Result<Self> {
+        let header = hg_metadata_header(pointer)?;
+
+        let null = hash::NULL;
+        let (h1, h2) = match (p1.map_or(null, |p| p.0), p2.map_or(null, |p| p.0)) {
+            (h1, h2) if h1 > h2 => (h2, h1),
+            parents => parents,
+        };
+        let mut context = Context::new();
+        context.update(h1);
+        context.update(h2);
+
+        let head = if header.is_empty() {
+            Some(Vec::new())
+        } else {
+            context.update(header);
+            None
+        };
+
+        Ok(LfsNodeHasher { context, head })
+    }
+
+    pub fn update<T>(&mut self, data: T)
+    where
+        T: AsRef<[u8]>,
+    {
+        let data = data.as_ref();
+        match self.head.take() {
+            Some(mut head) => {
+                head.extend_from_slice(data);
+                if head.len() < HG_METADATA_MARKER.len() {
+                    self.head = Some(head);
+                } else {
+                    if head.starts_with(HG_METADATA_MARKER) {
+                        self.context.update(HG_METADATA_MARKER);
+                        self.context.update(HG_METADATA_MARKER);
+                    }
+                    self.context.update(head);
+                }
+            }
+            None => self.context.update(data),
+        }
+    }
+
+    pub fn finish(mut self) -> HgNodeHash {
+        if let Some(head) = self.head.take() {
+            self.context.update(head);
+        }
+        HgNodeHash(self.context.finish())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use bytes::Bytes;
+
+    use blob::HgBlob;
+    use blobnode::HgBlobNode;
+
+    const EMPTY_SHA256: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+    #[test]
+    fn test_sha256() {
+        let empty = Sha256::from(&[][..]);
+        assert_eq!(empty.to_hex(), EMPTY_SHA256);
+        assert_eq!(Sha256::from_str(EMPTY_SHA256).unwrap(), empty);
+        assert!(Sha256::from_str(&EMPTY_SHA256[1..]).is_err());
+        assert!(Sha256::from_str(&EMPTY_SHA256.replace("e", "x")).is_err());
+        // 64 bytes, but a multibyte character straddles the boundary between two digits
+        assert!(Sha256::from_str(&format!("a\u{e9}{}", &EMPTY_SHA256[3..])).is_err());
+
+        let mut context = Sha256Context::new();
+        context.update(b"foo");
+        context.update(b"bar");
+        assert_eq!(context.finish(), Sha256::from(&b"foobar"[..]));
+    }
+
+    #[test]
+    fn test_parse_pointer() {
+        let pointer = LfsPointer::new(Sha256::from_str(EMPTY_SHA256).unwrap(), 12345);
+        let bytes = pointer.to_bytes();
+        assert!(LfsPointer::is_pointer(&bytes));
+        assert_eq!(LfsPointer::parse(&bytes).unwrap(), pointer);
+
+        // Pointers written by Mercurial have extra keys
+        let bytes = format!(
+            "{}oid sha256:{}\nsize 12345\nx-hg-copy foo\nx-hg-copyrev {}\nx-is-binary 0\n",
+            LFS_POINTER_VERSION, EMPTY_SHA256, "1111111111111111111111111111111111111111"
+        );
+        assert_eq!(LfsPointer::parse(bytes.as_bytes()).unwrap(), pointer);
+    }
+
+    #[test]
+    fn test_node_hash() {
+        let p1 = HgNodeHash::from_str("1111111111111111111111111111111111111111").unwrap();
+        let p2 = HgNodeHash::from_str("2222222222222222222222222222222222222222").unwrap();
+        let node_hash = |fulltext: &[u8], p1, p2| {
+            HgBlobNode::new(HgBlob::from(Bytes::from(fulltext)), p1, p2)
+                .nodeid()
+                .unwrap()
+        };
+        let lfs_node_hash = |pointer: &str, content: &[&[u8]], p1, p2| {
+            let mut hasher = LfsNodeHasher::new(pointer.as_bytes(), p1, p2).unwrap();
+            for chunk in content {
+                hasher.update(chunk);
+            }
+            hasher.finish()
+        };
+
+        let pointer = LfsPointer::new(Sha256::from_str(EMPTY_SHA256).unwrap(), 6).to_bytes();
+        let pointer = str::from_utf8(&pointer).unwrap();
+        assert_eq!(
+            lfs_node_hash(pointer, &[b"foo", b"bar"], Some(&p2), Some(&p1)),
+            node_hash(b"foobar", Some(&p1), Some(&p2))
+        );
+
+        // Content that starts like metadata gets an empty header, even if it arrives byte by byte
+        assert_eq!(
+            lfs_node_hash(pointer, &[b"\x01", b"\nfoo"], None, Some(&p2)),
+            node_hash(b"\x01\n\x01\n\x01\nfoo", Some(&p2), None)
+        );
+        assert_eq!(lfs_node_hash(pointer, &[b"\x01"], None, None), node_hash(b"\x01", None, None));
+
+        let pointer = format!(
+            "{}oid sha256:{}\nsize 6\nx-hg-copy foo\nx-hg-copyrev {}\nx-is-binary 0\n",
+            LFS_POINTER_VERSION, EMPTY_SHA256, p1
+        );
+        let fulltext = format!("\x01\ncopy: foo\ncopyrev: {}\n\x01\nfoobar", p1);
+        assert_eq!(
+            lfs_node_hash(&pointer, &[b"foobar"], Some(&p1), None),
+            node_hash(fulltext.as_bytes(), Some(&p1), None)
+        );
+        assert_eq!(
+            lfs_fulltext(pointer.as_bytes(), b"foobar").unwrap(),
+            fulltext.into_bytes()
+        );
+    }
+
+    #[test]
+    fn test_parse_bad_pointer() {
+        assert!(!LfsPointer::is_pointer(b"some file content"));
+        assert!(LfsPointer::parse(b"some file content").is_err());
+
+        let no_size = format!("{}oid sha256:{}\n", LFS_POINTER_VERSION, EMPTY_SHA256);
+        assert!(LfsPointer::is_pointer(no_size.as_bytes()));
+        assert!(LfsPointer::parse(no_size.as_bytes()).is_err());
+
+        let bad_oid = format!("{}oid md5:{}\nsize 1\n", LFS_POINTER_VERSION, EMPTY_SHA256);
+        assert!(LfsPointer::parse(bad_oid.as_bytes()).is_err());
+
+        let bad_line = format!("{}oid\nsize 1\n", LFS_POINTER_VERSION);
+        assert!(LfsPointer::parse(bad_line.as_bytes()).is_err());
+    }
+}
@@ -86,10 +86,13 @@ pub mod delta;
 pub mod errors;
 pub mod fsencode;
 pub mod hash;
+pub mod lfs;
 pub mod nodehash;
+pub mod obsmarker;
 pub mod utils;
 pub mod manifest;
 pub mod manifest_utils;
+pub mod narrowspec;
 pub mod blob;
 pub mod blobnode;
 pub mod changeset;
@@ -105,12 +108,15 @@ pub use delta::Delta;
 pub use envelope::{HgChangesetEnvelope, HgChangesetEnvelopeMut, HgFileEnvelope, HgFileEnvelopeMut,
                    HgManifestEnvelope, HgManifestEnvelopeMut};
 pub use fsencode::{fncache_fsencode, simple_fsencode};
+pub use lfs::{LfsPointer, Sha256};
 pub use manifest::{Entry, Manifest, Type};
+pub use narrowspec::NarrowSpec;
 pub use node::Node;
+pub use obsmarker::{HgObsmarker, ObsmarkersVersion};
 pub use nodehash::{HgChangesetId, HgEntryId, HgFileNodeId, HgManifestId, HgNodeHash, HgNodeKey,
                    NULL_HASH};
 pub use repo::RepositoryId;
-pub use utils::percent_encode;
+pub use utils::{b85decode, b85encode, percent_encode};
 
 // Re-exports from mononoke-types. Eventually these should go away and everything should depend
 // directly on mononoke-types;
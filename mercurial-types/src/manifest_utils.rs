@@ -15,7 +15,7 @@ use futures::future::{self, Future};
 use futures::stream::{empty, once, Stream};
 use futures_ext::{select_all, BoxFuture, BoxStream, FutureExt, StreamExt};
 
-use super::{Entry, MPath, MPathElement, Manifest};
+use super::{Entry, MPath, MPathElement, Manifest, NarrowSpec};
 use super::manifest::{Content, EmptyManifest, Type};
 
 use errors::*;
@@ -271,6 +271,40 @@ pub fn visited_pruner() -> impl FnMut(&ChangedEntry) -> bool + Send + Clone + 's
     }
 }
 
+/// Prunes the directories that are outside of the narrowspec. Files are never pruned, combine it
+/// with `file_pruner` if they are not needed either.
+pub fn narrow_pruner(
+    narrowspec: Arc<NarrowSpec>,
+) -> impl FnMut(&ChangedEntry) -> bool + Send + Clone + 'static {
+    move |entry: &ChangedEntry| {
+        if !entry.status.is_tree() {
+            return true;
+        }
+        let name = match entry.status {
+            EntryStatus::Added(ref entry) | EntryStatus::Deleted(ref entry) => entry.get_name(),
+            EntryStatus::Modified { ref to_entry, .. } => to_entry.get_name(),
+        };
+        let path = MPath::join_element_opt(entry.dirname.as_ref(), name);
+        narrowspec.visit_dir(path.as_ref())
+    }
+}
+
+/// Prunes the directories that are `depth` or more levels below `rootpath`, so that together with
+/// `rootpath` itself `depth` levels of directories are visited. Files are never pruned.
+pub fn depth_pruner(
+    rootpath: Option<MPath>,
+    depth: usize,
+) -> impl FnMut(&ChangedEntry) -> bool + Send + Clone + 'static {
+    let root_components = rootpath.as_ref().map_or(0, MPath::num_components);
+    move |entry: &ChangedEntry| {
+        if !entry.status.is_tree() {
+            return true;
+        }
+        let dir_components = entry.dirname.as_ref().map_or(0, MPath::num_components);
+        dir_components + 1 - root_components < depth
+    }
+}
+
 pub fn and_pruner_combinator<P1, P2>(
     mut p1: P1,
     mut p2: P2,
@@ -324,6 +358,35 @@ where
         .flatten_stream()
 }
 
+/// Given a manifest and a list of base manifests, returns the entries of the manifest that are
+/// not in any of the bases, i.e. the entries that are missing on a client that has all the bases.
+/// An entry is returned as Modified (compared to one of the bases) if a base has an entry of the
+/// same kind at the same path, and as Added otherwise. Deleted entries are never returned.
+/// Directories are only descended into if they differ from the directories at the same path in
+/// all the bases, and are then compared with all of those directories.
+pub fn changed_entry_stream_multiway_with_pruner<TM, BM>(
+    to: &TM,
+    bases: &[BM],
+    path: Option<MPath>,
+    pruner: impl FnMut(&ChangedEntry) -> bool + Send + Clone + 'static,
+) -> BoxStream<ChangedEntry, Error>
+where
+    TM: Manifest,
+    BM: Manifest,
+{
+    let diff = diff_manifests_multiway(path, to, bases);
+    select_all(
+        diff.into_iter()
+            .filter({
+                let mut pruner = pruner.clone();
+                move |&(ref entry, _)| pruner(entry)
+            })
+            .map(|(entry, other_bases)| {
+                recursive_changed_entry_stream_multiway(entry, other_bases, pruner.clone())
+            }),
+    ).boxify()
+}
+
 /// Given a ChangedEntry, return a stream that consists of this entry, and all subentries
 /// that differ. If input isn't a tree, then a stream with a single entry is returned, otherwise
 /// subtrees are recursively compared.
@@ -394,6 +457,51 @@ fn recursive_changed_entry_stream(
     once(Ok(changed_entry)).chain(substream).boxify()
 }
 
+/// Multi-way version of `recursive_changed_entry_stream`. `other_bases` are the base entries at
+/// the path of `changed_entry` that it is compared with, besides the one in `changed_entry`.
+fn recursive_changed_entry_stream_multiway(
+    changed_entry: ChangedEntry,
+    other_bases: Vec<Box<Entry + Sync>>,
+    pruner: impl FnMut(&ChangedEntry) -> bool + Send + Clone + 'static,
+) -> BoxStream<ChangedEntry, Error> {
+    if !changed_entry.status.is_tree() {
+        return once(Ok(changed_entry)).boxify();
+    }
+
+    let (to_mf, base_mfs, path) = {
+        let (to_entry, from_entry) = match &changed_entry.status {
+            EntryStatus::Added(entry) => (entry, None),
+            EntryStatus::Modified {
+                to_entry,
+                from_entry,
+            } => (to_entry, Some(from_entry)),
+            EntryStatus::Deleted(_) => panic!("multi-way diff doesn't produce deleted entries"),
+        };
+
+        let to_mf = to_entry.get_content().map(get_tree_content);
+        let base_mfs: Vec<_> = from_entry
+            .into_iter()
+            .chain(other_bases.iter())
+            .map(|entry| entry.get_content().map(get_tree_content))
+            .collect();
+
+        let dirname = changed_entry.dirname.clone();
+        let entry_path = to_entry.get_name().cloned();
+        let path = MPath::join_element_opt(dirname.as_ref(), entry_path.as_ref());
+
+        (to_mf, future::join_all(base_mfs), path)
+    };
+
+    let substream = to_mf
+        .join(base_mfs)
+        .map(move |(to_mf, base_mfs)| {
+            changed_entry_stream_multiway_with_pruner(&to_mf, &base_mfs, path, pruner)
+        })
+        .flatten_stream();
+
+    once(Ok(changed_entry)).chain(substream).boxify()
+}
+
 /// Given an entry and path from the root of the repo to this entry, returns all subentries with
 /// their path from the root of the repo.
 /// For a non-tree entry returns a stream with a single (entry, path) pair.
@@ -442,6 +550,51 @@ where
     future::ok(diff_sorted_vecs(path, to_vec, from_vec)).boxify()
 }
 
+/// Multi-way difference between a manifest and base manifests, non-recursive. Returns the entries
+/// that are not in any of the bases, each with the base entries of the same kind at its path
+/// other than the one that is already in the ChangedEntry.
+fn diff_manifests_multiway<TM, BM>(
+    path: Option<MPath>,
+    to: &TM,
+    bases: &[BM],
+) -> Vec<(ChangedEntry, Vec<Box<Entry + Sync>>)>
+where
+    TM: Manifest,
+    BM: Manifest,
+{
+    to.list()
+        .filter_map(|to_entry| {
+            let mut same_kind_bases: Vec<_> = match to_entry.get_name() {
+                Some(name) => bases
+                    .iter()
+                    .filter_map(|base| base.lookup(name))
+                    .filter(|base_entry| {
+                        base_entry.get_type().is_tree() == to_entry.get_type().is_tree()
+                    })
+                    .collect(),
+                None => vec![],
+            };
+
+            if same_kind_bases
+                .iter()
+                .any(|base_entry| base_entry.get_hash() == to_entry.get_hash())
+            {
+                return None;
+            }
+
+            if same_kind_bases.is_empty() {
+                Some((ChangedEntry::new_added(path.clone(), to_entry), vec![]))
+            } else {
+                let from_entry = same_kind_bases.remove(0);
+                Some((
+                    ChangedEntry::new_modified(path.clone(), to_entry, from_entry),
+                    same_kind_bases,
+                ))
+            }
+        })
+        .collect()
+}
+
 /// Compares vectors of entries and returns the difference
 // TODO(stash): T25644857 this method is made public to make it possible to test it.
 // Otherwise we need create dependency to mercurial_types_mocks, which depends on mercurial_types.
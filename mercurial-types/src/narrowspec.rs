@@ -0,0 +1,244 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Narrowspec matching, as used by Mercurial narrow clones.
+//!
+//! A narrowspec is a list of include patterns and a list of exclude patterns. A file is part of
+//! the narrow clone if it matches at least one include pattern and none of the exclude patterns.
+//! Like Mercurial, only two kinds of patterns are supported:
+//! - `path:DIR` matches every file under DIR (`path:` and `path:.` match the whole repo)
+//! - `rootfilesin:DIR` matches the files directly in DIR, but not in its subdirectories
+
+use std::fmt;
+
+use mononoke_types::MPath;
+
+use errors::*;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Pattern {
+    /// Everything below the path, None is the root of the repo
+    Path(Option<MPath>),
+    /// Files directly in the path, None is the root of the repo
+    RootFilesIn(Option<MPath>),
+}
+
+impl Pattern {
+    fn parse(pattern: &[u8]) -> Result<Self> {
+        let (kind, path) = match pattern.iter().position(|c| *c == b':') {
+            Some(pos) => (&pattern[..pos], &pattern[pos + 1..]),
+            None => bail_err!(ErrorKind::InvalidNarrowPattern(
+                String::from_utf8_lossy(pattern).into_owned(),
+                "missing pattern kind".into(),
+            )),
+        };
+        let path = match path {
+            b"" | b"." => None,
+            path => Some(MPath::new(path).with_context(|_| {
+                ErrorKind::InvalidNarrowPattern(
+                    String::from_utf8_lossy(pattern).into_owned(),
+                    "invalid path".into(),
+                )
+            })?),
+        };
+        match kind {
+            b"path" => Ok(Pattern::Path(path)),
+            b"rootfilesin" => Ok(Pattern::RootFilesIn(path)),
+            _ => bail_err!(ErrorKind::InvalidNarrowPattern(
+                String::from_utf8_lossy(pattern).into_owned(),
+                "only path: and rootfilesin: patterns are supported".into(),
+            )),
+        }
+    }
+
+    fn matches_file(&self, path: &MPath) -> bool {
+        match self {
+            Pattern::Path(None) => true,
+            Pattern::Path(Some(prefix)) => prefix.is_prefix_of(path),
+            Pattern::RootFilesIn(dir) => path.split_dirname().0.as_ref() == dir.as_ref(),
+        }
+    }
+
+    /// Whether files under the directory can match this pattern
+    fn may_match_in_dir(&self, dir: Option<&MPath>) -> bool {
+        match (self, dir) {
+            (Pattern::Path(None), _) | (_, None) => true,
+            (Pattern::Path(Some(prefix)), Some(dir)) => {
+                prefix.is_prefix_of(dir) || dir.is_prefix_of(prefix)
+            }
+            (Pattern::RootFilesIn(None), Some(_)) => false,
+            (Pattern::RootFilesIn(Some(filesdir)), Some(dir)) => dir.is_prefix_of(filesdir),
+        }
+    }
+
+    /// Whether all files under the directory match this pattern
+    fn matches_whole_dir(&self, dir: Option<&MPath>) -> bool {
+        match (self, dir) {
+            (Pattern::Path(None), _) => true,
+            (Pattern::Path(Some(prefix)), Some(dir)) => prefix.is_prefix_of(dir),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Pattern::Path(path) => write!(f, "path:{}", MPath::display_opt(path.as_ref())),
+            Pattern::RootFilesIn(path) => {
+                write!(f, "rootfilesin:{}", MPath::display_opt(path.as_ref()))
+            }
+        }
+    }
+}
+
+/// Include and exclude patterns of a narrow clone
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct NarrowSpec {
+    includes: Vec<Pattern>,
+    excludes: Vec<Pattern>,
+}
+
+impl NarrowSpec {
+    /// Parse the patterns as sent by Mercurial clients. No include patterns means that the
+    /// whole repo is included.
+    pub fn new<I, E>(includes: I, excludes: E) -> Result<Self>
+    where
+        I: IntoIterator,
+        I::Item: AsRef<[u8]>,
+        E: IntoIterator,
+        E::Item: AsRef<[u8]>,
+    {
+        let mut includes = includes
+            .into_iter()
+            .map(|pattern| Pattern::parse(pattern.as_ref()))
+            .collect::<Result<Vec<_>>>()?;
+        if includes.is_empty() {
+            includes.push(Pattern::Path(None));
+        }
+        let excludes = excludes
+            .into_iter()
+            .map(|pattern| Pattern::parse(pattern.as_ref()))
+            .collect::<Result<_>>()?;
+        Ok(NarrowSpec { includes, excludes })
+    }
+
+    /// A narrowspec that includes the whole repo
+    pub fn everything() -> Self {
+        NarrowSpec {
+            includes: vec![Pattern::Path(None)],
+            excludes: vec![],
+        }
+    }
+
+    /// Whether the narrowspec includes the whole repo
+    pub fn is_everything(&self) -> bool {
+        self.excludes.is_empty() && self.includes.contains(&Pattern::Path(None))
+    }
+
+    /// Whether the file is part of the narrow clone
+    pub fn matches_file(&self, path: &MPath) -> bool {
+        self.includes
+            .iter()
+            .any(|pattern| pattern.matches_file(path))
+            && !self.excludes
+                .iter()
+                .any(|pattern| pattern.matches_file(path))
+    }
+
+    /// Whether the directory (None is the root of the repo) has to be visited to find all files
+    /// of the narrow clone. The directories leading to included paths are visited too, so that
+    /// their manifests are available to the client.
+    pub fn visit_dir(&self, dir: Option<&MPath>) -> bool {
+        self.includes
+            .iter()
+            .any(|pattern| pattern.may_match_in_dir(dir))
+            && !self.excludes
+                .iter()
+                .any(|pattern| pattern.matches_whole_dir(dir))
+    }
+}
+
+impl fmt::Display for NarrowSpec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let includes: Vec<_> = self.includes.iter().map(|p| p.to_string()).collect();
+        let excludes: Vec<_> = self.excludes.iter().map(|p| p.to_string()).collect();
+        write!(
+            f,
+            "include: [{}], exclude: [{}]",
+            includes.join(", "),
+            excludes.join(", ")
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn path(p: &str) -> MPath {
+        MPath::new(p).unwrap()
+    }
+
+    #[test]
+    fn test_everything() {
+        let spec = NarrowSpec::new(Vec::<&[u8]>::new(), Vec::<&[u8]>::new()).unwrap();
+        assert!(spec.is_everything());
+        assert_eq!(spec, NarrowSpec::everything());
+        assert!(spec.matches_file(&path("a/b/c")));
+        assert!(spec.visit_dir(None));
+        assert!(spec.visit_dir(Some(&path("a/b"))));
+
+        let spec = NarrowSpec::new(vec!["path:."], Vec::<&[u8]>::new()).unwrap();
+        assert!(spec.is_everything());
+    }
+
+    #[test]
+    fn test_path_pattern() {
+        let spec = NarrowSpec::new(vec!["path:foo/bar"], vec!["path:foo/bar/baz"]).unwrap();
+        assert!(!spec.is_everything());
+
+        assert!(spec.matches_file(&path("foo/bar/file")));
+        assert!(spec.matches_file(&path("foo/bar/dir/file")));
+        assert!(!spec.matches_file(&path("foo/bar/baz/file")));
+        assert!(!spec.matches_file(&path("foo/file")));
+        assert!(!spec.matches_file(&path("foo/barbaz/file")));
+        assert!(!spec.matches_file(&path("other")));
+
+        assert!(spec.visit_dir(None));
+        assert!(spec.visit_dir(Some(&path("foo"))));
+        assert!(spec.visit_dir(Some(&path("foo/bar"))));
+        assert!(spec.visit_dir(Some(&path("foo/bar/dir"))));
+        assert!(!spec.visit_dir(Some(&path("foo/bar/baz"))));
+        assert!(!spec.visit_dir(Some(&path("foo/bar/baz/dir"))));
+        assert!(!spec.visit_dir(Some(&path("foo/other"))));
+        assert!(!spec.visit_dir(Some(&path("other"))));
+    }
+
+    #[test]
+    fn test_rootfilesin_pattern() {
+        let spec = NarrowSpec::new(vec!["rootfilesin:foo", "rootfilesin:."], vec![] as Vec<&str>)
+            .unwrap();
+
+        assert!(spec.matches_file(&path("file")));
+        assert!(spec.matches_file(&path("foo/file")));
+        assert!(!spec.matches_file(&path("foo/dir/file")));
+        assert!(!spec.matches_file(&path("bar/file")));
+
+        assert!(spec.visit_dir(None));
+        assert!(spec.visit_dir(Some(&path("foo"))));
+        assert!(!spec.visit_dir(Some(&path("foo/dir"))));
+        assert!(!spec.visit_dir(Some(&path("bar"))));
+    }
+
+    #[test]
+    fn test_invalid_patterns() {
+        assert!(NarrowSpec::new(vec!["foo"], vec![] as Vec<&str>).is_err());
+        assert!(NarrowSpec::new(vec!["glob:foo/*"], vec![] as Vec<&str>).is_err());
+        assert!(NarrowSpec::new(vec!["path:foo"], vec!["re:.*"]).is_err());
+        assert!(NarrowSpec::new(vec!["path:foo\nbar"], vec![] as Vec<&str>).is_err());
+    }
+}
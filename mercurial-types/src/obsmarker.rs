@@ -0,0 +1,583 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Obsolescence markers
+//!
+//! A marker records that a changeset (the predecessor) was rewritten into other changesets (its
+//! successors), for instance by an amend or a rebase. A marker without successors is a prune
+//! marker, which records that the predecessor was removed. Mercurial exchanges markers in two
+//! binary formats, version 0 in the `obsolete` pushkey namespace and usually version 1 in
+//! bundle2 `obsmarkers` parts.
+
+use std::io::Cursor;
+use std::str;
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+use errors::*;
+use hash::Sha1;
+use nodehash::{HgChangesetId, HgNodeHash};
+use utils::b85encode;
+
+/// Size of a node in a marker. Markers flagged as using SHA-256 nodes are not supported.
+const NODE_SIZE: usize = 20;
+
+/// Marker flag of nodes that are SHA-256 hashes
+const USING_SHA256: u16 = 2;
+
+/// Fixed part of a version 0 marker: number of successors (u8), size of the metadata (u32),
+/// flags (u8) and predecessor
+const FM0_FIXED_SIZE: usize = 1 + 4 + 1 + NODE_SIZE;
+
+/// Fixed part of a version 1 marker: total size (u32), date (f64), timezone in minutes (i16),
+/// flags (u16), number of successors (u8), number of parents (u8), number of metadata pairs (u8)
+/// and predecessor
+const FM1_FIXED_SIZE: usize = 4 + 8 + 2 + 2 + 1 + 1 + 1 + NODE_SIZE;
+
+/// Number of parents of version 1 markers that don't record parents
+const FM1_NO_PARENTS: u8 = 3;
+
+/// Largest version 0 payload of a single key of the `obsolete` pushkey namespace, the same as
+/// Mercurial uses
+const PUSHKEY_MAX_PAYLOAD: usize = 5300;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub enum ObsmarkersVersion {
+    V0,
+    V1,
+}
+
+impl ObsmarkersVersion {
+    /// Parse the header byte of a marker stream
+    pub fn from_byte(byte: u8) -> Result<Self> {
+        match byte {
+            0 => Ok(ObsmarkersVersion::V0),
+            1 => Ok(ObsmarkersVersion::V1),
+            _ => bail_err!(ErrorKind::InvalidObsmarker(format!(
+                "unknown format version {}",
+                byte
+            ))),
+        }
+    }
+
+    pub fn to_byte(&self) -> u8 {
+        match self {
+            &ObsmarkersVersion::V0 => 0,
+            &ObsmarkersVersion::V1 => 1,
+        }
+    }
+
+    /// Parse a version as advertised in the `obsmarkers` bundle2 capability, like "V1"
+    pub fn from_capability(cap: &str) -> Option<Self> {
+        match cap {
+            "V0" => Some(ObsmarkersVersion::V0),
+            "V1" => Some(ObsmarkersVersion::V1),
+            _ => None,
+        }
+    }
+
+    /// Size of the marker at the start of `data`, or None if `data` is too short to tell
+    pub fn marker_size(&self, data: &[u8]) -> Option<usize> {
+        match self {
+            &ObsmarkersVersion::V0 => {
+                if data.len() < FM0_FIXED_SIZE {
+                    return None;
+                }
+                let mut cursor = Cursor::new(data);
+                let numsuc = cursor.get_u8() as usize;
+                let mdsize = cursor.get_u32_be() as usize;
+                Some(FM0_FIXED_SIZE + numsuc * NODE_SIZE + mdsize)
+            }
+            &ObsmarkersVersion::V1 => {
+                if data.len() < 4 {
+                    return None;
+                }
+                Some(Cursor::new(data).get_u32_be() as usize)
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct HgObsmarker {
+    pub predecessor: HgChangesetId,
+    pub successors: Vec<HgChangesetId>,
+    /// Parents of the predecessor. Only prune markers record them, so that they are exchanged
+    /// along with the parents.
+    pub parents: Option<Vec<HgChangesetId>>,
+    pub flags: u16,
+    pub metadata: Vec<(Bytes, Bytes)>,
+    /// Seconds since the epoch
+    pub date: f64,
+    /// Offset from UTC in seconds, as in Mercurial dates
+    pub tz_offset: i32,
+}
+
+impl HgObsmarker {
+    /// A marker without successors, which records that `predecessor` was removed
+    pub fn is_prune(&self) -> bool {
+        self.successors.is_empty()
+    }
+
+    /// Hash of the version 1 encoding, which identifies a marker
+    pub fn id(&self) -> Result<Sha1> {
+        Ok(Sha1::from(self.encode(ObsmarkersVersion::V1)?.as_ref()))
+    }
+
+    pub fn encode(&self, version: ObsmarkersVersion) -> Result<Bytes> {
+        if self.flags & USING_SHA256 != 0 {
+            bail_err!(ErrorKind::InvalidObsmarker(
+                "SHA-256 nodes are not supported".into()
+            ));
+        }
+        match version {
+            ObsmarkersVersion::V0 => self.encode_fm0(),
+            ObsmarkersVersion::V1 => self.encode_fm1(),
+        }
+    }
+
+    /// Decode a single marker that takes the whole of `data`
+    pub fn decode(version: ObsmarkersVersion, data: &[u8]) -> Result<Self> {
+        if version.marker_size(data) != Some(data.len()) {
+            bail_err!(ErrorKind::InvalidObsmarker(format!(
+                "marker size doesn't match its {} bytes",
+                data.len()
+            )));
+        }
+        match version {
+            ObsmarkersVersion::V0 => Self::decode_fm0(data),
+            ObsmarkersVersion::V1 => Self::decode_fm1(data),
+        }
+    }
+
+    fn encode_fm0(&self) -> Result<Bytes> {
+        if self.flags > u8::max_value() as u16 {
+            bail_err!(ErrorKind::InvalidObsmarker(format!(
+                "flags {} don't fit in a version 0 marker",
+                self.flags
+            )));
+        }
+        if self.successors.len() > u8::max_value() as usize {
+            bail_err!(ErrorKind::InvalidObsmarker("too many successors".into()));
+        }
+
+        // Version 0 markers keep the date and the parents in the metadata
+        let mut metadata: Vec<(Bytes, Bytes)> = self.metadata.clone();
+        metadata.push((
+            Bytes::from("date"),
+            Bytes::from(format!("{:?} {}", self.date, self.tz_offset)),
+        ));
+        if let Some(ref parents) = self.parents {
+            if parents.is_empty() {
+                metadata.push((Bytes::from("p0"), Bytes::new()));
+            }
+            for (i, parent) in parents.iter().enumerate() {
+                metadata.push((
+                    Bytes::from(format!("p{}", i + 1)),
+                    Bytes::from(parent.to_hex().as_str()),
+                ));
+            }
+        }
+        metadata.sort();
+
+        let mut encoded_metadata = BytesMut::new();
+        for (i, &(ref key, ref value)) in metadata.iter().enumerate() {
+            if key.contains(&b':') || key.contains(&0) || value.contains(&0) {
+                bail_err!(ErrorKind::InvalidObsmarker(
+                    "metadata can't be encoded in a version 0 marker".into()
+                ));
+            }
+            if i > 0 {
+                encoded_metadata.extend_from_slice(b"\0");
+            }
+            encoded_metadata.extend_from_slice(key);
+            encoded_metadata.extend_from_slice(b":");
+            encoded_metadata.extend_from_slice(value);
+        }
+
+        let size = FM0_FIXED_SIZE + self.successors.len() * NODE_SIZE + encoded_metadata.len();
+        let mut out = BytesMut::with_capacity(size);
+        out.put_u8(self.successors.len() as u8);
+        out.put_u32_be(encoded_metadata.len() as u32);
+        out.put_u8(self.flags as u8);
+        out.put_slice(self.predecessor.as_nodehash().as_bytes());
+        for successor in &self.successors {
+            out.put_slice(successor.as_nodehash().as_bytes());
+        }
+        out.put_slice(&encoded_metadata);
+        Ok(out.freeze())
+    }
+
+    fn decode_fm0(data: &[u8]) -> Result<Self> {
+        let mut cursor = Cursor::new(data);
+        let numsuc = cursor.get_u8() as usize;
+        let mdsize = cursor.get_u32_be() as usize;
+        let flags = cursor.get_u8() as u16;
+        let predecessor = read_node(&mut cursor)?;
+        let successors = (0..numsuc)
+            .map(|_| read_node(&mut cursor))
+            .collect::<Result<Vec<_>>>()?;
+        let start = cursor.position() as usize;
+        let encoded_metadata = &data[start..start + mdsize];
+
+        let mut date = (0.0, 0);
+        let (mut p1, mut p2) = (None, None);
+        let mut no_parents = false;
+        let mut metadata = vec![];
+        for entry in encoded_metadata.split(|b| *b == 0) {
+            if entry.is_empty() {
+                continue;
+            }
+            let colon = match entry.iter().position(|b| *b == b':') {
+                Some(colon) => colon,
+                None => bail_err!(ErrorKind::InvalidObsmarker(
+                    "metadata entry without a key".into()
+                )),
+            };
+            let (key, value) = (&entry[..colon], &entry[colon + 1..]);
+            match key {
+                b"date" => date = parse_fm0_date(value),
+                b"p0" => no_parents = true,
+                b"p1" => p1 = parse_fm0_parent(value),
+                b"p2" => p2 = parse_fm0_parent(value),
+                _ => metadata.push((Bytes::from(key), Bytes::from(value))),
+            }
+        }
+        metadata.sort();
+
+        // Like Mercurial, drop the parents if any of them isn't a valid node
+        let parents = match (p1, p2) {
+            (Some(p1), Some(p2)) => Some(vec![p1, p2]),
+            (Some(p1), None) => Some(vec![p1]),
+            (None, None) if no_parents => Some(vec![]),
+            _ => None,
+        };
+
+        Ok(HgObsmarker {
+            predecessor,
+            successors,
+            parents,
+            flags,
+            metadata,
+            date: date.0,
+            tz_offset: date.1,
+        })
+    }
+
+    fn encode_fm1(&self) -> Result<Bytes> {
+        let numpar = match self.parents {
+            None => FM1_NO_PARENTS,
+            Some(ref parents) if parents.len() <= 2 => parents.len() as u8,
+            Some(_) => bail_err!(ErrorKind::InvalidObsmarker("too many parents".into())),
+        };
+        if self.successors.len() > u8::max_value() as usize
+            || self.metadata.len() > u8::max_value() as usize
+        {
+            bail_err!(ErrorKind::InvalidObsmarker(
+                "too many successors or metadata entries".into()
+            ));
+        }
+        if self.metadata.iter().any(|&(ref key, ref value)| {
+            key.len() > u8::max_value() as usize || value.len() > u8::max_value() as usize
+        }) {
+            bail_err!(ErrorKind::InvalidObsmarker(
+                "metadata entry longer than 255 bytes".into()
+            ));
+        }
+
+        let parents: &[HgChangesetId] = match self.parents {
+            Some(ref parents) => parents,
+            None => &[],
+        };
+        let size = FM1_FIXED_SIZE + (self.successors.len() + parents.len()) * NODE_SIZE
+            + self.metadata
+                .iter()
+                .map(|&(ref key, ref value)| 2 + key.len() + value.len())
+                .sum::<usize>();
+
+        let mut out = BytesMut::with_capacity(size);
+        out.put_u32_be(size as u32);
+        out.put_f64_be(self.date);
+        // The timezone is stored in minutes, rounded down like Python does
+        out.put_i16_be((self.tz_offset as f64 / 60.0).floor() as i16);
+        out.put_u16_be(self.flags);
+        out.put_u8(self.successors.len() as u8);
+        out.put_u8(numpar);
+        out.put_u8(self.metadata.len() as u8);
+        out.put_slice(self.predecessor.as_nodehash().as_bytes());
+        for node in self.successors.iter().chain(parents.iter()) {
+            out.put_slice(node.as_nodehash().as_bytes());
+        }
+        for &(ref key, ref value) in &self.metadata {
+            out.put_u8(key.len() as u8);
+            out.put_u8(value.len() as u8);
+        }
+        for &(ref key, ref value) in &self.metadata {
+            out.put_slice(key);
+            out.put_slice(value);
+        }
+        Ok(out.freeze())
+    }
+
+    fn decode_fm1(data: &[u8]) -> Result<Self> {
+        if data.len() < FM1_FIXED_SIZE {
+            bail_err!(ErrorKind::InvalidObsmarker(
+                "truncated version 1 marker".into()
+            ));
+        }
+        let mut cursor = Cursor::new(data);
+        let _size = cursor.get_u32_be();
+        let date = cursor.get_f64_be();
+        let tz_minutes = cursor.get_i16_be();
+        let flags = cursor.get_u16_be();
+        let numsuc = cursor.get_u8() as usize;
+        let numpar = cursor.get_u8();
+        let nummeta = cursor.get_u8() as usize;
+        if flags & USING_SHA256 != 0 {
+            bail_err!(ErrorKind::InvalidObsmarker(
+                "SHA-256 nodes are not supported".into()
+            ));
+        }
+
+        let fixed_size = |numpar: usize| {
+            FM1_FIXED_SIZE + (numsuc + numpar) * NODE_SIZE + nummeta * 2
+        };
+        let numpar_nodes = if numpar == FM1_NO_PARENTS {
+            0
+        } else {
+            numpar as usize
+        };
+        if numpar > FM1_NO_PARENTS || data.len() < fixed_size(numpar_nodes) {
+            bail_err!(ErrorKind::InvalidObsmarker(
+                "truncated version 1 marker".into()
+            ));
+        }
+
+        let predecessor = read_node(&mut cursor)?;
+        let successors = (0..numsuc)
+            .map(|_| read_node(&mut cursor))
+            .collect::<Result<Vec<_>>>()?;
+        let parents = if numpar == FM1_NO_PARENTS {
+            None
+        } else {
+            Some((0..numpar)
+                .map(|_| read_node(&mut cursor))
+                .collect::<Result<Vec<_>>>()?)
+        };
+        let lengths: Vec<_> = (0..nummeta)
+            .map(|_| (cursor.get_u8() as usize, cursor.get_u8() as usize))
+            .collect();
+        let mut offset = cursor.position() as usize;
+        let mut metadata = Vec::with_capacity(nummeta);
+        for (key_len, value_len) in lengths {
+            if data.len() < offset + key_len + value_len {
+                bail_err!(ErrorKind::InvalidObsmarker(
+                    "truncated version 1 marker metadata".into()
+                ));
+            }
+            let key = Bytes::from(&data[offset..offset + key_len]);
+            offset += key_len;
+            let value = Bytes::from(&data[offset..offset + value_len]);
+            offset += value_len;
+            metadata.push((key, value));
+        }
+
+        Ok(HgObsmarker {
+            predecessor,
+            successors,
+            parents,
+            flags,
+            metadata,
+            date,
+            tz_offset: tz_minutes as i32 * 60,
+        })
+    }
+}
+
+fn read_node(cursor: &mut Cursor<&[u8]>) -> Result<HgChangesetId> {
+    let mut node = [0u8; NODE_SIZE];
+    cursor.copy_to_slice(&mut node);
+    Ok(HgChangesetId::new(HgNodeHash::from_bytes(&node)?))
+}
+
+/// Parse a date stored as "<seconds> <offset>", which is (0, 0) if it's malformed
+fn parse_fm0_date(value: &[u8]) -> (f64, i32) {
+    let parsed = str::from_utf8(value).ok().and_then(|value| {
+        let mut parts = value.splitn(2, ' ');
+        let date = parts.next()?.parse().ok()?;
+        let tz_offset = parts.next()?.parse().ok()?;
+        Some((date, tz_offset))
+    });
+    parsed.unwrap_or((0.0, 0))
+}
+
+fn parse_fm0_parent(value: &[u8]) -> Option<HgChangesetId> {
+    match str::from_utf8(value) {
+        Ok(hex) if hex.len() == NODE_SIZE * 2 => hex.parse().ok(),
+        _ => None,
+    }
+}
+
+/// Encode markers as a stream of `version`, which starts with the version byte
+pub fn encode_markers(version: ObsmarkersVersion, markers: &[HgObsmarker]) -> Result<Bytes> {
+    let mut out = BytesMut::new();
+    out.put_u8(version.to_byte());
+    for marker in markers {
+        out.extend_from_slice(&marker.encode(version)?);
+    }
+    Ok(out.freeze())
+}
+
+/// Decode a stream of markers that starts with the version byte
+pub fn decode_markers(data: &[u8]) -> Result<Vec<HgObsmarker>> {
+    if data.is_empty() {
+        return Ok(vec![]);
+    }
+    let version = ObsmarkersVersion::from_byte(data[0])?;
+    let mut data = &data[1..];
+    let mut markers = vec![];
+    while !data.is_empty() {
+        let size = match version.marker_size(data) {
+            Some(size) if size <= data.len() => size,
+            _ => bail_err!(ErrorKind::InvalidObsmarker("truncated marker stream".into())),
+        };
+        markers.push(HgObsmarker::decode(version, &data[..size])?);
+        data = &data[size..];
+    }
+    Ok(markers)
+}
+
+/// Split markers into the keys and values of the `obsolete` pushkey namespace, which are named
+/// "dump0", "dump1", ... and hold base85-encoded version 0 marker streams
+pub fn encode_pushkey_dumps(markers: &[HgObsmarker]) -> Result<Vec<(String, String)>> {
+    let mut parts: Vec<Vec<Bytes>> = vec![];
+    let mut current_len = 0;
+    for marker in markers {
+        let encoded = marker.encode(ObsmarkersVersion::V0)?;
+        if parts.is_empty() || current_len + encoded.len() > PUSHKEY_MAX_PAYLOAD {
+            parts.push(vec![]);
+            current_len = 0;
+        }
+        current_len += encoded.len();
+        parts.last_mut().expect("just pushed a part").push(encoded);
+    }
+
+    Ok(parts
+        .into_iter()
+        .rev()
+        .enumerate()
+        .map(|(idx, part)| {
+            let mut data = vec![ObsmarkersVersion::V0.to_byte()];
+            for marker in part {
+                data.extend_from_slice(&marker);
+            }
+            (format!("dump{}", idx), b85encode(&data))
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use utils::b85decode;
+
+    fn node(byte: u8) -> HgChangesetId {
+        HgChangesetId::new(HgNodeHash::from_bytes(&[byte; 20]).unwrap())
+    }
+
+    fn amend_marker() -> HgObsmarker {
+        HgObsmarker {
+            predecessor: node(1),
+            successors: vec![node(2)],
+            parents: None,
+            flags: 0,
+            metadata: vec![
+                (Bytes::from("operation"), Bytes::from("amend")),
+                (Bytes::from("user"), Bytes::from("test")),
+            ],
+            date: 1500000000.5,
+            tz_offset: -7200,
+        }
+    }
+
+    fn prune_marker() -> HgObsmarker {
+        HgObsmarker {
+            predecessor: node(3),
+            successors: vec![],
+            parents: Some(vec![node(4)]),
+            flags: 0,
+            metadata: vec![(Bytes::from("user"), Bytes::from("test"))],
+            date: 0.0,
+            tz_offset: 0,
+        }
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let markers = vec![amend_marker(), prune_marker()];
+        for &version in &[ObsmarkersVersion::V0, ObsmarkersVersion::V1] {
+            let encoded = encode_markers(version, &markers).unwrap();
+            assert_eq!(encoded[0], version.to_byte());
+            assert_eq!(decode_markers(&encoded).unwrap(), markers);
+        }
+    }
+
+    #[test]
+    fn test_fm1_layout() {
+        let encoded = prune_marker().encode(ObsmarkersVersion::V1).unwrap();
+        assert_eq!(encoded.len(), FM1_FIXED_SIZE + 20 + 2 + 4 + 4);
+        assert_eq!(
+            ObsmarkersVersion::V1.marker_size(&encoded),
+            Some(encoded.len())
+        );
+        // One parent, one metadata pair
+        assert_eq!(&encoded[17..19], &[1, 1][..]);
+    }
+
+    #[test]
+    fn test_truncated() {
+        let encoded = encode_markers(ObsmarkersVersion::V1, &[amend_marker()]).unwrap();
+        assert!(decode_markers(&encoded[..encoded.len() - 1]).is_err());
+        assert!(decode_markers(&[2]).is_err());
+    }
+
+    #[test]
+    fn test_truncated_fm1_header() {
+        // The size field is the only part of the fixed header that is there
+        for size in 4..FM1_FIXED_SIZE {
+            let mut data = vec![1];
+            data.extend_from_slice(&[0, 0, 0, size as u8]);
+            data.resize(1 + size, 0);
+            assert!(decode_markers(&data).is_err());
+            assert!(HgObsmarker::decode(ObsmarkersVersion::V1, &data[1..]).is_err());
+        }
+    }
+
+    #[test]
+    fn test_pushkey_dumps() {
+        let markers: Vec<_> = (0..200).map(|_| amend_marker()).collect();
+        let dumps = encode_pushkey_dumps(&markers).unwrap();
+        assert!(dumps.len() > 1);
+
+        let mut decoded = vec![];
+        for (idx, &(ref key, ref value)) in dumps.iter().enumerate() {
+            assert_eq!(key, &format!("dump{}", idx));
+            decoded.extend(decode_markers(&b85decode(value).unwrap()).unwrap());
+        }
+        assert_eq!(decoded, markers);
+    }
+
+    #[test]
+    fn test_b85() {
+        for input in &[&b""[..], b"a", b"ab", b"abc", b"abcd", b"abcde\xff\x00"] {
+            assert_eq!(&b85decode(&b85encode(input)).unwrap()[..], *input);
+        }
+        // Matches Mercurial's base85.b85encode
+        assert_eq!(b85encode(b"hello"), "Xk~0{Zv");
+        assert!(b85decode("abc\"").is_err());
+    }
+}
@@ -4,8 +4,11 @@
 // This software may be used and distributed according to the terms of the
 // GNU General Public License version 2 or any later version.
 
+use bytes::{BigEndian, ByteOrder};
 use url::percent_encoding::{self, USERINFO_ENCODE_SET};
 
+use errors::*;
+
 define_encode_set! {
     // Python urllib also encodes ','
     pub HG_ENCODE_SET = [USERINFO_ENCODE_SET] | {','}
@@ -17,3 +20,61 @@ pub fn percent_encode(input: &str) -> String {
     // one.
     percent_encoding::utf8_percent_encode(input, HG_ENCODE_SET).collect::<String>()
 }
+
+/// Alphabet of Mercurial's base85 encoding, which is the one of git and RFC 1924
+const B85_CHARS: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ\
+                           abcdefghijklmnopqrstuvwxyz!#$%&()*+-;<=>?@^_`{|}~";
+
+/// Encode `input` in base85 the way Mercurial does, without padding the output
+pub fn b85encode(input: &[u8]) -> String {
+    let mut out = String::with_capacity((input.len() + 3) / 4 * 5);
+    for chunk in input.chunks(4) {
+        let mut word = [0u8; 4];
+        word[..chunk.len()].copy_from_slice(chunk);
+        let mut acc = BigEndian::read_u32(&word);
+        let mut digits = [0u8; 5];
+        for digit in digits.iter_mut().rev() {
+            *digit = B85_CHARS[(acc % 85) as usize];
+            acc /= 85;
+        }
+        // A partial chunk of n bytes only needs n + 1 digits
+        for &digit in &digits[..chunk.len() + 1] {
+            out.push(digit as char);
+        }
+    }
+    out
+}
+
+/// Decode the output of `b85encode`
+pub fn b85decode(input: &str) -> Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(input.len() / 5 * 4 + 4);
+    for chunk in input.as_bytes().chunks(5) {
+        if chunk.len() == 1 {
+            bail_err!(ErrorKind::InvalidBase85(
+                "trailing chunk of a single digit".into()
+            ));
+        }
+        let mut acc: u64 = 0;
+        for &c in chunk {
+            match B85_CHARS.iter().position(|&d| d == c) {
+                Some(value) => acc = acc * 85 + value as u64,
+                None => bail_err!(ErrorKind::InvalidBase85(format!(
+                    "invalid character {:?}",
+                    c as char
+                ))),
+            }
+        }
+        // Partial chunks are padded with the highest digit, so that truncating the decoded word
+        // gives back the bytes that were encoded
+        for _ in chunk.len()..5 {
+            acc = acc * 85 + 84;
+        }
+        if acc > u32::max_value() as u64 {
+            bail_err!(ErrorKind::InvalidBase85("overflow in chunk".into()));
+        }
+        let mut word = [0u8; 4];
+        BigEndian::write_u32(&mut word, acc as u32);
+        out.extend_from_slice(&word[..chunk.len() - 1]);
+    }
+    Ok(out)
+}
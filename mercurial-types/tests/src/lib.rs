@@ -27,13 +27,15 @@ use blobrepo::BlobRepo;
 use futures::{Future, Stream};
 use futures::executor::spawn;
 use futures_ext::select_all;
-use mercurial_types::{Changeset, Entry, FileType, MPath, MPathElement, Manifest, RepoPath, Type,
-                      NULL_HASH};
+use mercurial_types::{Changeset, Entry, FileType, MPath, MPathElement, Manifest, NarrowSpec,
+                      RepoPath, Type, NULL_HASH};
 use mercurial_types::manifest::{Content, EmptyManifest};
 use mercurial_types::manifest_utils::{and_pruner_combinator, changed_entry_stream,
-                                      changed_entry_stream_with_pruner, diff_sorted_vecs,
-                                      file_pruner, recursive_entry_stream, visited_pruner,
-                                      ChangedEntry, EntryStatus};
+                                      changed_entry_stream_multiway_with_pruner,
+                                      changed_entry_stream_with_pruner, depth_pruner,
+                                      diff_sorted_vecs, file_pruner, narrow_pruner,
+                                      recursive_entry_stream, visited_pruner, ChangedEntry,
+                                      EntryStatus};
 use mercurial_types::nodehash::{HgChangesetId, HgEntryId, HgNodeHash};
 use mercurial_types_mocks::manifest::{ContentFactory, MockEntry, MockManifest};
 use mercurial_types_mocks::nodehash;
@@ -591,6 +593,138 @@ fn test_recursive_changed_entry_prune() {
     }).expect("test failed")
 }
 
+#[test]
+fn test_recursive_changed_entry_prune_narrow() {
+    async_unit::tokio_unit_test(|| -> Result<_, !> {
+        let repo = Arc::new(many_files_dirs::getrepo(None));
+        let main_hash = HgNodeHash::from_str("a6cb7dddec32acaf9a28db46cdb3061682155531").unwrap();
+        let base_hash = HgNodeHash::from_str("473b2e715e0df6b2316010908879a3c78e275dd9").unwrap();
+
+        // Directories leading to the included path are visited, other directories are pruned.
+        // Files are not pruned.
+        let narrowspec = NarrowSpec::new(
+            vec!["path:dir1/subdir1/subsubdir2"],
+            Vec::<&str>::new(),
+        ).unwrap();
+
+        let expected_added = vec!["dir1"];
+        let expected_deleted = vec![
+            "dir1",
+            "dir1/file_1_in_dir1",
+            "dir1/file_2_in_dir1",
+            "dir1/subdir1",
+            "dir1/subdir1/file_1",
+            "dir1/subdir1/subsubdir2",
+            "dir1/subdir1/subsubdir2/file_1",
+            "dir1/subdir1/subsubdir2/file_2",
+        ];
+        do_check_with_pruner(
+            repo,
+            main_hash,
+            base_hash,
+            expected_added,
+            expected_deleted,
+            vec![],
+            narrow_pruner(Arc::new(narrowspec)),
+        );
+
+        Ok(())
+    }).expect("test failed")
+}
+
+fn find_changed_entries_multiway(
+    repo: Arc<BlobRepo>,
+    main_hash: HgNodeHash,
+    base_hashes: Vec<HgNodeHash>,
+    pruner: impl FnMut(&ChangedEntry) -> bool + Send + Clone + 'static,
+) -> Vec<ChangedEntry> {
+    let manifest = get_root_manifest(repo.clone(), &HgChangesetId::new(main_hash));
+    let base_manifests: Vec<_> = base_hashes
+        .into_iter()
+        .map(|base_hash| get_root_manifest(repo.clone(), &HgChangesetId::new(base_hash)))
+        .collect();
+
+    let stream =
+        changed_entry_stream_multiway_with_pruner(&manifest, &base_manifests, None, pruner);
+    spawn(stream.collect()).wait_future().unwrap()
+}
+
+#[test]
+fn test_changed_entry_stream_multiway() {
+    async_unit::tokio_unit_test(|| -> Result<_, !> {
+        let repo = Arc::new(many_files_dirs::getrepo(None));
+        let main_hash = HgNodeHash::from_str("473b2e715e0df6b2316010908879a3c78e275dd9").unwrap();
+        let base_hash_1 = HgNodeHash::from_str("5a28e25f924a5d209b82ce0713d8d83e68982bc8").unwrap();
+        let base_hash_2 = HgNodeHash::from_str("ecafdc4a4b6748b7a7215c6995f14c837dc1ebec").unwrap();
+
+        // Everything that is in either of the bases is subtracted, so the result is the same as
+        // the diff with the closest base, minus the deleted entries.
+        let expected_added = vec![
+            "dir1/subdir1/subsubdir1",
+            "dir1/subdir1/subsubdir1/file_1",
+            "dir1/subdir1/subsubdir2",
+            "dir1/subdir1/subsubdir2/file_1",
+            "dir1/subdir1/subsubdir2/file_2",
+        ];
+        let expected_modified = vec!["dir1", "dir1/subdir1"];
+
+        for base_hashes in vec![vec![base_hash_1, base_hash_2], vec![base_hash_2, base_hash_1]] {
+            let res = find_changed_entries_multiway(
+                repo.clone(),
+                main_hash,
+                base_hashes,
+                |_| true,
+            );
+            check_changed_paths(
+                res,
+                expected_added.clone(),
+                vec![],
+                expected_modified.clone(),
+            );
+        }
+
+        // Without bases everything is added
+        let res = find_changed_entries_multiway(
+            repo.clone(),
+            main_hash,
+            vec![],
+            &file_pruner,
+        );
+        let expected_added = vec![
+            "dir1",
+            "dir1/subdir1",
+            "dir1/subdir1/subsubdir1",
+            "dir1/subdir1/subsubdir2",
+            "dir2",
+        ];
+        check_changed_paths(res, expected_added, vec![], vec![]);
+
+        Ok(())
+    }).expect("test failed")
+}
+
+#[test]
+fn test_depth_pruner() {
+    async_unit::tokio_unit_test(|| -> Result<_, !> {
+        let repo = Arc::new(many_files_dirs::getrepo(None));
+        let main_hash = HgNodeHash::from_str("473b2e715e0df6b2316010908879a3c78e275dd9").unwrap();
+
+        let pruner = and_pruner_combinator(&file_pruner, depth_pruner(None, 1));
+        let res = find_changed_entries_multiway(repo.clone(), main_hash, vec![], pruner);
+        check_changed_paths(res, vec![], vec![], vec![]);
+
+        let pruner = and_pruner_combinator(&file_pruner, depth_pruner(None, 2));
+        let res = find_changed_entries_multiway(repo.clone(), main_hash, vec![], pruner);
+        check_changed_paths(res, vec!["dir1", "dir2"], vec![], vec![]);
+
+        let pruner = and_pruner_combinator(&file_pruner, depth_pruner(None, 3));
+        let res = find_changed_entries_multiway(repo.clone(), main_hash, vec![], pruner);
+        check_changed_paths(res, vec!["dir1", "dir1/subdir1", "dir2"], vec![], vec![]);
+
+        Ok(())
+    }).expect("test failed")
+}
+
 #[test]
 fn test_recursive_changed_entry_prune_visited() {
     async_unit::tokio_unit_test(|| -> Result<_, !> {
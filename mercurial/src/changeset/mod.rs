@@ -18,6 +18,9 @@ use mononoke_types::DateTime;
 #[cfg(test)]
 mod test;
 
+/// Branch of changesets that don't have a `branch` extra
+pub const DEFAULT_BRANCH: &[u8] = b"default";
+
 // The `user` and `comments` fields are expected to be utf8 encoded, but
 // some older commits might be corrupted. We handle them as pure binary here
 // and higher levels can convert to utf8 as needed.
@@ -120,6 +123,14 @@ impl Extra {
         self.0.is_empty()
     }
 
+    /// Name of the named branch the changeset is on
+    pub fn branch(&self) -> &[u8] {
+        self.0
+            .get(&b"branch"[..])
+            .map(|branch| branch.as_slice())
+            .unwrap_or(DEFAULT_BRANCH)
+    }
+
     fn from_slice<S: AsRef<[u8]>>(s: Option<S>) -> Result<Extra> {
         let mut ret = BTreeMap::new();
 
@@ -318,6 +329,10 @@ impl RevlogChangeset {
         &self.extra.0
     }
 
+    pub fn branch(&self) -> &[u8] {
+        self.extra.branch()
+    }
+
     pub fn comments(&self) -> &[u8] {
         self.comments.as_ref()
     }
@@ -52,6 +52,8 @@ the user expected."#.into(),
         }
     );
 
+    assert_eq!(cset.branch(), b"stable");
+
     let csid: HgNodeHash = "526722d24ee5b3b860d4060e008219e083488356".parse().unwrap();
     let p1: HgNodeHash = "db5eb6a86179ce819db03da9ef2090b32f8e3fc4".parse().unwrap();
     let cset = RevlogChangeset::parse(
@@ -80,6 +82,7 @@ clean up html code for w3c validation
 "#.into(),
         }
     );
+    assert_eq!(cset.branch(), b"default");
 }
 
 #[test]
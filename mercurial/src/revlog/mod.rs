@@ -27,6 +27,7 @@ pub use mercurial_types::bdiff::{self, Delta};
 mod parser;
 mod revidx;
 mod lz4;
+mod writer;
 
 #[cfg(test)]
 mod test;
@@ -34,6 +35,7 @@ mod test;
 use self::parser::{Header, Version};
 pub use self::parser::Entry;
 pub use self::revidx::RevIdx;
+pub use self::writer::RevlogWriter;
 
 #[derive(Debug)]
 enum Datafile {
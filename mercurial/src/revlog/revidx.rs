@@ -50,6 +50,13 @@ impl From<u32> for RevIdx {
     }
 }
 
+// Convert a `RevIdx` back into a `u32`, e.g. to serialize it
+impl From<RevIdx> for u32 {
+    fn from(v: RevIdx) -> Self {
+        v.0
+    }
+}
+
 // Construct a `RevIdx` from a `usize`
 // Panics if the usize is larger than u32::MAX
 impl From<usize> for RevIdx {
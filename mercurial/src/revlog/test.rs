@@ -6,6 +6,8 @@
 
 use super::*;
 
+use mercurial_types::NULL_HASH;
+
 static EMPTY: &[u8] = include_bytes!("empty.i.bin");
 
 #[test]
@@ -17,3 +19,48 @@ fn emptyrev() {
 
     assert_eq!(node.size(), Some(0));
 }
+
+#[test]
+fn writer_roundtrip() {
+    let root = HgBlobNode::new(Bytes::from(&b"root"[..]), None, None);
+    let root_id = root.nodeid().expect("root has data");
+    // Long enough to be compressed
+    let child_content = Bytes::from(vec![b'a'; 1000]);
+    let child = HgBlobNode::new(child_content.clone(), Some(&root_id), None);
+    let child_id = child.nodeid().expect("child has data");
+    let empty = HgBlobNode::new(Bytes::new(), Some(&root_id), Some(&child_id));
+    let empty_id = empty.nodeid().expect("empty has data");
+
+    let mut writer = RevlogWriter::new();
+    writer
+        .add_revision(&root_id, None, None, RevIdx::zero(), b"root")
+        .expect("failed to add root");
+    writer
+        .add_revision(&child_id, Some(&root_id), None, RevIdx::from(1u32), &child_content)
+        .expect("failed to add child");
+    writer
+        .add_revision(&empty_id, Some(&root_id), Some(&child_id), RevIdx::from(2u32), b"")
+        .expect("failed to add empty");
+    // Parents have to be added first
+    assert!(
+        writer
+            .add_revision(&NULL_HASH, Some(&NULL_HASH), None, RevIdx::zero(), b"")
+            .is_err()
+    );
+
+    let (idx, data) = writer.into_parts();
+    let revlog = Revlog::new(idx, Some(data)).expect("construction failed");
+
+    assert_eq!(revlog.get_rev_by_nodeid(&root_id).unwrap(), root);
+    assert_eq!(revlog.get_rev_by_nodeid(&child_id).unwrap(), child);
+    assert_eq!(revlog.get_rev_by_nodeid(&empty_id).unwrap(), empty);
+
+    let entry = revlog.get_entry(RevIdx::from(2u32)).unwrap();
+    assert_eq!(entry.p1, Some(RevIdx::zero()));
+    assert_eq!(entry.p2, Some(RevIdx::from(1u32)));
+    assert_eq!(entry.linkrev, RevIdx::from(2u32));
+    assert_eq!(
+        revlog.get_heads().unwrap(),
+        vec![empty_id].into_iter().collect()
+    );
+}
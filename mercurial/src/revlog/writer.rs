@@ -0,0 +1,149 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Writer for Mercurial revlogs
+//!
+//! Produces RevlogNG files that `Revlog` and stock Mercurial can read. Every revision is stored
+//! as a full text (no delta chains), in a separate index and data file. That is not as compact as
+//! a revlog written by Mercurial, but it is cheap to produce from a `BlobRepo` and a client can
+//! use the files as they are.
+
+use std::collections::HashMap;
+use std::io::Write;
+
+use bytes::BufMut;
+use flate2::Compression;
+use flate2::write::ZlibEncoder;
+
+use errors::*;
+use mercurial_types::HgNodeHash;
+
+use super::parser::{indexng_size, Features, Version};
+use super::revidx::RevIdx;
+
+// Revlogs store "no revision" as -1
+const NULL_REV: u32 = !0;
+
+// Full texts shorter than this are never worth compressing
+const MIN_COMPRESS_LEN: usize = 44;
+
+/// Builds the index and data files of a revlog in memory, one revision at a time. Revisions have
+/// to be added parents first.
+#[derive(Debug, Default)]
+pub struct RevlogWriter {
+    index: Vec<u8>,
+    data: Vec<u8>,
+    nodeidx: HashMap<HgNodeHash, RevIdx>,
+}
+
+impl RevlogWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of revisions added so far
+    pub fn len(&self) -> usize {
+        self.nodeidx.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodeidx.is_empty()
+    }
+
+    /// Return the `RevIdx` of a revision that was added already
+    pub fn get_idx_by_nodeid(&self, nodeid: &HgNodeHash) -> Option<RevIdx> {
+        self.nodeidx.get(nodeid).cloned()
+    }
+
+    /// Append a revision with the given full text. `linkrev` is the changelog revision that
+    /// introduced it, for the changelog itself it's the revision being added. Adding a revision
+    /// that is already in the revlog is a no-op that returns the existing `RevIdx`.
+    pub fn add_revision(
+        &mut self,
+        nodeid: &HgNodeHash,
+        p1: Option<&HgNodeHash>,
+        p2: Option<&HgNodeHash>,
+        linkrev: RevIdx,
+        content: &[u8],
+    ) -> Result<RevIdx> {
+        if let Some(idx) = self.get_idx_by_nodeid(nodeid) {
+            return Ok(idx);
+        }
+
+        let p1 = self.parent_rev(nodeid, p1)?;
+        let p2 = self.parent_rev(nodeid, p2)?;
+        let idx = RevIdx::from(self.len());
+        let chunk = compress(content)?;
+
+        let mut entry = Vec::with_capacity(indexng_size());
+        if self.is_empty() {
+            // The first entry has no room for an offset, its first 4 bytes are the revlog header
+            entry.put_u16_be(Features::empty().bits());
+            entry.put_u16_be(Version::RevlogNG as u16);
+            entry.put_u16_be(0);
+        } else {
+            let offset = self.data.len() as u64;
+            entry.put_uint_be(offset, 6);
+        }
+        entry.put_u16_be(0); // flags
+        entry.put_u32_be(chunk.len() as u32);
+        entry.put_u32_be(content.len() as u32);
+        // Every revision is a full text, so it's its own base
+        entry.put_u32_be(idx.into());
+        entry.put_u32_be(linkrev.into());
+        entry.put_u32_be(p1);
+        entry.put_u32_be(p2);
+        entry.put_slice(nodeid.as_bytes());
+        entry.resize(indexng_size(), 0);
+
+        self.index.extend_from_slice(&entry);
+        self.data.extend_from_slice(&chunk);
+        self.nodeidx.insert(*nodeid, idx);
+        Ok(idx)
+    }
+
+    /// Return the contents of the index (`.i`) and data (`.d`) files
+    pub fn into_parts(self) -> (Vec<u8>, Vec<u8>) {
+        (self.index, self.data)
+    }
+
+    fn parent_rev(&self, nodeid: &HgNodeHash, parent: Option<&HgNodeHash>) -> Result<u32> {
+        match parent {
+            None => Ok(NULL_REV),
+            Some(parent) => match self.get_idx_by_nodeid(parent) {
+                Some(idx) => Ok(idx.into()),
+                None => Err(ErrorKind::Revlog(format!(
+                    "parent {} of {} must be added first",
+                    parent, nodeid
+                )).into()),
+            },
+        }
+    }
+}
+
+/// Encode a full text the way Mercurial does: zlib compressed if that makes it smaller,
+/// otherwise as is, with a 'u' marker unless it starts with a NUL byte.
+fn compress(content: &[u8]) -> Result<Vec<u8>> {
+    if content.is_empty() {
+        return Ok(vec![]);
+    }
+
+    if content.len() >= MIN_COMPRESS_LEN {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(content)?;
+        let compressed = encoder.finish()?;
+        if compressed.len() < content.len() {
+            return Ok(compressed);
+        }
+    }
+
+    let mut chunk = Vec::with_capacity(content.len() + 1);
+    if content[0] != b'\0' {
+        chunk.push(b'u');
+    }
+    chunk.extend_from_slice(content);
+    Ok(chunk)
+}
@@ -21,6 +21,8 @@ extern crate futures_ext;
 #[cfg(test)]
 extern crate maplit;
 extern crate serde;
+#[cfg(test)]
+extern crate tempdir;
 #[macro_use]
 extern crate serde_derive;
 extern crate toml;
@@ -35,7 +37,10 @@ extern crate vfs;
 
 pub mod errors;
 pub mod repoconfig;
+pub mod validation;
 
-pub use repoconfig::{CacheWarmupParams, RepoConfigs};
+pub use repoconfig::{AclParams, CacheWarmupParams, CloneBundlesParams, RepoConfigs,
+                     StreamingCloneParams};
+pub use validation::{ConfigDiagnostic, ConfigValidation};
 
 pub use errors::{Error, ErrorKind};
@@ -11,19 +11,19 @@ use blobrepo::{BlobRepo, ManifoldArgs};
 use bookmarks::Bookmark;
 use bytes::Bytes;
 use errors::*;
-use failure::FutureFailureErrorExt;
+use failure::{FutureFailureErrorExt, ResultExt};
 use futures::{finished, future, Future};
 use futures::Stream;
-use futures_ext::FutureExt;
+use futures_ext::{BoxFuture, FutureExt};
 use mercurial_types::{Changeset, MPath, MPathElement, Manifest};
 use mercurial_types::manifest::Content;
 use mercurial_types::nodehash::HgChangesetId;
 use mononoke_types::FileContents;
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str;
 use toml;
-use vfs::{vfs_from_manifest, ManifestVfsDir, ManifestVfsFile, VfsDir, VfsFile, VfsNode, VfsWalker};
+use vfs::{vfs_from_dir, vfs_from_manifest, VfsDir, VfsFile, VfsNode, VfsWalker};
 
 /// Configuration of a single repository
 #[derive(Debug, Clone, Eq, PartialEq)]
@@ -44,6 +44,21 @@ pub struct RepoConfig {
     pub bookmarks: Option<Vec<BookmarkParams>>,
     /// Configuration for hooks
     pub hooks: Option<Vec<HookParams>>,
+    /// Lua modules from the `hooks/lib` directory of the config repo, by module name. Hooks can
+    /// load them with `require`.
+    pub hook_libs: Option<HashMap<String, String>>,
+    /// Parameters of the streaming clone artifact, if streaming clones are served
+    pub streaming_clone: Option<StreamingCloneParams>,
+    /// Parameters of the clone bundles advertised to clients, if clone bundles are served
+    pub clone_bundles: Option<CloneBundlesParams>,
+    /// Revisions are sent to clients as deltas against a revision they have if the delta is at
+    /// most this percentage of the size of the fulltext. 0 means that only fulltexts are sent.
+    pub delta_threshold: usize,
+    /// Whether bundle2 responses are compressed for clients that advertise a supported
+    /// compression engine
+    pub compress_responses: bool,
+    /// Who may read from and push to this repo. Everyone may if it's not set.
+    pub acl: Option<AclParams>,
 }
 
 /// Configuration of warming up the Mononoke cache. This warmup happens on startup
@@ -56,6 +71,75 @@ pub struct CacheWarmupParams {
     pub commit_limit: usize,
 }
 
+/// Configuration of streaming clones. The server periodically materializes the changelog and
+/// root manifest revlogs at a bookmark into an artifact in the blobstore, and clients that ask
+/// for a streaming clone get that artifact followed by a regular pull.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct StreamingCloneParams {
+    /// Bookmark to build the artifact for
+    pub bookmark: Bookmark,
+    /// How often to check whether the bookmark moved and a new artifact should be built
+    pub refresh_interval_secs: u64,
+}
+
+/// Configuration of clone bundles. Full bundles of the history of a bookmark are generated into
+/// the blobstore by a separate tool, and the server advertises the newest one to cloning clients,
+/// which download it over HTTP and pull the rest.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct CloneBundlesParams {
+    /// Bookmark the bundles are generated for
+    pub bookmark: Bookmark,
+    /// URL the bundles of this repo are served from. The changeset of a bundle is appended to it.
+    pub base_url: String,
+}
+
+/// Prefix of the entries of an ACL that refer to a group rather than to a single identity
+pub const ACL_GROUP_PREFIX: &'static str = "group:";
+
+/// Access control of a repo. Clients are identified by the subject of their TLS certificate.
+/// Entries of `readers` and `writers` are identities, or `group:<name>` for all members of one
+/// of `groups`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct AclParams {
+    /// Who may read from the repo, or everyone if not set. Writers may always read.
+    pub readers: Option<Vec<String>>,
+    /// Who may push to the repo, or everyone if not set
+    pub writers: Option<Vec<String>>,
+    /// Members of the groups used in `readers` and `writers`, by group name
+    pub groups: HashMap<String, Vec<String>>,
+}
+
+impl AclParams {
+    /// True if `identity` may read from the repo. Clients without an identity can only read a
+    /// repo that everyone may read.
+    pub fn can_read(&self, identity: Option<&str>) -> bool {
+        self.readers.is_none() || self.can_write(identity) || self.matches(&self.readers, identity)
+    }
+
+    /// True if `identity` may push to the repo. Clients without an identity can only push to a
+    /// repo that everyone may push to.
+    pub fn can_write(&self, identity: Option<&str>) -> bool {
+        self.writers.is_none() || self.matches(&self.writers, identity)
+    }
+
+    fn matches(&self, entries: &Option<Vec<String>>, identity: Option<&str>) -> bool {
+        let identity = match identity {
+            Some(identity) => identity,
+            None => return false,
+        };
+        entries.iter().flat_map(|entries| entries).any(|entry| {
+            if entry.starts_with(ACL_GROUP_PREFIX) {
+                let group = &entry[ACL_GROUP_PREFIX.len()..];
+                self.groups
+                    .get(group)
+                    .map_or(false, |members| members.iter().any(|member| member == identity))
+            } else {
+                entry == identity
+            }
+        })
+    }
+}
+
 /// Configuration for a bookmark
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct BookmarkParams {
@@ -66,12 +150,14 @@ pub struct BookmarkParams {
 }
 
 /// The type of the hook
-#[derive(Debug, Clone, Eq, PartialEq, Deserialize)]
+#[derive(Debug, Clone, Eq, PartialEq, Deserialize, Serialize)]
 pub enum HookType {
     /// A hook that runs on the whole changeset
     PerChangeset,
     /// A hook that runs on a file in a changeset
     PerFile,
+    /// A hook that runs whenever any bookmark is created, moved or deleted
+    PerBookmarkMove,
 }
 
 /// Configuration for a hook
@@ -93,6 +179,13 @@ pub enum RepoType {
     /// Blob repository with path pointing to on-disk files with data. The files are stored in a
     /// RocksDb database
     BlobRocks(PathBuf),
+    /// Same as BlobRocks, but writes to the RocksDb database are synced to disk before they are
+    /// acknowledged
+    BlobRocksDurable(PathBuf),
+    /// Blob repository with path pointing to a directory where blobs are stored as plain files,
+    /// and changesets, bonsai-hg mapping, filenodes and bookmarks in SQLite files next to them.
+    /// Needs no external services.
+    BlobFiles(PathBuf),
     /// Blob repository with path pointing to the directory where a server socket is going to be.
     BlobManifold {
         /// The arguments used to connect to Manifold.
@@ -106,12 +199,19 @@ pub enum RepoType {
     TestBlobDelayRocks(PathBuf, u64, u64),
 }
 
+/// Directory of the config repo with Lua modules shared by all hooks
+pub(crate) const HOOK_LIBS_DIR: &'static str = "hooks/lib";
+
+/// Hook paths starting with this are relative to the directory of the repo, all other hook paths
+/// are relative to the root of the config repo
+pub(crate) const HOOK_PATH_RELATIVE_PREFIX: &'static str = "./";
+
 /// Configuration of a metaconfig repository
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub struct MetaConfig {}
 
 /// Holds configuration all configuration that was read from metaconfig repository's manifest.
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct RepoConfigs {
     /// Config for the config repository
     pub metaconfig: MetaConfig,
@@ -136,6 +236,18 @@ impl RepoConfigs {
         )
     }
 
+    /// Read the config from a directory on disk with the same layout as the metaconfig repo
+    pub fn read_from_dir<P>(path: P) -> Box<Future<Item = Self, Error = Error> + Send>
+    where
+        P: AsRef<Path>,
+    {
+        let vfs = try_boxfuture!(vfs_from_dir(path.as_ref()).with_context(|_| format!(
+            "failed to read config directory {}",
+            path.as_ref().display()
+        )));
+        RepoConfigs::read_repos(vfs.into_node())
+    }
+
     /// Read the given manifest of metaconfig repo and yield the RepoConfigs for it
     fn read_manifest<M>(manifest: &M) -> Box<Future<Item = Self, Error = Error> + Send>
     where
@@ -148,9 +260,13 @@ impl RepoConfigs {
         )
     }
 
-    fn read_repos(
-        root_node: VfsNode<ManifestVfsDir, ManifestVfsFile>,
-    ) -> Box<Future<Item = Self, Error = Error> + Send> {
+    pub(crate) fn read_repos<D>(
+        root_node: VfsNode<D, D::TFile>,
+    ) -> Box<Future<Item = Self, Error = Error> + Send>
+    where
+        D: VfsDir,
+        D::TFile: VfsFile<TDir = D>,
+    {
         Box::new(
             finished(root_node.clone())
                 .and_then(|root_node| {
@@ -163,11 +279,17 @@ impl RepoConfigs {
                     }
                     VfsNode::Dir(dir) => Ok(dir),
                 })
-                .and_then(move |repos_dir| {
+                .join(Self::read_hook_libs(root_node.clone()))
+                .and_then(move |(repos_dir, hook_libs)| {
                     let repodirs: Vec<_> = repos_dir.read().into_iter().cloned().collect();
                     let repos_node = repos_dir.into_node();
                     future::join_all(repodirs.into_iter().map(move |repodir| {
-                        Self::read_repo(root_node.clone(), repos_node.clone(), repodir)
+                        Self::read_repo(
+                            root_node.clone(),
+                            repos_node.clone(),
+                            repodir,
+                            hook_libs.clone(),
+                        )
                     }))
                 })
                 .map(|repos| RepoConfigs {
@@ -177,11 +299,71 @@ impl RepoConfigs {
         )
     }
 
-    fn read_repo(
-        root_node: VfsNode<ManifestVfsDir, ManifestVfsFile>,
-        repos_dir: VfsNode<ManifestVfsDir, ManifestVfsFile>,
+    /// Read all `.lua` files directly in `HOOK_LIBS_DIR`. The module name of a file is its name
+    /// without the extension. Yields None if the directory does not exist.
+    pub(crate) fn read_hook_libs<D>(
+        root_node: VfsNode<D, D::TFile>,
+    ) -> Box<Future<Item = Option<HashMap<String, String>>, Error = Error> + Send>
+    where
+        D: VfsDir,
+        D::TFile: VfsFile<TDir = D>,
+    {
+        let mut node = root_node;
+        for element in try_boxfuture!(MPath::new(HOOK_LIBS_DIR)) {
+            node = match node {
+                VfsNode::Dir(dir) => match dir.step(&element) {
+                    Some(node) => node,
+                    None => return finished(None).boxify(),
+                },
+                VfsNode::File(_) => break,
+            };
+        }
+        let lib_dir = match node {
+            VfsNode::Dir(dir) => dir,
+            VfsNode::File(_) => {
+                return future::err(
+                    ErrorKind::InvalidFileStructure(format!(
+                        "{} must be a directory",
+                        HOOK_LIBS_DIR
+                    )).into(),
+                ).boxify()
+            }
+        };
+
+        let modules: Vec<_> = lib_dir
+            .read()
+            .into_iter()
+            .filter_map(|element| {
+                let name = str::from_utf8(element.as_bytes()).ok()?;
+                if name.ends_with(".lua") {
+                    Some((name[..name.len() - ".lua".len()].to_string(), element.clone()))
+                } else {
+                    None
+                }
+            })
+            .collect();
+        let lib_node = lib_dir.into_node();
+        future::join_all(modules.into_iter().map(move |(module, element)| {
+            RepoConfigs::read_file(lib_node.clone(), MPath::from(element)).and_then(
+                move |bytes| {
+                    let code = str::from_utf8(&bytes)?.to_string();
+                    Ok((module, code))
+                },
+            )
+        })).map(|modules| Some(modules.into_iter().collect()))
+            .boxify()
+    }
+
+    fn read_repo<D>(
+        root_node: VfsNode<D, D::TFile>,
+        repos_dir: VfsNode<D, D::TFile>,
         repo_dir: MPathElement,
-    ) -> Box<Future<Item = (String, RepoConfig), Error = Error> + Send> {
+        hook_libs: Option<HashMap<String, String>>,
+    ) -> Box<Future<Item = (String, RepoConfig), Error = Error> + Send>
+    where
+        D: VfsDir,
+        D::TFile: VfsFile<TDir = D>,
+    {
         let repo_name = try_boxfuture!(str::from_utf8(repo_dir.as_bytes())).to_string();
 
         VfsWalker::new(repos_dir, repo_dir.into_iter().cloned())
@@ -204,48 +386,65 @@ impl RepoConfigs {
                 // Easier to deal with empty vector than Option
                 let hooks = hooks.unwrap_or(Vec::new());
                 future::join_all(hooks.into_iter().map(move |raw_hook_config| {
-                    let path = raw_hook_config.path.clone();
-                    let relative_prefix = "./";
-                    let is_relative = path.starts_with(relative_prefix);
-                    let path_node;
-                    let path_adjusted;
-                    if is_relative {
-                        path_node = repo_dir.clone().into_node();
-                        path_adjusted = path.chars().skip(relative_prefix.len()).collect();
-                    } else {
-                        path_node = root_node.clone();
-                        path_adjusted = path;
-                    }
-                    RepoConfigs::read_file(
-                        path_node,
-                        try_boxfuture!(MPath::new(path_adjusted.as_bytes().to_vec())),
-                    ).and_then(|bytes| {
-                        let code = str::from_utf8(&bytes)?;
-                        let code = code.to_string();
-                        Ok(HookParams {
-                            name: raw_hook_config.name,
-                            code,
-                            hook_type: raw_hook_config.hook_type,
-                        })
-                    })
-                        .boxify()
+                    RepoConfigs::read_hook(
+                        root_node.clone(),
+                        repo_dir.clone().into_node(),
+                        raw_hook_config,
+                    )
                 })).map(|hook_params| (raw_config, hook_params))
                     .boxify()
             })
             .then(|res| match res {
                 Ok((raw_config, all_hook_params)) => Ok((
                     repo_name,
-                    RepoConfigs::convert_conf(raw_config, all_hook_params)?,
+                    RepoConfigs::convert_conf(raw_config, all_hook_params, hook_libs)?,
                 )),
                 Err(e) => Err(e),
             })
             .boxify()
     }
 
-    fn read_file(
-        file_dir: VfsNode<ManifestVfsDir, ManifestVfsFile>,
+    /// Read the code of a hook. The path in the hook config is relative to `repo_node` if it
+    /// starts with `HOOK_PATH_RELATIVE_PREFIX`, and to `root_node` otherwise.
+    pub(crate) fn read_hook<D>(
+        root_node: VfsNode<D, D::TFile>,
+        repo_node: VfsNode<D, D::TFile>,
+        raw_hook_config: RawHookConfig,
+    ) -> BoxFuture<HookParams, Error>
+    where
+        D: VfsDir,
+        D::TFile: VfsFile<TDir = D>,
+    {
+        let (path_node, path) = {
+            let path = raw_hook_config.path.as_str();
+            if path.starts_with(HOOK_PATH_RELATIVE_PREFIX) {
+                (repo_node, &path[HOOK_PATH_RELATIVE_PREFIX.len()..])
+            } else {
+                (root_node, path)
+            }
+        };
+        let path = try_boxfuture!(MPath::new(path.as_bytes().to_vec()));
+        RepoConfigs::read_file(path_node, path)
+            .and_then(|bytes| {
+                let code = str::from_utf8(&bytes)?;
+                let code = code.to_string();
+                Ok(HookParams {
+                    name: raw_hook_config.name,
+                    code,
+                    hook_type: raw_hook_config.hook_type,
+                })
+            })
+            .boxify()
+    }
+
+    pub(crate) fn read_file<D>(
+        file_dir: VfsNode<D, D::TFile>,
         file_path: MPath,
-    ) -> impl Future<Item = Bytes, Error = Error> {
+    ) -> impl Future<Item = Bytes, Error = Error>
+    where
+        D: VfsDir,
+        D::TFile: VfsFile<TDir = D>,
+    {
         VfsWalker::new(file_dir, file_path.clone())
             .collect()
             .and_then(move |nodes| {
@@ -268,15 +467,23 @@ impl RepoConfigs {
             })
     }
 
-    fn convert_conf(this: RawRepoConfig, hooks: Vec<HookParams>) -> Result<RepoConfig> {
+    fn convert_conf(
+        this: RawRepoConfig,
+        hooks: Vec<HookParams>,
+        hook_libs: Option<HashMap<String, String>>,
+    ) -> Result<RepoConfig> {
         let repotype = match this.repotype {
             RawRepoType::Revlog => RepoType::Revlog(this.path),
             RawRepoType::BlobRocks => RepoType::BlobRocks(this.path),
+            RawRepoType::BlobRocksDurable => RepoType::BlobRocksDurable(this.path),
+            RawRepoType::BlobFiles => RepoType::BlobFiles(this.path),
             RawRepoType::TestBlobManifold => {
                 let manifold_bucket = this.manifold_bucket.ok_or(ErrorKind::InvalidConfig(
                     "manifold bucket must be specified".into(),
                 ))?;
-                let db_address = this.db_address.expect("xdb tier was not specified");
+                let db_address = this.db_address.ok_or(ErrorKind::InvalidConfig(
+                    "xdb tier must be specified".into(),
+                ))?;
                 RepoType::BlobManifold {
                     args: ManifoldArgs {
                         bucket: manifold_bucket,
@@ -295,8 +502,12 @@ impl RepoConfigs {
             }
             RawRepoType::TestBlobDelayRocks => RepoType::TestBlobDelayRocks(
                 this.path,
-                this.delay_mean.expect("mean delay must be specified"),
-                this.delay_stddev.expect("stddev delay must be specified"),
+                this.delay_mean.ok_or(ErrorKind::InvalidConfig(
+                    "mean delay must be specified".into(),
+                ))?,
+                this.delay_stddev.ok_or(ErrorKind::InvalidConfig(
+                    "stddev delay must be specified".into(),
+                ))?,
             ),
         };
 
@@ -308,6 +519,21 @@ impl RepoConfigs {
             bookmark: Bookmark::new(cache_warmup.bookmark).expect("bookmark name must be ascii"),
             commit_limit: cache_warmup.commit_limit.unwrap_or(200000),
         });
+        let streaming_clone = this.streaming_clone.map(|streaming_clone| StreamingCloneParams {
+            bookmark: Bookmark::new(streaming_clone.bookmark).expect("bookmark name must be ascii"),
+            refresh_interval_secs: streaming_clone.refresh_interval_secs.unwrap_or(3600),
+        });
+        let clone_bundles = this.clone_bundles.map(|clone_bundles| CloneBundlesParams {
+            bookmark: Bookmark::new(clone_bundles.bookmark).expect("bookmark name must be ascii"),
+            base_url: clone_bundles.base_url.trim_right_matches('/').to_string(),
+        });
+        let delta_threshold = this.delta_threshold.unwrap_or(50);
+        let compress_responses = this.compress_responses.unwrap_or(true);
+        let acl = this.acl.map(|acl| AclParams {
+            readers: acl.readers,
+            writers: acl.writers,
+            groups: acl.groups.unwrap_or_default(),
+        });
         let bookmarks = match this.bookmarks {
             Some(bookmarks) => Some(
                 bookmarks
@@ -342,63 +568,99 @@ impl RepoConfigs {
             cache_warmup,
             bookmarks,
             hooks: hooks_opt,
+            hook_libs,
+            streaming_clone,
+            clone_bundles,
+            delta_threshold,
+            compress_responses,
+            acl,
         })
     }
 }
 
-#[derive(Debug, Deserialize, Clone)]
-struct RawRepoConfig {
-    path: PathBuf,
-    repotype: RawRepoType,
-    enabled: Option<bool>,
-    generation_cache_size: Option<usize>,
-    manifold_bucket: Option<String>,
-    manifold_prefix: Option<String>,
-    repoid: i32,
-    db_address: Option<String>,
-    scuba_table: Option<String>,
-    delay_mean: Option<u64>,
-    delay_stddev: Option<u64>,
-    blobstore_cache_size: Option<usize>,
-    changesets_cache_size: Option<usize>,
-    filenodes_cache_size: Option<usize>,
-    bonsai_hg_mapping_cache_size: Option<usize>,
-    io_thread_num: Option<usize>,
-    cache_warmup: Option<RawCacheWarmupConfig>,
-    max_concurrent_requests_per_io_thread: Option<usize>,
-    bookmarks: Option<Vec<RawBookmarkConfig>>,
-    hooks: Option<Vec<RawHookConfig>>,
+/// Contents of `server.toml`. Keys that aren't part of the format are ignored when reading it, the
+/// raw configs are serializable so that validation can find and report them.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub(crate) struct RawRepoConfig {
+    pub(crate) path: PathBuf,
+    pub(crate) repotype: RawRepoType,
+    pub(crate) enabled: Option<bool>,
+    pub(crate) generation_cache_size: Option<usize>,
+    pub(crate) manifold_bucket: Option<String>,
+    pub(crate) manifold_prefix: Option<String>,
+    pub(crate) repoid: i32,
+    pub(crate) db_address: Option<String>,
+    pub(crate) scuba_table: Option<String>,
+    pub(crate) delay_mean: Option<u64>,
+    pub(crate) delay_stddev: Option<u64>,
+    pub(crate) blobstore_cache_size: Option<usize>,
+    pub(crate) changesets_cache_size: Option<usize>,
+    pub(crate) filenodes_cache_size: Option<usize>,
+    pub(crate) bonsai_hg_mapping_cache_size: Option<usize>,
+    pub(crate) io_thread_num: Option<usize>,
+    pub(crate) cache_warmup: Option<RawCacheWarmupConfig>,
+    pub(crate) max_concurrent_requests_per_io_thread: Option<usize>,
+    pub(crate) bookmarks: Option<Vec<RawBookmarkConfig>>,
+    pub(crate) hooks: Option<Vec<RawHookConfig>>,
+    pub(crate) streaming_clone: Option<RawStreamingCloneConfig>,
+    pub(crate) clone_bundles: Option<RawCloneBundlesConfig>,
+    pub(crate) delta_threshold: Option<usize>,
+    pub(crate) compress_responses: Option<bool>,
+    pub(crate) acl: Option<RawAclConfig>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub(crate) struct RawCacheWarmupConfig {
+    pub(crate) bookmark: String,
+    pub(crate) commit_limit: Option<usize>,
 }
 
-#[derive(Debug, Deserialize, Clone)]
-struct RawCacheWarmupConfig {
-    bookmark: String,
-    commit_limit: Option<usize>,
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub(crate) struct RawStreamingCloneConfig {
+    pub(crate) bookmark: String,
+    pub(crate) refresh_interval_secs: Option<u64>,
 }
 
-#[derive(Debug, Deserialize, Clone)]
-struct RawBookmarkConfig {
-    name: String,
-    hooks: Option<Vec<RawBookmarkHook>>,
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub(crate) struct RawCloneBundlesConfig {
+    pub(crate) bookmark: String,
+    pub(crate) base_url: String,
 }
 
-#[derive(Debug, Deserialize, Clone)]
-struct RawBookmarkHook {
-    hook_name: String,
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub(crate) struct RawAclConfig {
+    pub(crate) readers: Option<Vec<String>>,
+    pub(crate) writers: Option<Vec<String>>,
+    pub(crate) groups: Option<HashMap<String, Vec<String>>>,
 }
 
-#[derive(Debug, Deserialize, Clone)]
-struct RawHookConfig {
-    name: String,
-    path: String,
-    hook_type: HookType,
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub(crate) struct RawBookmarkConfig {
+    pub(crate) name: String,
+    pub(crate) hooks: Option<Vec<RawBookmarkHook>>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub(crate) struct RawBookmarkHook {
+    pub(crate) hook_name: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub(crate) struct RawHookConfig {
+    pub(crate) name: String,
+    /// Path of the file with the hook code, relative to the root of the config repo or, if it
+    /// starts with `./`, to the directory of the repo
+    pub(crate) path: String,
+    pub(crate) hook_type: HookType,
 }
 
 /// Types of repositories supported
-#[derive(Clone, Debug, Deserialize)]
-enum RawRepoType {
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) enum RawRepoType {
     #[serde(rename = "revlog")] Revlog,
     #[serde(rename = "blob:rocks")] BlobRocks,
+    #[serde(rename = "blob:rocks-durable")] BlobRocksDurable,
+    #[serde(rename = "blob:files")] BlobFiles,
     #[serde(rename = "blob:testmanifold")] TestBlobManifold,
     #[serde(rename = "blob:testdelay")] TestBlobDelayRocks,
 }
@@ -407,8 +669,11 @@ enum RawRepoType {
 mod test {
     use super::*;
 
+    use std::fs;
+
     use mercurial_types::FileType;
     use mercurial_types_mocks::manifest::MockManifest;
+    use tempdir::TempDir;
 
     #[test]
     fn test_read_manifest() {
@@ -420,9 +685,21 @@ mod test {
             generation_cache_size=1048576
             repoid=0
             scuba_table="scuba_table"
+            delta_threshold=20
+            compress_responses=false
             [cache_warmup]
             bookmark="master"
             commit_limit=100
+            [streaming_clone]
+            bookmark="master"
+            [clone_bundles]
+            bookmark="master"
+            base_url="https://mononoke-api/fbsource/clonebundles/"
+            [acl]
+            readers=["group:engineers"]
+            writers=["svcscm"]
+            [acl.groups]
+            engineers=["alice", "bob"]
             [[bookmarks]]
             name="master"
             [[bookmarks.hooks]]
@@ -451,7 +728,12 @@ mod test {
             "repos/fbsource/hooks/hook2.lua" => (FileType::Regular, hook2_content),
             "repos/www/server.toml" => (FileType::Regular, www_content),
             "my_path/my_files" => (FileType::Regular, ""),
+            "hooks/lib/utils.lua" => (FileType::Regular, "this is utils"),
+            "hooks/lib/README" => (FileType::Regular, "not a module"),
         };
+        let hook_libs = Some(hashmap! {
+            "utils".to_string() => "this is utils".to_string(),
+        });
         let root_manifest = MockManifest::from_paths(paths).expect("manifest is valid");
         let repoconfig = RepoConfigs::read_manifest(&root_manifest)
             .wait()
@@ -488,6 +770,24 @@ mod test {
                         hook_type: HookType::PerChangeset,
                     },
                 ]),
+                hook_libs: hook_libs.clone(),
+                streaming_clone: Some(StreamingCloneParams {
+                    bookmark: Bookmark::new("master").unwrap(),
+                    refresh_interval_secs: 3600,
+                }),
+                clone_bundles: Some(CloneBundlesParams {
+                    bookmark: Bookmark::new("master").unwrap(),
+                    base_url: "https://mononoke-api/fbsource/clonebundles".to_string(),
+                }),
+                delta_threshold: 20,
+                compress_responses: false,
+                acl: Some(AclParams {
+                    readers: Some(vec!["group:engineers".to_string()]),
+                    writers: Some(vec!["svcscm".to_string()]),
+                    groups: hashmap! {
+                        "engineers".to_string() => vec!["alice".to_string(), "bob".to_string()],
+                    },
+                }),
             },
         );
         repos.insert(
@@ -501,6 +801,12 @@ mod test {
                 cache_warmup: None,
                 bookmarks: None,
                 hooks: None,
+                hook_libs,
+                streaming_clone: None,
+                clone_bundles: None,
+                delta_threshold: 50,
+                compress_responses: true,
+                acl: None,
             },
         );
         assert_eq!(
@@ -511,4 +817,70 @@ mod test {
             }
         )
     }
+
+    #[test]
+    fn test_acl() {
+        let acl = AclParams {
+            readers: Some(vec!["group:engineers".to_string(), "carol".to_string()]),
+            writers: Some(vec!["svcscm".to_string()]),
+            groups: hashmap! {
+                "engineers".to_string() => vec!["alice".to_string()],
+            },
+        };
+        assert!(acl.can_read(Some("alice")));
+        assert!(acl.can_read(Some("carol")));
+        assert!(acl.can_read(Some("svcscm")));
+        assert!(!acl.can_read(Some("mallory")));
+        assert!(!acl.can_read(Some("engineers")));
+        assert!(!acl.can_read(None));
+        assert!(acl.can_write(Some("svcscm")));
+        assert!(!acl.can_write(Some("alice")));
+        assert!(!acl.can_write(None));
+
+        let acl = AclParams {
+            readers: None,
+            writers: Some(vec![]),
+            groups: HashMap::new(),
+        };
+        assert!(acl.can_read(None));
+        assert!(!acl.can_write(Some("alice")));
+    }
+
+    #[test]
+    fn test_read_from_dir() {
+        let tmpdir = TempDir::new("config_dir").expect("failed to create tempdir");
+        let content = r#"
+            path="/tmp/fbsource"
+            repotype="blob:rocks"
+            repoid=0
+            [[hooks]]
+            name="hook1"
+            path="./hooks/hook1.lua"
+            hook_type="PerChangeset"
+        "#;
+        for (path, content) in vec![
+            ("repos/fbsource/server.toml", content),
+            ("repos/fbsource/hooks/hook1.lua", "this is hook1"),
+        ] {
+            let path = tmpdir.path().join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        }
+
+        let repoconfig = RepoConfigs::read_from_dir(tmpdir.path())
+            .wait()
+            .expect("failed to read config from directory");
+        let fbsource = repoconfig.repos.get("fbsource").expect("fbsource is missing");
+        assert_eq!(fbsource.repotype, RepoType::BlobRocks("/tmp/fbsource".into()));
+        assert_eq!(
+            fbsource.hooks,
+            Some(vec![
+                HookParams {
+                    name: "hook1".to_string(),
+                    code: "this is hook1".to_string(),
+                    hook_type: HookType::PerChangeset,
+                },
+            ])
+        );
+    }
 }
@@ -0,0 +1,818 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Validation of a metaconfig repo. Unlike `RepoConfigs::read_config_repo`, which stops at the
+//! first problem, this collects every problem it can find, each pointing to the file and key it
+//! comes from.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use bookmarks::Bookmark;
+use futures::{future, Future};
+use futures_ext::{BoxFuture, FutureExt};
+use toml;
+
+use blobrepo::BlobRepo;
+use mercurial_types::{Changeset, MPath, MPathElement, Manifest};
+use mercurial_types::nodehash::HgChangesetId;
+use vfs::{vfs_from_manifest, ManifestVfsDir, ManifestVfsFile, VfsDir, VfsNode};
+
+use errors::*;
+use repoconfig::{HookParams, RawRepoConfig, RawRepoType, RepoConfigs, ACL_GROUP_PREFIX,
+                 HOOK_LIBS_DIR, HOOK_PATH_RELATIVE_PREFIX};
+
+const REPOS_DIR: &'static str = "repos";
+const SERVER_CONFIG: &'static str = "server.toml";
+
+type Node = VfsNode<ManifestVfsDir, ManifestVfsFile>;
+
+/// A single problem found in the metaconfig repo
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ConfigDiagnostic {
+    /// Path of the file with the problem, relative to the root of the metaconfig repo
+    pub file: String,
+    /// Key in the file the problem is about, e.g. `bookmarks[0].hooks[1].hook_name`
+    pub key: Option<String>,
+    /// Description of the problem
+    pub message: String,
+}
+
+impl ConfigDiagnostic {
+    fn new<F, M>(file: F, key: Option<String>, message: M) -> Self
+    where
+        F: Into<String>,
+        M: Into<String>,
+    {
+        ConfigDiagnostic {
+            file: file.into(),
+            key,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for ConfigDiagnostic {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self.key {
+            Some(ref key) => write!(fmt, "{}: {}: {}", self.file, key, self.message),
+            None => write!(fmt, "{}: {}", self.file, self.message),
+        }
+    }
+}
+
+/// A hook that was read successfully, with where it was defined
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct HookLocation {
+    /// The file with the hook code
+    pub file: String,
+    /// The hook itself
+    pub hook: HookParams,
+}
+
+/// A Lua module from `HOOK_LIBS_DIR` that hooks can load with `require`
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct HookLibLocation {
+    /// The file with the module code
+    pub file: String,
+    /// Name of the module
+    pub name: String,
+    /// Code of the module
+    pub code: String,
+}
+
+/// Outcome of validating a metaconfig repo
+#[derive(Debug, Default)]
+pub struct ConfigValidation {
+    /// Every problem that was found
+    pub diagnostics: Vec<ConfigDiagnostic>,
+    /// All hooks that could be read. Callers can run checks that need more than the config
+    /// itself on them, like compiling the hook code.
+    pub hooks: Vec<HookLocation>,
+    /// All shared hook modules that could be read, to be checked like the hooks
+    pub hook_libs: Vec<HookLibLocation>,
+}
+
+impl ConfigValidation {
+    /// True if no problem was found
+    pub fn is_valid(&self) -> bool {
+        self.diagnostics.is_empty()
+    }
+}
+
+/// What a single repo config contributes to checks that span repos
+struct RepoSummary {
+    file: String,
+    name: String,
+    repoid: i32,
+    path: String,
+}
+
+impl RepoConfigs {
+    /// Validate the config at the given commit of the metaconfig repo
+    pub fn validate_config_repo(
+        repo: BlobRepo,
+        changesetid: HgChangesetId,
+    ) -> BoxFuture<ConfigValidation, Error> {
+        repo.get_changeset_by_changesetid(&changesetid)
+            .and_then(move |changeset| {
+                repo.get_manifest_by_nodeid(&changeset.manifestid().clone().into_nodehash())
+            })
+            .and_then(|manifest| Self::validate_manifest(&manifest))
+            .boxify()
+    }
+
+    /// Validate the given manifest of metaconfig repo
+    pub fn validate_manifest<M>(manifest: &M) -> BoxFuture<ConfigValidation, Error>
+    where
+        M: Manifest,
+    {
+        vfs_from_manifest(manifest)
+            .from_err()
+            .and_then(|vfs| Self::validate_repos(vfs.into_node()))
+            .boxify()
+    }
+
+    fn validate_repos(root_node: Node) -> BoxFuture<ConfigValidation, Error> {
+        let repos_dir = match root_node.clone() {
+            VfsNode::Dir(dir) => dir.step(&try_boxfuture!(MPathElement::new(
+                REPOS_DIR.as_bytes().to_vec()
+            ))),
+            VfsNode::File(_) => None,
+        };
+        let repos_dir = match repos_dir {
+            Some(VfsNode::Dir(dir)) => dir,
+            _ => {
+                let mut validation = ConfigValidation::default();
+                validation.diagnostics.push(ConfigDiagnostic::new(
+                    REPOS_DIR,
+                    None,
+                    "expected a directory with one subdirectory per repo",
+                ));
+                return future::ok(validation).boxify();
+            }
+        };
+
+        let repodirs: Vec<_> = repos_dir.read().into_iter().cloned().collect();
+        let repos_node = repos_dir.into_node();
+        let repos = repodirs.into_iter().map({
+            let root_node = root_node.clone();
+            move |repodir| Self::validate_repo(root_node.clone(), repos_node.clone(), repodir)
+        });
+        let hook_libs = read_hook_libs(root_node.clone());
+        future::join_all(repos).join(hook_libs).and_then(move |(repos, hook_libs)| {
+            let mut validation = ConfigValidation::default();
+            match hook_libs {
+                Ok(hook_libs) => validation.hook_libs = hook_libs,
+                Err(diagnostic) => validation.diagnostics.push(diagnostic),
+            }
+            let mut summaries = vec![];
+            for (diagnostics, hooks, summary) in repos {
+                validation.diagnostics.extend(diagnostics);
+                validation.hooks.extend(hooks);
+                summaries.extend(summary);
+            }
+            validation
+                .diagnostics
+                .extend(check_unique_repos(&mut summaries));
+
+            if !validation.is_valid() {
+                return future::ok(validation).boxify();
+            }
+            // Nothing wrong was found, but make sure that the server would accept the config
+            // as well, in case a problem slipped through the checks above
+            RepoConfigs::read_repos(root_node)
+                .then(move |res| {
+                    if let Err(err) = res {
+                        validation.diagnostics.push(ConfigDiagnostic::new(
+                            REPOS_DIR,
+                            None,
+                            format!("failed to read config: {}", err),
+                        ));
+                    }
+                    Ok(validation)
+                })
+                .boxify()
+        })
+            .boxify()
+    }
+
+    fn validate_repo(
+        root_node: Node,
+        repos_node: Node,
+        repo_dir: MPathElement,
+    ) -> BoxFuture<
+        (
+            Vec<ConfigDiagnostic>,
+            Vec<HookLocation>,
+            Option<RepoSummary>,
+        ),
+        Error,
+    > {
+        let name = String::from_utf8_lossy(repo_dir.as_bytes()).into_owned();
+        let file = format!("{}/{}/{}", REPOS_DIR, name, SERVER_CONFIG);
+
+        let repo_node = match repos_node {
+            VfsNode::Dir(dir) => dir.step(&repo_dir),
+            VfsNode::File(_) => None,
+        };
+        let repo_node = match repo_node {
+            Some(node @ VfsNode::Dir(_)) => node,
+            _ => {
+                let diagnostic = ConfigDiagnostic::new(
+                    format!("{}/{}", REPOS_DIR, name),
+                    None,
+                    "expected a directory",
+                );
+                return future::ok((vec![diagnostic], vec![], None)).boxify();
+            }
+        };
+
+        let config_path = try_boxfuture!(MPath::new(SERVER_CONFIG));
+        RepoConfigs::read_file(repo_node.clone(), config_path)
+            .then(move |res| {
+                let mut diagnostics = vec![];
+                let bytes = match res {
+                    Ok(bytes) => bytes,
+                    Err(err) => {
+                        diagnostics.push(ConfigDiagnostic::new(
+                            file,
+                            None,
+                            format!("failed to read: {}", err),
+                        ));
+                        return future::ok((diagnostics, vec![], None)).boxify();
+                    }
+                };
+
+                let value = match toml::from_slice::<toml::Value>(bytes.as_ref()) {
+                    Ok(value) => value,
+                    Err(err) => {
+                        diagnostics.push(ConfigDiagnostic::new(file, None, err.to_string()));
+                        return future::ok((diagnostics, vec![], None)).boxify();
+                    }
+                };
+                let raw_config = match value.clone().try_into::<RawRepoConfig>() {
+                    Ok(raw_config) => raw_config,
+                    Err(err) => {
+                        diagnostics.push(ConfigDiagnostic::new(file, None, err.to_string()));
+                        return future::ok((diagnostics, vec![], None)).boxify();
+                    }
+                };
+                check_unknown_keys(&value, &raw_config, &file, &mut diagnostics);
+                check_repo_config(&raw_config, &file, &mut diagnostics);
+
+                let summary = RepoSummary {
+                    file: file.clone(),
+                    name,
+                    repoid: raw_config.repoid,
+                    path: raw_config.path.to_string_lossy().into_owned(),
+                };
+                read_hooks(root_node, repo_node, raw_config, file)
+                    .map(move |(hook_diagnostics, hooks)| {
+                        diagnostics.extend(hook_diagnostics);
+                        (diagnostics, hooks, Some(summary))
+                    })
+                    .boxify()
+            })
+            .boxify()
+    }
+}
+
+/// Report the keys of `server.toml` that the config format doesn't know about. The server
+/// ignores them, so they are usually typos. They are found by comparing the file with what the
+/// config it was parsed into serializes to.
+fn check_unknown_keys(
+    value: &toml::Value,
+    raw_config: &RawRepoConfig,
+    file: &str,
+    diagnostics: &mut Vec<ConfigDiagnostic>,
+) {
+    let known = match toml::Value::try_from(raw_config) {
+        Ok(known) => known,
+        Err(err) => {
+            diagnostics.push(ConfigDiagnostic::new(
+                file,
+                None,
+                format!("failed to check for unknown keys: {}", err),
+            ));
+            return;
+        }
+    };
+    let mut unknown = vec![];
+    find_unknown_keys(value, &known, "", &mut unknown);
+    for key in unknown {
+        diagnostics.push(ConfigDiagnostic::new(
+            file,
+            Some(key),
+            "unknown key, it is ignored",
+        ));
+    }
+}
+
+fn find_unknown_keys(
+    value: &toml::Value,
+    known: &toml::Value,
+    prefix: &str,
+    unknown: &mut Vec<String>,
+) {
+    match (value, known) {
+        (&toml::Value::Table(ref table), &toml::Value::Table(ref known)) => {
+            for (key, value) in table {
+                let path = if prefix.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", prefix, key)
+                };
+                match known.get(key) {
+                    Some(known) => find_unknown_keys(value, known, &path, unknown),
+                    None => unknown.push(path),
+                }
+            }
+        }
+        (&toml::Value::Array(ref values), &toml::Value::Array(ref known)) => {
+            for (i, (value, known)) in values.iter().zip(known).enumerate() {
+                find_unknown_keys(value, known, &format!("{}[{}]", prefix, i), unknown);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn check_repo_config(
+    raw_config: &RawRepoConfig,
+    file: &str,
+    diagnostics: &mut Vec<ConfigDiagnostic>,
+) {
+    let mut error = |key: &str, message: String| {
+        diagnostics.push(ConfigDiagnostic::new(file, Some(key.to_string()), message))
+    };
+
+    if raw_config.path.as_os_str().is_empty() {
+        error("path", "must not be empty".into());
+    } else if !raw_config.path.is_absolute() {
+        error(
+            "path",
+            format!("{} must be an absolute path", raw_config.path.display()),
+        );
+    }
+
+    let required: &[(&str, bool)] = match raw_config.repotype {
+        RawRepoType::Revlog
+        | RawRepoType::BlobRocks
+        | RawRepoType::BlobRocksDurable
+        | RawRepoType::BlobFiles => &[],
+        RawRepoType::TestBlobManifold => &[
+            ("manifold_bucket", raw_config.manifold_bucket.is_some()),
+            ("db_address", raw_config.db_address.is_some()),
+        ],
+        RawRepoType::TestBlobDelayRocks => &[
+            ("delay_mean", raw_config.delay_mean.is_some()),
+            ("delay_stddev", raw_config.delay_stddev.is_some()),
+        ],
+    };
+    for &(key, present) in required {
+        if !present {
+            error(
+                key,
+                format!("required for repotype {:?}", raw_config.repotype),
+            );
+        }
+    }
+
+    if let Some(ref cache_warmup) = raw_config.cache_warmup {
+        if let Err(err) = Bookmark::new(cache_warmup.bookmark.as_str()) {
+            error("cache_warmup.bookmark", format!("invalid bookmark: {}", err));
+        }
+    }
+
+    if let Some(ref streaming_clone) = raw_config.streaming_clone {
+        if let Err(err) = Bookmark::new(streaming_clone.bookmark.as_str()) {
+            error(
+                "streaming_clone.bookmark",
+                format!("invalid bookmark: {}", err),
+            );
+        }
+        if streaming_clone.refresh_interval_secs == Some(0) {
+            error(
+                "streaming_clone.refresh_interval_secs",
+                "must be positive".into(),
+            );
+        }
+    }
+
+    if let Some(ref clone_bundles) = raw_config.clone_bundles {
+        if let Err(err) = Bookmark::new(clone_bundles.bookmark.as_str()) {
+            error(
+                "clone_bundles.bookmark",
+                format!("invalid bookmark: {}", err),
+            );
+        }
+        let base_url = clone_bundles.base_url.as_str();
+        if !base_url.starts_with("http://") && !base_url.starts_with("https://") {
+            error(
+                "clone_bundles.base_url",
+                format!("{} must be an http or https URL", base_url),
+            );
+        }
+    }
+
+    if let Some(ref acl) = raw_config.acl {
+        let groups = acl.groups.as_ref();
+        for (name, entries) in vec![("readers", &acl.readers), ("writers", &acl.writers)] {
+            for (i, entry) in entries.iter().flat_map(|e| e).enumerate() {
+                if !entry.starts_with(ACL_GROUP_PREFIX) {
+                    continue;
+                }
+                let group = &entry[ACL_GROUP_PREFIX.len()..];
+                if !groups.map_or(false, |groups| groups.contains_key(group)) {
+                    error(
+                        &format!("acl.{}[{}]", name, i),
+                        format!("group {} is not defined in acl.groups", group),
+                    );
+                }
+            }
+        }
+    }
+
+    let mut hook_names = HashSet::new();
+    for (i, hook) in raw_config.hooks.iter().flat_map(|hooks| hooks).enumerate() {
+        if !hook_names.insert(hook.name.as_str()) {
+            error(
+                &format!("hooks[{}].name", i),
+                format!("hook {} is defined more than once", hook.name),
+            );
+        }
+    }
+
+    for (i, bookmark) in raw_config.bookmarks.iter().flat_map(|b| b).enumerate() {
+        if let Err(err) = Bookmark::new(bookmark.name.as_str()) {
+            error(
+                &format!("bookmarks[{}].name", i),
+                format!("invalid bookmark: {}", err),
+            );
+        }
+        for (j, hook) in bookmark.hooks.iter().flat_map(|h| h).enumerate() {
+            if !hook_names.contains(hook.hook_name.as_str()) {
+                error(
+                    &format!("bookmarks[{}].hooks[{}].hook_name", i, j),
+                    format!(
+                        "bookmark {} refers to hook {} which is not defined",
+                        bookmark.name, hook.hook_name
+                    ),
+                );
+            }
+        }
+    }
+}
+
+/// Read the code of all hooks of a repo, the same way `RepoConfigs::read_repo` does
+fn read_hooks(
+    root_node: Node,
+    repo_node: Node,
+    raw_config: RawRepoConfig,
+    file: String,
+) -> BoxFuture<(Vec<ConfigDiagnostic>, Vec<HookLocation>), Error> {
+    let repo_dir = file.rsplitn(2, '/').nth(1).unwrap_or("").to_string();
+    let hooks = raw_config.hooks.unwrap_or(Vec::new());
+    future::join_all(hooks.into_iter().enumerate().map(move |(i, raw_hook)| {
+        let key = format!("hooks[{}].path", i);
+        let path = raw_hook.path.clone();
+        let hook_file = if path.starts_with(HOOK_PATH_RELATIVE_PREFIX) {
+            format!("{}/{}", repo_dir, &path[HOOK_PATH_RELATIVE_PREFIX.len()..])
+        } else {
+            path.clone()
+        };
+
+        let file = file.clone();
+        RepoConfigs::read_hook(root_node.clone(), repo_node.clone(), raw_hook).then(move |res| {
+            Ok::<_, Error>(match res {
+                Ok(hook) => Ok(HookLocation {
+                    file: hook_file,
+                    hook,
+                }),
+                Err(err) => Err(ConfigDiagnostic::new(
+                    file,
+                    Some(key),
+                    format!("failed to read hook file {}: {}", path, err),
+                )),
+            })
+        })
+    })).map(|results| {
+        let mut diagnostics = vec![];
+        let mut hooks = vec![];
+        for result in results {
+            match result {
+                Ok(hook) => hooks.push(hook),
+                Err(diagnostic) => diagnostics.push(diagnostic),
+            }
+        }
+        (diagnostics, hooks)
+    })
+        .boxify()
+}
+
+/// Read the shared hook modules, the same way `RepoConfigs::read_repos` does
+fn read_hook_libs(
+    root_node: Node,
+) -> BoxFuture<::std::result::Result<Vec<HookLibLocation>, ConfigDiagnostic>, Error> {
+    RepoConfigs::read_hook_libs(root_node)
+        .then(|res| {
+            Ok(match res {
+                Ok(libs) => {
+                    let mut libs: Vec<_> = libs.into_iter()
+                        .flat_map(|libs| libs)
+                        .map(|(name, code)| HookLibLocation {
+                            file: format!("{}/{}.lua", HOOK_LIBS_DIR, name),
+                            name,
+                            code,
+                        })
+                        .collect();
+                    libs.sort_by(|a, b| a.name.cmp(&b.name));
+                    Ok(libs)
+                }
+                Err(err) => Err(ConfigDiagnostic::new(
+                    HOOK_LIBS_DIR,
+                    None,
+                    format!("failed to read hook modules: {}", err),
+                )),
+            })
+        })
+        .boxify()
+}
+
+/// Repo ids and paths have to be unique across all repos served by one server
+fn check_unique_repos(summaries: &mut Vec<RepoSummary>) -> Vec<ConfigDiagnostic> {
+    // Sort so that the output doesn't depend on the order the repos were read in
+    summaries.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let mut diagnostics = vec![];
+    let mut repoids: HashMap<i32, &str> = HashMap::new();
+    let mut paths: HashMap<&str, &str> = HashMap::new();
+    for summary in summaries.iter() {
+        match repoids.get(&summary.repoid) {
+            Some(other) => diagnostics.push(ConfigDiagnostic::new(
+                summary.file.as_str(),
+                Some("repoid".to_string()),
+                format!(
+                    "repo id {} is already used by repo {}",
+                    summary.repoid, other
+                ),
+            )),
+            None => {
+                repoids.insert(summary.repoid, &summary.name);
+            }
+        }
+        match paths.get(summary.path.as_str()) {
+            Some(other) => diagnostics.push(ConfigDiagnostic::new(
+                summary.file.as_str(),
+                Some("path".to_string()),
+                format!("path {} is already used by repo {}", summary.path, other),
+            )),
+            None => {
+                paths.insert(&summary.path, &summary.name);
+            }
+        }
+    }
+    diagnostics
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::collections::BTreeMap;
+
+    use mercurial_types::FileType;
+    use mercurial_types_mocks::manifest::MockManifest;
+
+    fn validate(paths: BTreeMap<&str, (FileType, &str)>) -> ConfigValidation {
+        let root_manifest = MockManifest::from_paths(paths).expect("manifest is valid");
+        RepoConfigs::validate_manifest(&root_manifest)
+            .wait()
+            .expect("failed to validate manifest")
+    }
+
+    fn diagnostic(file: &str, key: Option<&str>, message: &str) -> ConfigDiagnostic {
+        ConfigDiagnostic::new(file, key.map(|key| key.to_string()), message)
+    }
+
+    #[test]
+    fn test_valid_config() {
+        let fbsource_content = r#"
+            path="/tmp/fbsource"
+            repotype="blob:rocks"
+            repoid=0
+            [[bookmarks]]
+            name="master"
+            [[bookmarks.hooks]]
+            hook_name="hook1"
+            [[hooks]]
+            name="hook1"
+            path="./hooks/hook1.lua"
+            hook_type="PerChangeset"
+            [[hooks]]
+            name="hook2"
+            path="common/hooks/hook2.lua"
+            hook_type="PerChangeset"
+        "#;
+        let validation = validate(btreemap! {
+            "repos/fbsource/server.toml" => (FileType::Regular, fbsource_content),
+            "repos/fbsource/hooks/hook1.lua" => (FileType::Regular, "this is hook1"),
+            "common/hooks/hook2.lua" => (FileType::Regular, "this is hook2"),
+        });
+        assert!(validation.is_valid(), "{:?}", validation.diagnostics);
+
+        let mut hooks: Vec<_> = validation
+            .hooks
+            .into_iter()
+            .map(|hook| (hook.file, hook.hook.name))
+            .collect();
+        hooks.sort();
+        assert_eq!(
+            hooks,
+            vec![
+                (
+                    "common/hooks/hook2.lua".to_string(),
+                    "hook2".to_string(),
+                ),
+                (
+                    "repos/fbsource/hooks/hook1.lua".to_string(),
+                    "hook1".to_string(),
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_reports_all_errors() {
+        let fbsource_content = r#"
+            path="/tmp/fbsource"
+            repotype="blob:testmanifold"
+            repoid=0
+            [[bookmarks]]
+            name="master"
+            [[bookmarks.hooks]]
+            hook_name="missing_hook"
+            [[hooks]]
+            name="hook1"
+            path="common/hooks/missing.lua"
+            hook_type="PerChangeset"
+        "#;
+        let www_content = r#"
+            path="relative/www"
+            repotype="revlog"
+            repoid=0
+        "#;
+        let validation = validate(btreemap! {
+            "repos/fbsource/server.toml" => (FileType::Regular, fbsource_content),
+            "repos/www/server.toml" => (FileType::Regular, www_content),
+        });
+
+        let mut diagnostics = validation.diagnostics;
+        diagnostics.sort_by(|a, b| (&a.file, &a.key).cmp(&(&b.file, &b.key)));
+        let fbsource = "repos/fbsource/server.toml";
+        let www = "repos/www/server.toml";
+        assert_eq!(diagnostics.len(), 6, "{:#?}", diagnostics);
+        assert_eq!(
+            diagnostics[0],
+            diagnostic(
+                fbsource,
+                Some("bookmarks[0].hooks[0].hook_name"),
+                "bookmark master refers to hook missing_hook which is not defined",
+            )
+        );
+        assert_eq!(
+            diagnostics[1],
+            diagnostic(fbsource, Some("db_address"), "required for repotype TestBlobManifold")
+        );
+        assert_eq!(diagnostics[2].key, Some("hooks[0].path".to_string()));
+        assert!(diagnostics[2].message.contains("common/hooks/missing.lua"));
+        assert_eq!(
+            diagnostics[3],
+            diagnostic(
+                fbsource,
+                Some("manifold_bucket"),
+                "required for repotype TestBlobManifold",
+            )
+        );
+        assert_eq!(
+            diagnostics[4],
+            diagnostic(www, Some("path"), "relative/www must be an absolute path")
+        );
+        assert_eq!(
+            diagnostics[5],
+            diagnostic(www, Some("repoid"), "repo id 0 is already used by repo fbsource")
+        );
+    }
+
+    #[test]
+    fn test_undefined_acl_group() {
+        let content = r#"
+            path="/tmp/fbsource"
+            repotype="blob:rocks"
+            repoid=0
+            [acl]
+            readers=["group:engineers", "alice"]
+            writers=["group:releng"]
+            [acl.groups]
+            engineers=["alice", "bob"]
+        "#;
+        let validation = validate(btreemap! {
+            "repos/fbsource/server.toml" => (FileType::Regular, content),
+        });
+        assert_eq!(
+            validation.diagnostics,
+            vec![
+                diagnostic(
+                    "repos/fbsource/server.toml",
+                    Some("acl.writers[0]"),
+                    "group releng is not defined in acl.groups",
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unknown_keys() {
+        let content = r#"
+            path="/tmp/fbsource"
+            repotype="blob:rocks"
+            repoid=0
+            scuba="mononoke"
+            [streaming_clone]
+            bookmark="master"
+            refresh_interval="60"
+            [clone_bundles]
+            bookmark="master"
+            base_url="ftp://bundles"
+            [[bookmarks]]
+            name="master"
+            hook="hook1"
+        "#;
+        let validation = validate(btreemap! {
+            "repos/fbsource/server.toml" => (FileType::Regular, content),
+        });
+        let file = "repos/fbsource/server.toml";
+        let mut diagnostics = validation.diagnostics;
+        diagnostics.sort_by(|a, b| (&a.file, &a.key).cmp(&(&b.file, &b.key)));
+        // The other checks still run when there are unknown keys
+        assert_eq!(
+            diagnostics,
+            vec![
+                diagnostic(file, Some("bookmarks[0].hook"), "unknown key, it is ignored"),
+                diagnostic(
+                    file,
+                    Some("clone_bundles.base_url"),
+                    "ftp://bundles must be an http or https URL",
+                ),
+                diagnostic(file, Some("scuba"), "unknown key, it is ignored"),
+                diagnostic(
+                    file,
+                    Some("streaming_clone.refresh_interval"),
+                    "unknown key, it is ignored",
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_hook_libs() {
+        let content = r#"
+            path="/tmp/fbsource"
+            repotype="blob:rocks"
+            repoid=0
+        "#;
+        let validation = validate(btreemap! {
+            "repos/fbsource/server.toml" => (FileType::Regular, content),
+            "hooks/lib/utils.lua" => (FileType::Regular, "return {}"),
+        });
+        assert!(validation.is_valid(), "{:?}", validation.diagnostics);
+        assert_eq!(
+            validation.hook_libs,
+            vec![
+                HookLibLocation {
+                    file: "hooks/lib/utils.lua".to_string(),
+                    name: "utils".to_string(),
+                    code: "return {}".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_invalid_toml() {
+        let validation = validate(btreemap! {
+            "repos/fbsource/server.toml" => (FileType::Regular, "path=\"/tmp/fbsource\"\nrepoid="),
+        });
+        assert_eq!(validation.diagnostics.len(), 1);
+        assert_eq!(validation.diagnostics[0].file, "repos/fbsource/server.toml");
+        assert!(
+            validation.diagnostics[0].message.contains("line 2"),
+            "{}",
+            validation.diagnostics[0]
+        );
+    }
+}
@@ -7,6 +7,8 @@
 #![deny(warnings)]
 
 extern crate blobrepo;
+extern crate blobstore;
+extern crate bytes;
 #[macro_use]
 extern crate failure_ext as failure;
 extern crate futures;
@@ -18,13 +20,15 @@ pub mod errors;
 
 use std::sync::Arc;
 
+use bytes::Bytes;
 use failure::Error;
 use futures::Future;
 
 use blobrepo::BlobRepo;
+use blobstore::Blobstore;
 use mercurial_types::{Changeset, HgChangesetId};
 use mercurial_types::manifest::Content;
-use mononoke_types::MPath;
+use mononoke_types::{BlobstoreBytes, MPath};
 
 use errors::ErrorKind;
 
@@ -44,3 +48,21 @@ pub fn get_content_by_path(
             content.ok_or_else(move || ErrorKind::NotFound(path.to_string()).into())
         })
 }
+
+/// Blobstore key of the clone bundle generated at `changesetid`
+pub fn clone_bundle_key(changesetid: &HgChangesetId) -> String {
+    format!("clonebundle.{}", changesetid)
+}
+
+pub fn get_clone_bundle(
+    repo: Arc<BlobRepo>,
+    changesetid: HgChangesetId,
+) -> impl Future<Item = Bytes, Error = Error> {
+    repo.get_blobstore()
+        .get(clone_bundle_key(&changesetid))
+        .and_then(move |bundle| {
+            bundle
+                .map(BlobstoreBytes::into_bytes)
+                .ok_or_else(move || ErrorKind::NotFound(changesetid.to_string()).into())
+        })
+}
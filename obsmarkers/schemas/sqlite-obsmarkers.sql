@@ -0,0 +1,19 @@
+CREATE TABLE obsmarkers (
+  repo_id INTEGER NOT NULL,
+  marker_id BINARY(20) NOT NULL,
+  predecessor BINARY(20) NOT NULL,
+  successors BLOB NOT NULL,
+  parents BLOB,
+  flags INTEGER NOT NULL,
+  metadata BLOB NOT NULL,
+  date DOUBLE NOT NULL,
+  tz_offset INTEGER NOT NULL,
+  PRIMARY KEY (repo_id, marker_id)
+);
+
+CREATE TABLE obsmarker_relevance (
+  repo_id INTEGER NOT NULL,
+  cs_id BINARY(20) NOT NULL,
+  marker_id BINARY(20) NOT NULL,
+  PRIMARY KEY (repo_id, cs_id, marker_id)
+);
@@ -0,0 +1,297 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Obsolescence markers
+//!
+//! Markers record which changesets were rewritten into which, so that clients that pull an
+//! amended or rebased changeset hide the changeset it replaces. Mononoke doesn't interpret them,
+//! it stores the markers clients push and sends them to the clients that pull the changesets
+//! they are relevant to.
+
+#![deny(warnings)]
+#![feature(never_type)]
+
+extern crate bytes;
+extern crate db_conn;
+#[macro_use]
+extern crate diesel;
+#[macro_use]
+extern crate failure_ext as failure;
+extern crate futures;
+
+extern crate futures_ext;
+#[macro_use]
+extern crate lazy_static;
+extern crate mercurial_types;
+#[macro_use]
+extern crate stats;
+
+use std::collections::{HashMap, HashSet};
+use std::result;
+use std::sync::{Arc, MutexGuard};
+
+use db_conn::{MysqlConnInner, SqliteConnInner};
+use diesel::{insert_or_ignore_into, MysqlConnection, SqliteConnection};
+use diesel::prelude::*;
+use diesel::r2d2::{ConnectionManager, PooledConnection};
+
+use futures_ext::{asynchronize, BoxFuture};
+use mercurial_types::{HgChangesetId, HgObsmarker, RepositoryId};
+use stats::Timeseries;
+
+mod errors;
+mod models;
+mod schema;
+
+pub use errors::*;
+use models::{ObsmarkerRow, RelevanceRow};
+use schema::{obsmarker_relevance, obsmarkers};
+
+define_stats! {
+    prefix = "mononoke.obsmarkers";
+    adds: timeseries(RATE, SUM),
+    gets_relevant: timeseries(RATE, SUM),
+    gets_all: timeseries(RATE, SUM),
+}
+
+/// Number of rows read or written by a single query, which keeps queries under the limit on the
+/// number of bound parameters of SQLite
+const CHUNK_SIZE: usize = 100;
+
+pub trait Obsmarkers: Send + Sync {
+    /// Store markers, markers that are stored already are ignored. Returns the number of new
+    /// markers.
+    fn add(&self, repo_id: RepositoryId, markers: Vec<HgObsmarker>) -> BoxFuture<usize, Error>;
+
+    /// The markers that Mercurial exchanges along with `cs_ids`: markers that have one of them as
+    /// a successor, prune markers of them or of their children, and recursively the relevant
+    /// markers of the predecessors of those markers
+    fn get_relevant(
+        &self,
+        repo_id: RepositoryId,
+        cs_ids: Vec<HgChangesetId>,
+    ) -> BoxFuture<Vec<HgObsmarker>, Error>;
+
+    /// All the markers of a repo
+    fn get_all(&self, repo_id: RepositoryId) -> BoxFuture<Vec<HgObsmarker>, Error>;
+}
+
+impl Obsmarkers for Arc<Obsmarkers> {
+    fn add(&self, repo_id: RepositoryId, markers: Vec<HgObsmarker>) -> BoxFuture<usize, Error> {
+        (**self).add(repo_id, markers)
+    }
+
+    fn get_relevant(
+        &self,
+        repo_id: RepositoryId,
+        cs_ids: Vec<HgChangesetId>,
+    ) -> BoxFuture<Vec<HgObsmarker>, Error> {
+        (**self).get_relevant(repo_id, cs_ids)
+    }
+
+    fn get_all(&self, repo_id: RepositoryId) -> BoxFuture<Vec<HgObsmarker>, Error> {
+        (**self).get_all(repo_id)
+    }
+}
+
+#[derive(Clone)]
+pub struct SqliteObsmarkers {
+    inner: SqliteConnInner,
+}
+
+impl SqliteObsmarkers {
+    fn from(inner: SqliteConnInner) -> Self {
+        Self { inner }
+    }
+
+    fn get_up_query() -> &'static str {
+        include_str!("../schemas/sqlite-obsmarkers.sql")
+    }
+
+    /// Create a new in-memory empty database. Great for tests.
+    pub fn in_memory() -> Result<Self> {
+        Ok(Self::from(SqliteConnInner::in_memory(
+            Self::get_up_query(),
+        )?))
+    }
+
+    pub fn open_or_create<P: AsRef<str>>(path: P) -> Result<Self> {
+        Ok(Self::from(SqliteConnInner::open_or_create(
+            path,
+            Self::get_up_query(),
+        )?))
+    }
+
+    fn get_master_conn(&self) -> result::Result<MutexGuard<SqliteConnection>, !> {
+        self.inner.get_master_conn()
+    }
+}
+
+#[derive(Clone)]
+pub struct MysqlObsmarkers {
+    inner: MysqlConnInner,
+}
+
+impl MysqlObsmarkers {
+    fn from(inner: MysqlConnInner) -> Self {
+        Self { inner }
+    }
+
+    pub fn open(db_address: &str) -> Result<Self> {
+        Ok(Self::from(MysqlConnInner::open(db_address)?))
+    }
+
+    fn get_up_query() -> &'static str {
+        include_str!("../schemas/mysql-obsmarkers.sql")
+    }
+
+    pub fn create_test_db<P: AsRef<str>>(prefix: P) -> Result<Self> {
+        Ok(Self::from(MysqlConnInner::create_test_db(
+            prefix,
+            Self::get_up_query(),
+        )?))
+    }
+
+    fn get_master_conn(&self) -> Result<PooledConnection<ConnectionManager<MysqlConnection>>> {
+        self.inner.get_master_conn()
+    }
+}
+
+/// Using a macro here is unfortunate, but it appears to be the only way to share this code
+/// between SQLite and MySQL.
+/// See https://github.com/diesel-rs/diesel/issues/882#issuecomment-300257476
+macro_rules! impl_obsmarkers {
+    ($struct:ty) => {
+        impl Obsmarkers for $struct {
+            fn add(
+                &self,
+                repo_id: RepositoryId,
+                markers: Vec<HgObsmarker>,
+            ) -> BoxFuture<usize, Error> {
+                STATS::adds.add_value(1);
+                let db = self.clone();
+
+                asynchronize(move || {
+                    // The same marker might be pushed several times in a single batch
+                    let mut rows = HashMap::new();
+                    for marker in &markers {
+                        let row = ObsmarkerRow::from_marker(repo_id, marker)?;
+                        rows.insert(row.marker_id.clone(), (row, relevant_cs_ids(marker)));
+                    }
+
+                    let mut marker_rows = vec![];
+                    let mut relevance_rows = vec![];
+                    for (marker_id, (row, cs_ids)) in rows {
+                        marker_rows.push(row);
+                        relevance_rows.extend(cs_ids.into_iter().map(|cs_id| RelevanceRow {
+                            repo_id,
+                            cs_id,
+                            marker_id: marker_id.clone(),
+                        }));
+                    }
+
+                    let connection = db.get_master_conn()?;
+                    connection.transaction::<_, Error, _>(|| {
+                        let mut new = 0;
+                        for rows in marker_rows.chunks(CHUNK_SIZE) {
+                            new += insert_or_ignore_into(obsmarkers::table)
+                                .values(rows)
+                                .execute(&*connection)?;
+                        }
+                        for rows in relevance_rows.chunks(CHUNK_SIZE) {
+                            insert_or_ignore_into(obsmarker_relevance::table)
+                                .values(rows)
+                                .execute(&*connection)?;
+                        }
+                        Ok(new)
+                    })
+                })
+            }
+
+            fn get_relevant(
+                &self,
+                repo_id: RepositoryId,
+                cs_ids: Vec<HgChangesetId>,
+            ) -> BoxFuture<Vec<HgObsmarker>, Error> {
+                STATS::gets_relevant.add_value(1);
+                let db = self.clone();
+
+                asynchronize(move || {
+                    // Markers were just pushed by another client in the typical case, so read
+                    // them from the master
+                    let connection = db.get_master_conn()?;
+
+                    let mut seen_cs_ids: HashSet<_> = cs_ids.iter().cloned().collect();
+                    let mut seen_marker_ids = HashSet::new();
+                    let mut markers = vec![];
+                    let mut pending = cs_ids;
+                    while !pending.is_empty() {
+                        let mut marker_ids = vec![];
+                        for cs_ids in pending.chunks(CHUNK_SIZE) {
+                            let ids = obsmarker_relevance::table
+                                .filter(obsmarker_relevance::repo_id.eq(repo_id))
+                                .filter(obsmarker_relevance::cs_id.eq_any(cs_ids))
+                                .select(obsmarker_relevance::marker_id)
+                                .load::<Vec<u8>>(&*connection)?;
+                            marker_ids.extend(
+                                ids.into_iter()
+                                    .filter(|id| seen_marker_ids.insert(id.clone())),
+                            );
+                        }
+
+                        pending = vec![];
+                        for marker_ids in marker_ids.chunks(CHUNK_SIZE) {
+                            let rows = obsmarkers::table
+                                .filter(obsmarkers::repo_id.eq(repo_id))
+                                .filter(obsmarkers::marker_id.eq_any(marker_ids))
+                                .load::<ObsmarkerRow>(&*connection)?;
+                            for row in rows {
+                                let marker = row.into_marker()?;
+                                if seen_cs_ids.insert(marker.predecessor) {
+                                    pending.push(marker.predecessor);
+                                }
+                                markers.push(marker);
+                            }
+                        }
+                    }
+                    Ok(markers)
+                })
+            }
+
+            fn get_all(&self, repo_id: RepositoryId) -> BoxFuture<Vec<HgObsmarker>, Error> {
+                STATS::gets_all.add_value(1);
+                let db = self.clone();
+
+                asynchronize(move || {
+                    let connection = db.get_master_conn()?;
+                    obsmarkers::table
+                        .filter(obsmarkers::repo_id.eq(repo_id))
+                        .load::<ObsmarkerRow>(&*connection)?
+                        .into_iter()
+                        .map(ObsmarkerRow::into_marker)
+                        .collect()
+                })
+            }
+        }
+    };
+}
+
+impl_obsmarkers!(MysqlObsmarkers);
+impl_obsmarkers!(SqliteObsmarkers);
+
+/// The changesets that a marker is directly relevant to: its successors, or for a prune marker
+/// the pruned changeset and its parents
+fn relevant_cs_ids(marker: &HgObsmarker) -> Vec<HgChangesetId> {
+    if marker.is_prune() {
+        let mut cs_ids = vec![marker.predecessor];
+        if let Some(ref parents) = marker.parents {
+            cs_ids.extend(parents.iter().cloned());
+        }
+        cs_ids
+    } else {
+        marker.successors.clone()
+    }
+}
@@ -0,0 +1,113 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use bytes::Bytes;
+use mercurial_types::{HgChangesetId, HgNodeHash, HgObsmarker, RepositoryId};
+
+use errors::*;
+use schema::{obsmarker_relevance, obsmarkers};
+
+const NODE_SIZE: usize = 20;
+
+#[derive(Clone, Debug, PartialEq)]
+#[derive(Queryable, Insertable)]
+#[table_name = "obsmarkers"]
+pub(crate) struct ObsmarkerRow {
+    pub repo_id: RepositoryId,
+    pub marker_id: Vec<u8>,
+    pub predecessor: HgChangesetId,
+    /// Concatenated successor nodes
+    pub successors: Vec<u8>,
+    /// Concatenated parent nodes, if the marker records them
+    pub parents: Option<Vec<u8>>,
+    pub flags: i32,
+    /// Metadata pairs, each as the lengths of the key and of the value (one byte each) followed
+    /// by the key and the value
+    pub metadata: Vec<u8>,
+    pub date: f64,
+    pub tz_offset: i32,
+}
+
+impl ObsmarkerRow {
+    pub fn from_marker(repo_id: RepositoryId, marker: &HgObsmarker) -> Result<Self> {
+        let mut metadata = vec![];
+        for &(ref key, ref value) in &marker.metadata {
+            // Markers that don't fit in a version 1 marker don't have an id, so they never get
+            // here with longer metadata
+            metadata.push(key.len() as u8);
+            metadata.push(value.len() as u8);
+            metadata.extend_from_slice(key);
+            metadata.extend_from_slice(value);
+        }
+
+        Ok(ObsmarkerRow {
+            repo_id,
+            marker_id: marker.id()?.as_ref().to_vec(),
+            predecessor: marker.predecessor,
+            successors: encode_nodes(&marker.successors),
+            parents: marker.parents.as_ref().map(|parents| encode_nodes(parents)),
+            flags: marker.flags as i32,
+            metadata,
+            date: marker.date,
+            tz_offset: marker.tz_offset,
+        })
+    }
+
+    pub fn into_marker(self) -> Result<HgObsmarker> {
+        let mut metadata = vec![];
+        let mut rest = &self.metadata[..];
+        while !rest.is_empty() {
+            if rest.len() < 2 || rest.len() < 2 + rest[0] as usize + rest[1] as usize {
+                return Err(ErrorKind::InvalidRow("truncated metadata".into()).into());
+            }
+            let (key_len, value_len) = (rest[0] as usize, rest[1] as usize);
+            let key = Bytes::from(&rest[2..2 + key_len]);
+            let value = Bytes::from(&rest[2 + key_len..2 + key_len + value_len]);
+            metadata.push((key, value));
+            rest = &rest[2 + key_len + value_len..];
+        }
+
+        Ok(HgObsmarker {
+            predecessor: self.predecessor,
+            successors: decode_nodes(&self.successors)?,
+            parents: match self.parents {
+                Some(parents) => Some(decode_nodes(&parents)?),
+                None => None,
+            },
+            flags: self.flags as u16,
+            metadata,
+            date: self.date,
+            tz_offset: self.tz_offset,
+        })
+    }
+}
+
+/// A changeset that a marker is relevant to, see `Obsmarkers::get_relevant`
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+#[derive(Queryable, Insertable)]
+#[table_name = "obsmarker_relevance"]
+pub(crate) struct RelevanceRow {
+    pub repo_id: RepositoryId,
+    pub cs_id: HgChangesetId,
+    pub marker_id: Vec<u8>,
+}
+
+fn encode_nodes(nodes: &[HgChangesetId]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(nodes.len() * NODE_SIZE);
+    for node in nodes {
+        out.extend_from_slice(node.as_nodehash().as_bytes());
+    }
+    out
+}
+
+fn decode_nodes(data: &[u8]) -> Result<Vec<HgChangesetId>> {
+    if data.len() % NODE_SIZE != 0 {
+        return Err(ErrorKind::InvalidRow(format!("{} bytes of nodes", data.len())).into());
+    }
+    data.chunks(NODE_SIZE)
+        .map(|node| Ok(HgChangesetId::new(HgNodeHash::from_bytes(node)?)))
+        .collect()
+}
@@ -0,0 +1,39 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! The `table!` macros in this module describe the schemas for these tables in SQL storage
+//! (MySQL or SQLite). These descriptions are *not* the source of truth, so if the schema ever
+//! changes it will need to be updated here as well.
+
+table! {
+    use diesel::sql_types::{Binary, Double, Integer, Nullable};
+
+    use mercurial_types::sql_types::HgChangesetIdSql;
+
+    obsmarkers (repo_id, marker_id) {
+        repo_id -> Integer,
+        marker_id -> Binary,
+        predecessor -> HgChangesetIdSql,
+        successors -> Binary,
+        parents -> Nullable<Binary>,
+        flags -> Integer,
+        metadata -> Binary,
+        date -> Double,
+        tz_offset -> Integer,
+    }
+}
+
+table! {
+    use diesel::sql_types::{Binary, Integer};
+
+    use mercurial_types::sql_types::HgChangesetIdSql;
+
+    obsmarker_relevance (repo_id, cs_id, marker_id) {
+        repo_id -> Integer,
+        cs_id -> HgChangesetIdSql,
+        marker_id -> Binary,
+    }
+}
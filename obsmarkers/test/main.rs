@@ -0,0 +1,182 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Tests for the Obsmarkers store.
+
+#![deny(warnings)]
+
+extern crate async_unit;
+extern crate bytes;
+extern crate futures;
+
+extern crate mercurial_types;
+extern crate mercurial_types_mocks;
+extern crate obsmarkers;
+
+use std::sync::Arc;
+
+use bytes::Bytes;
+use futures::Future;
+
+use mercurial_types::{HgChangesetId, HgObsmarker};
+use mercurial_types_mocks::nodehash::*;
+use mercurial_types_mocks::repo::{REPO_ONE, REPO_ZERO};
+use obsmarkers::{MysqlObsmarkers, Obsmarkers, SqliteObsmarkers};
+
+fn marker(predecessor: HgChangesetId, successors: Vec<HgChangesetId>) -> HgObsmarker {
+    HgObsmarker {
+        predecessor,
+        successors,
+        parents: None,
+        flags: 0,
+        metadata: vec![(Bytes::from("user"), Bytes::from("test"))],
+        date: 1500000000.0,
+        tz_offset: 0,
+    }
+}
+
+fn prune(predecessor: HgChangesetId, parent: HgChangesetId) -> HgObsmarker {
+    HgObsmarker {
+        parents: Some(vec![parent]),
+        ..marker(predecessor, vec![])
+    }
+}
+
+fn sorted(mut markers: Vec<HgObsmarker>) -> Vec<HgObsmarker> {
+    markers.sort_by(|a, b| {
+        (a.predecessor, &a.successors).cmp(&(b.predecessor, &b.successors))
+    });
+    markers
+}
+
+fn add_and_get_all<O: Obsmarkers>(store: O) {
+    let markers = vec![marker(ONES_CSID, vec![TWOS_CSID]), prune(THREES_CSID, ONES_CSID)];
+    let new = store
+        .add(REPO_ZERO, markers.clone())
+        .wait()
+        .expect("Adding markers failed");
+    assert_eq!(new, 2);
+
+    // Markers that are there already are not new
+    let new = store
+        .add(REPO_ZERO, vec![markers[0].clone(), markers[0].clone()])
+        .wait()
+        .expect("Adding markers again failed");
+    assert_eq!(new, 0);
+
+    let result = store
+        .get_all(REPO_ZERO)
+        .wait()
+        .expect("Getting all markers failed");
+    assert_eq!(sorted(result), sorted(markers));
+
+    let result = store
+        .get_all(REPO_ONE)
+        .wait()
+        .expect("Getting markers of another repo failed");
+    assert!(result.is_empty());
+}
+
+fn get_relevant<O: Obsmarkers>(store: O) {
+    // ONES was amended into TWOS, which was rebased into THREES. FIVES, a child of THREES, was
+    // pruned. FOURS was amended into SIXES, which is unrelated.
+    let amend = marker(ONES_CSID, vec![TWOS_CSID]);
+    let rebase = marker(TWOS_CSID, vec![THREES_CSID]);
+    let pruned_child = prune(FIVES_CSID, THREES_CSID);
+    let unrelated = marker(FOURS_CSID, vec![SIXES_CSID]);
+    store
+        .add(
+            REPO_ZERO,
+            vec![
+                amend.clone(),
+                rebase.clone(),
+                pruned_child.clone(),
+                unrelated.clone(),
+            ],
+        )
+        .wait()
+        .expect("Adding markers failed");
+
+    let result = store
+        .get_relevant(REPO_ZERO, vec![THREES_CSID])
+        .wait()
+        .expect("Getting relevant markers failed");
+    assert_eq!(sorted(result), sorted(vec![amend, rebase, pruned_child]));
+
+    let result = store
+        .get_relevant(REPO_ZERO, vec![ONES_CSID])
+        .wait()
+        .expect("Getting relevant markers of a predecessor failed");
+    assert!(result.is_empty());
+
+    let result = store
+        .get_relevant(REPO_ONE, vec![SIXES_CSID])
+        .wait()
+        .expect("Getting relevant markers of another repo failed");
+    assert!(result.is_empty());
+}
+
+macro_rules! obsmarkers_test_impl {
+    ($mod_name:ident => { new: $new_cb:expr, }) => {
+        mod $mod_name {
+            use super::*;
+
+            #[test]
+            fn test_add_and_get_all() {
+                async_unit::tokio_unit_test(|| {
+                    add_and_get_all($new_cb());
+                });
+            }
+
+            #[test]
+            fn test_get_relevant() {
+                async_unit::tokio_unit_test(|| {
+                    get_relevant($new_cb());
+                });
+            }
+        }
+    };
+}
+
+obsmarkers_test_impl! {
+    sqlite_test => {
+        new: new_sqlite,
+    }
+}
+
+obsmarkers_test_impl! {
+    sqlite_arced_test => {
+        new: new_sqlite_arced,
+    }
+}
+
+obsmarkers_test_impl! {
+    mysql_test => {
+        new: new_mysql,
+    }
+}
+
+obsmarkers_test_impl! {
+    mysql_arced_test => {
+        new: new_mysql_arced,
+    }
+}
+
+fn new_sqlite() -> SqliteObsmarkers {
+    SqliteObsmarkers::in_memory().expect("Creating an in-memory SQLite database failed")
+}
+
+fn new_sqlite_arced() -> Arc<Obsmarkers> {
+    Arc::new(new_sqlite())
+}
+
+fn new_mysql() -> MysqlObsmarkers {
+    MysqlObsmarkers::create_test_db("obsmarkers_test").expect("Failed to create test database")
+}
+
+fn new_mysql_arced() -> Arc<Obsmarkers> {
+    Arc::new(new_mysql())
+}
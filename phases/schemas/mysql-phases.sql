@@ -0,0 +1,7 @@
+CREATE TABLE phases (
+  repo_id INTEGER NOT NULL,
+  cs_id BINARY(20) NOT NULL,
+  phase INTEGER NOT NULL,
+  PRIMARY KEY (repo_id, cs_id),
+  INDEX phase_per_repo (repo_id, phase)
+);
@@ -0,0 +1,8 @@
+CREATE TABLE phases (
+  repo_id INTEGER NOT NULL,
+  cs_id BINARY(20) NOT NULL,
+  phase INTEGER NOT NULL,
+  PRIMARY KEY (repo_id, cs_id)
+);
+
+CREATE INDEX phase_per_repo ON phases (repo_id, phase);
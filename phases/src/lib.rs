@@ -0,0 +1,329 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Phases of changesets
+//!
+//! Changesets reachable from a bookmark are public, changesets that were pushed without becoming
+//! reachable from a bookmark, like the ones of infinitepush scratch branches, are draft. A public
+//! changeset never becomes draft again. Changesets without a phase in the store are draft.
+
+#![deny(warnings)]
+#![feature(never_type)]
+
+extern crate db_conn;
+#[macro_use]
+extern crate diesel;
+#[macro_use]
+extern crate failure_ext as failure;
+extern crate futures;
+
+extern crate futures_ext;
+#[macro_use]
+extern crate lazy_static;
+extern crate mercurial_types;
+#[macro_use]
+extern crate stats;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::result;
+use std::sync::{Arc, MutexGuard};
+
+use db_conn::{MysqlConnInner, SqliteConnInner};
+use diesel::{insert_or_ignore_into, replace_into, MysqlConnection, SqliteConnection};
+use diesel::prelude::*;
+use diesel::r2d2::{ConnectionManager, PooledConnection};
+
+use futures_ext::{asynchronize, BoxFuture};
+use mercurial_types::{HgChangesetId, RepositoryId};
+use stats::Timeseries;
+
+mod errors;
+mod models;
+mod schema;
+
+pub use errors::*;
+use models::PhaseRow;
+use schema::phases;
+
+define_stats! {
+    prefix = "mononoke.phases";
+    gets: timeseries(RATE, SUM),
+    gets_master: timeseries(RATE, SUM),
+    adds: timeseries(RATE, SUM),
+    lists: timeseries(RATE, SUM),
+}
+
+/// Number of changesets read or written by a single query, which keeps queries under the limit
+/// on the number of bound parameters of SQLite
+const CHUNK_SIZE: usize = 300;
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Phase {
+    Public,
+    Draft,
+}
+
+impl Phase {
+    /// Number of the phase in the Mercurial wire protocol, which is also how it is stored
+    pub fn to_hg(&self) -> u32 {
+        match self {
+            &Phase::Public => 0,
+            &Phase::Draft => 1,
+        }
+    }
+
+    fn from_sql(phase: i32) -> Result<Self> {
+        match phase {
+            0 => Ok(Phase::Public),
+            1 => Ok(Phase::Draft),
+            _ => Err(ErrorKind::InvalidPhase(phase).into()),
+        }
+    }
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &Phase::Public => write!(f, "public"),
+            &Phase::Draft => write!(f, "draft"),
+        }
+    }
+}
+
+pub trait Phases: Send + Sync {
+    /// Record the phases of changesets. Changesets that are public already stay public, even if
+    /// they are added as draft.
+    fn add(
+        &self,
+        repo_id: RepositoryId,
+        phases: Vec<(HgChangesetId, Phase)>,
+    ) -> BoxFuture<(), Error>;
+
+    /// The phases of those of `cs_ids` that have one
+    fn get(
+        &self,
+        repo_id: RepositoryId,
+        cs_ids: Vec<HgChangesetId>,
+    ) -> BoxFuture<HashMap<HgChangesetId, Phase>, Error>;
+
+    /// All changesets that are in `phase`
+    fn list_by_phase(
+        &self,
+        repo_id: RepositoryId,
+        phase: Phase,
+    ) -> BoxFuture<Vec<HgChangesetId>, Error>;
+}
+
+impl Phases for Arc<Phases> {
+    fn add(
+        &self,
+        repo_id: RepositoryId,
+        phases: Vec<(HgChangesetId, Phase)>,
+    ) -> BoxFuture<(), Error> {
+        (**self).add(repo_id, phases)
+    }
+
+    fn get(
+        &self,
+        repo_id: RepositoryId,
+        cs_ids: Vec<HgChangesetId>,
+    ) -> BoxFuture<HashMap<HgChangesetId, Phase>, Error> {
+        (**self).get(repo_id, cs_ids)
+    }
+
+    fn list_by_phase(
+        &self,
+        repo_id: RepositoryId,
+        phase: Phase,
+    ) -> BoxFuture<Vec<HgChangesetId>, Error> {
+        (**self).list_by_phase(repo_id, phase)
+    }
+}
+
+#[derive(Clone)]
+pub struct SqlitePhases {
+    inner: SqliteConnInner,
+}
+
+impl SqlitePhases {
+    fn from(inner: SqliteConnInner) -> Self {
+        Self { inner }
+    }
+
+    fn get_up_query() -> &'static str {
+        include_str!("../schemas/sqlite-phases.sql")
+    }
+
+    /// Create a new in-memory empty database. Great for tests.
+    pub fn in_memory() -> Result<Self> {
+        Ok(Self::from(SqliteConnInner::in_memory(
+            Self::get_up_query(),
+        )?))
+    }
+
+    pub fn open_or_create<P: AsRef<str>>(path: P) -> Result<Self> {
+        Ok(Self::from(SqliteConnInner::open_or_create(
+            path,
+            Self::get_up_query(),
+        )?))
+    }
+
+    fn get_conn(&self) -> result::Result<MutexGuard<SqliteConnection>, !> {
+        self.inner.get_conn()
+    }
+    fn get_master_conn(&self) -> result::Result<MutexGuard<SqliteConnection>, !> {
+        self.inner.get_master_conn()
+    }
+}
+
+#[derive(Clone)]
+pub struct MysqlPhases {
+    inner: MysqlConnInner,
+}
+
+impl MysqlPhases {
+    fn from(inner: MysqlConnInner) -> Self {
+        Self { inner }
+    }
+
+    pub fn open(db_address: &str) -> Result<Self> {
+        Ok(Self::from(MysqlConnInner::open(db_address)?))
+    }
+
+    fn get_up_query() -> &'static str {
+        include_str!("../schemas/mysql-phases.sql")
+    }
+
+    pub fn create_test_db<P: AsRef<str>>(prefix: P) -> Result<Self> {
+        Ok(Self::from(MysqlConnInner::create_test_db(
+            prefix,
+            Self::get_up_query(),
+        )?))
+    }
+
+    fn get_conn(&self) -> Result<PooledConnection<ConnectionManager<MysqlConnection>>> {
+        self.inner.get_conn()
+    }
+
+    fn get_master_conn(&self) -> Result<PooledConnection<ConnectionManager<MysqlConnection>>> {
+        self.inner.get_master_conn()
+    }
+}
+
+/// Using a macro here is unfortunate, but it appears to be the only way to share this code
+/// between SQLite and MySQL.
+/// See https://github.com/diesel-rs/diesel/issues/882#issuecomment-300257476
+macro_rules! impl_phases {
+    ($struct:ty, $connection:ty) => {
+        impl Phases for $struct {
+            fn add(
+                &self,
+                repo_id: RepositoryId,
+                phases: Vec<(HgChangesetId, Phase)>,
+            ) -> BoxFuture<(), Error> {
+                STATS::adds.add_value(1);
+                let db = self.clone();
+
+                asynchronize(move || {
+                    let connection = db.get_master_conn()?;
+                    let (public, draft): (Vec<_>, Vec<_>) = phases
+                        .into_iter()
+                        .map(|(cs_id, phase)| PhaseRow {
+                            repo_id,
+                            cs_id,
+                            phase: phase.to_hg() as i32,
+                        })
+                        .partition(|row| row.phase == Phase::Public.to_hg() as i32);
+
+                    // Drafts don't replace any phase, public replaces draft
+                    for rows in draft.chunks(CHUNK_SIZE) {
+                        insert_or_ignore_into(phases::table)
+                            .values(rows)
+                            .execute(&*connection)?;
+                    }
+                    for rows in public.chunks(CHUNK_SIZE) {
+                        replace_into(phases::table)
+                            .values(rows)
+                            .execute(&*connection)?;
+                    }
+                    Ok(())
+                })
+            }
+
+            fn get(
+                &self,
+                repo_id: RepositoryId,
+                cs_ids: Vec<HgChangesetId>,
+            ) -> BoxFuture<HashMap<HgChangesetId, Phase>, Error> {
+                STATS::gets.add_value(1);
+                let db = self.clone();
+
+                asynchronize(move || {
+                    let mut result = {
+                        let connection = db.get_conn()?;
+                        Self::actual_get(&connection, repo_id, &cs_ids)?
+                    };
+
+                    // A changeset that is draft or missing in a replica might have been made
+                    // public since
+                    let unknown: Vec<_> = cs_ids
+                        .into_iter()
+                        .filter(|cs_id| result.get(cs_id) != Some(&Phase::Public))
+                        .collect();
+                    if !unknown.is_empty() {
+                        STATS::gets_master.add_value(1);
+                        let connection = db.get_master_conn()?;
+                        result.extend(Self::actual_get(&connection, repo_id, &unknown)?);
+                    }
+                    Ok(result)
+                })
+            }
+
+            fn list_by_phase(
+                &self,
+                repo_id: RepositoryId,
+                phase: Phase,
+            ) -> BoxFuture<Vec<HgChangesetId>, Error> {
+                STATS::lists.add_value(1);
+                let db = self.clone();
+
+                asynchronize(move || {
+                    let connection = db.get_master_conn()?;
+                    phases::table
+                        .filter(phases::repo_id.eq(repo_id))
+                        .filter(phases::phase.eq(phase.to_hg() as i32))
+                        .select(phases::cs_id)
+                        .load::<HgChangesetId>(&*connection)
+                        .map_err(failure::Error::from)
+                })
+            }
+        }
+
+        impl $struct {
+            fn actual_get(
+                connection: &$connection,
+                repo_id: RepositoryId,
+                cs_ids: &[HgChangesetId],
+            ) -> Result<HashMap<HgChangesetId, Phase>> {
+                let mut result = HashMap::new();
+                for cs_ids in cs_ids.chunks(CHUNK_SIZE) {
+                    let rows = phases::table
+                        .filter(phases::repo_id.eq(repo_id))
+                        .filter(phases::cs_id.eq_any(cs_ids))
+                        .load::<PhaseRow>(connection)?;
+                    for row in rows {
+                        result.insert(row.cs_id, Phase::from_sql(row.phase)?);
+                    }
+                }
+                Ok(result)
+            }
+        }
+    };
+}
+
+impl_phases!(MysqlPhases, MysqlConnection);
+impl_phases!(SqlitePhases, SqliteConnection);
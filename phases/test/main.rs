@@ -0,0 +1,170 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Tests for the Phases store.
+
+#![deny(warnings)]
+
+extern crate async_unit;
+extern crate futures;
+
+extern crate mercurial_types_mocks;
+extern crate phases;
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use futures::Future;
+
+use mercurial_types_mocks::nodehash::*;
+use mercurial_types_mocks::repo::{REPO_ONE, REPO_ZERO};
+use phases::{MysqlPhases, Phase, Phases, SqlitePhases};
+
+fn add_and_get<P: Phases>(phases: P) {
+    phases
+        .add(
+            REPO_ZERO,
+            vec![(ONES_CSID, Phase::Public), (TWOS_CSID, Phase::Draft)],
+        )
+        .wait()
+        .expect("Adding phases failed");
+
+    let result = phases
+        .get(REPO_ZERO, vec![ONES_CSID, TWOS_CSID, THREES_CSID])
+        .wait()
+        .expect("Getting phases failed");
+    let expected: HashMap<_, _> = vec![(ONES_CSID, Phase::Public), (TWOS_CSID, Phase::Draft)]
+        .into_iter()
+        .collect();
+    assert_eq!(result, expected);
+
+    let result = phases
+        .get(REPO_ONE, vec![ONES_CSID, TWOS_CSID])
+        .wait()
+        .expect("Getting phases of another repo failed");
+    assert!(result.is_empty());
+}
+
+fn public_stays_public<P: Phases>(phases: P) {
+    phases
+        .add(REPO_ZERO, vec![(ONES_CSID, Phase::Draft)])
+        .wait()
+        .expect("Adding a draft failed");
+    phases
+        .add(REPO_ZERO, vec![(ONES_CSID, Phase::Public)])
+        .wait()
+        .expect("Making a draft public failed");
+    phases
+        .add(REPO_ZERO, vec![(ONES_CSID, Phase::Draft)])
+        .wait()
+        .expect("Adding a public changeset as draft failed");
+
+    let result = phases
+        .get(REPO_ZERO, vec![ONES_CSID])
+        .wait()
+        .expect("Getting phases failed");
+    assert_eq!(result.get(&ONES_CSID), Some(&Phase::Public));
+}
+
+fn list_by_phase<P: Phases>(phases: P) {
+    phases
+        .add(
+            REPO_ZERO,
+            vec![
+                (ONES_CSID, Phase::Public),
+                (TWOS_CSID, Phase::Draft),
+                (THREES_CSID, Phase::Draft),
+            ],
+        )
+        .wait()
+        .expect("Adding phases failed");
+    phases
+        .add(REPO_ONE, vec![(FOURS_CSID, Phase::Draft)])
+        .wait()
+        .expect("Adding phases to another repo failed");
+
+    let mut drafts = phases
+        .list_by_phase(REPO_ZERO, Phase::Draft)
+        .wait()
+        .expect("Listing drafts failed");
+    drafts.sort();
+    assert_eq!(drafts, vec![TWOS_CSID, THREES_CSID]);
+
+    let public = phases
+        .list_by_phase(REPO_ZERO, Phase::Public)
+        .wait()
+        .expect("Listing public changesets failed");
+    assert_eq!(public, vec![ONES_CSID]);
+}
+
+macro_rules! phases_test_impl {
+    ($mod_name:ident => { new: $new_cb:expr, }) => {
+        mod $mod_name {
+            use super::*;
+
+            #[test]
+            fn test_add_and_get() {
+                async_unit::tokio_unit_test(|| {
+                    add_and_get($new_cb());
+                });
+            }
+
+            #[test]
+            fn test_public_stays_public() {
+                async_unit::tokio_unit_test(|| {
+                    public_stays_public($new_cb());
+                });
+            }
+
+            #[test]
+            fn test_list_by_phase() {
+                async_unit::tokio_unit_test(|| {
+                    list_by_phase($new_cb());
+                });
+            }
+        }
+    };
+}
+
+phases_test_impl! {
+    sqlite_test => {
+        new: new_sqlite,
+    }
+}
+
+phases_test_impl! {
+    sqlite_arced_test => {
+        new: new_sqlite_arced,
+    }
+}
+
+phases_test_impl! {
+    mysql_test => {
+        new: new_mysql,
+    }
+}
+
+phases_test_impl! {
+    mysql_arced_test => {
+        new: new_mysql_arced,
+    }
+}
+
+fn new_sqlite() -> SqlitePhases {
+    SqlitePhases::in_memory().expect("Creating an in-memory SQLite database failed")
+}
+
+fn new_sqlite_arced() -> Arc<Phases> {
+    Arc::new(new_sqlite())
+}
+
+fn new_mysql() -> MysqlPhases {
+    MysqlPhases::create_test_db("phases_test").expect("Failed to create test database")
+}
+
+fn new_mysql_arced() -> Arc<Phases> {
+    Arc::new(new_mysql())
+}
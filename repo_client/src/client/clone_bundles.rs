@@ -0,0 +1,129 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Clone bundles (`clonebundles`)
+//!
+//! A cloning client first asks for the clone bundles manifest, downloads the bundle advertised in
+//! it over HTTP and applies it, and then pulls the changesets that were added since the bundle was
+//! generated with a regular `getbundle`. Serving a static bundle is much cheaper than generating
+//! a changegroup of the whole history for every clone.
+//!
+//! Bundles are generated for a bookmark by a separate tool and stored in the blobstore, keyed by
+//! the changeset they were generated at. Servers advertise the newest bundle they can find among
+//! the ancestors of the bookmark, so that nothing in the blobstore has to be updated in place.
+
+use std::sync::Arc;
+
+use async_compression::{CompressorType, FlateCompression};
+use bytes::{Bytes, BytesMut};
+use futures::{future, Future, IntoFuture, Stream};
+use futures::future::Loop;
+use futures_ext::{BoxFuture, FutureExt};
+use slog::Logger;
+
+use blobrepo::BlobRepo;
+use blobstore::Blobstore;
+use mercurial_bundles::{create_bundle_stream, parts};
+use mercurial_types::{Changeset, HgChangesetId};
+use mononoke_api::clone_bundle_key;
+use mononoke_types::BlobstoreBytes;
+
+use super::changelog_entries;
+use errors::*;
+
+/// Bundle spec of the generated bundles, as advertised in the manifest
+pub const CLONE_BUNDLE_SPEC: &str = "gzip-v2";
+
+/// How many first-parent ancestors of the bookmark are checked for a clone bundle. Bundles have
+/// to be generated often enough that the bookmark doesn't move further than that in between.
+const MAX_CLONE_BUNDLE_DISTANCE: usize = 10000;
+
+/// Make sure there is a clone bundle for `changesetid`, generating it if it doesn't exist yet.
+/// The bundle contains the changegroup of all ancestors of `changesetid`, with changesets sent as
+/// deltas if they are at most `delta_threshold` percent of the fulltext.
+pub fn build_clone_bundle(
+    repo: Arc<BlobRepo>,
+    changesetid: HgChangesetId,
+    delta_threshold: usize,
+    logger: Logger,
+) -> BoxFuture<(), Error> {
+    let key = clone_bundle_key(&changesetid);
+    let blobstore = repo.get_blobstore();
+
+    blobstore
+        .is_present(key.clone())
+        .and_then(move |present| {
+            if present {
+                debug!(logger, "clone bundle for {} exists", changesetid);
+                return Ok(()).into_future().boxify();
+            }
+
+            info!(logger, "generating clone bundle for {}", changesetid);
+            let entries = changelog_entries(repo, vec![changesetid.into_nodehash()], vec![], None);
+            let compression = Some(CompressorType::Zlib(FlateCompression::default()));
+            parts::changegroup_part(entries, delta_threshold)
+                .into_future()
+                .and_then(move |part| {
+                    create_bundle_stream(vec![part], compression).fold(
+                        BytesMut::new(),
+                        |mut bundle, chunk| {
+                            bundle.extend_from_slice(&chunk);
+                            Ok::<_, Error>(bundle)
+                        },
+                    )
+                })
+                .and_then(move |bundle| {
+                    info!(
+                        logger,
+                        "clone bundle for {} is {} bytes",
+                        changesetid,
+                        bundle.len()
+                    );
+                    blobstore.put(key, BlobstoreBytes::from_bytes(bundle.freeze()))
+                })
+                .boxify()
+        })
+        .boxify()
+}
+
+/// Find the newest clone bundle among `head` and its first-parent ancestors. The search stops at
+/// `known`, the bundle that is advertised already, so `None` means there is no newer bundle.
+pub fn find_clone_bundle(
+    repo: Arc<BlobRepo>,
+    head: HgChangesetId,
+    known: Option<HgChangesetId>,
+) -> BoxFuture<Option<HgChangesetId>, Error> {
+    future::loop_fn((head, 0), move |(changesetid, distance)| {
+        if Some(changesetid) == known || distance > MAX_CLONE_BUNDLE_DISTANCE {
+            return future::ok(Loop::Break(None)).boxify();
+        }
+
+        let repo = repo.clone();
+        repo.get_blobstore()
+            .is_present(clone_bundle_key(&changesetid))
+            .and_then(move |present| {
+                if present {
+                    return future::ok(Loop::Break(Some(changesetid))).boxify();
+                }
+                repo.get_changeset_by_changesetid(&changesetid)
+                    .map(move |cs| match cs.p1() {
+                        Some(p1) => Loop::Continue((HgChangesetId::new(*p1), distance + 1)),
+                        None => Loop::Break(None),
+                    })
+                    .boxify()
+            })
+            .boxify()
+    }).boxify()
+}
+
+/// Contents of the clone bundles manifest that advertises the bundle of `changesetid`, served
+/// under `base_url`
+pub fn clone_bundles_manifest(base_url: &str, changesetid: HgChangesetId) -> Bytes {
+    Bytes::from(format!(
+        "{}/{} BUNDLESPEC={}\n",
+        base_url, changesetid, CLONE_BUNDLE_SPEC
+    ))
+}
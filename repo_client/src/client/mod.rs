@@ -4,15 +4,18 @@
 // This software may be used and distributed according to the terms of the
 // GNU General Public License version 2 or any later version.
 
+pub mod clone_bundles;
 mod remotefilelog;
+pub mod streaming_clone;
 
 use std::collections::{HashMap, HashSet};
-use std::io::Cursor;
 use std::iter::FromIterator;
 use std::mem;
 use std::str::FromStr;
-use std::sync::Arc;
+use std::usize;
+use std::sync::{Arc, RwLock};
 
+use async_compression::CompressorType;
 use bytes::{BufMut, Bytes, BytesMut};
 use failure::err_msg;
 use futures::{future, stream, Async, Future, IntoFuture, Poll, Stream, stream::empty};
@@ -24,21 +27,29 @@ use slog::Logger;
 use blobrepo::BlobChangeset;
 use bundle2_resolver;
 use mercurial::{self, RevlogChangeset};
-use mercurial_bundles::{create_bundle_stream, parts, Bundle2EncodeBuilder, Bundle2Item};
-use mercurial_types::{percent_encode, Changeset, Entry, HgBlobNode, HgChangesetId, HgManifestId,
-                      HgNodeHash, MPath, RepoPath, Type, NULL_HASH};
-use mercurial_types::manifest_utils::{and_pruner_combinator, changed_entry_stream,
-                                      changed_entry_stream_with_pruner, file_pruner,
-                                      visited_pruner, ChangedEntry, EntryStatus};
+use mercurial_bundles::{create_bundle_stream, parts, response_compression, Bundle2Item,
+                       Capabilities};
+use mercurial_bundles::wirepack::{self, packer::WirePackPacker};
+use mercurial_types::{b85decode, percent_encode, Changeset, Entry, HgBlobNode, HgChangesetId,
+                      HgManifestId, HgNodeHash, MPath, NarrowSpec, RepoPath, Type, NULL_HASH};
+use mercurial_types::obsmarker::{decode_markers, encode_pushkey_dumps};
+use mercurial_types::manifest_utils::{and_pruner_combinator,
+                                      changed_entry_stream_multiway_with_pruner, depth_pruner,
+                                      file_pruner, narrow_pruner, visited_pruner, ChangedEntry,
+                                      EntryStatus};
+use phases::Phase;
 use scuba_ext::{ScubaSampleBuilder, ScubaSampleBuilderExt};
 use tracing::{TraceContext, Traced};
 
 use blobrepo::BlobRepo;
 use hgproto::{self, GetbundleArgs, GettreepackArgs, HgCommandRes, HgCommands};
-use revset::DifferenceOfUnionsOfAncestorsNodeStream;
+use revset::{AscendingGenerationNodeStream, DifferenceOfUnionsOfAncestorsNodeStream,
+             SetDifferenceNodeStream};
 
-use self::remotefilelog::create_remotefilelog_blob;
+use self::remotefilelog::{create_getpack_parts, create_remotefilelog_blob};
+use self::streaming_clone::{fetch_streaming_clone, stream_out_to_stream2, STREAM_REQUIREMENTS};
 use errors::*;
+use load_limiter::LoadLimiter;
 use mononoke_repo::MononokeRepo;
 
 const MAX_NODES_TO_LOG: usize = 5;
@@ -53,6 +64,12 @@ mod ops {
     pub const GETBUNDLE: &str = "getbundle";
     pub const GETTREEPACK: &str = "gettreepack";
     pub const GETFILES: &str = "getfiles";
+    pub const GETPACKV1: &str = "getpackv1";
+    pub const STREAM_OUT: &str = "stream_out";
+    pub const CLONEBUNDLES: &str = "clonebundles";
+    pub const BRANCHMAP: &str = "branchmap";
+    pub const PUSHKEY: &str = "pushkey";
+    pub const PROTOCAPS: &str = "protocaps";
 }
 
 fn format_nodes_list(mut nodes: Vec<HgNodeHash>) -> String {
@@ -76,12 +93,20 @@ fn wireprotocaps() -> Vec<String> {
         "unbundle=HG10GZ,HG10BZ,HG10UN".to_string(),
         "gettreepack".to_string(),
         "remotefilelog".to_string(),
+        "getpackv1".to_string(),
         "pushkey".to_string(),
+        "narrow".to_string(),
+        // Shallow clones with a depth, sent as changegroups with ellipsis nodes
+        "exp-ellipses-1".to_string(),
+        "clonebundles".to_string(),
+        "protocaps".to_string(),
     ]
 }
 
-fn bundle2caps() -> String {
-    let caps = vec![
+/// Bundle2 capabilities of the server. `stream` is advertised only if there is a streaming clone
+/// artifact to send.
+fn bundle2caps(stream: bool) -> String {
+    let mut caps = vec![
         ("HG20", vec![]),
         // Note that "listkeys" is *NOT* returned as a bundle2 capability; that's because there's
         // a race that can happen. Here's how:
@@ -112,12 +137,20 @@ fn bundle2caps() -> String {
         // * To repro the race, run test-bookmark-race.t with the following line enabled.
 
         // ("listkeys", vec![]),
-        ("changegroup", vec!["02"]),
+        // Clients push large files with changegroup 3, which has revlog flags. Changegroups
+        // sent to clients are always version 2.
+        ("changegroup", vec!["02", "03"]),
         ("b2x:infinitepush", vec![]),
         ("b2x:infinitepushscratchbookmarks", vec![]),
         ("pushkey", vec![]),
+        ("branchmap", vec![]),
+        ("phases", vec!["heads"]),
+        ("obsmarkers", vec!["V0", "V1"]),
         ("treemanifestserver", vec!["True"]),
     ];
+    if stream {
+        caps.push(("stream", vec!["v2"]));
+    }
 
     let mut encodedcaps = vec![];
 
@@ -140,6 +173,19 @@ pub struct RepoClient {
     logger: Logger,
     scuba_logger: ScubaSampleBuilder,
     trace: TraceContext,
+    // Identity of the user on the other end of the connection, if known
+    identity: Option<String>,
+    // Subject of the TLS certificate of the client, the identity that ACLs are checked against
+    tls_identity: Option<String>,
+    // Client the load limits apply to: the TLS identity if known, or the address of the client
+    client_id: String,
+    // Limits concurrent expensive commands
+    command_limiter: Arc<LoadLimiter>,
+    // History depth of a shallow clone, set by getbundle. File history sent by getpackv1 is
+    // limited to the same number of commits, older history is fetched by the client on demand.
+    shallow_depth: Arc<RwLock<Option<usize>>>,
+    // Compression of bundle2 responses, negotiated from the protocol capabilities of the client
+    response_compression: Arc<RwLock<Option<CompressorType>>>,
 }
 
 impl RepoClient {
@@ -148,12 +194,22 @@ impl RepoClient {
         logger: Logger,
         scuba_logger: ScubaSampleBuilder,
         trace: TraceContext,
+        identity: Option<String>,
+        tls_identity: Option<String>,
+        client_id: String,
+        command_limiter: Arc<LoadLimiter>,
     ) -> Self {
         RepoClient {
             repo,
             logger,
             scuba_logger,
             trace,
+            identity,
+            tls_identity,
+            client_id,
+            command_limiter,
+            shallow_depth: Arc::new(RwLock::new(None)),
+            response_compression: Arc::new(RwLock::new(None)),
         }
     }
 
@@ -162,6 +218,11 @@ impl RepoClient {
         &self.logger
     }
 
+    /// Wait until the command is admitted by the command limiter before running `work`
+    fn limit_expensive<T: Send + 'static>(&self, work: BoxStream<T, Error>) -> BoxStream<T, Error> {
+        LoadLimiter::limit_stream(&self.command_limiter, &self.client_id, work)
+    }
+
     fn scuba_logger(&self, op: &str, args: Option<String>) -> ScubaSampleBuilder {
         let mut scuba_logger = self.scuba_logger.clone();
 
@@ -175,18 +236,55 @@ impl RepoClient {
         scuba_logger
     }
 
-    fn create_bundle(&self, args: GetbundleArgs) -> hgproto::Result<HgCommandRes<Bytes>> {
-        let writer = Cursor::new(Vec::new());
-        let mut bundle = Bundle2EncodeBuilder::new(writer);
-        // Mercurial currently hangs while trying to read compressed bundles over the wire:
-        // https://bz.mercurial-scm.org/show_bug.cgi?id=5646
-        // TODO: possibly enable compression support once this is fixed.
-        bundle.set_compressor_type(None);
+    /// The narrowspec of a request with the given patterns. If there are none, the narrowspec
+    /// the client sent last is used, otherwise it's remembered for the later requests of the
+    /// client that don't send their patterns.
+    fn request_narrowspec(
+        &self,
+        includepattern: &Vec<Vec<u8>>,
+        excludepattern: &Vec<Vec<u8>>,
+    ) -> Result<Arc<NarrowSpec>> {
+        if includepattern.is_empty() && excludepattern.is_empty() {
+            return Ok(self.repo.narrowspec(&self.client_id));
+        }
+        let narrowspec = Arc::new(NarrowSpec::new(includepattern, excludepattern)?);
+        info!(self.logger, "narrowspec {}", narrowspec);
+        self.repo.set_narrowspec(&self.client_id, narrowspec.clone());
+        Ok(narrowspec)
+    }
+
+    /// Negotiate the compression of the bundle2 responses of the session from the protocol
+    /// capabilities of the client, unless the repo doesn't compress responses. They are sent with
+    /// the `protocaps` command over SSH, and in the `X-HgProto-<N>` headers over HTTP.
+    pub fn set_protocaps(&self, protocaps: &[String]) {
+        let compression = if self.repo.compress_responses() {
+            response_compression(protocaps)
+        } else {
+            None
+        };
+        debug!(self.logger, "response compression {:?}", compression);
+        *self.response_compression.write().expect("lock poisoned") = compression;
+    }
+
+    fn response_compression(&self) -> Option<CompressorType> {
+        *self.response_compression.read().expect("lock poisoned")
+    }
 
+    fn create_bundle(&self, args: GetbundleArgs) -> hgproto::Result<BoxStream<Bytes, Error>> {
         let blobrepo = self.repo.blobrepo();
 
+        // The changegroup only contains changesets, which are sent in full even to narrow
+        // clones. Trees and files are filtered by the narrowspec when they are requested with
+        // gettreepack and getfiles.
+        self.request_narrowspec(&args.includepattern, &args.excludepattern)?;
+
         let common_heads: HashSet<_> = HashSet::from_iter(args.common.iter());
 
+        let all_heads: Vec<_> = args.heads
+            .iter()
+            .map(|head| HgChangesetId::new(*head))
+            .collect();
+
         let heads: Vec<_> = args.heads
             .iter()
             .filter(|head| !common_heads.contains(head))
@@ -203,47 +301,92 @@ impl RepoClient {
             .map(|node| node.clone().into_option())
             .filter_map(|maybe_node| maybe_node)
             .collect();
-        let nodestosend =
-            DifferenceOfUnionsOfAncestorsNodeStream::new_with_excludes(&blobrepo, heads, excludes)
-                .boxify();
 
-        // TODO(stash): avoid collecting all the changelogs in the vector - T25767311
-        let nodestosend = nodestosend
-            .collect()
-            .map(|nodes| stream::iter_ok(nodes.into_iter().rev()))
-            .flatten_stream();
+        if let Some(depth) = args.depth {
+            info!(self.logger, "shallow clone with depth {}", depth);
+            *self.shallow_depth.write().expect("lock poisoned") = Some(depth);
+        }
 
-        let buffer_size = 100; // TODO(stash): make it configurable
-        let changelogentries = nodestosend
-            .map({
-                let blobrepo = blobrepo.clone();
-                move |node| {
-                    blobrepo
-                        .get_changeset_by_changesetid(&HgChangesetId::new(node))
-                        .map(move |cs| (node, cs))
-                }
-            })
-            .buffered(buffer_size)
-            .and_then(|(node, cs)| {
-                let revlogcs = RevlogChangeset::new_from_parts(
-                    cs.parents().clone(),
-                    cs.manifestid().clone(),
-                    cs.user().into(),
-                    cs.time().clone(),
-                    cs.extra().clone(),
-                    cs.files().into(),
-                    cs.comments().into(),
-                );
-
-                let mut v = Vec::new();
-                mercurial::changeset::serialize_cs(&revlogcs, &mut v)?;
-                Ok((
-                    node,
-                    HgBlobNode::new(Bytes::from(v), revlogcs.p1(), revlogcs.p2()),
-                ))
-            });
+        // A streaming clone sends the artifact in a stream2 part, and the changegroup only has
+        // the changesets that were added after the artifact was built
+        let mut stream_part = future::ok(None).boxify();
+        let mut changegroup_excludes = excludes.clone();
+        if args.stream && args.depth.is_none() && excludes.is_empty() {
+            if let Some(changesetid) = self.repo.streaming_clone() {
+                info!(self.logger, "streaming clone at {}", changesetid);
+                changegroup_excludes.push(changesetid.into_nodehash());
+                stream_part = fetch_streaming_clone(blobrepo.clone(), changesetid)
+                    .and_then(|artifact| {
+                        let (filecount, bytecount, data) = stream_out_to_stream2(&artifact)?;
+                        parts::stream2_part(filecount, bytecount, STREAM_REQUIREMENTS, data)
+                            .map(Some)
+                    })
+                    .boxify();
+            }
+        }
 
-        bundle.add_part(parts::changegroup_part(changelogentries)?);
+        let changegroup = match args.depth {
+            Some(depth) => parts::ellipsis_changegroup_part(
+                ellipsis_changelog_entries(
+                    blobrepo.clone(),
+                    heads.clone(),
+                    excludes.clone(),
+                    depth,
+                ),
+                self.repo.delta_threshold(),
+            )?,
+            None => parts::changegroup_part(
+                changelog_entries(blobrepo.clone(), heads.clone(), changegroup_excludes, None),
+                self.repo.delta_threshold(),
+            )?,
+        };
+        let mut bundle_parts = vec![changegroup];
+
+        if args.branchmap {
+            let branchmap = compute_branchmap(self.repo.clone());
+            bundle_parts.push(parts::branchmap_part(branchmap)?);
+        }
+
+        if args.phases {
+            let phase_heads = compute_phase_heads(blobrepo.clone(), all_heads);
+            bundle_parts.push(parts::phase_heads_part(phase_heads)?);
+        }
+
+        let mut obsmarkers_part = future::ok(None).boxify();
+        if args.obsmarkers {
+            if let Some(version) = Capabilities::from_bundlecaps(&args.bundlecaps)?
+                .obsmarkers_version()
+            {
+                // Mercurial sends the markers relevant to all the ancestors of the heads, but
+                // that means reading every marker of the repo on every pull. Only the markers
+                // relevant to the changesets in the changegroup are sent instead, which is what
+                // clients need to hide the changesets those replace.
+                // The markers are fetched before the bundle is sent, so that the part is only
+                // added when there are markers.
+                let markers = DifferenceOfUnionsOfAncestorsNodeStream::new_with_excludes_and_depth(
+                    &blobrepo,
+                    heads,
+                    excludes,
+                    args.depth,
+                ).map(HgChangesetId::new)
+                    .collect()
+                    .and_then({
+                        let blobrepo = blobrepo.clone();
+                        move |cs_ids| blobrepo.get_relevant_obsmarkers(cs_ids)
+                    });
+                let index = bundle_parts.len();
+                obsmarkers_part = markers
+                    .and_then(move |markers| {
+                        if markers.is_empty() {
+                            Ok(None)
+                        } else {
+                            parts::obsmarkers_part(future::ok(markers), version)
+                                .map(|part| Some((index, part)))
+                        }
+                    })
+                    .boxify();
+            }
+        }
 
         // XXX Note that listkeys is NOT returned as a bundle2 capability -- see comment in
         // bundle2caps() for why.
@@ -255,15 +398,23 @@ impl RepoClient {
                 let hash: Vec<u8> = cs.into_nodehash().to_hex().into();
                 (name.to_string(), hash)
             });
-            bundle.add_part(parts::listkey_part("bookmarks", items)?);
+            bundle_parts.push(parts::listkey_part("bookmarks", items)?);
         }
-        // TODO(stash): handle includepattern= and excludepattern=
 
-        let encode_fut = bundle.build();
-
-        Ok(encode_fut
-            .map(|cursor| Bytes::from(cursor.into_inner()))
-            .from_err()
+        let compression = self.response_compression();
+        Ok(obsmarkers_part
+            .join(stream_part)
+            .map(move |(obsmarkers_part, stream_part)| {
+                if let Some((index, part)) = obsmarkers_part {
+                    bundle_parts.insert(index, part);
+                }
+                // The store files have to be applied before the changegroup that builds on them
+                if let Some(part) = stream_part {
+                    bundle_parts.insert(0, part);
+                }
+                create_bundle_stream(bundle_parts, compression)
+            })
+            .flatten_stream()
             .boxify())
     }
 
@@ -275,16 +426,25 @@ impl RepoClient {
             return stream::once(Err(err_msg("directories param is not supported"))).boxify();
         }
 
-        // TODO(stash): T25850889 only one basemfnodes is used. That means that trees that client
-        // already has can be sent to the client.
-        let basemfnode = params.basemfnodes.get(0).cloned().unwrap_or(NULL_HASH);
-
         let rootpath = if params.rootdir.is_empty() {
             None
         } else {
             Some(try_boxstream!(MPath::new(params.rootdir)))
         };
 
+        let narrowspec = try_boxstream!(
+            self.request_narrowspec(&params.includepattern, &params.excludepattern)
+        );
+
+        let pruner = and_pruner_combinator(
+            &file_pruner,
+            and_pruner_combinator(
+                narrow_pruner(narrowspec),
+                depth_pruner(rootpath.clone(), params.depth.unwrap_or(usize::MAX)),
+            ),
+        );
+        let basemfnodes = params.basemfnodes;
+
         let changed_entries = if params.mfnodes.len() > 1 {
             let visited_pruner = visited_pruner();
             params
@@ -294,9 +454,9 @@ impl RepoClient {
                     let new_stream = get_changed_entry_stream(
                         self.repo.blobrepo(),
                         &manifest_id,
-                        &basemfnode,
+                        &basemfnodes,
                         rootpath.clone(),
-                        Some(and_pruner_combinator(&file_pruner, visited_pruner.clone())),
+                        and_pruner_combinator(pruner.clone(), visited_pruner.clone()),
                         self.trace.clone(),
                     );
                     cur_stream.select(new_stream).boxify()
@@ -306,9 +466,9 @@ impl RepoClient {
                 Some(mfnode) => get_changed_entry_stream(
                     self.repo.blobrepo(),
                     &mfnode,
-                    &basemfnode,
+                    &basemfnodes,
                     rootpath.clone(),
-                    Some(&file_pruner),
+                    pruner,
                     self.trace.clone(),
                 ),
                 None => empty().boxify(),
@@ -323,16 +483,19 @@ impl RepoClient {
             .map({
                 let blobrepo = self.repo.blobrepo();
                 let trace = self.trace.clone();
-                move |(entry, basepath)| {
-                    fetch_treepack_part_input(blobrepo.clone(), entry, basepath, trace.clone())
+                move |(entry, from_entry, basepath)| {
+                    fetch_treepack_part_input(
+                        blobrepo.clone(),
+                        entry,
+                        from_entry,
+                        basepath,
+                        trace.clone(),
+                    )
                 }
             });
 
-        let part = parts::treepack_part(changed_entries);
-        // Mercurial currently hangs while trying to read compressed bundles over the wire:
-        // https://bz.mercurial-scm.org/show_bug.cgi?id=5646
-        // TODO: possibly enable compression support once this is fixed.
-        let compression = None;
+        let part = parts::treepack_part(changed_entries, self.repo.delta_threshold());
+        let compression = self.response_compression();
         part.into_future()
             .map(move |part| create_bundle_stream(vec![part], compression))
             .flatten_stream()
@@ -435,6 +598,19 @@ impl HgCommands for RepoClient {
             .boxify()
     }
 
+    // @wireprotocommand('branchmap')
+    fn branchmap(&self) -> HgCommandRes<HashMap<String, HashSet<HgNodeHash>>> {
+        let logger = self.logger.clone();
+        let mut scuba_logger = self.scuba_logger(ops::BRANCHMAP, None);
+        let trace = self.trace.clone();
+
+        compute_branchmap(self.repo.clone())
+            .from_err()
+            .inspect(move |resp| debug!(logger, "branchmap response: {:?}", resp))
+            .timed(move |stats, _| scuba_logger.add_stats(&stats).log_with_trace(&trace))
+            .boxify()
+    }
+
     // @wireprotocommand('lookup', 'key')
     fn lookup(&self, key: String) -> HgCommandRes<Bytes> {
         info!(self.logger, "lookup: {:?}", key);
@@ -472,6 +648,19 @@ impl HgCommands for RepoClient {
             .boxify()
     }
 
+    // @wireprotocommand('protocaps', 'caps')
+    fn protocaps(&self, caps: Vec<String>) -> HgCommandRes<()> {
+        info!(self.logger, "protocaps: {:?}", caps);
+
+        let mut scuba_logger = self.scuba_logger(ops::PROTOCAPS, Some(caps.join(" ")));
+        let trace = self.trace.clone();
+
+        self.set_protocaps(&caps);
+        future::ok(())
+            .timed(move |stats, _| scuba_logger.add_stats(&stats).log_with_trace(&trace))
+            .boxify()
+    }
+
     // @wireprotocommand('known', 'nodes *'), but the '*' is ignored
     fn known(&self, nodes: Vec<HgNodeHash>) -> HgCommandRes<Vec<bool>> {
         if nodes.len() > MAX_NODES_TO_LOG {
@@ -493,16 +682,18 @@ impl HgCommands for RepoClient {
     }
 
     // @wireprotocommand('getbundle', '*')
-    fn getbundle(&self, args: GetbundleArgs) -> HgCommandRes<Bytes> {
+    fn getbundle(&self, args: GetbundleArgs) -> BoxStream<Bytes, Error> {
         info!(self.logger, "Getbundle: {:?}", args);
 
         let mut scuba_logger = self.scuba_logger(ops::GETBUNDLE, None);
         let trace = self.trace.clone();
 
-        match self.create_bundle(args) {
+        let bundle = match self.create_bundle(args) {
             Ok(res) => res,
-            Err(err) => Err(err).into_future().boxify(),
-        }.timed(move |stats, _| scuba_logger.add_stats(&stats).log_with_trace(&trace))
+            Err(err) => stream::once(Err(err)).boxify(),
+        };
+        self.limit_expensive(bundle)
+            .timed(move |stats, _| scuba_logger.add_stats(&stats).log_with_trace(&trace))
             .boxify()
     }
 
@@ -512,7 +703,11 @@ impl HgCommands for RepoClient {
 
         let mut res = HashMap::new();
         let mut caps = wireprotocaps();
-        caps.push(format!("bundle2={}", bundle2caps()));
+        let stream = self.repo.streaming_clone().is_some();
+        caps.push(format!("bundle2={}", bundle2caps(stream)));
+        if stream {
+            caps.push(format!("streamreqs={}", STREAM_REQUIREMENTS.join(",")));
+        }
         res.insert("capabilities".to_string(), caps);
 
         let mut scuba_logger = self.scuba_logger(ops::HELLO, None);
@@ -541,6 +736,54 @@ impl HgCommands for RepoClient {
                     HashMap::from_iter(bookiter)
                 })
                 .boxify()
+        } else if namespace == "phases" {
+            // Only the roots of the draft changesets are listed, everything else is public. The
+            // "publishing" key is not set, so that pushed changesets stay draft. Infinitepush
+            // changesets are not listed, clients learn their phase from the phase-heads part of
+            // the getbundle that fetches them.
+            self.repo
+                .blobrepo()
+                .get_draft_roots()
+                .map(|roots| {
+                    let rootiter = roots.into_iter().map(|cs| {
+                        let hash: Vec<u8> = cs.into_nodehash().to_hex().into();
+                        (hash, Phase::Draft.to_hg().to_string().into_bytes())
+                    });
+                    HashMap::from_iter(rootiter)
+                })
+                .boxify()
+        } else if namespace == "namespaces" {
+            // Clients push obsolescence markers with pushkey only if the "obsolete" namespace is
+            // listed here
+            let namespaces = ["bookmarks", "namespaces", "phases", "obsolete"]
+                .iter()
+                .map(|namespace| (namespace.as_bytes().to_vec(), vec![]));
+            future::ok(HashMap::from_iter(namespaces)).boxify()
+        } else if namespace == "obsolete" {
+            // Only the markers relevant to the drafts and the bookmarked changesets are listed.
+            // Those are the changesets clients without the obsmarkers bundle2 capability can
+            // pull, and the rest of the markers would only make them read the whole obsstore.
+            let blobrepo = self.repo.blobrepo().clone();
+            blobrepo
+                .get_drafts()
+                .join(
+                    blobrepo
+                        .get_bookmarks()
+                        .map(|(_name, cs_id)| cs_id)
+                        .collect(),
+                )
+                .and_then(move |(mut cs_ids, bookmarked)| {
+                    cs_ids.extend(bookmarked);
+                    blobrepo.get_relevant_obsmarkers(cs_ids)
+                })
+                .and_then(|markers| encode_pushkey_dumps(&markers))
+                .map(|dumps| {
+                    let dumpiter = dumps
+                        .into_iter()
+                        .map(|(key, value)| (key.into_bytes(), value.into_bytes()));
+                    HashMap::from_iter(dumpiter)
+                })
+                .boxify()
         } else {
             info!(
                 self.get_logger(),
@@ -551,6 +794,54 @@ impl HgCommands for RepoClient {
         }
     }
 
+    // @wireprotocommand('pushkey', 'namespace key old new')
+    fn pushkey(
+        &self,
+        namespace: String,
+        key: String,
+        old: String,
+        new: String,
+    ) -> HgCommandRes<bool> {
+        let mut scuba_logger = self.scuba_logger(ops::PUSHKEY, Some(namespace.clone()));
+        let trace = self.trace.clone();
+
+        let tls_identity = self.tls_identity.as_ref().map(String::as_str);
+        if let Err(err) = self.repo.check_write_access(tls_identity) {
+            warn!(self.logger, "Pushkey rejected: {}", err);
+            scuba_logger.log_with_msg("Pushkey rejected", format!("{}", err));
+            return future::err(err).boxify();
+        }
+
+        // Bookmarks and phases are only pushed in bundle2 parts by the clients Mononoke supports.
+        // Markers are pushed here by clients that don't see the obsmarkers bundle2 capability, as
+        // the dumps listed by listkeys.
+        if namespace != "obsolete" {
+            info!(self.logger, "unsupported pushkey namespace: {}", namespace);
+            return future::ok(false).boxify();
+        }
+        if !key.starts_with("dump") || !old.is_empty() {
+            info!(self.logger, "unexpected obsolete pushkey {} from {:?}", key, old);
+            return future::ok(false).boxify();
+        }
+
+        let blobrepo = self.repo.blobrepo();
+        let res = b85decode(&new)
+            .and_then(|data| decode_markers(&data))
+            .into_future()
+            .and_then(move |markers| blobrepo.add_obsmarkers(markers))
+            .map({
+                let logger = self.logger.clone();
+                move |count| {
+                    info!(logger, "{} new obsolescence markers", count);
+                    true
+                }
+            });
+
+        res.traced(&trace, "pushkey", trace_args!())
+            .timed(move |stats, _| scuba_logger.add_stats(&stats).log_with_trace(&trace))
+            .boxify()
+    }
+
     // @wireprotocommand('unbundle')
     fn unbundle(
         &self,
@@ -560,10 +851,20 @@ impl HgCommands for RepoClient {
         let mut scuba_logger = self.scuba_logger(ops::UNBUNDLE, None);
         let trace = self.trace.clone();
 
+        let tls_identity = self.tls_identity.as_ref().map(String::as_str);
+        if let Err(err) = self.repo.check_write_access(tls_identity) {
+            warn!(self.logger, "Push rejected: {}", err);
+            scuba_logger.log_with_msg("Push rejected", format!("{}", err));
+            return future::err(err).boxify();
+        }
+
         let res = bundle2_resolver::resolve(
             self.repo.blobrepo(),
+            self.repo.hook_manager(),
+            self.identity.clone(),
             self.logger.new(o!("command" => "unbundle")),
             scuba_logger.clone(),
+            self.response_compression(),
             heads,
             stream,
         );
@@ -576,17 +877,20 @@ impl HgCommands for RepoClient {
     // @wireprotocommand('gettreepack', 'rootdir mfnodes basemfnodes directories')
     fn gettreepack(&self, params: GettreepackArgs) -> BoxStream<Bytes, Error> {
         let args = format!(
-            "rootdir: {}, mfnodes: {}, basemfnodes: {}, directories: {}",
+            "rootdir: {}, mfnodes: {}, basemfnodes: {}, directories: {}, \
+             includepattern: {}, excludepattern: {}",
             String::from_utf8_lossy(&params.rootdir),
             format_nodes_list(params.mfnodes.clone()),
             format_nodes_list(params.basemfnodes.clone()),
             format_utf8_bytes_list(params.directories.clone()),
+            format_utf8_bytes_list(params.includepattern.iter().map(|p| p[..].into()).collect()),
+            format_utf8_bytes_list(params.excludepattern.iter().map(|p| p[..].into()).collect()),
         );
 
         let mut scuba_logger = self.scuba_logger(ops::GETTREEPACK, Some(args));
         let trace = self.trace.clone();
 
-        self.gettreepack_untimed(params)
+        self.limit_expensive(self.gettreepack_untimed(params))
             .timed(move |stats, _| scuba_logger.add_stats(&stats).log_with_trace(&trace))
             .boxify()
     }
@@ -598,14 +902,22 @@ impl HgCommands for RepoClient {
         info!(logger, "getfiles");
 
         let this = self.clone();
+        // Files outside of the narrowspec of the client are not sent
+        let narrowspec = self.repo.narrowspec(&self.client_id);
         let getfiles_buffer_size = 100; // TODO(stash): make it configurable
-        params
+        let files = params
+            .filter(move |&(_, ref path)| narrowspec.matches_file(path))
             .map(move |(node, path)| {
                 let args = format!("node: {}, path: {}", node, path);
                 let mut scuba_logger = this.scuba_logger(ops::GETFILES, Some(args));
 
                 let repo = this.repo.clone();
-                create_remotefilelog_blob(repo.blobrepo(), node, path.clone(), trace.clone())
+                create_remotefilelog_blob(
+                    repo.blobrepo(),
+                    node,
+                    path.clone(),
+                    trace.clone(),
+                )
                     .traced(
                         &trace,
                         "getfile",
@@ -617,33 +929,325 @@ impl HgCommands for RepoClient {
                     })
             })
             .buffered(getfiles_buffer_size)
+            .boxify();
+        self.limit_expensive(files)
+    }
+
+    // @wireprotocommand('getpackv1', '*')
+    fn getpackv1(
+        &self,
+        params: BoxStream<(MPath, Vec<HgNodeHash>), Error>,
+    ) -> BoxStream<Bytes, Error> {
+        info!(self.logger, "getpackv1");
+
+        let mut scuba_logger = self.scuba_logger(ops::GETPACKV1, None);
+        let trace = self.trace.clone();
+
+        let repo = self.repo.clone();
+        // Files outside of the narrowspec of the client are not sent
+        let narrowspec = self.repo.narrowspec(&self.client_id);
+        let shallow_depth = *self.shallow_depth.read().expect("lock poisoned");
+        let delta_threshold = self.repo.delta_threshold();
+        let getpack_buffer_size = 100; // TODO: make it configurable
+        let parts = params
+            .filter(move |&(ref path, _)| narrowspec.matches_file(path))
+            .map({
+                let trace = trace.clone();
+                move |(path, nodes)| {
+                    create_getpack_parts(
+                        repo.blobrepo(),
+                        path,
+                        nodes,
+                        shallow_depth,
+                        delta_threshold,
+                        trace.clone(),
+                    )
+                }
+            })
+            .buffered(getpack_buffer_size)
+            .map(stream::iter_ok)
+            .flatten()
+            .chain(stream::once(Ok(wirepack::Part::End)));
+
+        let pack = WirePackPacker::new(parts, wirepack::Kind::File)
+            .and_then(|chunk| chunk.into_bytes())
+            .boxify();
+        self.limit_expensive(pack)
+            .timed(move |stats, _| scuba_logger.add_stats(&stats).log_with_trace(&trace))
             .boxify()
     }
+
+    // @wireprotocommand('clonebundles', '')
+    fn clonebundles(&self) -> HgCommandRes<Bytes> {
+        info!(self.logger, "clonebundles at {:?}", self.repo.clone_bundle());
+
+        let mut scuba_logger = self.scuba_logger(ops::CLONEBUNDLES, None);
+        let trace = self.trace.clone();
+
+        future::ok(self.repo.clone_bundles_manifest())
+            .timed(move |stats, _| scuba_logger.add_stats(&stats).log_with_trace(&trace))
+            .boxify()
+    }
+
+    // @wireprotocommand('stream_out')
+    fn stream_out(&self) -> BoxStream<Bytes, Error> {
+        let mut scuba_logger = self.scuba_logger(ops::STREAM_OUT, None);
+        let trace = self.trace.clone();
+
+        let changesetid = match self.repo.streaming_clone() {
+            Some(changesetid) => changesetid,
+            None => {
+                // Mercurial's way of saying that streaming clones are disabled
+                info!(self.logger, "stream_out: no streaming clone artifact");
+                return stream::once(Ok(Bytes::from(&b"1\n"[..]))).boxify();
+            }
+        };
+        info!(self.logger, "stream_out at {}", changesetid);
+
+        // The status line is only sent once the artifact was fetched, so that a failure can still
+        // be reported to the client
+        fetch_streaming_clone(self.repo.blobrepo(), changesetid)
+            .map(|artifact| stream::iter_ok(vec![Bytes::from(&b"0\n"[..]), artifact]))
+            .flatten_stream()
+            .timed(move |stats, _| scuba_logger.add_stats(&stats).log_with_trace(&trace))
+            .boxify()
+    }
+}
+
+/// Heads of the repo grouped by the named branch they are on, which is taken from the `branch`
+/// extra of their changesets. The result is cached until the set of heads changes.
+fn compute_branchmap(
+    repo: Arc<MononokeRepo>,
+) -> BoxFuture<HashMap<String, HashSet<HgNodeHash>>, Error> {
+    let blobrepo = repo.blobrepo();
+
+    blobrepo
+        .get_heads()
+        .collect()
+        .and_then(move |mut heads| {
+            heads.sort();
+            heads.dedup();
+            if let Some(branchmap) = repo.cached_branchmap(&heads) {
+                return future::ok(branchmap).boxify();
+            }
+
+            let branch_heads = heads.clone().into_iter().map(move |head| {
+                blobrepo
+                    .get_changeset_by_changesetid(&HgChangesetId::new(head))
+                    .map(move |cs| (String::from_utf8_lossy(cs.branch()).into_owned(), head))
+            });
+            future::join_all(branch_heads)
+                .map(move |branch_heads| {
+                    let mut branchmap = HashMap::new();
+                    for (branch, head) in branch_heads {
+                        branchmap
+                            .entry(branch)
+                            .or_insert_with(HashSet::new)
+                            .insert(head);
+                    }
+                    repo.set_branchmap(heads, branchmap.clone());
+                    branchmap
+                })
+                .boxify()
+        })
+        .boxify()
+}
+
+/// Entries of the phase-heads part for a pull of `heads`: the phases of the heads themselves, and
+/// the public changesets right below the draft ones, so that the client learns which ancestors of
+/// the draft heads are public.
+fn compute_phase_heads(
+    blobrepo: Arc<BlobRepo>,
+    heads: Vec<HgChangesetId>,
+) -> BoxFuture<Vec<(u32, HgChangesetId)>, Error> {
+    blobrepo
+        .get_phases(heads)
+        .and_then(move |head_phases| {
+            let drafts: Vec<_> = head_phases
+                .iter()
+                .filter(|&(_, phase)| *phase == Phase::Draft)
+                .map(|(cs_id, _)| *cs_id)
+                .collect();
+
+            future::loop_fn(
+                (drafts, HashSet::new(), HashSet::new()),
+                move |(frontier, mut seen, mut boundary): (Vec<_>, HashSet<_>, HashSet<_>)| {
+                    let frontier: Vec<_> = frontier
+                        .into_iter()
+                        .filter(|cs_id| seen.insert(*cs_id))
+                        .collect();
+                    if frontier.is_empty() {
+                        return future::ok(future::Loop::Break(boundary)).boxify();
+                    }
+
+                    let parents = frontier.into_iter().map({
+                        let blobrepo = blobrepo.clone();
+                        move |cs_id| blobrepo.get_changeset_parents(&cs_id)
+                    });
+                    future::join_all(parents)
+                        .and_then({
+                            let blobrepo = blobrepo.clone();
+                            move |parents| {
+                                let parents: Vec<_> = parents.into_iter().flatten().collect();
+                                blobrepo.get_phases(parents)
+                            }
+                        })
+                        .map(move |parent_phases| {
+                            let mut next = Vec::new();
+                            for (cs_id, phase) in parent_phases {
+                                match phase {
+                                    Phase::Public => {
+                                        boundary.insert(cs_id);
+                                    }
+                                    Phase::Draft => next.push(cs_id),
+                                }
+                            }
+                            future::Loop::Continue((next, seen, boundary))
+                        })
+                        .boxify()
+                },
+            ).map(move |boundary| {
+                let mut phase_heads: Vec<_> = head_phases
+                    .into_iter()
+                    .map(|(cs_id, phase)| (phase.to_hg(), cs_id))
+                    .chain(
+                        boundary
+                            .into_iter()
+                            .map(|cs_id| (Phase::Public.to_hg(), cs_id)),
+                    )
+                    .collect();
+                phase_heads.sort();
+                phase_heads.dedup();
+                phase_heads
+            })
+        })
+        .boxify()
 }
 
+/// Changesets that are ancestors of `heads` but not of `excludes`, and at most `depth` steps away
+/// from `heads`, as entries of a changegroup part.
+fn changelog_entries(
+    blobrepo: Arc<BlobRepo>,
+    heads: Vec<HgNodeHash>,
+    excludes: Vec<HgNodeHash>,
+    depth: Option<usize>,
+) -> BoxStream<(HgNodeHash, HgBlobNode), Error> {
+    // Changesets have to be sent parents first. Only the hashes of a bounded window of changesets
+    // are kept in memory, the changesets themselves are fetched while the bundle is being sent.
+    let nodestosend = AscendingGenerationNodeStream::new(&blobrepo, {
+        let blobrepo = blobrepo.clone();
+        move || {
+            DifferenceOfUnionsOfAncestorsNodeStream::new_with_excludes_and_depth(
+                &blobrepo,
+                heads.clone(),
+                excludes.clone(),
+                depth,
+            )
+        }
+    });
+
+    let buffer_size = 100; // TODO(stash): make it configurable
+    nodestosend
+        .map(move |node| {
+            blobrepo
+                .get_changeset_by_changesetid(&HgChangesetId::new(node))
+                .map(move |cs| (node, cs))
+        })
+        .buffered(buffer_size)
+        .and_then(|(node, cs)| {
+            let revlogcs = serialize_changeset(&cs)?;
+            Ok((
+                node,
+                HgBlobNode::new(Bytes::from(revlogcs), cs.p1(), cs.p2()),
+            ))
+        })
+        .boxify()
+}
+
+/// Changesets of a shallow clone, see `changelog_entries`. The parents of the oldest of them are
+/// neither sent nor known to the client, so these changesets are sent as ellipsis nodes with
+/// those parents replaced by the null hash, like the narrow extension does.
+fn ellipsis_changelog_entries(
+    blobrepo: Arc<BlobRepo>,
+    heads: Vec<HgNodeHash>,
+    excludes: Vec<HgNodeHash>,
+    depth: usize,
+) -> BoxStream<(HgNodeHash, HgBlobNode, bool), Error> {
+    // The parents that are not sent are exactly `depth` steps away from the heads
+    let boundary = SetDifferenceNodeStream::new(
+        &blobrepo,
+        DifferenceOfUnionsOfAncestorsNodeStream::new_with_excludes_and_depth(
+            &blobrepo,
+            heads.clone(),
+            excludes.clone(),
+            Some(depth + 1),
+        ),
+        DifferenceOfUnionsOfAncestorsNodeStream::new_with_excludes_and_depth(
+            &blobrepo,
+            heads.clone(),
+            excludes.clone(),
+            Some(depth),
+        ),
+    ).collect();
+
+    boundary
+        .map(move |boundary| {
+            let boundary: HashSet<_> = boundary.into_iter().collect();
+            changelog_entries(blobrepo, heads, excludes, Some(depth)).map(move |(node, blobnode)| {
+                let parents = blobnode.parents().get_nodes();
+                let p1 = parents.0.filter(|p| !boundary.contains(*p));
+                let p2 = parents.1.filter(|p| !boundary.contains(*p));
+                let ellipsis = (p1, p2) != parents;
+                let blobnode = HgBlobNode::new(blobnode.as_blob().clone(), p1, p2);
+                (node, blobnode, ellipsis)
+            })
+        })
+        .flatten_stream()
+        .boxify()
+}
+
+/// Serialize a changeset the way it's stored in the changelog
+fn serialize_changeset(cs: &BlobChangeset) -> Result<Vec<u8>> {
+    let revlogcs = RevlogChangeset::new_from_parts(
+        cs.parents().clone(),
+        cs.manifestid().clone(),
+        cs.user().into(),
+        cs.time().clone(),
+        cs.extra().clone(),
+        cs.files().into(),
+        cs.comments().into(),
+    );
+
+    let mut v = Vec::new();
+    mercurial::changeset::serialize_cs(&revlogcs, &mut v)?;
+    Ok(v)
+}
+
+/// Trees of the manifest `mfid` that are not in any of the manifests `basemfids`, and the root
+/// tree itself. Modified trees come with the version of the tree in the base manifests.
 fn get_changed_entry_stream(
     repo: Arc<BlobRepo>,
     mfid: &HgNodeHash,
-    basemfid: &HgNodeHash,
+    basemfids: &[HgNodeHash],
     rootpath: Option<MPath>,
-    pruner: Option<impl FnMut(&ChangedEntry) -> bool + Send + Clone + 'static>,
+    pruner: impl FnMut(&ChangedEntry) -> bool + Send + Clone + 'static,
     trace: TraceContext,
-) -> BoxStream<(Box<Entry + Sync>, Option<MPath>), Error> {
+) -> BoxStream<(Box<Entry + Sync>, Option<Box<Entry + Sync>>, Option<MPath>), Error> {
     let manifest = repo.get_manifest_by_nodeid(mfid)
         .traced(&trace, "fetch rootmf", trace_args!());
-    let basemanifest =
-        repo.get_manifest_by_nodeid(basemfid)
-            .traced(&trace, "fetch baserootmf", trace_args!());
+    let basemanifests = future::join_all(
+        basemfids
+            .iter()
+            .map(|basemfid| repo.get_manifest_by_nodeid(basemfid))
+            .collect::<Vec<_>>(),
+    ).traced(&trace, "fetch baserootmfs", trace_args!());
 
     let changed_entries = manifest
-        .join(basemanifest)
+        .join(basemanifests)
         .map({
             let rootpath = rootpath.clone();
-            move |(mf, basemf)| match pruner {
-                Some(pruner) => {
-                    changed_entry_stream_with_pruner(&mf, &basemf, rootpath, pruner).boxify()
-                }
-                None => changed_entry_stream(&mf, &basemf, rootpath).boxify(),
+            move |(mf, basemfs)| {
+                changed_entry_stream_multiway_with_pruner(&mf, &basemfs, rootpath, pruner)
             }
         })
         .flatten_stream();
@@ -652,14 +1256,17 @@ fn get_changed_entry_stream(
         changed_entries.filter_map(move |entry_status| match entry_status.status {
             EntryStatus::Added(entry) => {
                 if entry.get_type() == Type::Tree {
-                    Some((entry, entry_status.dirname))
+                    Some((entry, None, entry_status.dirname))
                 } else {
                     None
                 }
             }
-            EntryStatus::Modified { to_entry, .. } => {
+            EntryStatus::Modified {
+                to_entry,
+                from_entry,
+            } => {
                 if to_entry.get_type() == Type::Tree {
-                    Some((to_entry, entry_status.dirname))
+                    Some((to_entry, Some(from_entry), entry_status.dirname))
                 } else {
                     None
                 }
@@ -670,15 +1277,19 @@ fn get_changed_entry_stream(
     // Append root manifest
     let root_entry_stream = stream::once(Ok((
         repo.get_root_entry(&HgManifestId::new(*mfid)),
+        None,
         rootpath,
     )));
 
     changed_entries.chain(root_entry_stream).boxify()
 }
 
+/// `from_entry` is the version of the tree the client already has, if any. The tree is sent as a
+/// delta against it if it is the p1 of the tree.
 fn fetch_treepack_part_input(
     repo: Arc<BlobRepo>,
     entry: Box<Entry + Sync>,
+    from_entry: Option<Box<Entry + Sync>>,
     basepath: Option<MPath>,
     trace: TraceContext,
 ) -> BoxFuture<parts::TreepackPartInput, Error> {
@@ -728,10 +1339,36 @@ fn fetch_treepack_part_input(
             ),
         );
 
-    parents
+    let delta_base_fut = parents.and_then({
+        let trace = trace.clone();
+        move |parents| {
+            let p1 = parents.get_nodes().0.cloned();
+            match from_entry {
+                Some(ref from_entry) if Some(from_entry.get_hash().into_nodehash()) == p1 => {
+                    let base = from_entry.get_hash().into_nodehash();
+                    from_entry
+                        .get_raw_content()
+                        .and_then(|blob| blob.into_inner().ok_or(err_msg("bad blob content")))
+                        .map(move |content| (parents, Some((base, content))))
+                        .traced(
+                            &trace,
+                            "fetching delta base",
+                            trace_args!(
+                                "node" => format!("{}", base),
+                                "path" => format!("{}", path)
+                            ),
+                        )
+                        .left_future()
+                }
+                _ => Ok((parents, None)).into_future().right_future(),
+            }
+        }
+    });
+
+    delta_base_fut
         .join(linknode_fut)
         .join(content_fut)
-        .map(move |((parents, linknode), content)| {
+        .map(move |(((parents, delta_base), linknode), content)| {
             let (p1, p2) = parents.get_nodes();
             parts::TreepackPartInput {
                 node: node.into_nodehash(),
@@ -741,6 +1378,7 @@ fn fetch_treepack_part_input(
                 name: entry.get_name().cloned(),
                 linknode: linknode.into_nodehash(),
                 basepath,
+                delta_base,
             }
         })
         .boxify()
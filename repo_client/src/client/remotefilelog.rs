@@ -4,18 +4,24 @@
 // This software may be used and distributed according to the terms of the
 // GNU General Public License version 2 or any later version.
 
+use std::cmp::Reverse;
 use std::collections::{HashMap, HashSet, VecDeque};
 use std::io::{Cursor, Write};
 use std::sync::Arc;
 
 use bytes::Bytes;
-use futures::{stream, Future, IntoFuture, Stream, future::Either};
+use futures::{future, stream, Future, IntoFuture, Stream, future::Either};
 use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
 use pylz4;
 
 use blobrepo::BlobRepo;
 use filenodes::FilenodeInfo;
-use mercurial_types::{HgChangesetId, HgNodeHash, HgParents, MPath, RepoPath, NULL_HASH};
+use mercurial_bundles::select_delta;
+use mercurial_bundles::wirepack::{DataEntry, HistoryEntry, Part};
+use mercurial_types::{HgChangesetId, HgNodeHash, HgParents, LfsPointer, MPath, RepoPath,
+                      NULL_HASH};
+use mercurial_types::lfs::{lfs_fulltext, REVIDX_EXTSTORED};
+use revset::DifferenceOfUnionsOfAncestorsNodeStream;
 use tracing::{TraceContext, Traced};
 
 use errors::*;
@@ -24,7 +30,8 @@ const METAKEYFLAG: &str = "f";
 const METAKEYSIZE: &str = "s";
 
 /// Remotefilelog blob consists of file content in `node` revision and all the history
-/// of the file up to `node`
+/// of the file up to `node`. Clients expect the history in a blob to be complete, so it isn't
+/// limited for shallow clones. Linknodes the client doesn't have are adjusted by the client.
 pub fn create_remotefilelog_blob(
     repo: Arc<BlobRepo>,
     node: HgNodeHash,
@@ -32,8 +39,10 @@ pub fn create_remotefilelog_blob(
     trace: TraceContext,
 ) -> BoxFuture<Bytes, Error> {
     // raw_content includes copy information
+    // Large files are stored as pointers, their flag tells the client to resolve them
     let raw_content_bytes = repo.get_file_content(&node)
-        .and_then(move |raw_content| {
+        .join(repo.get_file_revlog_flags(node))
+        .and_then(move |(raw_content, flags)| {
             let raw_content = raw_content.into_bytes();
             // requires digit counting to know for sure, use reasonable approximation
             let approximate_header_size = 12;
@@ -42,14 +51,13 @@ pub fn create_remotefilelog_blob(
             ));
 
             // Write header
-            // TODO(stash): support LFS files using METAKEYFLAG
             let res = write!(
                 writer,
                 "v1\n{}{}\n{}{}\0",
                 METAKEYSIZE,
                 raw_content.len(),
                 METAKEYFLAG,
-                0,
+                flags,
             );
 
             res.and_then(|_| writer.write_all(&raw_content))
@@ -74,7 +82,8 @@ pub fn create_remotefilelog_blob(
             let node = node.clone();
             let trace = trace.clone();
             move |prefetched_filenodes| {
-                get_file_history(repo, node, path, prefetched_filenodes, trace).collect()
+                get_file_history(repo, vec![node], path, prefetched_filenodes, None, trace)
+                    .collect()
             }
         })
         .and_then(|history| {
@@ -84,21 +93,7 @@ pub fn create_remotefilelog_blob(
             ));
 
             for (node, parents, linknode, copy) in history {
-                let (p1, p2) = match parents {
-                    HgParents::None => (NULL_HASH, NULL_HASH),
-                    HgParents::One(p) => (p, NULL_HASH),
-                    HgParents::Two(p1, p2) => (p1, p2),
-                };
-
-                let (p1, p2, copied_from) = if let Some((copied_from, copied_rev)) = copy {
-                    // Mercurial has a complicated copy/renames logic.
-                    // If (path1, filenode1) is copied/renamed from (path2, filenode2),
-                    // filenode1's p1 is set to filenode2, and copy_from path is set to path2
-                    // filenode1's p2 is null for non-merge commits. It might be non-null for merges.
-                    (copied_rev, p1, Some(copied_from))
-                } else {
-                    (p1, p2, None)
-                };
+                let (p1, p2, copied_from) = history_parents(parents, copy);
 
                 writer.write_all(node.as_bytes())?;
                 writer.write_all(p1.as_bytes())?;
@@ -125,11 +120,214 @@ pub fn create_remotefilelog_blob(
         .boxify()
 }
 
+/// The parents of a file revision as they are sent to the client, and the path it was copied from
+fn history_parents(
+    parents: HgParents,
+    copy: Option<(MPath, HgNodeHash)>,
+) -> (HgNodeHash, HgNodeHash, Option<MPath>) {
+    let (p1, p2) = match parents {
+        HgParents::None => (NULL_HASH, NULL_HASH),
+        HgParents::One(p) => (p, NULL_HASH),
+        HgParents::Two(p1, p2) => (p1, p2),
+    };
+
+    if let Some((copied_from, copied_rev)) = copy {
+        // Mercurial has a complicated copy/renames logic.
+        // If (path1, filenode1) is copied/renamed from (path2, filenode2),
+        // filenode1's p1 is set to filenode2, and copy_from path is set to path2
+        // filenode1's p2 is null for non-merge commits. It might be non-null for merges.
+        (copied_rev, p1, Some(copied_from))
+    } else {
+        (p1, p2, None)
+    }
+}
+
+/// Wirepack parts with the history and the content of several revisions of a file. The history
+/// is walked once for all of the revisions. If `history_depth` is set, only the history linked
+/// to changesets at most that many commits older than the requested revisions is sent, and the
+/// client fetches the rest when it needs it. A revision is sent as a delta against its p1 if the
+/// p1 is sent before it and the delta is at most `delta_threshold` percent of the fulltext, and
+/// as a fulltext otherwise.
+pub fn create_getpack_parts(
+    repo: Arc<BlobRepo>,
+    path: MPath,
+    mut nodes: Vec<HgNodeHash>,
+    history_depth: Option<usize>,
+    delta_threshold: usize,
+    trace: TraceContext,
+) -> BoxFuture<Vec<Part>, Error> {
+    nodes.sort();
+    nodes.dedup();
+
+    let contents = nodes.clone().into_iter().map({
+        let repo = repo.clone();
+        move |node| get_getpack_content(repo.clone(), node).map(move |content| (node, content))
+    });
+    let contents = future::join_all(contents)
+        .traced(&trace, "fetching getpack content", trace_args!());
+
+    let shallow_linknodes = match history_depth {
+        Some(depth) => Either::A(
+            linknodes_within_depth(repo.clone(), &path, &nodes, depth)
+                .map(|linknodes| Some(Arc::new(linknodes))),
+        ),
+        None => Either::B(future::ok(None)),
+    };
+
+    let history = repo.get_all_filenodes(RepoPath::FilePath(path.clone()))
+        .map(|filenodes| {
+            filenodes
+                .into_iter()
+                .map(|filenode| (filenode.filenode.into_nodehash(), filenode))
+                .collect()
+        })
+        .join(shallow_linknodes)
+        .and_then({
+            let path = path.clone();
+            let trace = trace.clone();
+            move |(prefetched_filenodes, shallow_linknodes)| {
+                get_file_history(
+                    repo,
+                    nodes,
+                    path,
+                    prefetched_filenodes,
+                    shallow_linknodes,
+                    trace,
+                ).collect()
+            }
+        })
+        .traced(&trace, "fetching getpack history", trace_args!());
+
+    history
+        .join(contents)
+        .and_then(move |(history, mut contents)| {
+            let repo_path = RepoPath::FilePath(path);
+            let mut parts = vec![
+                Part::HistoryMeta {
+                    path: repo_path.clone(),
+                    entry_count: history.len() as u32,
+                },
+            ];
+
+            // History is walked from the requested revisions towards the root, so sending
+            // revisions in reverse order of the walk sends most of the parents first
+            let mut order = HashMap::new();
+            let mut file_parents = HashMap::new();
+            for (idx, (node, parents, linknode, copy)) in history.into_iter().enumerate() {
+                order.insert(node, idx);
+                match parents {
+                    HgParents::One(p1) | HgParents::Two(p1, _) => {
+                        file_parents.insert(node, p1);
+                    }
+                    HgParents::None => {}
+                }
+
+                let (p1, p2, copy_from) = history_parents(parents, copy);
+                parts.push(Part::History(HistoryEntry {
+                    node,
+                    p1,
+                    p2,
+                    linknode: linknode.into_nodehash(),
+                    copy_from: copy_from.map(RepoPath::FilePath),
+                }));
+            }
+
+            contents.sort_by_key(|&(ref node, _)| Reverse(order.get(node).cloned()));
+
+            parts.push(Part::DataMeta {
+                path: repo_path,
+                entry_count: contents.len() as u32,
+            });
+            let mut sent = HashMap::new();
+            for (node, content) in contents {
+                let (delta_base, delta) = {
+                    let base = file_parents.get(&node).and_then(|p1| {
+                        sent.get(p1).map(|base: &Bytes| (*p1, base.as_ref()))
+                    });
+                    select_delta(&content, base, delta_threshold)
+                };
+                parts.push(Part::Data(DataEntry {
+                    node,
+                    delta_base,
+                    delta,
+                }));
+                sent.insert(node, content);
+            }
+
+            Ok(parts)
+        })
+        .boxify()
+}
+
+/// Content of a file revision as it's sent by getpackv1. Wirepack data entries have no revlog
+/// flags, so clients couldn't tell a large file's pointer from its content. Large files are sent
+/// as their fulltext instead.
+fn get_getpack_content(repo: Arc<BlobRepo>, node: HgNodeHash) -> BoxFuture<Bytes, Error> {
+    repo.get_file_content(&node)
+        .join(repo.get_file_revlog_flags(node))
+        .and_then(move |(content, flags)| {
+            let content = content.into_bytes();
+            if flags & REVIDX_EXTSTORED == 0 {
+                return future::ok(content).boxify();
+            }
+            let pointer = content;
+            let oid = try_boxfuture!(LfsPointer::parse(&pointer)).oid;
+            repo.get_lfs_object(oid)
+                .and_then(move |object| match object {
+                    Some((_, content)) => Ok(content),
+                    None => Err(ErrorKind::LfsObjectMissing(node, oid).into()),
+                })
+                .flatten_stream()
+                .fold(vec![], |mut content, chunk| {
+                    content.extend_from_slice(&chunk);
+                    Ok::<_, Error>(content)
+                })
+                .and_then(move |content| lfs_fulltext(&pointer, &content))
+                .map(Bytes::from)
+                .boxify()
+        })
+        .boxify()
+}
+
+/// The changesets at most `depth` commits away from the changesets that introduced `nodes`
+fn linknodes_within_depth(
+    repo: Arc<BlobRepo>,
+    path: &MPath,
+    nodes: &[HgNodeHash],
+    depth: usize,
+) -> BoxFuture<HashSet<HgChangesetId>, Error> {
+    let repo_path = RepoPath::FilePath(path.clone());
+    let linknodes = nodes
+        .iter()
+        .filter(|node| **node != NULL_HASH)
+        .map(|node| {
+            repo.get_linknode(repo_path.clone(), node)
+                .map(HgChangesetId::into_nodehash)
+        })
+        .collect::<Vec<_>>();
+
+    future::join_all(linknodes)
+        .and_then(move |linknodes| {
+            DifferenceOfUnionsOfAncestorsNodeStream::new_with_excludes_and_depth(
+                &repo,
+                linknodes,
+                vec![],
+                Some(depth),
+            ).map(HgChangesetId::new)
+                .collect()
+        })
+        .map(|linknodes| linknodes.into_iter().collect())
+        .boxify()
+}
+
+/// History of a file starting from `startnodes`. If `shallow_linknodes` is set, entries linked
+/// to other changesets are not returned and the history behind them isn't walked.
 fn get_file_history(
     repo: Arc<BlobRepo>,
-    startnode: HgNodeHash,
+    startnodes: Vec<HgNodeHash>,
     path: MPath,
     prefetched_history: HashMap<HgNodeHash, FilenodeInfo>,
+    shallow_linknodes: Option<Arc<HashSet<HgChangesetId>>>,
     trace: TraceContext,
 ) -> BoxStream<
     (
@@ -140,12 +338,13 @@ fn get_file_history(
     ),
     Error,
 > {
-    if startnode == NULL_HASH {
-        return stream::empty().boxify();
-    }
     let mut startstate = VecDeque::new();
-    startstate.push_back(startnode);
-    let seen_nodes: HashSet<_> = [startnode].iter().cloned().collect();
+    let mut seen_nodes = HashSet::new();
+    for startnode in startnodes {
+        if startnode != NULL_HASH && seen_nodes.insert(startnode) {
+            startstate.push_back(startnode);
+        }
+    }
     let path = RepoPath::FilePath(path);
 
     stream::unfold(
@@ -203,10 +402,17 @@ fn get_file_history(
                 .join(copy)
                 .map(|(pl, c)| (pl.0, pl.1, c));
 
+            let shallow_linknodes = shallow_linknodes.clone();
             Some(joined.map(move |(parents, linknode, copy)| {
+                let shallow = shallow_linknodes
+                    .map_or(false, |linknodes| !linknodes.contains(&linknode));
+                if shallow {
+                    return (None, (nodes, seen_nodes));
+                }
                 nodes.extend(parents.into_iter().filter(|p| seen_nodes.insert(*p)));
-                ((node, parents, linknode, copy), (nodes, seen_nodes))
+                (Some((node, parents, linknode, copy)), (nodes, seen_nodes))
             }))
         },
-    ).boxify()
+    ).filter_map(|entry| entry)
+        .boxify()
 }
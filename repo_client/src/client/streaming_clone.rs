@@ -0,0 +1,275 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Streaming clones (`stream_out` and the `stream2` bundle2 part)
+//!
+//! A streaming clone sends the client revlog files that it copies into its store as they are,
+//! which is much cheaper for both sides than a changegroup of the whole history. Mononoke has no
+//! revlogs, so they are materialized from the `BlobRepo` at a bookmark and stored as a single
+//! artifact in the blobstore. The artifact is keyed by the changeset it was built for, so every
+//! server serving the repo can reuse it. A client that streamed an artifact pulls the commits
+//! that were added after it was built with a normal `getbundle`, or gets them in the changegroup
+//! that follows the `stream2` part.
+//!
+//! Only the changelog and the root tree manifests are materialized. Trees below the root and
+//! files are fetched on demand by the treemanifest and remotefilelog extensions.
+
+use std::str;
+use std::sync::Arc;
+
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::{Future, IntoFuture, Stream};
+use futures_ext::{BoxFuture, FutureExt};
+use slog::Logger;
+
+use blobrepo::BlobRepo;
+use blobstore::Blobstore;
+use mercurial::revlog::{RevIdx, RevlogWriter};
+use mercurial_types::{Changeset, HgChangesetId, HgManifestId, NULL_HASH};
+use mononoke_types::BlobstoreBytes;
+use revset::{AscendingGenerationNodeStream, DifferenceOfUnionsOfAncestorsNodeStream};
+
+use super::serialize_changeset;
+use errors::*;
+
+/// Requirements a client needs to support to use the artifact
+pub const STREAM_REQUIREMENTS: &[&str] = &["revlogv1"];
+
+/// Blobstore key of the streaming clone artifact built at `changesetid`
+pub fn streaming_clone_key(changesetid: &HgChangesetId) -> String {
+    format!("streamclone.{}", changesetid)
+}
+
+/// Make sure there is a streaming clone artifact for `changesetid`, materializing it if it
+/// doesn't exist yet. This is expensive, it reads every changeset and root manifest that is an
+/// ancestor of `changesetid`.
+pub fn build_streaming_clone(
+    repo: Arc<BlobRepo>,
+    changesetid: HgChangesetId,
+    logger: Logger,
+) -> BoxFuture<(), Error> {
+    let key = streaming_clone_key(&changesetid);
+    let blobstore = repo.get_blobstore();
+
+    blobstore
+        .is_present(key.clone())
+        .and_then(move |present| {
+            if present {
+                debug!(logger, "streaming clone artifact for {} exists", changesetid);
+                return Ok(()).into_future().boxify();
+            }
+
+            info!(logger, "building streaming clone artifact for {}", changesetid);
+            materialize_revlogs(repo, changesetid)
+                .and_then(move |artifact| {
+                    info!(
+                        logger,
+                        "streaming clone artifact for {} is {} bytes",
+                        changesetid,
+                        artifact.len()
+                    );
+                    blobstore.put(key, BlobstoreBytes::from_bytes(artifact))
+                })
+                .boxify()
+        })
+        .boxify()
+}
+
+/// Fetch the artifact for `changesetid`, ready to be sent as a `stream_out` response
+pub fn fetch_streaming_clone(
+    repo: Arc<BlobRepo>,
+    changesetid: HgChangesetId,
+) -> BoxFuture<Bytes, Error> {
+    let key = streaming_clone_key(&changesetid);
+    repo.get_blobstore()
+        .get(key.clone())
+        .and_then(move |artifact| {
+            artifact
+                .map(BlobstoreBytes::into_bytes)
+                .ok_or(ErrorKind::StreamingCloneArtifactMissing(key).into())
+        })
+        .boxify()
+}
+
+/// Write the changelog and root manifest revlogs of all ancestors of `changesetid`, in the
+/// `stream_out` format.
+fn materialize_revlogs(
+    repo: Arc<BlobRepo>,
+    changesetid: HgChangesetId,
+) -> BoxFuture<Bytes, Error> {
+    let nodes = AscendingGenerationNodeStream::new(&repo, {
+        let repo = repo.clone();
+        move || DifferenceOfUnionsOfAncestorsNodeStream::new(&repo, changesetid.into_nodehash())
+    });
+
+    let buffer_size = 100;
+    nodes
+        .map({
+            let repo = repo.clone();
+            move |node| {
+                let repo = repo.clone();
+                repo.get_changeset_by_changesetid(&HgChangesetId::new(node))
+                    .and_then(move |cs| {
+                        let mfid = cs.manifestid().into_nodehash();
+                        if mfid == NULL_HASH {
+                            return Ok((node, cs, None)).into_future().boxify();
+                        }
+                        let root = repo.get_root_entry(&HgManifestId::new(mfid));
+                        root.get_parents()
+                            .join(root.get_raw_content())
+                            .map(move |(parents, content)| (node, cs, Some((parents, content))))
+                            .boxify()
+                    })
+            }
+        })
+        .buffered(buffer_size)
+        .fold(
+            (RevlogWriter::new(), RevlogWriter::new()),
+            |(mut changelog, mut manifests), (node, cs, root_manifest)| {
+                let linkrev = RevIdx::from(changelog.len());
+                changelog.add_revision(
+                    &node,
+                    cs.p1(),
+                    cs.p2(),
+                    linkrev,
+                    &serialize_changeset(&cs)?,
+                )?;
+
+                if let Some((parents, content)) = root_manifest {
+                    let mfid = cs.manifestid().into_nodehash();
+                    let content = content
+                        .as_slice()
+                        .ok_or(ErrorKind::MissingManifestContent(mfid))?;
+                    let (p1, p2) = parents.get_nodes();
+                    manifests.add_revision(&mfid, p1, p2, linkrev, content)?;
+                }
+
+                Ok::<_, Error>((changelog, manifests))
+            },
+        )
+        .map(|(changelog, manifests)| {
+            let (changelog_idx, changelog_data) = changelog.into_parts();
+            let (manifests_idx, manifests_data) = manifests.into_parts();
+            encode_stream_out(vec![
+                ("00changelog.i", changelog_idx),
+                ("00changelog.d", changelog_data),
+                ("00manifesttree.i", manifests_idx),
+                ("00manifesttree.d", manifests_data),
+            ])
+        })
+        .boxify()
+}
+
+/// `stream_out` format, without the leading status line:
+/// <number of files> <total size of the files>\n
+/// then for each file:
+/// <store path>\0<size>\n<contents>
+fn encode_stream_out(files: Vec<(&str, Vec<u8>)>) -> Bytes {
+    let files: Vec<_> = files
+        .into_iter()
+        .filter(|&(_, ref content)| !content.is_empty())
+        .collect();
+    let total_size: usize = files.iter().map(|&(_, ref content)| content.len()).sum();
+
+    let mut out = BytesMut::with_capacity(total_size + 100);
+    out.put_slice(format!("{} {}\n", files.len(), total_size).as_bytes());
+    for (name, content) in files {
+        out.put_slice(format!("{}\0{}\n", name, content.len()).as_bytes());
+        out.put_slice(&content);
+    }
+    out.freeze()
+}
+
+/// Convert an artifact from the `stream_out` format to the version 2 format of the `stream2`
+/// bundle2 part. Yields the number of files, their total size and the converted data.
+/// The version 2 format has for each file:
+/// s<uvarint length of the store path><uvarint size><store path><contents>
+pub fn stream_out_to_stream2(artifact: &Bytes) -> Result<(usize, usize, Bytes)> {
+    let invalid = |msg: &str| Error::from(ErrorKind::InvalidStreamingCloneArtifact(msg.into()));
+
+    let (filecount, bytecount, mut rest) = {
+        let (line, rest) = split_line(artifact, b'\n').ok_or_else(|| invalid("no header"))?;
+        let mut counts = line.splitn(2, |b| *b == b' ').map(parse_usize);
+        match (counts.next(), counts.next()) {
+            (Some(Some(filecount)), Some(Some(bytecount))) => (filecount, bytecount, rest),
+            _ => return Err(invalid("bad header")),
+        }
+    };
+
+    let mut out = BytesMut::with_capacity(artifact.len() + 10 * filecount);
+    for _ in 0..filecount {
+        let (name, after_name) = split_line(&rest, b'\0').ok_or_else(|| invalid("no name"))?;
+        let (size, after_size) =
+            split_line(&after_name, b'\n').ok_or_else(|| invalid("no file size"))?;
+        let size = parse_usize(&size).ok_or_else(|| invalid("bad file size"))?;
+        if after_size.len() < size {
+            return Err(invalid("truncated file"));
+        }
+
+        out.reserve(name.len() + size + 21);
+        out.put_u8(b's');
+        put_uvarint(&mut out, name.len() as u64);
+        put_uvarint(&mut out, size as u64);
+        out.put_slice(&name);
+        out.put_slice(&after_size[..size]);
+        rest = after_size.slice_from(size);
+    }
+    if !rest.is_empty() {
+        return Err(invalid("trailing data"));
+    }
+
+    Ok((filecount, bytecount, out.freeze()))
+}
+
+/// Split `data` at the first `separator`, which is dropped
+fn split_line(data: &Bytes, separator: u8) -> Option<(Bytes, Bytes)> {
+    let pos = data.iter().position(|b| *b == separator)?;
+    Some((data.slice_to(pos), data.slice_from(pos + 1)))
+}
+
+fn parse_usize(data: &[u8]) -> Option<usize> {
+    str::from_utf8(data).ok()?.parse().ok()
+}
+
+/// Unsigned LEB128 varint, like Mercurial's `util.uvarintencode`
+fn put_uvarint(out: &mut BytesMut, mut value: u64) {
+    while value >= 0x80 {
+        out.put_u8((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    out.put_u8(value as u8);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_stream_out_to_stream2() {
+        let big = vec![b'x'; 200];
+        let artifact = encode_stream_out(vec![
+            ("00changelog.i", b"index".to_vec()),
+            ("00changelog.d", vec![]),
+            ("00manifesttree.i", big.clone()),
+        ]);
+        let (filecount, bytecount, data) =
+            stream_out_to_stream2(&artifact).expect("artifact is valid");
+        assert_eq!(filecount, 2);
+        assert_eq!(bytecount, 205);
+
+        let mut expected = b"s\x0d\x0500changelog.iindex".to_vec();
+        expected.extend_from_slice(b"s\x10\xc8\x0100manifesttree.i");
+        expected.extend_from_slice(&big);
+        assert_eq!(data, Bytes::from(expected));
+    }
+
+    #[test]
+    fn test_stream_out_to_stream2_invalid() {
+        for artifact in &["", "1 5\n", "1 5\n00changelog.i\x005\nind", "0 0\nextra"] {
+            assert!(stream_out_to_stream2(&Bytes::from(*artifact)).is_err());
+        }
+    }
+}
@@ -6,10 +6,22 @@
 
 pub use failure::{Error, Result, ResultExt};
 
-use mercurial_types::RepoPath;
+use mercurial_types::{HgNodeHash, RepoPath, Sha256};
 
 #[derive(Debug, Fail)]
 pub enum ErrorKind {
     #[fail(display = "internal error: file {} copied from directory {}", _0, _1)]
     InconsistenCopyInfo(RepoPath, RepoPath),
+    #[fail(display = "streaming clone artifact {} is missing", _0)]
+    StreamingCloneArtifactMissing(String),
+    #[fail(display = "streaming clone artifact is invalid: {}", _0)]
+    InvalidStreamingCloneArtifact(String),
+    #[fail(display = "content of manifest {} is missing", _0)]
+    MissingManifestContent(HgNodeHash),
+    #[fail(display = "server is overloaded: too many concurrent {}, try again later", _0)]
+    Overloaded(&'static str),
+    #[fail(display = "content of large file {} (sha256 {}) is missing", _0, _1)]
+    LfsObjectMissing(HgNodeHash, Sha256),
+    #[fail(display = "{} is not allowed to {} this repo", _0, _1)]
+    AccessDenied(String, &'static str),
 }
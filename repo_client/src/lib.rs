@@ -8,6 +8,7 @@
 
 //! State for a single source control Repo
 
+extern crate async_compression;
 extern crate bytes;
 #[macro_use]
 extern crate failure_ext as failure;
@@ -22,22 +23,36 @@ extern crate rand;
 #[macro_use]
 extern crate slog;
 #[macro_use]
+extern crate stats;
+#[cfg(test)]
+extern crate tokio;
+#[macro_use]
 extern crate tracing;
 
 extern crate blobrepo;
+extern crate blobstore;
 extern crate bundle2_resolver;
 extern crate filenodes;
 extern crate hgproto;
+extern crate hooks;
 extern crate mercurial;
 extern crate mercurial_bundles;
 extern crate mercurial_types;
 extern crate metaconfig;
+extern crate mononoke_api;
+extern crate mononoke_types;
+extern crate phases;
 extern crate revset;
 extern crate scuba_ext;
 
 mod client;
 mod errors;
+mod load_limiter;
 mod mononoke_repo;
 
 pub use client::RepoClient;
+pub use client::clone_bundles::{build_clone_bundle, clone_bundles_manifest, find_clone_bundle};
+pub use client::streaming_clone::build_streaming_clone;
+pub use errors::ErrorKind;
+pub use load_limiter::{LoadKind, LoadLimiter, LoadLimits, Permit};
 pub use mononoke_repo::MononokeRepo;
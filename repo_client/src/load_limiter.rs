@@ -0,0 +1,384 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Admission control for sessions and expensive commands
+//!
+//! A `LoadLimiter` caps how many units of work run at the same time, in total and for each
+//! client. Work that doesn't fit waits in a queue, in order of arrival, and is shed with
+//! `ErrorKind::Overloaded` if it couldn't start within the maximum wait. A client that reached
+//! its own limit doesn't hold back the clients queued behind it.
+
+use std::collections::{HashMap, VecDeque};
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::{stream, Async, Future, IntoFuture, Stream};
+use futures::sync::oneshot;
+use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
+use stats::Timeseries;
+
+use errors::*;
+
+define_stats! {
+    prefix = "mononoke.load_limiter";
+    sessions_admitted: timeseries(RATE, SUM),
+    sessions_queued: timeseries(RATE, SUM),
+    sessions_shed: timeseries(RATE, SUM),
+    sessions_wait_ms: timeseries(RATE, AVG, SUM),
+    commands_admitted: timeseries(RATE, SUM),
+    commands_queued: timeseries(RATE, SUM),
+    commands_shed: timeseries(RATE, SUM),
+    commands_wait_ms: timeseries(RATE, AVG, SUM),
+}
+
+/// What a `LoadLimiter` limits, used to report its decisions
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LoadKind {
+    /// Connected sessions
+    Sessions,
+    /// Commands that are expensive to serve: `getbundle`, `gettreepack` and `getfiles`
+    Commands,
+}
+
+impl LoadKind {
+    fn name(&self) -> &'static str {
+        match self {
+            &LoadKind::Sessions => "sessions",
+            &LoadKind::Commands => "expensive commands",
+        }
+    }
+
+    fn record_admitted(&self, waited: Option<Duration>) {
+        match self {
+            &LoadKind::Sessions => STATS::sessions_admitted.add_value(1),
+            &LoadKind::Commands => STATS::commands_admitted.add_value(1),
+        }
+        if let Some(waited) = waited {
+            let wait_ms = duration_ms(waited);
+            match self {
+                &LoadKind::Sessions => {
+                    STATS::sessions_queued.add_value(1);
+                    STATS::sessions_wait_ms.add_value(wait_ms);
+                }
+                &LoadKind::Commands => {
+                    STATS::commands_queued.add_value(1);
+                    STATS::commands_wait_ms.add_value(wait_ms);
+                }
+            }
+        }
+    }
+
+    fn record_shed(&self) {
+        match self {
+            &LoadKind::Sessions => STATS::sessions_shed.add_value(1),
+            &LoadKind::Commands => STATS::commands_shed.add_value(1),
+        }
+    }
+}
+
+/// Limits of a `LoadLimiter`
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LoadLimits {
+    /// Maximum number of units of work running at the same time
+    pub max_total: usize,
+    /// Maximum number of units of work of a single client running at the same time
+    pub max_per_client: usize,
+    /// How long work can wait in the queue before it is shed
+    pub max_wait: Duration,
+}
+
+impl LoadLimits {
+    /// Limits that admit everything immediately
+    pub fn unlimited() -> Self {
+        LoadLimits {
+            max_total: usize::max_value(),
+            max_per_client: usize::max_value(),
+            max_wait: Duration::from_secs(0),
+        }
+    }
+}
+
+pub struct LoadLimiter {
+    kind: LoadKind,
+    limits: LoadLimits,
+    state: Mutex<LoadState>,
+}
+
+struct LoadState {
+    total: usize,
+    per_client: HashMap<String, usize>,
+    queue: VecDeque<Waiter>,
+}
+
+struct Waiter {
+    client: String,
+    sender: oneshot::Sender<Permit>,
+}
+
+/// Permission to run one unit of work of a client. The work stops counting towards the limits
+/// when the permit is dropped.
+pub struct Permit {
+    limiter: Arc<LoadLimiter>,
+    client: String,
+}
+
+impl LoadLimiter {
+    pub fn new(kind: LoadKind, limits: LoadLimits) -> Arc<Self> {
+        Arc::new(LoadLimiter {
+            kind,
+            limits,
+            state: Mutex::new(LoadState {
+                total: 0,
+                per_client: HashMap::new(),
+                queue: VecDeque::new(),
+            }),
+        })
+    }
+
+    /// Wait for a permit to run a unit of work of `client`
+    pub fn acquire(this: &Arc<Self>, client: &str) -> BoxFuture<Permit, Error> {
+        // The new work goes to the end of the queue: the waiters before it are admitted first,
+        // and then it is admitted right away if it still fits
+        let (admitted, receiver) = {
+            let mut state = this.state.lock().expect("lock poisoned");
+            let admitted = this.admit_waiters(&mut state);
+            if this.fits(&state, client) {
+                this.admit(&mut state, client);
+                (admitted, None)
+            } else {
+                let (sender, receiver) = oneshot::channel();
+                state.queue.push_back(Waiter {
+                    client: client.to_owned(),
+                    sender,
+                });
+                (admitted, Some(receiver))
+            }
+        };
+        LoadLimiter::send_permits(this, admitted);
+
+        let receiver = match receiver {
+            Some(receiver) => receiver,
+            None => {
+                this.kind.record_admitted(None);
+                let permit = Permit {
+                    limiter: this.clone(),
+                    client: client.to_owned(),
+                };
+                return Ok(permit).into_future().boxify();
+            }
+        };
+
+        let kind = this.kind;
+        let start = Instant::now();
+        // A waiter that timed out is removed from the queue when its receiver is found to be
+        // dropped, see `release`
+        receiver
+            .timeout(this.limits.max_wait)
+            .then(move |res| match res {
+                Ok(permit) => {
+                    kind.record_admitted(Some(start.elapsed()));
+                    Ok(permit)
+                }
+                Err(_) => {
+                    kind.record_shed();
+                    Err(Error::from(ErrorKind::Overloaded(kind.name())))
+                }
+            })
+            .boxify()
+    }
+
+    /// Run `work` once a permit for `client` is acquired, and hold the permit until `work`
+    /// finishes or is dropped
+    pub fn limit_stream<T: Send + 'static>(
+        this: &Arc<Self>,
+        client: &str,
+        work: BoxStream<T, Error>,
+    ) -> BoxStream<T, Error> {
+        LoadLimiter::acquire(this, client)
+            .map(move |permit| permit.hold_during(work))
+            .flatten_stream()
+            .boxify()
+    }
+
+    fn fits(&self, state: &LoadState, client: &str) -> bool {
+        state.total < self.limits.max_total
+            && state.per_client.get(client).cloned().unwrap_or(0) < self.limits.max_per_client
+    }
+
+    fn admit(&self, state: &mut LoadState, client: &str) {
+        state.total += 1;
+        *state.per_client.entry(client.to_owned()).or_insert(0) += 1;
+    }
+
+    fn release(this: &Arc<Self>, client: &str) {
+        let admitted = {
+            let mut state = this.state.lock().expect("lock poisoned");
+            state.total -= 1;
+            let remove = match state.per_client.get_mut(client) {
+                Some(count) => {
+                    *count -= 1;
+                    *count == 0
+                }
+                None => false,
+            };
+            if remove {
+                state.per_client.remove(client);
+            }
+            this.admit_waiters(&mut state)
+        };
+        LoadLimiter::send_permits(this, admitted);
+    }
+
+    /// Admit the oldest waiters that fit, and drop the ones that timed out. Waiters whose client
+    /// is at its limit keep their place in the queue.
+    fn admit_waiters(&self, state: &mut LoadState) -> Vec<Waiter> {
+        let mut admitted = Vec::new();
+        let queue = mem::replace(&mut state.queue, VecDeque::new());
+        for waiter in queue {
+            if waiter.sender.is_canceled() {
+                continue;
+            }
+            if self.fits(state, &waiter.client) {
+                self.admit(state, &waiter.client);
+                admitted.push(waiter);
+            } else {
+                state.queue.push_back(waiter);
+            }
+        }
+        admitted
+    }
+
+    /// Permits are sent without holding the lock: a permit that can't be delivered because its
+    /// waiter just timed out is dropped, and that releases it again
+    fn send_permits(this: &Arc<Self>, admitted: Vec<Waiter>) {
+        for waiter in admitted {
+            let permit = Permit {
+                limiter: this.clone(),
+                client: waiter.client,
+            };
+            let _ = waiter.sender.send(permit);
+        }
+    }
+}
+
+impl Permit {
+    /// Keep the permit until `work` finishes or is dropped
+    pub fn hold_during<T: Send + 'static>(self, work: BoxStream<T, Error>) -> BoxStream<T, Error> {
+        let mut permit = Some(self);
+        work.chain(stream::poll_fn(move || {
+            permit.take();
+            Ok(Async::Ready(None))
+        })).boxify()
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        LoadLimiter::release(&self.limiter, &self.client);
+    }
+}
+
+fn duration_ms(duration: Duration) -> i64 {
+    (duration.as_secs() * 1000 + u64::from(duration.subsec_nanos() / 1_000_000)) as i64
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use tokio::runtime::Runtime;
+
+    fn limiter(max_total: usize, max_per_client: usize, max_wait_ms: u64) -> Arc<LoadLimiter> {
+        LoadLimiter::new(
+            LoadKind::Commands,
+            LoadLimits {
+                max_total,
+                max_per_client,
+                max_wait: Duration::from_millis(max_wait_ms),
+            },
+        )
+    }
+
+    fn queued(limiter: &LoadLimiter) -> usize {
+        limiter.state.lock().expect("lock poisoned").queue.len()
+    }
+
+    #[test]
+    fn test_release() {
+        let mut runtime = Runtime::new().expect("failed to create runtime");
+        let limiter = limiter(1, 1, 10_000);
+
+        let first = LoadLimiter::acquire(&limiter, "a")
+            .wait()
+            .expect("first work is admitted");
+        let second = LoadLimiter::acquire(&limiter, "b");
+        assert_eq!(queued(&limiter), 1);
+
+        drop(first);
+        assert_eq!(queued(&limiter), 0);
+        let second = runtime
+            .block_on(second)
+            .expect("released permit goes to the waiter");
+
+        drop(second);
+        let state = limiter.state.lock().expect("lock poisoned");
+        assert_eq!(state.total, 0);
+        assert!(state.per_client.is_empty());
+    }
+
+    #[test]
+    fn test_per_client_fairness() {
+        let mut runtime = Runtime::new().expect("failed to create runtime");
+        let limiter = limiter(2, 1, 10_000);
+
+        let a1 = LoadLimiter::acquire(&limiter, "a")
+            .wait()
+            .expect("first work of a is admitted");
+        let a2 = LoadLimiter::acquire(&limiter, "a");
+        assert_eq!(queued(&limiter), 1);
+
+        // a is at its limit, that doesn't hold back b
+        let b1 = LoadLimiter::acquire(&limiter, "b")
+            .wait()
+            .expect("work of b is admitted while a waits");
+        assert_eq!(queued(&limiter), 1);
+
+        // Releasing b doesn't admit a, that is still at its limit
+        drop(b1);
+        assert_eq!(queued(&limiter), 1);
+
+        drop(a1);
+        let _a2 = runtime
+            .block_on(a2)
+            .expect("a is admitted once its first work is done");
+        assert_eq!(queued(&limiter), 0);
+    }
+
+    #[test]
+    fn test_queue_timeout() {
+        let mut runtime = Runtime::new().expect("failed to create runtime");
+        let limiter = limiter(1, 1, 10);
+
+        let first = LoadLimiter::acquire(&limiter, "a")
+            .wait()
+            .expect("first work is admitted");
+        let err = runtime
+            .block_on(LoadLimiter::acquire(&limiter, "b"))
+            .err()
+            .expect("waiting work is shed");
+        match err.downcast::<ErrorKind>() {
+            Ok(ErrorKind::Overloaded(kind)) => assert_eq!(kind, "expensive commands"),
+            bad => panic!("unexpected error {:?}", bad),
+        }
+
+        // The shed waiter is dropped from the queue and doesn't take the released permit
+        drop(first);
+        assert_eq!(queued(&limiter), 0);
+        let state = limiter.state.lock().expect("lock poisoned");
+        assert_eq!(state.total, 0);
+    }
+}
@@ -4,18 +4,22 @@
 // This software may be used and distributed according to the terms of the
 // GNU General Public License version 2 or any later version.
 
+use std::collections::{HashMap, HashSet};
 use std::fmt::{self, Debug};
 use std::path::Path;
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
 use std::time::Duration;
 
+use bytes::Bytes;
 use rand::Isaac64Rng;
 use rand::distributions::{Distribution, LogNormal};
 use slog::Logger;
 
 use blobrepo::BlobRepo;
-use mercurial_types::RepositoryId;
-use metaconfig::repoconfig::RepoType;
+use hooks::{BlobRepoChangesetStore, HookManager};
+use hooks::hook_loader::load_hooks;
+use mercurial_types::{HgChangesetId, HgNodeHash, NarrowSpec, RepositoryId};
+use metaconfig::repoconfig::{AclParams, RepoConfig, RepoType};
 
 use errors::*;
 
@@ -24,19 +28,64 @@ struct LogNormalGenerator {
     distribution: LogNormal,
 }
 
+// Limits of the cache of file hook results
+const HOOK_CACHE_ENTRY_LIMIT: usize = 1024;
+const HOOK_CACHE_WEIGHT_LIMIT: usize = 1024 * 1024;
+
+// Number of clients whose narrowspecs are remembered
+const NARROWSPEC_CLIENT_LIMIT: usize = 10000;
+
 pub struct MononokeRepo {
     path: String,
     blobrepo: Arc<BlobRepo>,
+    hook_manager: RwLock<Arc<HookManager>>,
+    // Changeset of the newest streaming clone artifact in the blobstore, if there is one
+    streaming_clone: RwLock<Option<HgChangesetId>>,
+    // Changeset of the newest clone bundle in the blobstore and the manifest that advertises it
+    clone_bundle: RwLock<Option<(HgChangesetId, Bytes)>>,
+    // Branchmap computed for the last seen set of heads, and the sorted heads themselves
+    branchmap: RwLock<Option<(Vec<HgNodeHash>, HashMap<String, HashSet<HgNodeHash>>)>>,
+    // Narrowspecs that clients last sent with getbundle or gettreepack, by client id
+    narrowspecs: RwLock<HashMap<String, Arc<NarrowSpec>>>,
+    delta_threshold: usize,
+    compress_responses: bool,
+    acl: RwLock<Option<AclParams>>,
 }
 
 impl MononokeRepo {
-    pub fn new(logger: Logger, repo: &RepoType, repoid: RepositoryId) -> Result<Self> {
+    pub fn new(logger: Logger, reponame: String, config: &RepoConfig) -> Result<Self> {
+        let repo = &config.repotype;
+        let blobrepo = Arc::new(repo.open(logger, RepositoryId::new(config.repoid))?);
+        let hook_manager = new_hook_manager(blobrepo.clone(), reponame, config)?;
         Ok(MononokeRepo {
             path: format!("{}", repo.path().to_owned().display()),
-            blobrepo: Arc::new(repo.open(logger, repoid)?),
+            blobrepo,
+            hook_manager: RwLock::new(hook_manager),
+            streaming_clone: RwLock::new(None),
+            clone_bundle: RwLock::new(None),
+            branchmap: RwLock::new(None),
+            narrowspecs: RwLock::new(HashMap::new()),
+            delta_threshold: config.delta_threshold,
+            compress_responses: config.compress_responses,
+            acl: RwLock::new(config.acl.clone()),
         })
     }
 
+    /// Build a hook manager for this repo from a (possibly updated) config. Used to pick up hook
+    /// changes without reopening the repo.
+    pub fn create_hook_manager(
+        &self,
+        reponame: String,
+        config: &RepoConfig,
+    ) -> Result<Arc<HookManager>> {
+        new_hook_manager(self.blobrepo.clone(), reponame, config)
+    }
+
+    /// Replace the hook manager. Pushes that are already in progress keep using the old one.
+    pub fn set_hook_manager(&self, hook_manager: Arc<HookManager>) {
+        *self.hook_manager.write().expect("lock poisoned") = hook_manager;
+    }
+
     pub fn path(&self) -> &String {
         &self.path
     }
@@ -44,6 +93,142 @@ impl MononokeRepo {
     pub fn blobrepo(&self) -> Arc<BlobRepo> {
         self.blobrepo.clone()
     }
+
+    /// Maximum size of a delta sent to clients, in percent of the size of the fulltext
+    pub fn delta_threshold(&self) -> usize {
+        self.delta_threshold
+    }
+
+    /// Whether bundle2 responses may be compressed with an engine the client supports
+    pub fn compress_responses(&self) -> bool {
+        self.compress_responses
+    }
+
+    /// Replace the ACL. Sessions that are already established are only checked against the new
+    /// ACL when they push.
+    pub fn set_acl(&self, acl: Option<AclParams>) {
+        *self.acl.write().expect("lock poisoned") = acl;
+    }
+
+    /// Fails with `ErrorKind::AccessDenied` unless `identity` may read from this repo
+    pub fn check_read_access(&self, identity: Option<&str>) -> Result<()> {
+        match *self.acl.read().expect("lock poisoned") {
+            Some(ref acl) if !acl.can_read(identity) => Err(access_denied(identity, "read")),
+            _ => Ok(()),
+        }
+    }
+
+    /// Fails with `ErrorKind::AccessDenied` unless `identity` may push to this repo
+    pub fn check_write_access(&self, identity: Option<&str>) -> Result<()> {
+        match *self.acl.read().expect("lock poisoned") {
+            Some(ref acl) if !acl.can_write(identity) => Err(access_denied(identity, "push to")),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn hook_manager(&self) -> Arc<HookManager> {
+        self.hook_manager.read().expect("lock poisoned").clone()
+    }
+
+    /// Changeset of the streaming clone artifact that is served to clients
+    pub fn streaming_clone(&self) -> Option<HgChangesetId> {
+        *self.streaming_clone.read().expect("lock poisoned")
+    }
+
+    /// Start serving the streaming clone artifact built at `changesetid`. The artifact has to be
+    /// in the blobstore already.
+    pub fn set_streaming_clone(&self, changesetid: HgChangesetId) {
+        *self.streaming_clone.write().expect("lock poisoned") = Some(changesetid);
+    }
+
+    /// Changeset of the clone bundle that is advertised to clients
+    pub fn clone_bundle(&self) -> Option<HgChangesetId> {
+        self.clone_bundle
+            .read()
+            .expect("lock poisoned")
+            .as_ref()
+            .map(|&(changesetid, _)| changesetid)
+    }
+
+    /// Contents of the clone bundles manifest, empty if there is no clone bundle to advertise
+    pub fn clone_bundles_manifest(&self) -> Bytes {
+        self.clone_bundle
+            .read()
+            .expect("lock poisoned")
+            .as_ref()
+            .map(|&(_, ref manifest)| manifest.clone())
+            .unwrap_or_default()
+    }
+
+    /// Start advertising the clone bundle generated at `changesetid` with `manifest`
+    pub fn set_clone_bundle(&self, changesetid: HgChangesetId, manifest: Bytes) {
+        *self.clone_bundle.write().expect("lock poisoned") = Some((changesetid, manifest));
+    }
+
+    /// Branchmap computed for exactly the sorted set of `heads`, if it was cached
+    pub fn cached_branchmap(
+        &self,
+        heads: &[HgNodeHash],
+    ) -> Option<HashMap<String, HashSet<HgNodeHash>>> {
+        match *self.branchmap.read().expect("lock poisoned") {
+            Some((ref cached_heads, ref branchmap)) if cached_heads.as_slice() == heads => {
+                Some(branchmap.clone())
+            }
+            _ => None,
+        }
+    }
+
+    /// Cache the branchmap computed for the sorted set of `heads`, replacing the previous one
+    pub fn set_branchmap(
+        &self,
+        heads: Vec<HgNodeHash>,
+        branchmap: HashMap<String, HashSet<HgNodeHash>>,
+    ) {
+        *self.branchmap.write().expect("lock poisoned") = Some((heads, branchmap));
+    }
+
+    /// Narrowspec that `client` last sent, the whole repo if it didn't send one
+    pub fn narrowspec(&self, client: &str) -> Arc<NarrowSpec> {
+        self.narrowspecs
+            .read()
+            .expect("lock poisoned")
+            .get(client)
+            .cloned()
+            .unwrap_or_else(|| Arc::new(NarrowSpec::everything()))
+    }
+
+    /// Remember the narrowspec of `client` for the commands that don't send their patterns, f.e.
+    /// getfiles. Some other client is forgotten if too many are remembered already.
+    pub fn set_narrowspec(&self, client: &str, narrowspec: Arc<NarrowSpec>) {
+        let mut narrowspecs = self.narrowspecs.write().expect("lock poisoned");
+        if narrowspecs.len() >= NARROWSPEC_CLIENT_LIMIT && !narrowspecs.contains_key(client) {
+            let forgotten = narrowspecs.keys().next().cloned();
+            if let Some(forgotten) = forgotten {
+                narrowspecs.remove(&forgotten);
+            }
+        }
+        narrowspecs.insert(client.to_owned(), narrowspec);
+    }
+}
+
+fn access_denied(identity: Option<&str>, access: &'static str) -> Error {
+    let identity = identity.unwrap_or("unauthenticated client").to_string();
+    ErrorKind::AccessDenied(identity, access).into()
+}
+
+fn new_hook_manager(
+    blobrepo: Arc<BlobRepo>,
+    reponame: String,
+    config: &RepoConfig,
+) -> Result<Arc<HookManager>> {
+    let mut hook_manager = HookManager::new(
+        reponame,
+        Box::new(BlobRepoChangesetStore::new((*blobrepo).clone())),
+        HOOK_CACHE_ENTRY_LIMIT,
+        HOOK_CACHE_WEIGHT_LIMIT,
+    );
+    load_hooks(&mut hook_manager, config.clone())?;
+    Ok(Arc::new(hook_manager))
 }
 
 impl Debug for MononokeRepo {
@@ -65,6 +250,8 @@ impl OpenableRepoType for RepoType {
         let ret = match *self {
             Revlog(_) => Err(ErrorKind::CantServeRevlogRepo)?,
             BlobRocks(ref path) => BlobRepo::new_rocksdb(logger, &path, repoid)?,
+            BlobRocksDurable(ref path) => BlobRepo::new_rocksdb_durable(logger, &path, repoid)?,
+            BlobFiles(ref path) => BlobRepo::new_files(logger, &path, repoid)?,
             BlobManifold { ref args, .. } => BlobRepo::new_manifold(logger, args, repoid)?,
             TestBlobDelayRocks(ref path, mean, stddev) => {
                 // We take in an arithmetic mean and stddev, and deduce a log normal
@@ -115,7 +302,10 @@ impl OpenableRepoType for RepoType {
         use metaconfig::repoconfig::RepoType::*;
 
         match *self {
-            Revlog(ref path) | BlobRocks(ref path) => path.as_ref(),
+            Revlog(ref path)
+            | BlobRocks(ref path)
+            | BlobRocksDurable(ref path)
+            | BlobFiles(ref path) => path.as_ref(),
             BlobManifold { ref path, .. } => path.as_ref(),
             TestBlobDelayRocks(ref path, ..) => path.as_ref(),
         }
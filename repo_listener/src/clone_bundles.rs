@@ -0,0 +1,83 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use failure::SlogKVError;
+use futures::{future, stream, Future, Stream};
+use futures_ext::{BoxFuture, FutureExt};
+use slog::Logger;
+use tokio;
+
+use metaconfig::repoconfig::CloneBundlesParams;
+use repo_client::{clone_bundles_manifest, find_clone_bundle};
+
+use errors::*;
+use repo_handlers::{RepoHandler, RepoHandlers};
+
+/// How often the repos are checked for a clone bundle newer than the advertised one
+const TICK_SECS: u64 = 60;
+
+/// Keep the clone bundles advertised by all repos that have them configured up to date. The
+/// bundles themselves are generated by a separate tool, this only looks for the newest one.
+pub fn refresh_clone_bundles(
+    root_log: Logger,
+    handlers: Arc<RepoHandlers>,
+) -> impl Future<Item = (), Error = ()> {
+    let tick = Duration::from_secs(TICK_SECS);
+
+    tokio::timer::Interval::new(Instant::now(), tick)
+        .map_err(Error::from)
+        .for_each({
+            cloned!(root_log);
+            move |_| {
+                cloned!(root_log);
+                let repos = handlers.clone_bundles_repos();
+                stream::iter_ok(repos).for_each(move |(reponame, params, handler)| {
+                    cloned!(root_log);
+                    refresh_repo(params, handler).then(move |res| {
+                        if let Err(err) = res {
+                            error!(
+                                root_log,
+                                "Failed to refresh clone bundles of repo {}", reponame;
+                                SlogKVError(err),
+                            );
+                        }
+                        Ok(())
+                    })
+                })
+            }
+        })
+        .map_err(move |err| error!(root_log, "Clone bundles refresh stopped"; SlogKVError(err)))
+}
+
+fn refresh_repo(params: CloneBundlesParams, handler: RepoHandler) -> BoxFuture<(), Error> {
+    let (logger, _, repo) = handler;
+    let blobrepo = repo.blobrepo();
+
+    let known = repo.clone_bundle();
+
+    blobrepo
+        .get_bookmark(&params.bookmark)
+        .and_then(move |changesetid| match changesetid {
+            Some(changesetid) => find_clone_bundle(blobrepo, changesetid, known)
+                .map(move |found| {
+                    if let Some(changesetid) = found {
+                        info!(logger, "Advertising clone bundle at {}", changesetid);
+                    }
+                    // The manifest is rebuilt even if the bundle didn't change, to pick up
+                    // changes of the base URL
+                    if let Some(changesetid) = found.or(known) {
+                        let manifest = clone_bundles_manifest(&params.base_url, changesetid);
+                        repo.set_clone_bundle(changesetid, manifest);
+                    }
+                })
+                .boxify(),
+            None => future::ok(()).boxify(),
+        })
+        .boxify()
+}
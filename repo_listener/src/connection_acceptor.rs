@@ -4,27 +4,32 @@
 // This software may be used and distributed according to the terms of the
 // GNU General Public License version 2 or any later version.
 
-use std::collections::HashMap;
 use std::io;
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::sync::Arc;
 
 use failure::SlogKVError;
 use futures::{future, Future, IntoFuture, Stream};
 use futures::sync::mpsc;
 use futures_ext::{BoxFuture, FutureExt, StreamExt};
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use openssl::nid::Nid;
 use openssl::ssl::SslAcceptor;
 use slog::Logger;
 use tokio;
 use tokio::net::{TcpListener, TcpStream};
 use tokio_codec::{FramedRead, FramedWrite};
 use tokio_io::{AsyncRead, AsyncWrite, IoStream};
-use tokio_openssl::SslAcceptorExt;
+use tokio_openssl::{SslAcceptorExt, SslStream};
 
 use sshrelay::{SshDecoder, SshEncoder, SshMsg, SshStream, Stdio};
 
+use Limiters;
 use errors::*;
-use repo_handlers::RepoHandler;
+use http_request_handler::http_request_handler;
+use repo_handlers::RepoHandlers;
 use request_handler::request_handler;
 
 /// This function accepts connections, reads Preamble and routes request to a thread responsible for
@@ -32,20 +37,26 @@ use request_handler::request_handler;
 pub fn connection_acceptor(
     sockname: String,
     root_log: Logger,
-    repo_handlers: HashMap<String, RepoHandler>,
-    tls_acceptor: SslAcceptor,
+    repo_handlers: Arc<RepoHandlers>,
+    tls_acceptor: Arc<SslAcceptor>,
+    limiters: Limiters,
+    record_dir: Option<PathBuf>,
 ) -> BoxFuture<(), Error> {
-    let repo_handlers = Arc::new(repo_handlers);
-    let tls_acceptor = Arc::new(tls_acceptor);
-
     listener(sockname)
         .expect("failed to create listener")
         .map_err(Error::from)
         .for_each(move |sock| {
             // Accept the request without blocking the listener
-            cloned!(root_log, repo_handlers, tls_acceptor);
+            cloned!(root_log, repo_handlers, tls_acceptor, limiters, record_dir);
             tokio::spawn(future::lazy(move || {
-                accept(sock, root_log, repo_handlers, tls_acceptor)
+                accept(
+                    sock,
+                    root_log,
+                    repo_handlers,
+                    tls_acceptor,
+                    limiters,
+                    record_dir,
+                )
             }));
             Ok(())
         })
@@ -55,8 +66,10 @@ pub fn connection_acceptor(
 fn accept(
     sock: TcpStream,
     root_log: Logger,
-    repo_handlers: Arc<HashMap<String, RepoHandler>>,
+    repo_handlers: Arc<RepoHandlers>,
     tls_acceptor: Arc<SslAcceptor>,
+    limiters: Limiters,
+    record_dir: Option<PathBuf>,
 ) -> impl Future<Item = (), Error = ()> {
     let addr = sock.peer_addr();
 
@@ -75,13 +88,16 @@ fn accept(
         .and_then({
             cloned!(root_log);
             move |sock| {
-                ssh_server_mux(sock).map_err(move |err| {
-                    error!(
-                        root_log,
-                        "Error while reading preamble";
-                        SlogKVError(Error::from(err)),
-                    )
-                })
+                let tls_identity = peer_identity(&sock);
+                ssh_server_mux(sock)
+                    .map(move |stdio| (stdio, tls_identity))
+                    .map_err(move |err| {
+                        error!(
+                            root_log,
+                            "Error while reading preamble";
+                            SlogKVError(Error::from(err)),
+                        )
+                    })
             }
         })
         .join(addr.into_future().map_err({
@@ -93,16 +109,85 @@ fn accept(
                 )
             }
         }))
-        .and_then(move |(stdio, addr)| {
+        .and_then(move |((stdio, tls_identity), addr)| {
             repo_handlers
                 .get(&stdio.preamble.reponame)
-                .cloned()
                 .ok_or_else(|| error!(root_log, "Unknown repo: {}", stdio.preamble.reponame))
                 .into_future()
-                .and_then(move |handler| request_handler(handler.clone(), stdio, addr))
+                .and_then(move |handler| {
+                    request_handler(handler, stdio, addr, limiters, tls_identity, record_dir)
+                })
+        })
+}
+
+/// Accept connections of clients that use the HTTP protocol of Mercurial. The repo is chosen by
+/// the path of every request, see `http_request_handler`.
+pub fn http_connection_acceptor(
+    sockname: String,
+    root_log: Logger,
+    repo_handlers: Arc<RepoHandlers>,
+    tls_acceptor: Arc<SslAcceptor>,
+    limiters: Limiters,
+) -> BoxFuture<(), Error> {
+    listener(sockname)
+        .expect("failed to create http listener")
+        .map_err(Error::from)
+        .for_each(move |sock| {
+            cloned!(root_log, repo_handlers, tls_acceptor, limiters);
+            tokio::spawn(future::lazy(move || {
+                http_accept(sock, root_log, repo_handlers, tls_acceptor, limiters)
+            }));
+            Ok(())
+        })
+        .boxify()
+}
+
+fn http_accept(
+    sock: TcpStream,
+    root_log: Logger,
+    repo_handlers: Arc<RepoHandlers>,
+    tls_acceptor: Arc<SslAcceptor>,
+    limiters: Limiters,
+) -> impl Future<Item = (), Error = ()> {
+    let addr = sock.peer_addr();
+
+    tls_acceptor
+        .accept_async(sock)
+        .map_err(Error::from)
+        .join(addr.into_future().map_err(Error::from))
+        .and_then(move |(sock, addr)| {
+            let tls_identity = peer_identity(&sock);
+            let service = service_fn(move |req| {
+                http_request_handler(
+                    repo_handlers.clone(),
+                    req,
+                    addr,
+                    limiters.clone(),
+                    tls_identity.clone(),
+                )
+            });
+            Http::new()
+                .serve_connection(sock, service)
+                .map_err(Error::from)
+        })
+        .map_err(move |err| {
+            error!(root_log, "Error while serving http connection"; SlogKVError(err))
         })
 }
 
+/// Identity of the client that ACLs are checked against: the common name of the subject of its
+/// TLS certificate
+fn peer_identity<S>(sock: &SslStream<S>) -> Option<String> {
+    let cert = sock.get_ref().ssl().peer_certificate()?;
+    let common_name = cert.subject_name()
+        .entries_by_nid(Nid::COMMONNAME)
+        .next()?
+        .data()
+        .as_utf8()
+        .ok()?;
+    Some(common_name.to_string())
+}
+
 fn listener<P>(sockname: P) -> io::Result<IoStream<TcpStream>>
 where
     P: AsRef<str>,
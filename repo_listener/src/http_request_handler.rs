@@ -0,0 +1,170 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::io;
+use std::mem;
+use std::net::SocketAddr;
+use std::ops::DerefMut;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use bytes::Bytes;
+use failure::SlogKVError;
+use futures::{future, Future, Stream};
+use futures_ext::{BoxFuture, FutureExt, StreamExt};
+use futures_stats::TimedStreamTrait;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+use tracing::TraceContext;
+use uuid::Uuid;
+
+use hgproto::{httpproto, HgProtoHandler};
+use repo_client::{LoadLimiter, RepoClient};
+use scuba_ext::ScubaSampleBuilderExt;
+
+use Limiters;
+use repo_handlers::RepoHandlers;
+
+/// Serve a single Mercurial command sent over HTTP to `/<reponame>?cmd=<command>`. Unlike SSH
+/// connections that serve many commands, every HTTP request is a session on its own.
+pub fn http_request_handler(
+    repo_handlers: Arc<RepoHandlers>,
+    req: Request<Body>,
+    addr: SocketAddr,
+    limiters: Limiters,
+    tls_identity: Option<String>,
+) -> BoxFuture<Response<Body>, io::Error> {
+    let reponame = req.uri().path().trim_matches('/').to_owned();
+    let (logger, mut scuba_logger, repo) = match repo_handlers.get(&reponame) {
+        Some(handler) => handler,
+        None => {
+            return future::ok(error_response(
+                StatusCode::NOT_FOUND,
+                format!("unknown repo: {}", reponame),
+            )).boxify()
+        }
+    };
+    if *req.method() != Method::GET && *req.method() != Method::POST {
+        return future::ok(error_response(
+            StatusCode::METHOD_NOT_ALLOWED,
+            format!("unsupported method: {}", req.method()),
+        )).boxify();
+    }
+    if let Err(err) = repo.check_read_access(tls_identity.as_ref().map(String::as_str)) {
+        let msg = format!("{}", err);
+        info!(logger, "Request rejected"; SlogKVError(err));
+        return future::ok(error_response(StatusCode::FORBIDDEN, msg)).boxify();
+    }
+
+    let session_uuid = Uuid::new_v4();
+    let conn_log = logger.new(o!("session_uuid" => format!("{}", session_uuid)));
+    scuba_logger
+        .add("session_uuid", format!("{}", session_uuid))
+        .add("client_ip", format!("{}", addr.ip()))
+        .add("protocol", "http");
+    if let Some(ref tls_identity) = tls_identity {
+        scuba_logger.add("tls_identity", tls_identity.clone());
+    }
+
+    let query = req.uri().query().unwrap_or("").to_owned();
+    let (parts, body) = req.into_parts();
+    let body = body.map(|chunk| chunk.into_bytes())
+        .map_err(|err| io::Error::new(io::ErrorKind::Other, err));
+    let request = httpproto::decode_request(
+        &query,
+        |name| {
+            parts
+                .headers
+                .get(name)
+                .map(|value| Bytes::from(value.as_bytes()))
+        },
+        body,
+    );
+
+    // Clients are limited by their TLS identity if they have one, otherwise by address
+    let client_id = tls_identity
+        .clone()
+        .unwrap_or_else(|| addr.ip().to_string());
+    let session = LoadLimiter::acquire(&limiters.sessions, &client_id);
+
+    request
+        .join(session.then(|permit| Ok::<_, failure::Error>(permit)))
+        .then(move |res| {
+            let (request, permit) = match res {
+                Ok(res) => res,
+                Err(err) => {
+                    let msg = format!("{}", err);
+                    info!(conn_log, "Invalid HTTP request"; SlogKVError(err));
+                    return Ok(error_response(StatusCode::BAD_REQUEST, msg));
+                }
+            };
+            let permit = match permit {
+                Ok(permit) => permit,
+                Err(err) => {
+                    let msg = format!("{}", err);
+                    info!(conn_log, "Request shed"; SlogKVError(err));
+                    return Ok(error_response(StatusCode::SERVICE_UNAVAILABLE, msg));
+                }
+            };
+            debug!(conn_log, "HTTP command {}", request.command);
+
+            let wireproto_calls = Arc::new(Mutex::new(Vec::new()));
+            let trace = TraceContext::new(session_uuid, Instant::now());
+            let client = RepoClient::new(
+                repo,
+                conn_log.clone(),
+                scuba_logger.clone(),
+                trace,
+                None,
+                tls_identity,
+                client_id,
+                limiters.commands,
+            );
+            // There is no protocaps command over HTTP, the capabilities come with every request
+            client.set_protocaps(&request.protocaps);
+            let proto_handler = HgProtoHandler::new(
+                request.input,
+                client,
+                httpproto::HgHttpCommandDecode,
+                httpproto::HgHttpCommandEncode::new(request.media_type),
+                &conn_log,
+                wireproto_calls.clone(),
+            );
+
+            let body = permit
+                .hold_during(proto_handler.boxify())
+                .map_err({
+                    let conn_log = conn_log.clone();
+                    move |err| {
+                        error!(conn_log, "Command failed: {}", err);
+                        err.compat()
+                    }
+                })
+                .timed(move |stats, _| {
+                    let mut wireproto_calls = wireproto_calls.lock().expect("lock poisoned");
+                    let wireproto_calls = mem::replace(wireproto_calls.deref_mut(), Vec::new());
+
+                    scuba_logger
+                        .add_stats(&stats)
+                        .add("wireproto_commands", wireproto_calls)
+                        .log_with_msg("Request finished", None)
+                });
+
+            let mut response = Response::new(Body::wrap_stream(body));
+            response.headers_mut().insert(
+                CONTENT_TYPE,
+                HeaderValue::from_static(request.media_type.content_type()),
+            );
+            Ok::<_, io::Error>(response)
+        })
+        .boxify()
+}
+
+fn error_response(status: StatusCode, msg: String) -> Response<Body> {
+    let mut response = Response::new(Body::from(msg));
+    *response.status_mut() = status;
+    response
+}
@@ -10,12 +10,14 @@
 
 #[macro_use]
 extern crate cloned;
+extern crate bytes;
 extern crate dns_lookup;
 #[macro_use]
 extern crate failure_ext as failure;
 extern crate futures;
 extern crate futures_ext;
 extern crate futures_stats;
+extern crate hyper;
 #[macro_use]
 extern crate maplit;
 extern crate openssl;
@@ -39,39 +41,130 @@ extern crate repo_client;
 extern crate scuba_ext;
 extern crate sshrelay;
 
+mod clone_bundles;
 mod connection_acceptor;
 mod errors;
+mod http_request_handler;
 mod request_handler;
 mod repo_handlers;
+mod session_recorder;
+mod streaming_clone;
 
-use futures::Future;
-use futures_ext::{BoxFuture, FutureExt};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use failure::SlogKVError;
+use futures::{future, Future, Stream};
+use futures_ext::{BoxFuture, BoxStream, FutureExt};
 use openssl::ssl::SslAcceptor;
 use slog::Logger;
 
+use metaconfig::RepoConfigs;
 use metaconfig::repoconfig::RepoConfig;
+use repo_client::{LoadKind, LoadLimiter};
+
+pub use repo_client::LoadLimits;
 
-use connection_acceptor::connection_acceptor;
+use clone_bundles::refresh_clone_bundles;
+use connection_acceptor::{connection_acceptor, http_connection_acceptor};
 use errors::*;
-use repo_handlers::repo_handlers;
+use repo_handlers::{repo_handlers, RepoHandlers};
+use streaming_clone::refresh_streaming_clones;
+
+/// Admission control shared by all connections of the listeners
+#[derive(Clone)]
+struct Limiters {
+    sessions: Arc<LoadLimiter>,
+    commands: Arc<LoadLimiter>,
+}
 
+/// Serve the repos over SSH on `sockname`, and over HTTP on `http_sockname` if it's set.
+/// Concurrent sessions and expensive commands are limited by `session_limits` and
+/// `command_limits`. If `record_dir` is set, the requests of every SSH session are recorded into
+/// a file in it, to be replayed later.
 pub fn create_repo_listeners<I>(
     repos: I,
     root_log: &Logger,
     sockname: &str,
+    http_sockname: Option<&str>,
     tls_acceptor: SslAcceptor,
+    config_updates: BoxStream<RepoConfigs, Error>,
+    session_limits: LoadLimits,
+    command_limits: LoadLimits,
+    record_dir: Option<PathBuf>,
 ) -> (BoxFuture<(), Error>, ready_state::ReadyState)
 where
     I: IntoIterator<Item = (String, RepoConfig)>,
 {
     let sockname = String::from(sockname);
+    let http_sockname = http_sockname.map(String::from);
     let root_log = root_log.clone();
+    let tls_acceptor = Arc::new(tls_acceptor);
+    let limiters = Limiters {
+        sessions: LoadLimiter::new(LoadKind::Sessions, session_limits),
+        commands: LoadLimiter::new(LoadKind::Commands, command_limits),
+    };
     let mut ready = ready_state::ReadyStateBuilder::new();
 
     (
         repo_handlers(repos, &root_log, &mut ready)
-            .and_then(move |handles| connection_acceptor(sockname, root_log, handles, tls_acceptor))
+            .and_then(move |handlers| {
+                tokio::spawn(apply_config_updates(
+                    root_log.clone(),
+                    handlers.clone(),
+                    config_updates,
+                ));
+                tokio::spawn(refresh_streaming_clones(root_log.clone(), handlers.clone()));
+                tokio::spawn(refresh_clone_bundles(root_log.clone(), handlers.clone()));
+                let http_listener = match http_sockname {
+                    Some(http_sockname) => http_connection_acceptor(
+                        http_sockname,
+                        root_log.clone(),
+                        handlers.clone(),
+                        tls_acceptor.clone(),
+                        limiters.clone(),
+                    ),
+                    None => future::ok(()).boxify(),
+                };
+                connection_acceptor(
+                    sockname,
+                    root_log,
+                    handlers,
+                    tls_acceptor,
+                    limiters,
+                    record_dir,
+                ).join(http_listener)
+                    .map(|((), ())| ())
+            })
             .boxify(),
         ready.freeze(),
     )
 }
+
+/// Reload the repos every time a new config arrives. A config that can't be applied is logged and
+/// the repos keep running with the previous one.
+fn apply_config_updates(
+    root_log: Logger,
+    handlers: Arc<RepoHandlers>,
+    config_updates: BoxStream<RepoConfigs, Error>,
+) -> impl Future<Item = (), Error = ()> {
+    config_updates
+        .for_each({
+            cloned!(root_log);
+            move |config| {
+                cloned!(root_log);
+                RepoHandlers::reload(handlers.clone(), config.repos).then(move |res| {
+                    match res {
+                        Ok(()) => info!(root_log, "Applied new repo configs"),
+                        Err(err) => error!(
+                            root_log,
+                            "Failed to apply new repo configs, keeping the current ones";
+                            SlogKVError(err),
+                        ),
+                    }
+                    Ok(())
+                })
+            }
+        })
+        .map_err(move |err| error!(root_log, "Config updates stopped"; SlogKVError(err)))
+}
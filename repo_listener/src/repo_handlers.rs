@@ -5,7 +5,7 @@
 // GNU General Public License version 2 or any later version.
 
 use std::collections::HashMap;
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
 
 use failure::prelude::*;
 use futures::{future, Future};
@@ -13,24 +13,168 @@ use futures_ext::{BoxFuture, FutureExt};
 use slog::Logger;
 
 use cache_warmup::cache_warmup;
-use mercurial_types::RepositoryId;
-use metaconfig::repoconfig::RepoConfig;
+use metaconfig::repoconfig::{CloneBundlesParams, RepoConfig, StreamingCloneParams};
 use ready_state::ReadyStateBuilder;
 use repo_client::MononokeRepo;
 use scuba_ext::{ScubaSampleBuilder, ScubaSampleBuilderExt};
 
 pub type RepoHandler = (Logger, ScubaSampleBuilder, Arc<MononokeRepo>);
 
-pub fn repo_handlers<I>(
-    repos: I,
-    root_log: &Logger,
-    ready: &mut ReadyStateBuilder,
-) -> BoxFuture<HashMap<String, RepoHandler>, Error>
+/// All repos served by the listener, together with the config they were created from.
+/// The set of repos can be replaced with `reload` while the server is running. Connections that
+/// are already established keep the `RepoHandler` they started with, so a repo that is removed
+/// is drained: it stops accepting new connections and is dropped once the last one finishes.
+pub struct RepoHandlers {
+    root_log: Logger,
+    repos: RwLock<HashMap<String, (RepoConfig, RepoHandler)>>,
+}
+
+impl RepoHandlers {
+    pub fn get(&self, reponame: &str) -> Option<RepoHandler> {
+        self.repos
+            .read()
+            .expect("lock poisoned")
+            .get(reponame)
+            .map(|(_, handler)| handler.clone())
+    }
+
+    /// All repos that serve streaming clones, with their streaming clone config
+    pub fn streaming_clone_repos(&self) -> Vec<(String, StreamingCloneParams, RepoHandler)> {
+        self.repos
+            .read()
+            .expect("lock poisoned")
+            .iter()
+            .filter_map(|(reponame, (config, handler))| {
+                config
+                    .streaming_clone
+                    .clone()
+                    .map(|params| (reponame.clone(), params, handler.clone()))
+            })
+            .collect()
+    }
+
+    /// All repos that advertise clone bundles, with their clone bundles config
+    pub fn clone_bundles_repos(&self) -> Vec<(String, CloneBundlesParams, RepoHandler)> {
+        self.repos
+            .read()
+            .expect("lock poisoned")
+            .iter()
+            .filter_map(|(reponame, (config, handler))| {
+                config
+                    .clone_bundles
+                    .clone()
+                    .map(|params| (reponame.clone(), params, handler.clone()))
+            })
+            .collect()
+    }
+
+    /// Apply a new set of repo configs. Repos where only hot-swappable settings changed (see
+    /// `needs_reopen`) are updated in place, other changed and new repos are opened and warmed up
+    /// before they start accepting connections, and repos that are gone or disabled are drained.
+    /// If any repo fails to be set up, the whole new config is rejected and the current one stays
+    /// active.
+    pub fn reload<I>(this: Arc<Self>, repos: I) -> BoxFuture<(), Error>
+    where
+        I: IntoIterator<Item = (String, RepoConfig)>,
+    {
+        let current = this.repos.read().expect("lock poisoned").clone();
+        let root_log = this.root_log.clone();
+
+        let mut updates = vec![];
+        let mut opened = vec![];
+        for (reponame, config) in enabled_repos(repos, &root_log) {
+            match current.get(&reponame) {
+                Some((ref old_config, ref handler)) if *old_config == config => {
+                    updates.push((reponame, config, handler.clone(), None));
+                }
+                Some((ref old_config, ref handler)) if !needs_reopen(old_config, &config) => {
+                    let repo = &handler.2;
+                    let hook_manager = try_boxfuture!(
+                        repo.create_hook_manager(reponame.clone(), &config)
+                            .context(format!("while loading hooks for repo: {}", reponame))
+                    );
+                    if old_config.cache_warmup != config.cache_warmup {
+                        // Warming up is best effort, the repo is serving requests already
+                        let warmup_log = handler.0.clone();
+                        let warmup = cache_warmup(
+                            repo.blobrepo(),
+                            config.cache_warmup.clone(),
+                            warmup_log.clone(),
+                        ).map_err(move |err| {
+                            error!(warmup_log, "Cache warmup failed: {:?}", err);
+                        });
+                        opened.push(warmup.then(|_| Ok::<(), Error>(())).boxify());
+                    }
+                    info!(root_log, "Updating config of repo {}", reponame);
+                    updates.push((reponame, config, handler.clone(), Some(hook_manager)));
+                }
+                current_repo => {
+                    if current_repo.is_some() {
+                        warn!(
+                            root_log,
+                            "Config of repo {} changed settings that can't be updated in place, \
+                             reopening it. Connections that are already established keep the \
+                             old config.",
+                            reponame
+                        );
+                    }
+                    info!(root_log, "Opening repo {}", reponame);
+                    let (handler, warmup) = try_boxfuture!(open_repo(&root_log, &reponame, &config));
+                    opened.push(
+                        warmup
+                            .context(format!("while warming up cache for repo: {}", reponame))
+                            .from_err()
+                            .boxify(),
+                    );
+                    updates.push((reponame, config, handler, None));
+                }
+            }
+        }
+
+        future::join_all(opened)
+            .map(move |_| {
+                let mut repos = this.repos.write().expect("lock poisoned");
+                let mut new_repos = HashMap::new();
+                for (reponame, config, handler, hook_manager) in updates {
+                    if let Some(hook_manager) = hook_manager {
+                        handler.2.set_hook_manager(hook_manager);
+                    }
+                    handler.2.set_acl(config.acl.clone());
+                    new_repos.insert(reponame, (config, handler));
+                }
+                for reponame in repos.keys() {
+                    if !new_repos.contains_key(reponame) {
+                        info!(this.root_log, "Draining repo {}", reponame);
+                    }
+                }
+                *repos = new_repos;
+            })
+            .boxify()
+    }
+}
+
+/// Whether a repo has to be reopened to apply `new` to it. The hooks, the ACL, the cache warmup,
+/// streaming clones and clone bundles are applied to a running repo, all the other settings are
+/// only read when the repo is opened.
+fn needs_reopen(old: &RepoConfig, new: &RepoConfig) -> bool {
+    let hot_swapped = RepoConfig {
+        cache_warmup: old.cache_warmup.clone(),
+        bookmarks: old.bookmarks.clone(),
+        hooks: old.hooks.clone(),
+        hook_libs: old.hook_libs.clone(),
+        streaming_clone: old.streaming_clone.clone(),
+        clone_bundles: old.clone_bundles.clone(),
+        acl: old.acl.clone(),
+        ..new.clone()
+    };
+    *old != hot_swapped
+}
+
+fn enabled_repos<I>(repos: I, root_log: &Logger) -> Vec<(String, RepoConfig)>
 where
     I: IntoIterator<Item = (String, RepoConfig)>,
 {
-    // compute eagerly to avoid lifetime issues
-    let repos: Vec<_> = repos
+    repos
         .into_iter()
         .filter(|(reponame, config)| {
             if !config.enabled {
@@ -38,34 +182,71 @@ where
             };
             config.enabled
         })
-        .map(|(reponame, config)| {
-            info!(root_log, "Start listening for repo {:?}", config.repotype);
-            let ready_handle = ready.create_handle(reponame.as_ref());
+        .collect()
+}
 
-            let repo = MononokeRepo::new(
-                root_log.new(o!("repo" => reponame.clone())),
-                &config.repotype,
-                RepositoryId::new(config.repoid),
-            ).expect(&format!("failed to initialize repo {}", reponame));
+/// Open a repo and return its handler together with a future that warms up its cache
+fn open_repo(
+    root_log: &Logger,
+    reponame: &String,
+    config: &RepoConfig,
+) -> Result<(RepoHandler, BoxFuture<(), Error>)> {
+    info!(root_log, "Start listening for repo {:?}", config.repotype);
+
+    let repo = MononokeRepo::new(
+        root_log.new(o!("repo" => reponame.clone())),
+        reponame.clone(),
+        config,
+    )?;
+
+    let listen_log = root_log.new(o!("repo" => repo.path().clone()));
 
-            let listen_log = root_log.new(o!("repo" => repo.path().clone()));
+    let mut scuba_logger = ScubaSampleBuilder::with_opt_table(config.scuba_table.clone());
+    scuba_logger.add_common_server_data();
 
-            let mut scuba_logger = ScubaSampleBuilder::with_opt_table(config.scuba_table.clone());
-            scuba_logger.add_common_server_data();
+    let repo = Arc::new(repo);
+
+    let warmup = cache_warmup(
+        repo.blobrepo(),
+        config.cache_warmup.clone(),
+        listen_log.clone(),
+    );
+    Ok(((listen_log, scuba_logger, repo), warmup))
+}
+
+pub fn repo_handlers<I>(
+    repos: I,
+    root_log: &Logger,
+    ready: &mut ReadyStateBuilder,
+) -> BoxFuture<Arc<RepoHandlers>, Error>
+where
+    I: IntoIterator<Item = (String, RepoConfig)>,
+{
+    // compute eagerly to avoid lifetime issues
+    let repos: Vec<_> = enabled_repos(repos, root_log)
+        .into_iter()
+        .map(|(reponame, config)| {
+            let ready_handle = ready.create_handle(reponame.as_ref());
 
-            let repo = Arc::new(repo);
+            let (handler, initial_warmup) = open_repo(root_log, &reponame, &config)
+                .expect(&format!("failed to initialize repo {}", reponame));
 
-            let initial_warmup =
-                cache_warmup(repo.blobrepo(), config.cache_warmup, listen_log.clone())
-                    .context(format!("while warming up cache for repo: {}", reponame))
-                    .from_err();
+            let initial_warmup = initial_warmup
+                .context(format!("while warming up cache for repo: {}", reponame))
+                .from_err();
             ready_handle
                 .wait_for(initial_warmup)
-                .map(move |()| (reponame, (listen_log, scuba_logger, repo)))
+                .map(move |()| (reponame, (config, handler)))
         })
         .collect();
 
+    let root_log = root_log.clone();
     future::join_all(repos)
-        .map(|repos| repos.into_iter().collect())
+        .map(move |repos| {
+            Arc::new(RepoHandlers {
+                root_log,
+                repos: RwLock::new(repos.into_iter().collect()),
+            })
+        })
         .boxify()
 }
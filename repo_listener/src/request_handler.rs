@@ -6,13 +6,14 @@
 
 use std::mem;
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::ops::DerefMut;
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
 use dns_lookup::getnameinfo;
 use failure::{SlogKVError, prelude::*};
-use futures::{Future, Sink, Stream};
+use futures::{future, Future, Sink, Stream};
 use futures_stats::Timed;
 use slog::{self, Drain, Level, Logger};
 use slog_kvfilter::KVFilter;
@@ -22,16 +23,21 @@ use tracing::TraceContext;
 use uuid::Uuid;
 
 use hgproto::{sshproto, HgProtoHandler};
-use repo_client::RepoClient;
+use repo_client::{LoadLimiter, RepoClient};
 use scuba_ext::ScubaSampleBuilderExt;
 use sshrelay::{SenderBytesWrite, Stdio};
 
+use Limiters;
 use repo_handlers::RepoHandler;
+use session_recorder::{RecordingDecode, SessionRecorder};
 
 pub fn request_handler(
     (logger, mut scuba_logger, repo): RepoHandler,
     stdio: Stdio,
     addr: SocketAddr,
+    limiters: Limiters,
+    tls_identity: Option<String>,
+    record_dir: Option<PathBuf>,
 ) -> impl Future<Item = (), Error = ()> {
     let Stdio {
         stdin,
@@ -91,26 +97,72 @@ pub fn request_handler(
         scuba_logger
             .add_preamble(&preamble)
             .add("client_hostname", client_hostname);
+        if let Some(ref tls_identity) = tls_identity {
+            scuba_logger.add("tls_identity", tls_identity.clone());
+        }
         scuba_logger
     };
 
     scuba_logger.log_with_msg("Connection established", None);
+    info!(conn_log, "Client identity: {:?}", tls_identity);
+
+    let read_access = repo.check_read_access(tls_identity.as_ref().map(String::as_str));
+
+    let recorder = record_dir.and_then(|record_dir| {
+        match SessionRecorder::new(
+            &record_dir,
+            session_uuid,
+            &preamble.reponame,
+            conn_log.clone(),
+        ) {
+            Ok(recorder) => Some(Arc::new(recorder)),
+            Err(err) => {
+                warn!(conn_log, "Failed to start recording session"; SlogKVError(err));
+                None
+            }
+        }
+    });
+
+    // The pusher that hooks see. The username in the preamble is only used for clients without
+    // a TLS identity, as they can set it to anything.
+    let identity = tls_identity
+        .clone()
+        .or_else(|| preamble.misc.get("unix_username").cloned());
+    // Clients are limited by their TLS identity if they have one, otherwise by address. The
+    // username in the preamble is set by the client, so it can't be trusted to identify it.
+    let client_id = tls_identity
+        .clone()
+        .unwrap_or_else(|| addr.ip().to_string());
 
     // Construct a hg protocol handler
     let proto_handler = HgProtoHandler::new(
         stdin,
-        RepoClient::new(repo.clone(), conn_log.clone(), scuba_logger.clone(), trace),
-        sshproto::HgSshCommandDecode,
+        RepoClient::new(
+            repo.clone(),
+            conn_log.clone(),
+            scuba_logger.clone(),
+            trace,
+            identity,
+            tls_identity,
+            client_id.clone(),
+            limiters.commands.clone(),
+        ),
+        RecordingDecode::new(recorder),
         sshproto::HgSshCommandEncode,
         &conn_log,
         wireproto_calls.clone(),
     );
 
-    // send responses back
-    let endres = proto_handler
-        .map_err(Error::from)
-        .forward(stdout)
-        .map(|_| ());
+    // send responses back once the session is allowed and admitted, a rejected session gets the
+    // error through the remote log
+    let endres = future::result(read_access)
+        .and_then(move |()| LoadLimiter::acquire(&limiters.sessions, &client_id))
+        .and_then(move |permit| {
+            proto_handler
+                .map_err(Error::from)
+                .forward(stdout)
+                .map(move |_| drop(permit))
+        });
 
     // If we got an error at this point, then catch it and print a message
     endres
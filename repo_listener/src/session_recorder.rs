@@ -0,0 +1,97 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime};
+
+use bytes::BytesMut;
+use failure::SlogKVError;
+use slog::Logger;
+use tokio_io::codec::Decoder;
+use uuid::Uuid;
+
+use hgproto::{self, sshproto, Request};
+use hgproto::sshproto::record;
+
+use errors::*;
+
+/// Writes the requests of a session to a session record file, see `hgproto::sshproto::record`.
+/// Recording stops at the first write error, the session itself carries on. Writes are buffered,
+/// so that decoding a request rarely waits for the disk, and flushed when the session ends.
+pub struct SessionRecorder {
+    start: Instant,
+    file: Mutex<Option<BufWriter<File>>>,
+    logger: Logger,
+}
+
+impl SessionRecorder {
+    /// Start recording a session of `reponame` into `<dir>/<session_uuid>.rec`
+    pub fn new(dir: &Path, session_uuid: Uuid, reponame: &str, logger: Logger) -> Result<Self> {
+        let mut file = BufWriter::new(File::create(dir.join(format!("{}.rec", session_uuid)))?);
+        file.write_all(&record::encode_header(reponame, SystemTime::now()))?;
+        Ok(SessionRecorder {
+            start: Instant::now(),
+            file: Mutex::new(Some(file)),
+            logger,
+        })
+    }
+
+    fn record(&self, request: &Request) {
+        let mut file = self.file.lock().expect("lock poisoned");
+        let res = match *file {
+            Some(ref mut file) => {
+                file.write_all(&record::encode_recorded_request(self.start.elapsed(), request))
+            }
+            None => return,
+        };
+        if let Err(err) = res {
+            warn!(self.logger, "Failed to record session, recording stopped";
+                SlogKVError(Error::from(err)));
+            *file = None;
+        }
+    }
+}
+
+impl Drop for SessionRecorder {
+    fn drop(&mut self) {
+        let file = self.file.get_mut().expect("lock poisoned");
+        if let Some(ref mut file) = *file {
+            if let Err(err) = file.flush() {
+                warn!(self.logger, "Failed to record session";
+                    SlogKVError(Error::from(err)));
+            }
+        }
+    }
+}
+
+/// Decodes requests like `HgSshCommandDecode`, and records every decoded request if there is a
+/// recorder
+#[derive(Clone)]
+pub struct RecordingDecode {
+    recorder: Option<Arc<SessionRecorder>>,
+}
+
+impl RecordingDecode {
+    pub fn new(recorder: Option<Arc<SessionRecorder>>) -> Self {
+        RecordingDecode { recorder }
+    }
+}
+
+impl Decoder for RecordingDecode {
+    type Item = Request;
+    type Error = hgproto::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> hgproto::Result<Option<Request>> {
+        let request = sshproto::HgSshCommandDecode.decode(buf)?;
+        if let (Some(recorder), Some(request)) = (self.recorder.as_ref(), request.as_ref()) {
+            recorder.record(request);
+        }
+        Ok(request)
+    }
+}
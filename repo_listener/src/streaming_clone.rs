@@ -0,0 +1,96 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use failure::SlogKVError;
+use futures::{future, stream, Future, Stream};
+use futures_ext::{BoxFuture, FutureExt};
+use slog::Logger;
+use tokio;
+
+use metaconfig::repoconfig::StreamingCloneParams;
+use repo_client::build_streaming_clone;
+
+use errors::*;
+use repo_handlers::{RepoHandler, RepoHandlers};
+
+/// How often the repos are checked for a streaming clone artifact that is due to be rebuilt
+const TICK_SECS: u64 = 60;
+
+/// Keep the streaming clone artifacts of all repos that have them configured up to date. The
+/// artifact of a repo is rebuilt at most once per its `refresh_interval_secs`, and only if its
+/// bookmark moved. Artifacts are built one at a time, as building one reads the whole history of
+/// the repo.
+pub fn refresh_streaming_clones(
+    root_log: Logger,
+    handlers: Arc<RepoHandlers>,
+) -> impl Future<Item = (), Error = ()> {
+    let mut last_refresh: HashMap<String, Instant> = HashMap::new();
+    let tick = Duration::from_secs(TICK_SECS);
+
+    tokio::timer::Interval::new(Instant::now(), tick)
+        .map_err(Error::from)
+        .map(move |now| {
+            let due: Vec<_> = handlers
+                .streaming_clone_repos()
+                .into_iter()
+                .filter(|&(ref reponame, ref params, _)| {
+                    let interval = Duration::from_secs(params.refresh_interval_secs);
+                    match last_refresh.get(reponame) {
+                        Some(last) if now < *last + interval => false,
+                        _ => true,
+                    }
+                })
+                .collect();
+            for &(ref reponame, ..) in due.iter() {
+                last_refresh.insert(reponame.clone(), now);
+            }
+            due
+        })
+        .for_each({
+            cloned!(root_log);
+            move |due| {
+                cloned!(root_log);
+                stream::iter_ok(due).for_each(move |(reponame, params, handler)| {
+                    cloned!(root_log);
+                    refresh_repo(params, handler).then(move |res| {
+                        if let Err(err) = res {
+                            error!(
+                                root_log,
+                                "Failed to refresh streaming clone of repo {}", reponame;
+                                SlogKVError(err),
+                            );
+                        }
+                        Ok(())
+                    })
+                })
+            }
+        })
+        .map_err(move |err| error!(root_log, "Streaming clone refresh stopped"; SlogKVError(err)))
+}
+
+fn refresh_repo(params: StreamingCloneParams, handler: RepoHandler) -> BoxFuture<(), Error> {
+    let (logger, _, repo) = handler;
+    let blobrepo = repo.blobrepo();
+
+    blobrepo
+        .get_bookmark(&params.bookmark)
+        .and_then(move |changesetid| match changesetid {
+            Some(changesetid) if repo.streaming_clone() != Some(changesetid) => {
+                build_streaming_clone(blobrepo, changesetid, logger.clone())
+                    .map(move |()| {
+                        info!(logger, "Serving streaming clone at {}", changesetid);
+                        repo.set_streaming_clone(changesetid);
+                    })
+                    .boxify()
+            }
+            _ => future::ok(()).boxify(),
+        })
+        .boxify()
+}
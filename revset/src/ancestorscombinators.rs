@@ -22,7 +22,8 @@
 ///
 /// The stream below aims to solve the aforementioned problems. It's primary usage is in
 /// Mercurial pull to find commits that need to be sent to a client.
-use std::collections::{BTreeMap, HashSet};
+use std::cmp::min;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::collections::hash_set::IntoIter;
 use std::iter;
 use std::sync::Arc;
@@ -88,9 +89,10 @@ pub struct DifferenceOfUnionsOfAncestorsNodeStream {
     // returned
     current_generation: Generation,
 
-    // Parents of entries from `drain`. We fetch generation number for them.
+    // Parents of entries from `drain`, together with their distance from the requested nodes.
+    // We fetch generation number for them.
     pending_changesets:
-        SelectAll<Box<Stream<Item = (HgNodeHash, Generation), Error = Error> + Send>>,
+        SelectAll<Box<Stream<Item = (HgNodeHash, Generation, usize), Error = Error> + Send>>,
 
     // Stream of (Hashset, Generation) that needs to be excluded
     exclude_ancestors: Peekable<stream::Fuse<GroupedByGenenerationStream>>,
@@ -101,12 +103,21 @@ pub struct DifferenceOfUnionsOfAncestorsNodeStream {
 
     // max heap of all relevant unique generation numbers
     sorted_unique_generations: UniqueHeap<Generation>,
+
+    // If set, only nodes that are less than `depth` steps away from the requested nodes are
+    // returned
+    depth: Option<usize>,
+
+    // Shortest distance from the requested nodes to the nodes that weren't returned yet. Only
+    // tracked if `depth` is set.
+    distances: HashMap<HgNodeHash, usize>,
 }
 
 fn make_pending(
     repo: Arc<BlobRepo>,
     hash: HgNodeHash,
-) -> Box<Stream<Item = (HgNodeHash, Generation), Error = Error> + Send> {
+    parent_distance: usize,
+) -> Box<Stream<Item = (HgNodeHash, Generation, usize), Error = Error> + Send> {
     let new_repo_changesets = repo.clone();
     let new_repo_gennums = repo.clone();
 
@@ -127,7 +138,7 @@ fn make_pending(
                     .and_then(move |genopt| {
                         genopt.ok_or_else(|| err_msg(format!("{} not found", node_hash)))
                     })
-                    .map(move |gen_id| (node_hash, gen_id))
+                    .map(move |gen_id| (node_hash, gen_id, parent_distance))
                     .map_err(|err| err.context(ErrorKind::GenerationFetchFailed).into())
             }),
     )
@@ -147,6 +158,22 @@ impl DifferenceOfUnionsOfAncestorsNodeStream {
         hashes: Vec<HgNodeHash>,
         excludes: Vec<HgNodeHash>,
     ) -> Box<NodeStream> {
+        Self::new_with_excludes_and_depth(repo, hashes, excludes, None)
+    }
+
+    /// Like `new_with_excludes`, but if `depth` is set only ancestors that are less than `depth`
+    /// steps away from `hashes` are returned, e.g. depth 1 returns just `hashes`. This is the
+    /// set of changesets of a shallow clone.
+    pub fn new_with_excludes_and_depth(
+        repo: &Arc<BlobRepo>,
+        hashes: Vec<HgNodeHash>,
+        excludes: Vec<HgNodeHash>,
+        depth: Option<usize>,
+    ) -> Box<NodeStream> {
+        if depth == Some(0) {
+            return empty().boxify();
+        }
+
         let excludes = if !excludes.is_empty() {
             Self::new_union(repo, excludes)
         } else {
@@ -160,7 +187,11 @@ impl DifferenceOfUnionsOfAncestorsNodeStream {
                 move |hashes_generations| {
                     let mut next_generation = BTreeMap::new();
                     let mut sorted_unique_generations = UniqueHeap::new();
+                    let mut distances = HashMap::new();
                     for (hash, generation) in hashes_generations {
+                        if depth.is_some() {
+                            distances.insert(hash, 0);
+                        }
                         next_generation
                             .entry(generation.clone())
                             .or_insert_with(HashSet::new)
@@ -182,6 +213,8 @@ impl DifferenceOfUnionsOfAncestorsNodeStream {
                             .peekable(),
                         drain: hashset!{}.into_iter().peekable(),
                         sorted_unique_generations,
+                        depth,
+                        distances,
                     }.boxify()
                 }
             })
@@ -240,12 +273,21 @@ impl Stream for DifferenceOfUnionsOfAncestorsNodeStream {
                 let next_in_drain = *self.drain.peek().unwrap();
                 if try_ready!(self.exclude_node(next_in_drain, current_generation)) {
                     self.drain.next();
+                    self.distances.remove(&next_in_drain);
                     continue;
                 } else {
-                    let next_in_drain = self.drain.next();
-                    self.pending_changesets
-                        .push(make_pending(self.repo.clone(), next_in_drain.unwrap()));
-                    return Ok(Async::Ready(next_in_drain));
+                    let next_in_drain = self.drain.next().unwrap();
+                    // All children of the node have been returned already, because they have
+                    // bigger generation numbers, so its distance is final
+                    let parent_distance = self.distances.remove(&next_in_drain).unwrap_or(0) + 1;
+                    if self.depth.map_or(true, |depth| parent_distance < depth) {
+                        self.pending_changesets.push(make_pending(
+                            self.repo.clone(),
+                            next_in_drain,
+                            parent_distance,
+                        ));
+                    }
+                    return Ok(Async::Ready(Some(next_in_drain)));
                 }
             }
 
@@ -253,7 +295,11 @@ impl Stream for DifferenceOfUnionsOfAncestorsNodeStream {
             // know about all parents of the just-output generation
             loop {
                 match self.pending_changesets.poll()? {
-                    Async::Ready(Some((hash, generation))) => {
+                    Async::Ready(Some((hash, generation, distance))) => {
+                        if self.depth.is_some() {
+                            let min_distance = self.distances.entry(hash).or_insert(distance);
+                            *min_distance = min(*min_distance, distance);
+                        }
                         self.next_generation
                             .entry(generation)
                             .or_insert_with(HashSet::new)
@@ -524,6 +570,71 @@ mod test {
         });
     }
 
+    #[test]
+    fn linear_ancestors_with_depth() {
+        async_unit::tokio_unit_test(|| {
+            let repo = Arc::new(linear::getrepo(None));
+
+            let nodestream = DifferenceOfUnionsOfAncestorsNodeStream::new_with_excludes_and_depth(
+                &repo,
+                vec![
+                    string_to_nodehash("a9473beb2eb03ddb1cccc3fbaeb8a4820f9cd157"),
+                ],
+                vec![],
+                Some(3),
+            ).boxify();
+
+            assert_node_sequence(
+                &repo,
+                vec![
+                    string_to_nodehash("a9473beb2eb03ddb1cccc3fbaeb8a4820f9cd157"),
+                    string_to_nodehash("0ed509bf086fadcb8a8a5384dc3b550729b0fc17"),
+                    string_to_nodehash("eed3a8c0ec67b6a6fe2eb3543334df3f0b4f202b"),
+                ],
+                nodestream,
+            );
+
+            let nodestream = DifferenceOfUnionsOfAncestorsNodeStream::new_with_excludes_and_depth(
+                &repo,
+                vec![
+                    string_to_nodehash("a9473beb2eb03ddb1cccc3fbaeb8a4820f9cd157"),
+                ],
+                vec![],
+                Some(0),
+            ).boxify();
+
+            assert_node_sequence(&repo, vec![], nodestream);
+        });
+    }
+
+    #[test]
+    fn merge_uneven_ancestors_with_depth() {
+        async_unit::tokio_unit_test(|| {
+            let repo = Arc::new(merge_uneven::getrepo(None));
+
+            // The second parent of the merge has a much lower generation number than the first
+            // one, but it's still just one step away
+            let nodestream = DifferenceOfUnionsOfAncestorsNodeStream::new_with_excludes_and_depth(
+                &repo,
+                vec![
+                    string_to_nodehash("75742e6fc286a359b39a89fdfa437cc7e2a0e1ce"),
+                ],
+                vec![],
+                Some(2),
+            ).boxify();
+
+            assert_node_sequence(
+                &repo,
+                vec![
+                    string_to_nodehash("75742e6fc286a359b39a89fdfa437cc7e2a0e1ce"),
+                    string_to_nodehash("264f01429683b3dd8042cb3979e8bf37007118bc"),
+                    string_to_nodehash("16839021e338500b3cf7c9b871c8a07351697d68"),
+                ],
+                nodestream,
+            );
+        });
+    }
+
     #[test]
     fn linear_ancestors_with_excludes_empty() {
         async_unit::tokio_unit_test(|| {
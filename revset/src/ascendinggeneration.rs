@@ -0,0 +1,305 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+/// All other revsets produce nodes in descending generation order, i.e. children before their
+/// parents. Mercurial expects changegroups the other way around, so that every changeset arrives
+/// after its parents. Mononoke doesn't store children of a changeset, so the input can only be
+/// walked from the top. To keep memory bounded the nodes are returned in windows: each pass over
+/// the input keeps only the lowest generations that weren't returned yet, up to `window_size`
+/// nodes, and stops as soon as it reaches generations that were already returned. A window is
+/// returned completely before the next pass starts, so a slow consumer holds back the input.
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use failure::{err_msg, Error};
+use futures::{Async, Poll};
+use futures::future::Future;
+use futures::stream::Stream;
+
+use blobrepo::BlobRepo;
+use mercurial_types::HgNodeHash;
+use mercurial_types::nodehash::HgChangesetId;
+use mononoke_types::Generation;
+
+use NodeStream;
+use errors::*;
+
+/// How many generation numbers are fetched concurrently while reading the input
+const GENERATION_FETCH_BUFFER: usize = 100;
+
+/// How many nodes are kept in memory by default. Only hashes and generation numbers are kept,
+/// never the changesets themselves.
+const DEFAULT_WINDOW_SIZE: usize = 100_000;
+
+type GenerationStream = Box<Stream<Item = (HgNodeHash, Generation), Error = Error> + Send>;
+
+/// Reorders the nodes of a NodeStream so that they come out in ascending generation order, which
+/// is a topological order: every node is returned after all of its ancestors from the input.
+/// `make_input` is called once per window and has to return the same nodes every time, in
+/// descending generation order.
+pub struct AscendingGenerationNodeStream {
+    repo: Arc<BlobRepo>,
+    make_input: Box<Fn() -> Box<NodeStream> + Send>,
+    window_size: usize,
+
+    // The current pass over the input. None between passes.
+    input: Option<GenerationStream>,
+
+    // Generation of the last node read in the current pass.
+    last_generation: Option<Generation>,
+
+    // All generations up to and including this one were returned already.
+    returned_up_to: Option<Generation>,
+
+    // Lowest nodes read in the current pass, in descending generation order. Whole generations
+    // are dropped from the front when it grows over `window_size`.
+    window: VecDeque<(HgNodeHash, Generation)>,
+
+    // Whether the current pass had to drop generations, i.e. another pass is needed.
+    more_above: bool,
+
+    // Nodes of the window that is being returned, in descending generation order.
+    drain: Vec<HgNodeHash>,
+
+    done: bool,
+}
+
+impl AscendingGenerationNodeStream {
+    pub fn new<F>(repo: &Arc<BlobRepo>, make_input: F) -> Self
+    where
+        F: Fn() -> Box<NodeStream> + Send + 'static,
+    {
+        Self::new_with_window_size(repo, make_input, DEFAULT_WINDOW_SIZE)
+    }
+
+    /// Keep at most `window_size` nodes in memory. A single generation is never split between
+    /// windows, so a generation wider than `window_size` is kept whole.
+    pub fn new_with_window_size<F>(repo: &Arc<BlobRepo>, make_input: F, window_size: usize) -> Self
+    where
+        F: Fn() -> Box<NodeStream> + Send + 'static,
+    {
+        AscendingGenerationNodeStream {
+            repo: repo.clone(),
+            make_input: Box::new(make_input),
+            window_size: window_size.max(1),
+            input: None,
+            last_generation: None,
+            returned_up_to: None,
+            window: VecDeque::new(),
+            more_above: false,
+            drain: Vec::new(),
+            done: false,
+        }
+    }
+
+    pub fn boxed(self) -> Box<NodeStream> {
+        Box::new(self)
+    }
+
+    fn start_pass(&self) -> GenerationStream {
+        let repo = self.repo.clone();
+        let input = (self.make_input)()
+            .map(move |node_hash| {
+                repo.get_generation_number(&HgChangesetId::new(node_hash))
+                    .and_then(move |genopt| {
+                        genopt.ok_or_else(|| err_msg(format!("{} not found", node_hash)))
+                    })
+                    .map(move |gen_id| (node_hash, gen_id))
+                    .map_err(|err| err.context(ErrorKind::GenerationFetchFailed).into())
+            })
+            .buffered(GENERATION_FETCH_BUFFER);
+        Box::new(input)
+    }
+
+    fn add_to_window(&mut self, hash: HgNodeHash, generation: Generation) {
+        self.window.push_back((hash, generation));
+        while self.window.len() > self.window_size {
+            let highest = match (self.window.front(), self.window.back()) {
+                // Never drop the lowest generation, or the pass would make no progress
+                (Some(&(_, highest)), Some(&(_, lowest))) if highest != lowest => highest,
+                _ => break,
+            };
+            while self.window.front().map(|&(_, generation)| generation) == Some(highest) {
+                self.window.pop_front();
+            }
+            self.more_above = true;
+        }
+    }
+
+    fn finish_pass(&mut self) {
+        self.input = None;
+        self.last_generation = None;
+        match self.window.front() {
+            Some(&(_, highest)) => self.returned_up_to = Some(highest),
+            None => self.done = true,
+        }
+        if !self.more_above {
+            self.done = true;
+        }
+        self.more_above = false;
+        self.drain = self.window.drain(..).map(|(hash, _)| hash).collect();
+    }
+}
+
+impl Stream for AscendingGenerationNodeStream {
+    type Item = HgNodeHash;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            if let Some(next) = self.drain.pop() {
+                return Ok(Async::Ready(Some(next)));
+            }
+            if self.done {
+                return Ok(Async::Ready(None));
+            }
+            if self.input.is_none() {
+                self.input = Some(self.start_pass());
+            }
+
+            loop {
+                let next_input = match self.input {
+                    Some(ref mut input) => try_ready!(input.poll()),
+                    None => break,
+                };
+                let (hash, generation) = match next_input {
+                    Some(next_input) => next_input,
+                    None => {
+                        self.finish_pass();
+                        break;
+                    }
+                };
+
+                if self.last_generation.map_or(false, |last| generation > last) {
+                    return Err(ErrorKind::GenerationOrderViolated(hash).into());
+                }
+                self.last_generation = Some(generation);
+
+                // Everything from here on was returned in an earlier window
+                if self.returned_up_to.map_or(false, |up_to| generation <= up_to) {
+                    self.finish_pass();
+                    break;
+                }
+                self.add_to_window(hash, generation);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use DifferenceOfUnionsOfAncestorsNodeStream;
+    use async_unit;
+    use linear;
+    use merge_uneven;
+    use tests::assert_node_sequence;
+    use tests::string_to_nodehash;
+
+    #[test]
+    fn linear_ascending() {
+        async_unit::tokio_unit_test(|| {
+            let repo = Arc::new(linear::getrepo(None));
+
+            let nodestream = AscendingGenerationNodeStream::new(&repo, {
+                let repo = repo.clone();
+                move || {
+                    DifferenceOfUnionsOfAncestorsNodeStream::new_with_excludes(
+                        &repo,
+                        vec![
+                            string_to_nodehash("a9473beb2eb03ddb1cccc3fbaeb8a4820f9cd157"),
+                        ],
+                        vec![
+                            string_to_nodehash("cb15ca4a43a59acff5388cea9648c162afde8372"),
+                        ],
+                    )
+                }
+            }).boxed();
+
+            assert_node_sequence(
+                &repo,
+                vec![
+                    string_to_nodehash("eed3a8c0ec67b6a6fe2eb3543334df3f0b4f202b"),
+                    string_to_nodehash("0ed509bf086fadcb8a8a5384dc3b550729b0fc17"),
+                    string_to_nodehash("a9473beb2eb03ddb1cccc3fbaeb8a4820f9cd157"),
+                ],
+                nodestream,
+            );
+        })
+    }
+
+    fn merge_uneven_input(repo: &Arc<BlobRepo>) -> impl Fn() -> Box<NodeStream> + Send {
+        let repo = repo.clone();
+        move || {
+            DifferenceOfUnionsOfAncestorsNodeStream::new(
+                &repo,
+                string_to_nodehash("75742e6fc286a359b39a89fdfa437cc7e2a0e1ce"),
+            )
+        }
+    }
+
+    fn merge_uneven_expected() -> Vec<HgNodeHash> {
+        vec![
+            string_to_nodehash("15c40d0abc36d47fb51c8eaec51ac7aad31f669c"),
+            string_to_nodehash("d7542c9db7f4c77dab4b315edd328edf1514952f"),
+            string_to_nodehash("3cda5c78aa35f0f5b09780d971197b51cad4613a"),
+            string_to_nodehash("b65231269f651cfe784fd1d97ef02a049a37b8a0"),
+            string_to_nodehash("1d8a907f7b4bf50c6a09c16361e2205047ecc5e5"),
+            string_to_nodehash("4f7f3fd428bec1a48f9314414b063c706d9c1aed"),
+            string_to_nodehash("16839021e338500b3cf7c9b871c8a07351697d68"),
+            string_to_nodehash("795b8133cf375f6d68d27c6c23db24cd5d0cd00f"),
+            string_to_nodehash("bc7b4d0f858c19e2474b03e442b8495fd7aeef33"),
+            string_to_nodehash("fc2cef43395ff3a7b28159007f63d6529d2f41ca"),
+            string_to_nodehash("5d43888a3c972fe68c224f93d41b30e9f888df7c"),
+            string_to_nodehash("264f01429683b3dd8042cb3979e8bf37007118bc"),
+            string_to_nodehash("75742e6fc286a359b39a89fdfa437cc7e2a0e1ce"),
+        ]
+    }
+
+    #[test]
+    fn merge_uneven_ascending() {
+        async_unit::tokio_unit_test(|| {
+            let repo = Arc::new(merge_uneven::getrepo(None));
+
+            let nodestream =
+                AscendingGenerationNodeStream::new(&repo, merge_uneven_input(&repo)).boxed();
+
+            assert_node_sequence(&repo, merge_uneven_expected(), nodestream);
+        })
+    }
+
+    #[test]
+    fn merge_uneven_ascending_small_window() {
+        async_unit::tokio_unit_test(|| {
+            let repo = Arc::new(merge_uneven::getrepo(None));
+
+            // Several passes over the input, with generations wider than the window
+            for window_size in 1..4 {
+                let nodestream = AscendingGenerationNodeStream::new_with_window_size(
+                    &repo,
+                    merge_uneven_input(&repo),
+                    window_size,
+                ).boxed();
+
+                assert_node_sequence(&repo, merge_uneven_expected(), nodestream);
+            }
+        })
+    }
+
+    #[test]
+    fn empty_ascending() {
+        async_unit::tokio_unit_test(|| {
+            let repo = Arc::new(linear::getrepo(None));
+
+            let nodestream = AscendingGenerationNodeStream::new(&repo, {
+                let repo = repo.clone();
+                move || DifferenceOfUnionsOfAncestorsNodeStream::new_union(&repo, vec![])
+            }).boxed();
+
+            assert_node_sequence(&repo, vec![], nodestream);
+        })
+    }
+}
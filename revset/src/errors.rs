@@ -13,4 +13,6 @@ pub enum ErrorKind {
     #[fail(display = "repo error checking for node: {}", _0)] RepoError(HgNodeHash),
     #[fail(display = "could not fetch node generation")] GenerationFetchFailed,
     #[fail(display = "failed to fetch parent nodes")] ParentsFetchFailed,
+    #[fail(display = "node {} is out of descending generation order", _0)]
+    GenerationOrderViolated(HgNodeHash),
 }
@@ -49,6 +49,9 @@ pub use ancestorscombinators::DifferenceOfUnionsOfAncestorsNodeStream;
 mod range;
 pub use range::RangeNodeStream;
 
+mod ascendinggeneration;
+pub use ascendinggeneration::AscendingGenerationNodeStream;
+
 mod uniqueheap;
 use uniqueheap::UniqueHeap;
 
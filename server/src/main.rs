@@ -8,8 +8,11 @@
 #![feature(never_type)]
 
 extern crate clap;
+#[macro_use]
+extern crate cloned;
 extern crate failure_ext as failure;
 extern crate futures;
+extern crate futures_ext;
 extern crate openssl;
 extern crate secure_utils;
 extern crate services;
@@ -38,17 +41,21 @@ use std::io;
 use std::panic;
 use std::path::PathBuf;
 use std::str::FromStr;
+use std::time::{Duration, Instant};
 
 use clap::{App, ArgMatches};
 use failure::SlogKVError;
-use futures::Future;
+use futures::{stream, Future, Stream};
+use futures_ext::{BoxStream, StreamExt};
 use slog::{Drain, Level, Logger};
 use slog_glog_fmt::{kv_categorizer, kv_defaults, GlogFormat};
 use slog_logview::LogViewDrain;
 
 use blobrepo::BlobRepo;
-use mercurial_types::RepositoryId;
+use bookmarks::Bookmark;
+use mercurial_types::{HgChangesetId, RepositoryId};
 use metaconfig::RepoConfigs;
+use repo_listener::LoadLimits;
 
 use errors::*;
 
@@ -68,16 +75,30 @@ fn setup_app<'a, 'b>() -> App<'a, 'b> {
         .about("serve repos")
         .args_from_usage(
             r#"
-            <crpath>      -P, --configrepo_path [PATH]           'path to the config repo in rocksdb form'
+            [crpath]      -P, --configrepo_path [PATH]           'path to the config repo in rocksdb form'
 
             -C, --configrepo_hash [HASH]                         'config repo commit hash'
 
-            <crbook>      -C, --configrepo_book [BOOK]           'config repo bookmark'
+            [crbook]      -C, --configrepo_book [BOOK]           'config repo bookmark'
+
+            [config-dir]  --config-dir [PATH]                    'directory with the config files, instead of a config repo'
+
+                          --config-poll-interval [SECS]          'how often to check the config repo bookmark or config directory for a new config'
 
                           --listening-host-port <PATH>           'tcp address to listen to in format `host:port`'
 
+                          --http-listening-host-port [PATH]      'if provided, also serve the hg http protocol on this `host:port`'
+
             -p, --thrift_port [PORT] 'if provided the thrift server will start on this port'
 
+                          --max-sessions [N]                     'maximum number of sessions served at the same time'
+                          --max-sessions-per-client [N]          'maximum number of sessions of a single client served at the same time'
+                          --max-expensive-commands [N]           'maximum number of getbundle, gettreepack and getfiles commands served at the same time'
+                          --max-expensive-commands-per-client [N] 'maximum number of expensive commands of a single client served at the same time'
+                          --max-queue-wait-secs [SECS]           'how long sessions and commands over the limits wait before being rejected'
+
+                          --record-sessions-dir [PATH]           'if provided, record the requests of every ssh session into a file in this directory'
+
             <cert>        --cert [PATH]                         'path to a file with certificate'
             <private_key> --private-key [PATH]                  'path to a file with private key'
             <ca_pem>      --ca-pem [PATH]                       'path to a file with CA certificate'
@@ -113,29 +134,58 @@ fn setup_logger<'a>(matches: &ArgMatches<'a>) -> Logger {
     )
 }
 
-fn get_config<'a>(logger: &Logger, matches: &ArgMatches<'a>) -> Result<RepoConfigs> {
+fn get_config<'a>(
+    logger: &Logger,
+    matches: &ArgMatches<'a>,
+) -> Result<(RepoConfigs, BoxStream<RepoConfigs, Error>)> {
+    let interval = matches
+        .value_of("config-poll-interval")
+        .map(|secs| secs.parse::<u64>())
+        .unwrap_or(Ok(DEFAULT_CONFIG_POLL_INTERVAL_SECS))?;
+    let interval = Duration::from_secs(interval);
+
+    if let Some(config_dir) = matches.value_of("config-dir") {
+        info!(logger, "Config will be read from directory: {}", config_dir);
+        let config = RepoConfigs::read_from_dir(config_dir).wait()?;
+        let updates = config_dir_updates(
+            logger.clone(),
+            PathBuf::from(config_dir),
+            config.clone(),
+            interval,
+        );
+        return Ok((config, updates));
+    }
+
     // TODO: This needs to cope with blob repos, too
-    let crpath = PathBuf::from(matches.value_of("crpath").unwrap());
+    let crpath = PathBuf::from(
+        matches
+            .value_of("crpath")
+            .expect("either configrepo_path or config-dir must be specified"),
+    );
     let config_repo = BlobRepo::new_rocksdb(
         logger.new(o!["repo" => "Config repo"]),
         &crpath,
         RepositoryId::new(0),
     )?;
 
-    let changesetid = match matches.value_of("crbook") {
+    let (changesetid, book) = match matches.value_of("crbook") {
         Some(book) => {
-            let book = bookmarks::Bookmark::new(book).expect("book must be ascii");
+            let book = Bookmark::new(book).expect("book must be ascii");
             println!("Looking for bookmark {:?}", book);
-            config_repo
+            let changesetid = config_repo
                 .get_bookmark(&book)
                 .wait()?
-                .expect("bookmark not found")
+                .expect("bookmark not found");
+            (changesetid, Some(book))
         }
-        None => mercurial_types::nodehash::HgChangesetId::from_str(
-            matches
-                .value_of("crhash")
-                .expect("crhash and crbook are not specified"),
-        )?,
+        None => (
+            HgChangesetId::from_str(
+                matches
+                    .value_of("crhash")
+                    .expect("crhash and crbook are not specified"),
+            )?,
+            None,
+        ),
     };
 
     info!(
@@ -143,9 +193,135 @@ fn get_config<'a>(logger: &Logger, matches: &ArgMatches<'a>) -> Result<RepoConfi
         "Config repository will be read from commit: {}", changesetid
     );
 
-    RepoConfigs::read_config_repo(config_repo, changesetid)
+    let config = RepoConfigs::read_config_repo(config_repo.clone(), changesetid)
         .from_err()
-        .wait()
+        .wait()?;
+
+    // A pinned commit never changes, so there is nothing to poll for
+    let updates = match book {
+        Some(book) => config_updates(logger.clone(), config_repo, book, changesetid, interval),
+        None => stream::empty().boxify(),
+    };
+
+    Ok((config, updates))
+}
+
+const DEFAULT_CONFIG_POLL_INTERVAL_SECS: u64 = 30;
+const DEFAULT_MAX_QUEUE_WAIT_SECS: u64 = 10;
+
+/// Limits on sessions and on expensive commands, unlimited unless set on the command line
+fn get_load_limits<'a>(matches: &ArgMatches<'a>) -> Result<(LoadLimits, LoadLimits)> {
+    let parse_limit = |name: &str| -> Result<usize> {
+        match matches.value_of(name) {
+            Some(limit) => Ok(limit.parse::<usize>()?),
+            None => Ok(LoadLimits::unlimited().max_total),
+        }
+    };
+    let max_wait = matches
+        .value_of("max-queue-wait-secs")
+        .map(|secs| secs.parse::<u64>())
+        .unwrap_or(Ok(DEFAULT_MAX_QUEUE_WAIT_SECS))?;
+    let max_wait = Duration::from_secs(max_wait);
+
+    let sessions = LoadLimits {
+        max_total: parse_limit("max-sessions")?,
+        max_per_client: parse_limit("max-sessions-per-client")?,
+        max_wait,
+    };
+    let commands = LoadLimits {
+        max_total: parse_limit("max-expensive-commands")?,
+        max_per_client: parse_limit("max-expensive-commands-per-client")?,
+        max_wait,
+    };
+    Ok((sessions, commands))
+}
+
+/// Poll the config repo bookmark and yield a new config every time it moves. A commit with a
+/// config that fails to parse is logged and skipped, the server keeps the config it has until the
+/// bookmark moves again.
+fn config_updates(
+    logger: Logger,
+    config_repo: BlobRepo,
+    book: Bookmark,
+    current: HgChangesetId,
+    interval: Duration,
+) -> BoxStream<RepoConfigs, Error> {
+    let mut last_seen = current;
+
+    tokio::timer::Interval::new(Instant::now() + interval, interval)
+        .map_err(Error::from)
+        .and_then({
+            cloned!(config_repo);
+            move |_| config_repo.get_bookmark(&book)
+        })
+        .filter_map(move |maybe_changesetid| match maybe_changesetid {
+            Some(changesetid) if changesetid != last_seen => {
+                last_seen = changesetid;
+                Some(changesetid)
+            }
+            _ => None,
+        })
+        .and_then({
+            cloned!(logger);
+            move |changesetid| {
+                info!(
+                    logger,
+                    "Config repository bookmark moved, reading config from commit: {}", changesetid
+                );
+                RepoConfigs::read_config_repo(config_repo.clone(), changesetid).from_err()
+            }
+        })
+        .then(move |res| match res {
+            Ok(config) => Ok(Some(config)),
+            Err(err) => {
+                error!(logger, "Failed to read new config"; SlogKVError(err));
+                Ok(None)
+            }
+        })
+        .filter_map(|config| config)
+        .boxify()
+}
+
+/// Reread the config directory periodically and yield the new config every time it changes. A
+/// config that fails to parse is logged and skipped, the server keeps the config it has until
+/// the directory changes again.
+fn config_dir_updates(
+    logger: Logger,
+    config_dir: PathBuf,
+    current: RepoConfigs,
+    interval: Duration,
+) -> BoxStream<RepoConfigs, Error> {
+    let mut last_seen = current;
+
+    tokio::timer::Interval::new(Instant::now() + interval, interval)
+        .map_err(Error::from)
+        .and_then({
+            cloned!(config_dir);
+            move |_| RepoConfigs::read_from_dir(&config_dir)
+        })
+        .then({
+            cloned!(logger);
+            move |res| match res {
+                Ok(config) => Ok(Some(config)),
+                Err(err) => {
+                    error!(logger, "Failed to read new config"; SlogKVError(err));
+                    Ok(None)
+                }
+            }
+        })
+        .filter_map(move |config| match config {
+            Some(config) if config != last_seen => {
+                info!(
+                    logger,
+                    "Config directory changed, reading config from: {}",
+                    config_dir.display()
+                );
+                last_seen = config.clone();
+                Some(config)
+            }
+            _ => None,
+        })
+        .boxify()
 }
 
 fn main() {
@@ -170,7 +346,8 @@ fn main() {
         let stats_aggregation = stats::schedule_stats_aggregation()
             .expect("failed to create stats aggregation scheduler");
 
-        let config = get_config(root_log, &matches)?;
+        let (config, config_updates) = get_config(root_log, &matches)?;
+        let (session_limits, command_limits) = get_load_limits(&matches)?;
         let cert = matches.value_of("cert").unwrap().to_string();
         let private_key = matches.value_of("private_key").unwrap().to_string();
         let ca_pem = matches.value_of("ca_pem").unwrap().to_string();
@@ -187,7 +364,12 @@ fn main() {
             matches
                 .value_of("listening-host-port")
                 .expect("listening path must be specified"),
+            matches.value_of("http-listening-host-port"),
             secure_utils::build_tls_acceptor(ssl).expect("failed to build tls acceptor"),
+            config_updates,
+            session_limits,
+            command_limits,
+            matches.value_of("record-sessions-dir").map(PathBuf::from),
         );
 
         tracing_fb303::register();
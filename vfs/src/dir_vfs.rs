@@ -0,0 +1,303 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::collections::HashSet;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use bytes::Bytes;
+use futures::{future, Future};
+
+use mercurial_types::manifest::Content;
+use mononoke_types::FileContents;
+use mononoke_types::path::{MPathElement, DOT, DOTDOT};
+
+use node::{VfsDir, VfsFile, VfsNode};
+use tree::{TNodeId, Tree, TreeValue, ROOT_ID};
+
+use errors::*;
+
+const INCONSISTENCY: &str = "Internal inconsistency in Tree detected, a nodeid is missing";
+
+/// Directories of version control systems, which are never part of the Vfs
+const SKIPPED_DIRS: &[&str] = &[".hg", ".git"];
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+struct TEntryId(usize);
+
+/// For a given directory on disk return a VfsDir representing the file system below it. The
+/// directory structure is read once, when the Vfs is created, but the content of files is read
+/// from disk every time they are read.
+pub fn vfs_from_dir<P>(path: P) -> Result<DirVfsDir>
+where
+    P: AsRef<Path>,
+{
+    let mut path_tree = Tree::new();
+    let mut entries = vec![];
+    add_dir(
+        path.as_ref(),
+        &mut vec![],
+        &mut HashSet::new(),
+        &mut path_tree,
+        &mut entries,
+    )?;
+    Ok(DirVfsDir {
+        root: Arc::new(DirVfsRoot { entries, path_tree }),
+        nodeid: ROOT_ID,
+    })
+}
+
+/// Add the content of `dir` to the tree. `ancestors` are the canonical paths of the directories
+/// that are being added, so that a symlink to one of them is reported instead of followed forever.
+fn add_dir(
+    dir: &Path,
+    prefix: &mut Vec<MPathElement>,
+    ancestors: &mut HashSet<PathBuf>,
+    path_tree: &mut Tree<MPathElement, TEntryId>,
+    entries: &mut Vec<PathBuf>,
+) -> Result<()> {
+    let canonical = fs::canonicalize(dir).with_context(|_| format!("failed to read {:?}", dir))?;
+    if !ancestors.insert(canonical.clone()) {
+        return Err(ErrorKind::DirInvalidPath(format!(
+            "{:?} is a symlink loop, it points to {:?}",
+            dir, canonical
+        )).into());
+    }
+    for dir_entry in fs::read_dir(dir).with_context(|_| format!("failed to read {:?}", dir))? {
+        let dir_entry = dir_entry?;
+        let path = dir_entry.path();
+        let name = dir_entry.file_name();
+        let name = name.to_str().ok_or_else(|| {
+            ErrorKind::DirInvalidPath(format!("{:?} is not valid utf-8", path))
+        })?;
+        let element = MPathElement::new(name.as_bytes().to_vec())
+            .with_context(|_| ErrorKind::DirInvalidPath(format!("{:?}", path)))?;
+
+        // Follow symlinks, the Vfs exposes what they point to
+        let metadata = fs::metadata(&path)?;
+        if metadata.is_dir() {
+            if SKIPPED_DIRS.contains(&name) {
+                continue;
+            }
+            prefix.push(element);
+            add_dir(&path, prefix, ancestors, path_tree, entries)?;
+            prefix.pop();
+        } else {
+            path_tree.insert(prefix.clone(), element, TEntryId(entries.len()))?;
+            entries.push(path);
+        }
+    }
+    ancestors.remove(&canonical);
+    Ok(())
+}
+
+#[derive(Debug)]
+struct DirVfsRoot {
+    entries: Vec<PathBuf>,
+    path_tree: Tree<MPathElement, TEntryId>,
+}
+
+impl DirVfsRoot {
+    fn get_node(this: &Arc<Self>, nodeid: TNodeId) -> VfsNode<DirVfsDir, DirVfsFile> {
+        match this.path_tree.get_value(nodeid).expect(INCONSISTENCY) {
+            &TreeValue::Leaf(_) => VfsNode::File(DirVfsFile {
+                root: this.clone(),
+                nodeid,
+            }),
+            &TreeValue::Node(_) => VfsNode::Dir(DirVfsDir {
+                root: this.clone(),
+                nodeid,
+            }),
+        }
+    }
+}
+
+/// Structure implementing the VfsDir interface that represents a dir within a directory on disk
+#[derive(Debug)]
+pub struct DirVfsDir {
+    root: Arc<DirVfsRoot>,
+    nodeid: TNodeId,
+}
+
+impl Clone for DirVfsDir {
+    fn clone(&self) -> Self {
+        DirVfsDir {
+            root: self.root.clone(),
+            nodeid: self.nodeid,
+        }
+    }
+}
+
+impl VfsDir for DirVfsDir {
+    type TFile = DirVfsFile;
+
+    fn read(&self) -> Vec<&MPathElement> {
+        self.root
+            .path_tree
+            .get_value(self.nodeid)
+            .expect(INCONSISTENCY)
+            .get_node()
+            .expect("Expected an internal node, not a leaf")
+            .keys()
+            .collect()
+    }
+
+    fn step(&self, path: &MPathElement) -> Option<VfsNode<Self, Self::TFile>> {
+        if path == &*DOT {
+            return Some(VfsNode::Dir(self.clone()));
+        }
+
+        let tree = &self.root.path_tree;
+        let nodeid = if path == &*DOTDOT {
+            tree.get_parent(self.nodeid)
+        } else {
+            tree.get_child(self.nodeid, path)
+        };
+        nodeid.map(|nodeid| DirVfsRoot::get_node(&self.root, nodeid))
+    }
+}
+
+/// Structure implementing the VfsFile interface that represents a file within a directory on disk
+#[derive(Debug)]
+pub struct DirVfsFile {
+    root: Arc<DirVfsRoot>,
+    nodeid: TNodeId,
+}
+
+impl Clone for DirVfsFile {
+    fn clone(&self) -> Self {
+        DirVfsFile {
+            root: self.root.clone(),
+            nodeid: self.nodeid,
+        }
+    }
+}
+
+impl VfsFile for DirVfsFile {
+    type TDir = DirVfsDir;
+
+    fn read(&self) -> Box<Future<Item = Content, Error = Error> + Send> {
+        let &TEntryId(entryid) = self.root
+            .path_tree
+            .get_value(self.nodeid)
+            .expect(INCONSISTENCY)
+            .get_leaf()
+            .expect("Expected a leaf, not an internal node");
+        let path = self.root
+            .entries
+            .get(entryid)
+            .expect("Path not found in entries list")
+            .clone();
+
+        Box::new(future::lazy(move || {
+            let bytes = fs::read(&path).with_context(|_| format!("failed to read {:?}", path))?;
+            let contents = FileContents::Bytes(Bytes::from(bytes));
+            let mode = fs::metadata(&path)?.permissions().mode();
+            if mode & 0o111 != 0 {
+                Ok(Content::Executable(contents))
+            } else {
+                Ok(Content::File(contents))
+            }
+        }))
+    }
+
+    fn parent_dir(&self) -> Self::TDir {
+        let parentid = self.root
+            .path_tree
+            .get_parent(self.nodeid)
+            .expect("No parent node found for a file");
+        match DirVfsRoot::get_node(&self.root, parentid) {
+            VfsNode::Dir(vfs) => vfs,
+            _ => panic!("Parent of a file is not a dir"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use test::*;
+
+    use std::fs::File;
+    use std::io::Write;
+    use std::os::unix::fs::symlink;
+
+    use tempdir::TempDir;
+
+    use mercurial_types::MPath;
+    use node::VfsWalker;
+
+    fn example_vfs() -> (TempDir, DirVfsDir) {
+        let tmpdir = TempDir::new("dir_vfs").expect("failed to create tempdir");
+        for (path, content) in vec![
+            ("a/b", "this is b"),
+            ("a/ab", ""),
+            ("c/d/e", ""),
+            ("c/ca/afsd", ""),
+            ("f", "this is f"),
+            (".hg/store", ""),
+        ] {
+            let path = tmpdir.path().join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            File::create(path)
+                .unwrap()
+                .write_all(content.as_bytes())
+                .unwrap();
+        }
+        let vfs = vfs_from_dir(tmpdir.path()).expect("failed to get vfs");
+        (tmpdir, vfs)
+    }
+
+    #[test]
+    fn test_dir() {
+        let (_tmpdir, vfs) = example_vfs();
+        cmp(vfs.read(), vec!["a", "c", "f"]);
+
+        match vfs.step(&pel("a")).unwrap() {
+            VfsNode::Dir(dir) => cmp(dir.read(), vec!["ab", "b"]),
+            _ => panic!("Expected dir, found file"),
+        }
+        assert!(vfs.step(&pel("g")).is_none());
+    }
+
+    #[test]
+    fn test_read_file() {
+        let (_tmpdir, vfs) = example_vfs();
+        let node = VfsWalker::new(vfs.into_node(), MPath::new("a/b").unwrap())
+            .walk()
+            .wait()
+            .expect("failed to walk");
+        let file = match node {
+            VfsNode::File(file) => file,
+            _ => panic!("Expected file, found dir"),
+        };
+        match file.read().wait().expect("failed to read") {
+            Content::File(FileContents::Bytes(bytes)) => assert_eq!(bytes.as_ref(), b"this is b"),
+            content => panic!("unexpected content {:?}", content),
+        }
+        cmp(file.parent_dir().read(), vec!["ab", "b"]);
+    }
+
+    #[test]
+    fn test_symlinks() {
+        let tmpdir = TempDir::new("dir_vfs").expect("failed to create tempdir");
+        fs::create_dir_all(tmpdir.path().join("a/b")).unwrap();
+        File::create(tmpdir.path().join("a/b/c")).unwrap();
+        // A symlink to a directory that is not an ancestor is followed
+        symlink(tmpdir.path().join("a/b"), tmpdir.path().join("d")).unwrap();
+        let vfs = vfs_from_dir(tmpdir.path()).expect("failed to get vfs");
+        cmp(vfs.read(), vec!["a", "d"]);
+        match vfs.step(&pel("d")).unwrap() {
+            VfsNode::Dir(dir) => cmp(dir.read(), vec!["c"]),
+            _ => panic!("Expected dir, found file"),
+        }
+
+        symlink(tmpdir.path().join("a"), tmpdir.path().join("a/b/loop")).unwrap();
+        assert!(vfs_from_dir(tmpdir.path()).is_err());
+    }
+}
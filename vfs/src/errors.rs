@@ -33,4 +33,8 @@ pub enum ErrorKind {
     /// One of the paths in entries listed by manifest contained an invalid (f.e. empty) Path
     #[fail(display = "manifest contained an invalid path: {}", _0)]
     ManifestInvalidPath(String),
+    /// One of the files in the directory a Vfs was created from has a name that can't be
+    /// represented as an MPathElement
+    #[fail(display = "directory contained an invalid path: {}", _0)]
+    DirInvalidPath(String),
 }
@@ -5,10 +5,11 @@
 // GNU General Public License version 2 or any later version.
 
 //! Provides traits for walking and reading the content of a Virtual File System as well as
-//! implementation of those traits for Vfs based on Manifest
+//! implementations of those traits for Vfs based on Manifest and on a directory on disk
 #![deny(missing_docs)]
 #![deny(warnings)]
 
+extern crate bytes;
 #[macro_use]
 extern crate failure_ext as failure;
 extern crate futures;
@@ -25,12 +26,16 @@ extern crate mononoke_types;
 extern crate boxfnonce;
 #[cfg(test)]
 extern crate mercurial_types_mocks;
+#[cfg(test)]
+extern crate tempdir;
 
+mod dir_vfs;
 pub mod errors;
 mod manifest_vfs;
 mod node;
 mod tree;
 
+pub use dir_vfs::{vfs_from_dir, DirVfsDir, DirVfsFile};
 pub use manifest_vfs::{vfs_from_manifest, ManifestVfsDir, ManifestVfsFile};
 pub use node::{VfsDir, VfsFile, VfsNode, VfsWalker};
 